@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, collections::VecDeque, time::Duration};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// How many latency samples to keep per RPC method when computing percentiles.
+const LATENCY_WINDOW: usize = 200;
+
+/// Cache and latency statistics for one kind of binder call.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BinderCallStats {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Number of calls that actually went over the network.
+    pub calls: u64,
+    pub failures: u64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+}
+
+#[derive(Default)]
+struct Entry {
+    stats: BinderCallStats,
+    latencies: VecDeque<Duration>,
+}
+
+static BINDER_STATS: Lazy<Mutex<BTreeMap<String, Entry>>> = Lazy::new(Default::default);
+
+/// Records whether a lookup in the binder cache hit, keyed by the cache key. Keys that embed an exit name, like the bridge lists, are folded together.
+pub fn record_cache(key: &str, hit: bool) {
+    let mut map = BINDER_STATS.lock();
+    let entry = map.entry(cache_category(key).to_string()).or_default();
+    if hit {
+        entry.stats.cache_hits += 1;
+    } else {
+        entry.stats.cache_misses += 1;
+    }
+}
+
+/// Records how long a binder RPC took over the network, including every retry across fronts.
+pub fn record_call(method: &str, latency: Duration, success: bool) {
+    let mut map = BINDER_STATS.lock();
+    let entry = map.entry(method.to_string()).or_default();
+    entry.stats.calls += 1;
+    if !success {
+        entry.stats.failures += 1;
+    }
+    entry.latencies.push_back(latency);
+    if entry.latencies.len() > LATENCY_WINDOW {
+        entry.latencies.pop_front();
+    }
+}
+
+/// Returns the statistics for every cache key and RPC method seen so far.
+pub fn binder_stats() -> Vec<(String, BinderCallStats)> {
+    BINDER_STATS
+        .lock()
+        .iter()
+        .map(|(name, entry)| {
+            let mut sorted: Vec<Duration> = entry.latencies.iter().copied().collect();
+            sorted.sort_unstable();
+            let mut stats = entry.stats.clone();
+            stats.latency_p50_ms = percentile_ms(&sorted, 0.50);
+            stats.latency_p90_ms = percentile_ms(&sorted, 0.90);
+            stats.latency_p99_ms = percentile_ms(&sorted, 0.99);
+            (name.clone(), stats)
+        })
+        .collect()
+}
+
+fn cache_category(key: &str) -> &str {
+    if key.starts_with("bridges") {
+        "bridges"
+    } else {
+        key
+    }
+}
+
+fn percentile_ms(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx].as_secs_f64() * 1000.0
+}
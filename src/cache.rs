@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{get_cache_dir, AuthOpt, CommonOpt},
+    l10n::{tr, tr_args},
+    storage,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct CacheOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+
+    #[structopt(subcommand)]
+    pub action: CacheAction,
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub enum CacheAction {
+    /// Lists the cache entries of the given user, with their size and expiry.
+    Show,
+    /// Clears the cache of the given user.
+    Clear {
+        #[structopt(long)]
+        /// Only clear the cached bridge lists, keeping credentials and the exit list.
+        bridges_only: bool,
+    },
+    /// Exports the cache of the given user to a JSON file.
+    Export { path: PathBuf },
+    /// Imports the cache of the given user from a JSON file produced by export.
+    Import { path: PathBuf },
+}
+
+/// An exported cache: a map from file name to hex-encoded contents.
+#[derive(Serialize, Deserialize)]
+struct CacheExport {
+    files: BTreeMap<String, String>,
+}
+
+/// Entry point to the cache subcommand, which inspects and manages the on-disk binder cache.
+pub fn main_cache(opt: CacheOpt) -> anyhow::Result<()> {
+    let dir = get_cache_dir(&opt.auth);
+    if !matches!(opt.action, CacheAction::Show) {
+        storage::refuse_if_ephemeral("this cache subcommand")?;
+    }
+    match opt.action {
+        CacheAction::Show => {
+            println!("cache directory: {:?}", dir);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            for (name, contents) in read_entries(&dir)? {
+                let key = name.trim_end_matches(".json");
+                match bincode::deserialize::<(u64, Bytes)>(&contents) {
+                    Ok((expiry, bts)) if expiry > now => {
+                        println!(
+                            "{:<40} {:>8} B  expires in {}s",
+                            key,
+                            bts.len(),
+                            expiry - now
+                        )
+                    }
+                    Ok((_, bts)) => println!("{:<40} {:>8} B  stale", key, bts.len()),
+                    Err(_) => println!("{:<40} {:>8} B  corrupted", key, contents.len()),
+                }
+            }
+        }
+        CacheAction::Clear { bridges_only } => {
+            if bridges_only {
+                for (name, _) in read_entries(&dir)? {
+                    if name.starts_with("bridges") {
+                        std::fs::remove_file(dir.join(&name))?;
+                    }
+                }
+            } else if dir.exists() {
+                std::fs::remove_dir_all(&dir)?;
+            }
+            println!("{}", tr("cache-cleared"));
+        }
+        CacheAction::Export { path } => {
+            let export = CacheExport {
+                files: read_entries(&dir)?
+                    .into_iter()
+                    .map(|(name, contents)| (name, hex::encode(contents)))
+                    .collect(),
+            };
+            std::fs::write(&path, serde_json::to_vec_pretty(&export)?)?;
+            println!(
+                "{}",
+                tr_args(
+                    "cache-exported",
+                    &[("count", &export.files.len()), ("path", &path.display())]
+                )
+            );
+        }
+        CacheAction::Import { path } => {
+            let export: CacheExport = serde_json::from_slice(&std::fs::read(&path)?)
+                .context("cannot parse cache export")?;
+            std::fs::create_dir_all(&dir)?;
+            for (name, contents) in export.files.iter() {
+                // never let an import write outside the cache directory
+                if name.contains(['/', '\\']) || name.starts_with('.') {
+                    anyhow::bail!("invalid cache entry name {:?}", name)
+                }
+                std::fs::write(dir.join(name), hex::decode(contents)?)?;
+            }
+            println!(
+                "{}",
+                tr_args(
+                    "cache-imported",
+                    &[("count", &export.files.len()), ("path", &path.display())]
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads every entry in the cache directory, sorted by name.
+fn read_entries(dir: &PathBuf) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
+    let mut entries = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(entries);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            entries.insert(
+                entry.file_name().to_string_lossy().to_string(),
+                std::fs::read(entry.path())?,
+            );
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use structopt::{
+    clap::{App, ArgSettings, Shell},
+    StructOpt,
+};
+
+use crate::config::{Cli, CommonOpt};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct CompletionsOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// Shell to generate completions for: bash, zsh, fish, powershell, or elvish.
+    pub shell: Option<String>,
+
+    #[structopt(long)]
+    /// Instead of completions, print a JSON description of every subcommand and flag, with their defaults and possible values.
+    pub schema_json: bool,
+}
+
+/// Entry point to the completions subcommand, which describes the command-line interface for shells and GUI wrappers.
+pub fn main_completions(opt: CompletionsOpt) -> anyhow::Result<()> {
+    let app = Cli::clap();
+    if opt.schema_json {
+        println!("{}", serde_json::to_string_pretty(&app_schema(&app))?);
+        return Ok(());
+    }
+    let shell: Shell = opt
+        .shell
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("must specify a shell, or --schema-json"))?
+        .parse()
+        .map_err(|e: String| anyhow::anyhow!(e))?;
+    let mut app = app;
+    app.gen_completions_to("geph4-client", shell, &mut std::io::stdout());
+    Ok(())
+}
+
+/// Describes a clap app and its subcommands as JSON.
+fn app_schema(app: &App) -> serde_json::Value {
+    let mut args = vec![];
+    for flag in app.p.flags.iter() {
+        if flag.b.name == "help" || flag.b.name == "version" {
+            continue;
+        }
+        args.push(json!({
+            "name": flag.b.name,
+            "long": flag.s.long,
+            "short": flag.s.short,
+            "help": flag.b.help,
+            "type": "flag",
+            "multiple": flag.b.is_set(ArgSettings::Multiple),
+        }));
+    }
+    for option in app.p.opts.iter() {
+        args.push(json!({
+            "name": option.b.name,
+            "long": option.s.long,
+            "short": option.s.short,
+            "help": option.b.help,
+            "type": "value",
+            "required": option.b.is_set(ArgSettings::Required),
+            "multiple": option.b.is_set(ArgSettings::Multiple),
+            "default": option.v.default_val.map(|v| v.to_string_lossy()),
+            "possible_values": option.v.possible_vals,
+        }));
+    }
+    for (_, positional) in app.p.positionals.iter() {
+        args.push(json!({
+            "name": positional.b.name,
+            "help": positional.b.help,
+            "type": "positional",
+            "required": positional.b.is_set(ArgSettings::Required),
+            "multiple": positional.b.is_set(ArgSettings::Multiple),
+            "default": positional.v.default_val.map(|v| v.to_string_lossy()),
+            "possible_values": positional.v.possible_vals,
+        }));
+    }
+    json!({
+        "name": app.p.meta.name,
+        "about": app.p.meta.about,
+        "args": args,
+        "subcommands": app.p.subcommands.iter().map(app_schema).collect::<Vec<_>>(),
+    })
+}
@@ -1,18 +1,26 @@
 use std::{
+    collections::HashSet,
+    num::NonZeroU32,
     path::PathBuf,
     str::FromStr,
     sync::atomic::{AtomicUsize, Ordering},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::fronts::parse_fronts;
+use crate::{
+    binder_stats::record_cache,
+    fronts::parse_fronts,
+    log_format::{set_log_format, LogFormat},
+    plain_output::enable_plain_output,
+    storage::{self, enable_ephemeral},
+};
 use bytes::Bytes;
 use geph4_protocol::binder::client::{CachedBinderClient, DynBinderClient};
-use geph4_protocol::binder::protocol::BinderClient;
+use geph4_protocol::binder::protocol::{BinderClient, BlindToken, UserInfo};
 use once_cell::sync::{Lazy, OnceCell};
 
 use serde::{Deserialize, Serialize};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use structopt::StructOpt;
 
 static INIT_CONFIG: OnceCell<Opt> = OnceCell::new();
@@ -23,16 +31,157 @@ pub fn override_config(opt: Opt) {
 }
 
 /// The global configuration of the client.
-pub static CONFIG: Lazy<Opt> = Lazy::new(|| INIT_CONFIG.get_or_init(Opt::from_args).clone());
+pub static CONFIG: Lazy<Opt> = Lazy::new(|| {
+    INIT_CONFIG
+        .get_or_init(|| Cli::from_iter(args_with_profiles()).apply())
+        .clone()
+});
+
+/// Returns the command-line arguments, with every `@path` argument replaced by the arguments stored in that profile file.
+fn args_with_profiles() -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    // `config show` expands profiles itself, so that it can tell where each setting came from
+    if args.get(1).map(|s| s.as_str()) == Some("config") {
+        return args;
+    }
+    expand_profiles(args)
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1)
+        })
+        .into_iter()
+        .map(|(arg, _)| arg)
+        .collect()
+}
+
+/// Replaces every `@path` argument with the arguments stored in that profile file (or, for `@uci:config`, in OpenWrt's UCI), returning each argument together with the profile it came from, if any. Only arguments in a positional slot are profiles; the value of an option, like a password starting with '@', is left alone.
+pub fn expand_profiles(
+    args: impl IntoIterator<Item = String>,
+) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    let valueless = valueless_options();
+    let mut expanded = vec![];
+    let mut prev: Option<String> = None;
+    for arg in args {
+        let slot_is_positional = is_positional_slot(prev.as_deref(), &valueless);
+        prev = Some(arg.clone());
+        match arg.strip_prefix('@').filter(|_| slot_is_positional) {
+            Some(path) => {
+                let profile_args: Vec<String> = match path.strip_prefix("uci:") {
+                    Some(spec) => crate::uci::uci_args(spec),
+                    None => std::fs::read(path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|bts| Ok(serde_json::from_slice(&bts)?)),
+                }
+                .map_err(|err| anyhow::anyhow!("cannot read profile {:?}: {}", path, err))?;
+                expanded.extend(
+                    profile_args
+                        .into_iter()
+                        .map(|arg| (arg, Some(path.to_string()))),
+                );
+            }
+            None => expanded.push((arg, None)),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Whether the argument after the given one is positional rather than the value of an option.
+pub(crate) fn is_positional_slot(prev: Option<&str>, valueless: &HashSet<String>) -> bool {
+    match prev {
+        Some(prev) if prev.starts_with('-') && prev != "-" && prev != "--" => {
+            // "--option=value" carries its value along
+            prev.contains('=') || valueless.contains(prev)
+        }
+        _ => true,
+    }
+}
+
+/// Every option, as "--long" or "-s", that takes no value, in any subcommand.
+pub(crate) fn valueless_options() -> HashSet<String> {
+    fn walk(app: &structopt::clap::App, out: &mut HashSet<String>) {
+        for flag in app.p.flags.iter() {
+            out.extend(flag.s.long.map(|long| format!("--{}", long)));
+            out.extend(flag.s.short.map(|short| format!("-{}", short)));
+        }
+        for sub in app.p.subcommands.iter() {
+            walk(sub, out);
+        }
+    }
+    let mut out: HashSet<String> = ["--help", "-h", "--version", "-V"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    walk(&Cli::clap(), &mut out);
+    out
+}
+
+/// The whole command line: a subcommand, along with the options every subcommand takes.
+#[derive(Debug, StructOpt)]
+pub struct Cli {
+    #[structopt(flatten)]
+    pub global: GlobalOpt,
+
+    #[structopt(subcommand)]
+    pub cmd: Opt,
+}
+
+impl Cli {
+    /// Puts the global options into effect, returning the subcommand.
+    pub fn apply(self) -> Opt {
+        if self.global.ephemeral {
+            enable_ephemeral();
+        }
+        if self.global.plain_output {
+            enable_plain_output();
+        }
+        set_log_format(self.global.log_format);
+        self.cmd
+    }
+}
+
+/// Options every subcommand takes, given anywhere on the command line.
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct GlobalOpt {
+    #[structopt(long, global = true)]
+    /// Keeps credentials, bridge lists, usage counters, tokens and logs in memory only, writing nothing to disk. Subcommands whose only point is writing a file refuse to run.
+    pub ephemeral: bool,
+
+    #[structopt(long, global = true)]
+    /// Turns off ANSI colors and alignment padding, and prints status updates as stable "status: <state>" lines that screen readers can follow.
+    pub plain_output: bool,
+
+    #[structopt(long, global = true, default_value = "text")]
+    /// How log lines are written: "text" for people, or "json" for one JSON object per line, for frontends.
+    pub log_format: LogFormat,
+}
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum Opt {
     Connect(ConnectOpt),
+    #[cfg(not(feature = "router"))]
     BridgeTest(crate::main_bridgetest::BridgeTestOpt),
     Sync(crate::sync::SyncOpt),
     BinderProxy(crate::binderproxy::BinderProxyOpt),
     Debugpack(crate::debugpack::DebugPackOpt),
+    Exits(crate::exits::ExitsOpt),
+    Cache(crate::cache::CacheOpt),
+    /// Exports the daily bandwidth usage recorded by connect. Also runs as `stats`; its main name is `usage` because connect's `--stats-listen` and the control API already use "stats" for live tunnel statistics.
+    #[structopt(visible_alias = "stats")]
+    Usage(crate::usage::UsageOpt),
+    #[cfg(not(feature = "router"))]
+    Setup(crate::setup::SetupOpt),
+    #[cfg(not(feature = "router"))]
+    Completions(crate::completions::CompletionsOpt),
+    Config(crate::configshow::ConfigOpt),
+    Run(crate::run::RunOpt),
+    PingExit(crate::ping_exit::PingExitOpt),
+    Speedtest(crate::speedtest::SpeedtestOpt),
+    Doctor(crate::doctor::DoctorOpt),
+    ImportUri(crate::import_uri::ImportUriOpt),
+    PreviousLogs(crate::logs::PreviousLogsOpt),
+    #[cfg(not(feature = "router"))]
+    Pair(crate::pair::PairOpt),
 }
 
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
@@ -48,12 +197,20 @@ pub struct ConnectOpt {
     pub use_bridges: bool,
 
     #[structopt(long)]
-    /// Overrides everything else, forcing connection to a particular sosistab URL (of the form pk@host:port). This also disables any form of authentication.
+    /// Before handshaking with a private bridge, performs a cover step so that scanners are less likely to pick out the handshake. Given as IP=knock:PORT,PORT,... (one small UDP datagram to each port, in order) or IP=https://host/path (an ordinary HTTPS fetch). Can be repeated.
+    pub bridge_cover: Vec<String>,
+
+    #[structopt(long)]
+    /// Overrides everything else, forcing connection to a particular sosistab URL (of the form pk@host:port, where host may be a domain). Endpoints of other protocols are given as protocol:key@host:port, where key is the hex of the key material as the binder would list it for a bridge. This also disables any form of authentication.
     pub override_connect: Option<String>,
 
     #[structopt(long)]
-    /// Force a particular bridge
-    pub force_bridge: Option<Ipv4Addr>,
+    /// With --override-connect, the hex of the exit's end-to-end public key. The session then refuses to talk to anyone else, so that a private bridge in front of the exit cannot stand in for it.
+    pub override_exit_key: Option<String>,
+
+    #[structopt(long)]
+    /// Force a particular bridge, by its IPv4 or IPv6 address
+    pub force_bridge: Option<IpAddr>,
 
     #[structopt(long, default_value = "1")]
     /// Number of local UDP ports to use per session. This works around situations where unlucky ECMP routing sends flows down a congested path even when other paths exist, by "averaging out" all the possible routes.
@@ -72,27 +229,144 @@ pub struct ConnectOpt {
     pub tcp_shard_lifetime: u64,
 
     #[structopt(long, default_value = "127.0.0.1:9910")]
-    /// Where to listen for HTTP proxy connections
+    /// Where to listen for HTTP proxy connections. A proxy auto-config file for pointing browsers at Geph is served at /proxy.pac on the same address.
     pub http_listen: SocketAddr,
+    #[structopt(long, default_value = "0")]
+    /// How many times the HTTP proxy transparently retries a GET or HEAD request that the tunnel dropped before any response arrived. 0 disables retries.
+    pub http_idempotent_retries: u32,
+    #[structopt(long)]
+    /// Requires HTTP proxy clients to authenticate with these Basic credentials, given as USERNAME:PASSWORD. Set this before binding --http-listen to a LAN address. The proxy auto-config file stays available without them.
+    pub http_auth: Option<String>,
+
     #[structopt(long, default_value = "127.0.0.1:9909")]
     /// Where to listen for SOCKS5 connections
     pub socks5_listen: SocketAddr,
     #[structopt(long, default_value = "127.0.0.1:9809")]
     /// Where to listen for REST-based local connections
     pub stats_listen: SocketAddr,
+    #[structopt(long)]
+    /// Also serves the control API (status, current exit, bandwidth counters, reconnect, change exit, shutdown and the rest) as newline-delimited JSON-RPC on a unix socket at this path, which only the current user can open. Unix only.
+    pub control_socket: Option<PathBuf>,
+    #[structopt(long)]
+    /// Serves tunnel metrics (pipe count, per-pipe RTT, stalls and reconnects, bytes up and down, sessions, the current exit and how the repair loop replaces dead pipes) in the Prometheus text format at this address, like 127.0.0.1:9090. Scrapers must present the read-only token from --control-token-path, like the control API.
+    pub metrics_listen: Option<SocketAddr>,
+
+    #[structopt(long)]
+    /// Serve the REST-based local connections over TLS, using a locally generated certificate that can be installed into the OS trust store.
+    pub stats_tls: bool,
+
+    #[structopt(
+        long,
+        default_value = "auto",
+        parse(from_str = str_to_control_token_path)
+    )]
+    /// Where the read-only and full-control tokens for the REST-based local connections are stored. The default value is "auto", meaning a platform-specific path that Geph gets to pick. Tokens are generated at startup if there are none yet.
+    pub control_token_path: PathBuf,
+
+    #[structopt(
+        long,
+        default_value = "auto",
+        parse(from_str = str_to_usage_path)
+    )]
+    /// Where daily totals of bytes sent and received and sessions, overall and per exit, are recorded as an SQLite database, for "usage export". A usage log that older versions kept as JSON is imported on first use. The default value is "auto", meaning a platform-specific path that Geph gets to pick.
+    pub usage_path: PathBuf,
+
+    #[structopt(long)]
+    /// Append-only log file where every control action (exit selection, shutdown, etc) is recorded. Each entry is hash-chained to the previous one, so that tampering is evident.
+    pub audit_log: Option<PathBuf>,
 
     #[structopt(long, default_value = "127.0.0.1:15353")]
     /// Where to listen for proxied DNS requests.
     pub dns_listen: SocketAddr,
 
+    #[structopt(long)]
+    /// Resolve proxied DNS requests with DNS-over-HTTPS to this URL through the tunnel, e.g. "https://cloudflare-dns.com/dns-query". By default, they are sent to 1.0.0.1 as plain DNS over TCP through the tunnel. In VPN mode on Windows and macOS, this is also what the OS's own encrypted DNS is set up to use.
+    pub doh_upstream: Option<String>,
+
     #[structopt(long)]
     /// Which exit server to connect to. If there isn't an exact match, the exit server with the most similar hostname is picked. If not given, a random server will be selected.
     pub exit_server: Option<String>,
 
+    #[structopt(long, alias = "exit-strategy", default_value = "closest")]
+    /// How to pick among exits matching --exit-server. Possible options are:
+    /// - "closest" (always the single most similar exit)
+    /// - "weighted" (a random pick among the few most similar exits, favoring less loaded ones)
+    /// - "lowest-latency" (the fastest to reach, through its bridges, of the few most similar exits)
+    /// - "lowest-load" (the least loaded of the few most similar exits)
+    /// - "sticky" (the most similar exit, then the same one on every reconnect while it stays available)
+    pub exit_select: ExitSelect,
+
+    #[structopt(long)]
+    /// Ignore the load reported by exits when picking one. By default, overloaded exits are avoided in favor of similarly-named ones.
+    pub ignore_load: bool,
+
+    #[structopt(long)]
+    /// Keeps a standby session for the current exit, with its bridge list fetched and one pipe dialed and authenticated ahead of time, so that when every pipe dies at once (as when UDP is cut nationwide for a while) a new session carries traffic right away instead of waiting on the binder and a handshake. Costs one extra idle connection.
+    pub warm_spare: bool,
+
+    #[structopt(long)]
+    /// Once the plan expires mid-session, holds all traffic (SOCKS5, HTTP, port forwarding, and VPN) to this many KiB/s on our side. The binder doesn't say how fast the free plan is, so by default this is left to the free exits, which enforce their own limit once the session moves to one.
+    pub free_cap_kib: Option<NonZeroU32>,
+
+    #[structopt(long, parse(try_from_str = str_to_duration))]
+    /// Bound on the whole connection establishment process (binder, bridges, and authentication with the exit), e.g. "30s" or "2m". If the tunnel is not up by then, a JSON failure report is printed and the process exits with a non-zero status.
+    pub connect_deadline: Option<Duration>,
+
+    #[structopt(long)]
+    /// Validates the configuration (flags, listeners, VPN permissions) and prints what would happen, without connecting. Exits with a non-zero status if anything is wrong.
+    pub check: bool,
+
+    #[structopt(long, default_value = "immediate")]
+    /// What the SOCKS5, HTTP, DNS and port-forwarding listeners do before the tunnel first comes up. Possible options are:
+    /// - "immediate" (listen right away, holding connections until the tunnel is up)
+    /// - "delay-bind" (don't listen until the tunnel is up, so apps get "connection refused")
+    /// - "reject" (listen right away, but fail tunneled connections with a SOCKS5 server failure or an HTTP 502 until the tunnel is up)
+    pub listener_gate: ListenerGate,
+
+    #[structopt(long, conflicts_with = "fail_closed")]
+    /// Once the tunnel has been up, keep accepting connections whenever it drops, holding them until it is back. This is the default.
+    pub fail_open: bool,
+
+    #[structopt(long)]
+    /// Once the tunnel has been up, fail new tunneled connections right away whenever it drops, like --listener-gate reject does before it first comes up. Connections that would not go through the tunnel anyway are unaffected.
+    pub fail_closed: bool,
+
+    #[structopt(long, default_value = "balanced")]
+    /// How eagerly the tunnel keeps itself alive, traded off against battery drain. Possible options are:
+    /// - "performance" (quick failover and keepalives even when idle)
+    /// - "balanced" (the defaults)
+    /// - "battery" (longer connect timeouts, rarer keepalives and bridge checks, and pings that mostly stop while idle)
+    /// These intervals are stretched further on battery and in low power mode, as set by the platform.
+    pub power_profile: PowerProfile,
+
+    #[structopt(long, default_value = "10s", parse(try_from_str = str_to_duration))]
+    /// On shutdown, how long to wait for open proxied connections to finish after no longer accepting new ones, e.g. "30s".
+    pub drain_timeout: Duration,
+
+    #[structopt(long)]
+    /// Keeps the latest logs in a memory-mapped ring file at this path, which survives crashes and the OS killing the process. The previous run's file is moved aside to the same path with ".prev" appended; read it with the previous-logs subcommand.
+    pub crash_log: Option<PathBuf>,
+
+    #[structopt(long, parse(try_from_str = str_to_duration))]
+    /// Send TCP keepalives on proxied connections after they have been idle this long, e.g. "60s", so that long-idle sessions (IMAP IDLE, SSH) survive NAT timeouts.
+    pub tcp_keepalive: Option<Duration>,
+
+    #[structopt(long)]
+    /// Log the SNI (server name) of tunneled TLS connections and count connections per SNI in stats. Connection contents are never recorded. Off by default for privacy.
+    pub log_sni: bool,
+
+    #[structopt(long)]
+    /// Show desktop notifications when the connection comes up or drops, and for other important events.
+    pub notifications: bool,
+
     #[structopt(long)]
     /// Whether or not to exclude PRC domains
     pub exclude_prc: bool,
 
+    #[structopt(long)]
+    /// Pre-login mode: only allow connections to Geph account, recovery, and payment pages. Meant for reaching the account recovery flow before being able to log in normally.
+    pub prelogin: bool,
+
     #[structopt(long)]
     /// Whether or not to wait for VPN commands on stdio
     pub stdio_vpn: bool,
@@ -101,19 +375,97 @@ pub struct ConnectOpt {
     /// Whether or not to stick to the same set of bridges
     pub sticky_bridges: bool,
 
+    #[structopt(long)]
+    /// Only ever dial this many bridges per protocol, picked from the exit's bridge list in a way that stays the same for this user. This limits which bridges an observer of this client's traffic can learn, and lets periodic bridge refreshes come from the cache instead of the binder. It does not limit what the binder reveals: the binder still hands out the full list, and the sample is taken from it on this side.
+    pub bridge_sample: Option<usize>,
+
     #[structopt(long)]
     /// Specify whether and how to create a L3 VPN tunnel. Possible options are:
     /// - nothing (no VPN)
     /// - "inherited-fd" (reads a TUN device file descriptor number, inherited from the parent process, from the GEPH_VPN_FD environment variable)
     /// - "tun-no-route" (Unix only; creates and configures a TUN device named "tun-geph", but does not change the routing table)
     /// - "tun-route" (Unix only; creates and configures a TUN device, as well as executing platform-specific actions to force all non-Geph traffic through the tunnel)
+    /// - "tun" (Linux and macOS; creates and configures a TUN device, a utun device on macOS, as given by the --tun-* options, routing only the --tun-route subnets through it unless --tun-default-route is set)
     /// - "windivert" (Windows only; uses WinDivert to capture non-Geph traffic to feed into the VPN)
+    /// - "wintun" (Windows only; creates a WinTun adapter as given by the --tun-* options and routes all non-Geph traffic through it, needing only wintun.dll next to the executable)
     pub vpn_mode: Option<VpnMode>,
 
+    #[structopt(long)]
+    /// Linux only: creates a network namespace with this name, whose only way out is a Geph TUN device, for running programs with no possibility of leaking traffic (e.g. through "run" or "ip netns exec"). Cannot be combined with --vpn-mode.
+    pub netns: Option<String>,
+
+    #[structopt(long, default_value = "tun-geph")]
+    /// With --vpn-mode tun or wintun, the name of the TUN device to create. On macOS, only "utunN" names are honored; otherwise the next free utun device is used.
+    pub tun_name: String,
+
+    #[structopt(long, default_value = "100.64.89.64")]
+    /// With --vpn-mode tun or wintun, the address of the TUN device.
+    pub tun_address: Ipv4Addr,
+
+    #[structopt(long, default_value = "255.255.255.0")]
+    /// With --vpn-mode tun or wintun, the netmask of the TUN device.
+    pub tun_netmask: Ipv4Addr,
+
+    #[structopt(long, default_value = "16384")]
+    /// With --vpn-mode tun or wintun, the MTU of the TUN device.
+    pub tun_mtu: u16,
+
+    #[structopt(long)]
+    /// With --vpn-mode tun, an IPv6 address to give the TUN device, with a /64 prefix, like "fd64:8964::2". Without one, IPv6 traffic is not routed through the TUN device, and with --tun-default-route it is blocked rather than leaked.
+    pub tun_address6: Option<Ipv6Addr>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// With --vpn-mode tun, IPv4 subnets, like "10.8.0.0/16,203.0.113.7", to route through the TUN device. They should not contain any of the bridges Geph connects to.
+    pub tun_route: Vec<Subnet>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// With --vpn-mode tun and --tun-address6, IPv6 subnets, like "2001:db8::/32", to route through the TUN device.
+    pub tun_route6: Vec<Subnet6>,
+
+    #[structopt(long)]
+    /// With --vpn-mode tun, sends all non-Geph traffic through the TUN device, like "tun-route" does, honoring split tunneling.
+    pub tun_default_route: bool,
+
     #[structopt(long)]
     /// Forces the protocol selected to match the given regex.
     pub force_protocol: Option<String>,
 
+    #[structopt(long, use_delimiter = true)]
+    /// Bridge protocols to use, in order of preference, e.g. "obfstls,obfsudp". Each protocol is only dialed if none of those before it could connect, and protocols not listed are never used. A protocol may be given as "obfstls:1" to keep at most that many pipes of it.
+    pub transport_priority: Vec<TransportPriority>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// IPv4 or IPv6 subnets, like "192.168.0.0/16,203.0.113.7,2001:db8::/32", whose traffic goes directly rather than through Geph. Applies to the SOCKS5 and HTTP proxies when the destination is given as an IP address, and to the "tun-route", "windivert" and "wintun" VPN modes, as well as "tun" with --tun-default-route. When both lists match a destination, the more specific subnet wins.
+    pub bypass_subnet: Vec<Subnet>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// IPv4 or IPv6 subnets whose traffic always goes through Geph, even when --exclude-prc or a broader --bypass-subnet would send it directly.
+    pub force_subnet: Vec<Subnet>,
+
+    #[structopt(long)]
+    /// Linux only, in "tun-route" VPN mode: apps whose traffic goes directly rather than through Geph, given as executable names (like "steam", matched against the file each process runs) or cgroup v2 paths (like "/user.slice/user-1000.slice"). Named processes are moved into a "geph-bypass" cgroup when they start. Can be repeated.
+    pub bypass_app: Vec<String>,
+
+    #[structopt(long)]
+    /// Linux only, in "tun-route" VPN mode: apps whose traffic always goes through Geph, even to a --bypass-subnet, given like --bypass-app. Can be repeated.
+    pub force_app: Vec<String>,
+
+    #[structopt(long)]
+    /// Unix only, for censorship-measurement research: mirrors anonymized metadata of every SOCKS5 and HTTP proxy connection (truncated source networks, hashed destinations, byte counts and timing, but no payloads) as JSON lines to the unix socket at this path, which an analysis program should be listening on. Opt-in, and off by default.
+    pub flow_mirror: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Blocks every other program's internet traffic whenever the tunnel drops, until it reconnects, using nftables on Linux, pf on macOS and Windows Firewall on Windows. Geph's own traffic is told apart by user on Linux and macOS (so run Geph as a dedicated user for full protection) and by executable on Windows. Needs administrator privileges.
+    pub kill_switch: bool,
+
+    #[structopt(long)]
+    /// Turns on the OS's hotspot (Mobile Hotspot on Windows, Internet Sharing on macOS) and routes every shared device through Geph, DNS included. Needs --vpn-mode windivert on Windows and tun-route on macOS; on macOS, pick the devices to share to in System Settings once beforehand.
+    pub share_hotspot: bool,
+
+    #[structopt(long)]
+    /// A file of domain rules for the SOCKS5 and HTTP proxies, one per line: "direct *.bank.example" sends bank.example and its subdomains directly, while "tunnel *.blocked.example" always sends them through Geph. A pattern without "*." only matches that exact domain. The most specific matching rule wins. Lines starting with "#" are comments.
+    pub domain_rules: Option<PathBuf>,
+
     #[structopt(long)]
     /// SSH-style local-remote port forwarding. For example, "0.0.0.0:8888:::example.com:22" will forward local port 8888 to example.com:22. Must be in form host:port:::host:port! May have multiple ones.
     pub forward_ports: Vec<String>,
@@ -125,7 +477,9 @@ pub enum VpnMode {
     InheritedFd,
     TunNoRoute,
     TunRoute,
+    Tun,
     WinDivert,
+    WinTun,
     Stdio,
 }
 
@@ -136,7 +490,9 @@ impl FromStr for VpnMode {
             "inherited-fd" => Ok(Self::InheritedFd),
             "tun-no-route" => Ok(Self::TunNoRoute),
             "tun-route" => Ok(Self::TunRoute),
+            "tun" => Ok(Self::Tun),
             "windivert" => Ok(Self::WinDivert),
+            "wintun" => Ok(Self::WinTun),
             "stdio" => Ok(Self::Stdio),
 
             x => anyhow::bail!("unrecognized VPN mode {}", x),
@@ -144,6 +500,284 @@ impl FromStr for VpnMode {
     }
 }
 
+/// An enum representing the exit selection strategies.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+pub enum ExitSelect {
+    Closest,
+    Weighted,
+    LowestLatency,
+    LowestLoad,
+    Sticky,
+}
+
+impl FromStr for ExitSelect {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "closest" => Ok(Self::Closest),
+            "weighted" => Ok(Self::Weighted),
+            "lowest-latency" => Ok(Self::LowestLatency),
+            "lowest-load" => Ok(Self::LowestLoad),
+            "sticky" => Ok(Self::Sticky),
+            x => anyhow::bail!("unrecognized exit selection strategy {}", x),
+        }
+    }
+}
+
+/// How much of the client's version is reported to the binder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VersionPolicy {
+    /// The exact version, like "4.7.3".
+    Full,
+    /// Only the major and minor version, like "4.7", which is shared by many more users.
+    Coarse,
+    /// Nothing at all.
+    None,
+}
+
+impl FromStr for VersionPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "coarse" => Ok(Self::Coarse),
+            "none" => Ok(Self::None),
+            x => anyhow::bail!("unrecognized version reporting policy {}", x),
+        }
+    }
+}
+
+impl VersionPolicy {
+    /// The version string reported under this policy, if any.
+    pub fn reported_version(&self) -> Option<String> {
+        let version = env!("CARGO_PKG_VERSION");
+        match self {
+            Self::Full => Some(version.to_string()),
+            Self::Coarse => Some(version.split('.').take(2).collect::<Vec<_>>().join(".")),
+            Self::None => None,
+        }
+    }
+
+    /// Puts the reported version into a cached auth token, which is what carries our version to the binder and the exits. The binder client would otherwise fill it in from the GEPH_VERSION environment variable when minting the token, so a token it just minted carries no version until it is cached. Nothing about the platform is reported under any policy.
+    fn stamp_token(&self, cached: &[u8]) -> Option<Bytes> {
+        let (user_info, mut token): (UserInfo, BlindToken) = serde_json::from_slice(cached).ok()?;
+        token.version = self.reported_version().map(|v| v.into());
+        serde_json::to_vec(&(user_info, token))
+            .ok()
+            .map(Bytes::from)
+    }
+}
+
+/// What the proxy listeners do before the tunnel first comes up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ListenerGate {
+    /// Listen right away, holding connections until the tunnel is up.
+    Immediate,
+    /// Don't listen at all until the tunnel is up, so connections are refused by the OS.
+    DelayBind,
+    /// Listen right away, but fail connections (with a SOCKS5 "general server failure", or an HTTP 502) until the tunnel is up.
+    Reject,
+}
+
+impl FromStr for ListenerGate {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Self::Immediate),
+            "delay-bind" => Ok(Self::DelayBind),
+            "reject" => Ok(Self::Reject),
+            x => anyhow::bail!("unrecognized listener gate {}", x),
+        }
+    }
+}
+
+/// How eagerly the tunnel keeps itself alive and reconnects, traded off against background battery drain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PowerProfile {
+    /// Fails over and rebalances quickly, and keeps the tunnel warm even when idle.
+    Performance,
+    /// The long-standing defaults.
+    Balanced,
+    /// Wakes up as rarely as it can, at the cost of noticing dead pipes later.
+    Battery,
+}
+
+impl FromStr for PowerProfile {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "performance" => Ok(Self::Performance),
+            "balanced" => Ok(Self::Balanced),
+            "battery" => Ok(Self::Battery),
+            x => anyhow::bail!("unrecognized power profile {}", x),
+        }
+    }
+}
+
+impl PowerProfile {
+    /// How long to wait between checks for dead bridges to replace.
+    pub fn rebalance_interval(&self) -> Duration {
+        match self {
+            Self::Performance => Duration::from_secs(120),
+            Self::Balanced => Duration::from_secs(300),
+            Self::Battery => Duration::from_secs(900),
+        }
+    }
+
+    /// The bounds of the random wait before retrying a failed pipe connection.
+    pub fn retry_jitter(&self) -> (Duration, Duration) {
+        match self {
+            Self::Performance => (Duration::from_millis(500), Duration::from_millis(1500)),
+            Self::Balanced => (Duration::from_secs(1), Duration::from_secs(3)),
+            Self::Battery => (Duration::from_secs(3), Duration::from_secs(9)),
+        }
+    }
+
+    /// How long one pipe connection attempt may take.
+    pub fn connect_timeout(&self) -> Duration {
+        match self {
+            Self::Performance => Duration::from_secs(5),
+            Self::Balanced => Duration::from_secs(10),
+            Self::Battery => Duration::from_secs(20),
+        }
+    }
+
+    /// How often the watchdog pings the exit, which doubles as the tunnel's keepalive.
+    pub fn keepalive_interval(&self) -> Duration {
+        match self {
+            Self::Performance => Duration::from_secs(5),
+            Self::Balanced => Duration::from_secs(10),
+            Self::Battery => Duration::from_secs(30),
+        }
+    }
+
+    /// How long the keepalive waits for any traffic before pinging an idle tunnel anyway.
+    pub fn idle_timeout(&self) -> Duration {
+        match self {
+            Self::Performance => Duration::from_secs(120),
+            Self::Balanced => Duration::from_secs(600),
+            Self::Battery => Duration::from_secs(1800),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 subnet given in CIDR notation, like "10.0.0.0/8" or "2001:db8::/32". A bare address is a /32, or a /128 for IPv6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix_len: u32,
+}
+
+impl Subnet {
+    /// Whether the subnet contains the given address. Addresses of the other family are never contained.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(net) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(net) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len.parse()?)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            anyhow::bail!("invalid subnet prefix length {}", prefix_len)
+        }
+        // normalize away host bits, so that "10.1.2.3/8" means 10.0.0.0/8
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                IpAddr::V4((u32::from(addr) & mask).into())
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                IpAddr::V6((u128::from(addr) & mask).into())
+            }
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// An IPv6 subnet given in CIDR notation, like "2001:db8::/32". A bare address is a /128.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Subnet6 {
+    pub addr: Ipv6Addr,
+    pub prefix_len: u32,
+}
+
+impl std::fmt::Display for Subnet6 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet6 {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, prefix_len.parse()?),
+            None => (s.trim(), 128),
+        };
+        if prefix_len > 128 {
+            anyhow::bail!("invalid subnet prefix length {}", prefix_len)
+        }
+        let addr: Ipv6Addr = addr.parse()?;
+        // normalize away host bits, like Subnet does
+        let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+        Ok(Self {
+            addr: (u128::from(addr) & mask).into(),
+            prefix_len,
+        })
+    }
+}
+
+/// One entry of --transport-priority: a bridge protocol, and optionally the most pipes of it to keep.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TransportPriority {
+    /// The full protocol name, like "sosistab2-obfstls".
+    pub protocol: String,
+    pub limit: Option<usize>,
+}
+
+impl FromStr for TransportPriority {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, limit) = match s.split_once(':') {
+            Some((name, limit)) => (name, Some(limit.parse()?)),
+            None => (s, None),
+        };
+        let name = name.trim().trim_start_matches("sosistab2-");
+        match name {
+            "obfsudp" | "obfstls" | "wss" | "quic" => Ok(Self {
+                protocol: format!("sosistab2-{}", name),
+                limit,
+            }),
+            x => anyhow::bail!("unrecognized bridge protocol {}", x),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
 pub struct CommonOpt {
     #[structopt(
@@ -186,9 +820,28 @@ pub struct CommonOpt {
 
     #[structopt(long, default_value = "file::memory:?cache=shared")]
     pub debugpack_path: String,
+
+    #[structopt(long, default_value = "full")]
+    /// How much of the client's version to report to the binder: "full", "coarse" (only the major and minor version), or "none".
+    pub report_version: VersionPolicy,
 }
 
 impl CommonOpt {
+    /// The hosts that binder requests are sent to, which are dialed directly rather than through the tunnel.
+    pub fn binder_front_hosts(&self) -> Vec<String> {
+        self.binder_http_fronts
+            .split(',')
+            .filter_map(|front| {
+                let rest = front.split_once("://").map_or(front, |(_, rest)| rest);
+                let authority = rest.split('/').next()?;
+                let host = authority
+                    .rsplit_once(':')
+                    .map_or(authority, |(host, _)| host);
+                (!host.is_empty()).then(|| host.to_string())
+            })
+            .collect()
+    }
+
     /// Connects to the binder, given these parameters.
     pub fn get_binder_client(&self) -> DynBinderClient {
         BinderClient(parse_fronts(
@@ -231,6 +884,43 @@ fn str_to_path(src: &str) -> PathBuf {
     }
 }
 
+fn str_to_control_token_path(src: &str) -> PathBuf {
+    if src == "auto" {
+        let mut config_dir = dirs::config_dir().unwrap();
+        config_dir.push("geph4-control-tokens.json");
+        config_dir
+    } else {
+        PathBuf::from(src)
+    }
+}
+
+pub(crate) fn str_to_usage_path(src: &str) -> PathBuf {
+    if src == "auto" {
+        let mut config_dir = dirs::config_dir().unwrap();
+        config_dir.push("geph4-usage.db");
+        config_dir
+    } else {
+        PathBuf::from(src)
+    }
+}
+
+pub(crate) fn str_to_duration(src: &str) -> anyhow::Result<Duration> {
+    let src = src.trim();
+    let (number, unit) = src.split_at(
+        src.find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(src.len()),
+    );
+    let number: f64 = number.parse()?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        x => anyhow::bail!("unrecognized duration unit {}", x),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
 fn str_to_x25519_pk(src: &str) -> x25519_dalek::PublicKey {
     let raw_bts = hex::decode(src).unwrap();
     let raw_bts: [u8; 32] = raw_bts.as_slice().try_into().unwrap();
@@ -265,11 +955,28 @@ impl CacheStaleGuard {
     }
 }
 
-/// Given the common and authentication options, produce a binder client.
-pub fn get_cached_binder_client(
-    common_opt: &CommonOpt,
-    auth_opt: &AuthOpt,
-) -> anyhow::Result<CachedBinderClient> {
+/// If greater than zero, then the cache is bypassed and everything is fetched afresh.
+static CACHE_REFRESH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Keep this alive to make every cache lookup miss, e.g. to fetch a new authentication token after the plan changed.
+#[non_exhaustive]
+pub struct CacheRefreshGuard {}
+
+impl Drop for CacheRefreshGuard {
+    fn drop(&mut self) {
+        CACHE_REFRESH_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl CacheRefreshGuard {
+    pub fn new() -> Self {
+        CACHE_REFRESH_COUNT.fetch_add(1, Ordering::SeqCst);
+        Self {}
+    }
+}
+
+/// Given the authentication options, returns the directory where the binder cache for that user lives.
+pub fn get_cache_dir(auth_opt: &AuthOpt) -> PathBuf {
     let mut dbpath = auth_opt.credential_cache.clone();
     // create a dbpath based on hashing the username together with the password
     let quasi_user_id = hex::encode(
@@ -280,38 +987,71 @@ pub fn get_cached_binder_client(
         .as_bytes(),
     );
     dbpath.push(&quasi_user_id);
-    std::fs::create_dir_all(&dbpath)?;
+    dbpath
+}
+
+/// Given the authentication options, returns a salt that stays the same for the same user, for client-stable choices like bridge sampling.
+pub fn get_client_salt(auth_opt: &AuthOpt) -> [u8; 32] {
+    *blake3::keyed_hash(
+        blake3::hash(auth_opt.password.as_bytes()).as_bytes(),
+        format!("client-salt-{}", auth_opt.username).as_bytes(),
+    )
+    .as_bytes()
+}
+
+/// Given the common and authentication options, produce a binder client.
+pub fn get_cached_binder_client(
+    common_opt: &CommonOpt,
+    auth_opt: &AuthOpt,
+) -> anyhow::Result<CachedBinderClient> {
+    let dbpath = get_cache_dir(auth_opt);
+    storage::create_dir_all(&dbpath)?;
+    let policy = common_opt.report_version;
     let cbc = CachedBinderClient::new(
         {
             let dbpath = dbpath.clone();
             move |key| {
-                let mut dbpath = dbpath.clone();
-                dbpath.push(format!("{}.json", key));
-                let r = std::fs::read(dbpath).ok()?;
-                let (tstamp, bts): (u64, Bytes) = bincode::deserialize(&r).ok()?;
-                if tstamp > SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs()
-                    || CACHE_STALELOCK_COUNT.load(Ordering::SeqCst) > 0
-                {
-                    Some(bts)
-                } else {
-                    None
+                let load = || {
+                    if CACHE_REFRESH_COUNT.load(Ordering::SeqCst) > 0 {
+                        return None;
+                    }
+                    let mut dbpath = dbpath.clone();
+                    dbpath.push(format!("{}.json", key));
+                    let r = storage::read(&dbpath).ok()?;
+                    let (tstamp, bts): (u64, Bytes) = bincode::deserialize(&r).ok()?;
+                    if tstamp > SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs()
+                        || CACHE_STALELOCK_COUNT.load(Ordering::SeqCst) > 0
+                    {
+                        Some(bts)
+                    } else {
+                        None
+                    }
+                };
+                let res = load();
+                record_cache(key, res.is_some());
+                match res {
+                    Some(bts) if key == "auth_token" => policy.stamp_token(&bts).or(Some(bts)),
+                    res => res,
                 }
             }
         },
-        {
-            let dbpath = dbpath.clone();
-            move |k, v, expires| {
-                let noviy_taymstamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    + expires.as_secs();
-                let to_write =
-                    bincode::serialize(&(noviy_taymstamp, Bytes::copy_from_slice(v))).unwrap();
-                let mut dbpath = dbpath.clone();
-                dbpath.push(format!("{}.json", k));
-                let _ = std::fs::write(dbpath, to_write);
-            }
+        move |k, v, expires| {
+            let stamped = if k == "auth_token" {
+                policy.stamp_token(v)
+            } else {
+                None
+            };
+            let v = stamped.as_deref().unwrap_or(v);
+            let noviy_taymstamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + expires.as_secs();
+            let to_write =
+                bincode::serialize(&(noviy_taymstamp, Bytes::copy_from_slice(v))).unwrap();
+            let mut dbpath = dbpath.clone();
+            dbpath.push(format!("{}.json", k));
+            let _ = storage::write(&dbpath, to_write);
         },
         common_opt.get_binder_client(),
         &auth_opt.username,
@@ -319,3 +1059,36 @@ pub fn get_cached_binder_client(
     );
     Ok(cbc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_only_in_positional_slots() {
+        let valueless: HashSet<String> = ["--use-bridges".to_string()].into_iter().collect();
+        assert!(is_positional_slot(None, &valueless));
+        assert!(is_positional_slot(Some("connect"), &valueless));
+        assert!(is_positional_slot(Some("--use-bridges"), &valueless));
+        assert!(is_positional_slot(Some("--exit-server=us"), &valueless));
+        assert!(!is_positional_slot(Some("--password"), &valueless));
+    }
+
+    #[test]
+    fn subnets_of_both_families() {
+        let v4: Subnet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(v4.to_string(), "10.0.0.0/8");
+        assert!(v4.contains("10.200.0.1".parse().unwrap()));
+        assert!(!v4.contains("11.0.0.1".parse().unwrap()));
+        let v6: Subnet = "2001:db8:1::1/32".parse().unwrap();
+        assert_eq!(v6.to_string(), "2001:db8::/32");
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        // a bare address is a single host of its family, and families never mix
+        let host: Subnet = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.prefix_len, 128);
+        assert!(!"0.0.0.0/0".parse::<Subnet>().unwrap().contains(host.addr));
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+        assert!("2001:db8::/129".parse::<Subnet>().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use colored::Colorize;
+use pad::PadStr;
+use serde::{Deserialize, Serialize};
+use structopt::{clap::AppSettings, StructOpt};
+
+use crate::config::{
+    expand_profiles, is_positional_slot, valueless_options, CommonOpt, ConnectOpt,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct ConfigOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(subcommand)]
+    pub action: ConfigAction,
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub enum ConfigAction {
+    /// Prints every effective setting of `connect` with the given arguments, together with where it came from.
+    #[structopt(setting = AppSettings::TrailingVarArg, setting = AppSettings::AllowLeadingHyphen)]
+    Show {
+        /// Arguments as they would be passed to `connect`, including any @profile files.
+        args: Vec<String>,
+    },
+}
+
+/// Environment variables that change the behavior of `connect`.
+const RELEVANT_ENV_VARS: &[(&str, bool)] = &[
+    ("RUST_LOG", false),
+    ("GEPH_RPC_KEY", true),
+    ("GEPH_VPN_FD", false),
+];
+
+/// Entry point to the config subcommand.
+pub fn main_config(opt: ConfigOpt) -> anyhow::Result<()> {
+    match opt.action {
+        ConfigAction::Show { args } => show_config(args),
+    }
+}
+
+/// Where each option given in the expanded arguments came from, keyed by how it was spelled ("--long" or "-s"): the profile it was read from, or None for the command line. Only the last occurrence counts, since that is the one that takes effect.
+fn option_sources(expanded: &[(String, Option<String>)]) -> HashMap<String, Option<String>> {
+    let valueless = valueless_options();
+    let mut sources = HashMap::new();
+    let mut prev: Option<&str> = None;
+    for (arg, profile) in expanded {
+        let is_option = arg.starts_with('-') && arg != "-" && arg != "--";
+        if is_option && is_positional_slot(prev, &valueless) {
+            let name = if arg.starts_with("--") {
+                arg.split('=').next().unwrap_or_default()
+            } else {
+                // "-xVALUE" carries its value along
+                arg.get(..2).unwrap_or(arg)
+            };
+            sources.insert(name.to_string(), profile.clone());
+        }
+        prev = Some(arg);
+    }
+    sources
+}
+
+fn show_config(args: Vec<String>) -> anyhow::Result<()> {
+    let expanded = expand_profiles(std::iter::once("connect".to_string()).chain(args))?;
+    let app = ConnectOpt::clap();
+    let matches = app
+        .clone()
+        .get_matches_from_safe(expanded.iter().map(|(arg, _)| arg))?;
+    let sources = option_sources(&expanded);
+    let origin = |long: Option<&str>, short: Option<char>| -> String {
+        let source = long
+            .and_then(|long| sources.get(&format!("--{}", long)))
+            .or_else(|| short.and_then(|short| sources.get(&format!("-{}", short))));
+        match source {
+            Some(Some(profile)) => format!("profile {}", profile),
+            _ => "command line".into(),
+        }
+    };
+
+    let mut rows = vec![];
+    for flag in app.p.flags.iter() {
+        let name = flag.b.name;
+        if name == "help" || name == "version" {
+            continue;
+        }
+        if matches.is_present(name) {
+            rows.push((name, "true".to_string(), origin(flag.s.long, flag.s.short)));
+        } else {
+            rows.push((name, "false".to_string(), "default".to_string()));
+        }
+    }
+    for option in app.p.opts.iter() {
+        let name = option.b.name;
+        if matches.occurrences_of(name) > 0 {
+            let value = matches
+                .values_of(name)
+                .map(|vals| vals.collect::<Vec<_>>().join(","))
+                .unwrap_or_default();
+            let value = if name == "password" {
+                "********".to_string()
+            } else {
+                value
+            };
+            rows.push((name, value, origin(option.s.long, option.s.short)));
+        } else if let Some(default) = option.v.default_val {
+            rows.push((name, default.to_string_lossy().into(), "default".into()));
+        } else {
+            rows.push((name, "(unset)".into(), "default".into()));
+        }
+    }
+    rows.sort();
+    for (_, value, _) in rows.iter_mut() {
+        if value.chars().count() > 46 {
+            *value = value.chars().take(43).collect::<String>() + "...";
+        }
+    }
+
+    println!(
+        "{}{}SOURCE",
+        "SETTING".pad_to_width(24),
+        "VALUE".pad_to_width(48)
+    );
+    for (name, value, source) in rows {
+        let source = if source == "default" {
+            source.dimmed()
+        } else {
+            source.bright_green()
+        };
+        println!(
+            "{}{}{}",
+            name.pad_to_width(24),
+            value.pad_to_width(48),
+            source
+        );
+    }
+    println!();
+    println!("environment:");
+    for (var, secret) in RELEVANT_ENV_VARS {
+        match std::env::var(var) {
+            Ok(_) if *secret => println!("  {} = ********", var),
+            Ok(value) => println!("  {} = {}", var, value),
+            Err(_) => println!("  {} {}", var, "(unset)".dimmed()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(profile: Option<&str>, args: &[&str]) -> Vec<(String, Option<String>)> {
+        args.iter()
+            .map(|arg| (arg.to_string(), profile.map(|p| p.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn sources_follow_the_last_occurrence() {
+        let mut expanded = from(None, &["connect"]);
+        expanded.extend(from(
+            Some("home.json"),
+            &[
+                "--exit-server=us-hio",
+                "--use-bridges",
+                "--username",
+                "--sticky",
+            ],
+        ));
+        expanded.extend(from(None, &["--exit-server", "ca-mtl", "--password", "-x"]));
+        let sources = option_sources(&expanded);
+        assert_eq!(sources["--exit-server"], None);
+        assert_eq!(sources["--use-bridges"].as_deref(), Some("home.json"));
+        assert_eq!(sources["--username"].as_deref(), Some("home.json"));
+        // values are not options, however they look
+        assert!(!sources.contains_key("--sticky"));
+        assert!(!sources.contains_key("-x"));
+    }
+}
@@ -13,29 +13,84 @@ use smol::{prelude::*, Task};
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    config::{get_cached_binder_client, ConnectOpt, Opt, CONFIG},
-    connect::tunnel::{BinderTunnelParams, ClientTunnel, EndpointSource, TunnelStatus},
+    config::{get_cached_binder_client, get_client_salt, ConnectOpt, Opt, CONFIG},
+    connect::tunnel::{
+        bridge_sample::BridgeSampler, tunnel_actor::LAST_TUNNEL_ERROR, BinderTunnelParams,
+        ClientTunnel, EndpointSource, TunnelStatus,
+    },
 };
 
 use crate::china;
 
+mod audit;
+mod check;
 mod dns;
+mod doh;
+pub(crate) mod drain;
+mod flow_mirror;
+mod ftp;
+mod gate;
+pub(crate) mod http_wire;
+mod keepalive;
+mod kill_switch;
+pub(crate) mod notify;
+pub(crate) mod pac;
+pub(crate) mod ping;
+pub(crate) mod plan_expiry;
+pub(crate) mod power;
 mod port_forwarder;
+mod prelogin;
 mod socks5;
-mod stats;
-mod tunnel;
+pub(crate) mod split_tunnel;
+pub(crate) mod stats;
+pub(crate) mod tunnel;
+mod usage_log;
 pub(crate) mod vpn;
+pub(crate) mod warm;
 
 /// Main function for `connect` subcommand
 pub fn start_main_connect() {
+    if CONNECT_CONFIG.check {
+        let ok = check::check_config(&CONNECT_CONFIG);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Err(err) = check::preflight(&CONNECT_CONFIG) {
+        for line in err.to_string().lines() {
+            log::error!("{}", line);
+        }
+        log::error!("{}", crate::l10n::tr("check-preflight-failed"));
+        std::process::exit(1);
+    }
+    if let Err(err) = stats::init_control_tokens() {
+        log::error!("cannot prepare control API tokens: {:?}", err);
+        std::process::exit(1);
+    }
+    if let Err(err) = split_tunnel::load_domain_rules() {
+        log::error!("{:?}", err);
+        std::process::exit(1);
+    }
+    if let Some(path) = &CONNECT_CONFIG.crash_log {
+        crate::logs::init_crash_log(path);
+    }
     Lazy::force(&CONNECT_TASK);
 }
 
+/// Stops the `connect` subcommand started by [start_main_connect] without exiting the process: listeners stop accepting, open streams get up to the drain timeout, then the tunnel is torn down and the usage log flushed. Blocks until done.
+pub fn stop_main_connect() {
+    if Lazy::get(&CONNECT_TASK).is_none() {
+        return;
+    }
+    smol::future::block_on(drain::stop())
+}
+
 /// The configured binder client
 static CACHED_BINDER_CLIENT: Lazy<Arc<CachedBinderClient>> = Lazy::new(|| {
     Arc::new({
         let (common, auth) = match CONFIG.deref() {
             Opt::Connect(c) => (&c.common, &c.auth),
+            Opt::Run(r) => (&r.connect.common, &r.connect.auth),
+            Opt::PingExit(p) => (&p.connect.common, &p.connect.auth),
+            Opt::Speedtest(s) => (&s.connect.common, &s.connect.auth),
             _ => panic!(),
         };
         get_cached_binder_client(common, auth).unwrap()
@@ -44,6 +99,9 @@ static CACHED_BINDER_CLIENT: Lazy<Arc<CachedBinderClient>> = Lazy::new(|| {
 
 static CONNECT_CONFIG: Lazy<ConnectOpt> = Lazy::new(|| match CONFIG.deref() {
     Opt::Connect(c) => c.clone(),
+    Opt::Run(r) => r.connect.clone(),
+    Opt::PingExit(p) => p.connect.clone(),
+    Opt::Speedtest(s) => s.connect.clone(),
     _ => panic!(),
 });
 
@@ -82,6 +140,7 @@ pub static TUNNEL: Lazy<ClientTunnel> = Lazy::new(|| {
         if let Some(override_url) = CONNECT_CONFIG.override_connect.clone() {
             EndpointSource::Independent {
                 endpoint: override_url,
+                exit_key: CONNECT_CONFIG.override_exit_key.clone(),
             }
         } else {
             EndpointSource::Binder(BinderTunnelParams {
@@ -90,6 +149,13 @@ pub static TUNNEL: Lazy<ClientTunnel> = Lazy::new(|| {
                 use_bridges: *SHOULD_USE_BRIDGES,
                 force_bridge: CONNECT_CONFIG.force_bridge,
                 force_protocol: CONNECT_CONFIG.force_protocol.clone(),
+                transport_priority: CONNECT_CONFIG.transport_priority.clone(),
+                bridge_sampler: CONNECT_CONFIG
+                    .bridge_sample
+                    .map(|k| BridgeSampler::new(k, get_client_salt(&CONNECT_CONFIG.auth))),
+                ignore_load: CONNECT_CONFIG.ignore_load,
+                exit_select: CONNECT_CONFIG.exit_select,
+                warm_spare: CONNECT_CONFIG.warm_spare,
             })
         }
     };
@@ -106,9 +172,76 @@ static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
             CONNECT_CONFIG.force_protocol,
             CONNECT_CONFIG.use_bridges
         );
+        audit::audit(
+            "startup",
+            "start",
+            &format!(
+                "exit = {:?}, force_protocol = {:?}, use_bridges = {}, vpn_mode = {:?}",
+                CONNECT_CONFIG.exit_server,
+                CONNECT_CONFIG.force_protocol,
+                CONNECT_CONFIG.use_bridges,
+                CONNECT_CONFIG.vpn_mode
+            ),
+        );
         smol::Timer::after(Duration::from_secs(1)).await;
+        // preflight has already refused pre-login mode together with VPN mode or port forwarding
+        if CONNECT_CONFIG.prelogin {
+            log::info!("pre-login mode: only account and payment pages are reachable");
+        }
+
+        smolscale::spawn(notify::notify_loop()).detach();
+        stats::init_scopes();
+        smolscale::spawn(usage_log::usage_loop()).detach();
+        smolscale::spawn(tunnel::roaming::network_watch_loop()).detach();
+        if let Some(path) = CONNECT_CONFIG.flow_mirror.clone() {
+            smolscale::spawn(flow_mirror::mirror_loop(path)).detach();
+        }
+        if let Some(path) = CONNECT_CONFIG.control_socket.clone() {
+            smolscale::spawn(async move {
+                if let Err(err) = stats::control_socket_loop(path).await {
+                    log::error!("control socket failed: {:?}", err);
+                }
+            })
+            .detach();
+        }
+        if let Some(listen) = CONNECT_CONFIG.metrics_listen {
+            std::thread::spawn(move || {
+                if let Err(err) = stats::metrics_loop(listen) {
+                    log::error!("metrics endpoint failed: {:?}", err);
+                }
+            });
+        }
+        if CONNECT_CONFIG.kill_switch {
+            kill_switch::prepare();
+            smolscale::spawn(kill_switch::kill_switch_loop()).detach();
+        }
+        if CONNECT_CONFIG.override_connect.is_none() {
+            smolscale::spawn(plan_expiry::plan_expiry_loop()).detach();
+        }
+
+        // routing modes install their own signal handlers, which tear down routes and exit right away
+        #[cfg(unix)]
+        if CONNECT_CONFIG.netns.is_none() && CONNECT_CONFIG.vpn_mode.is_none() {
+            drain::drain_on_signals();
+        }
+
+        if let Some(deadline) = CONNECT_CONFIG.connect_deadline {
+            smolscale::spawn(enforce_connect_deadline(deadline)).detach();
+        }
+
+        // the stats API and VPN are not proxy listeners, so the listener gate doesn't hold them back
+        Lazy::force(&stats::STATS_THREAD);
+        Lazy::force(&vpn::VPN_SHUFFLE_TASK);
+
+        gate::wait_bind_allowed().await;
 
         // http proxy
+        if !CONNECT_CONFIG.http_listen.ip().is_loopback() && CONNECT_CONFIG.http_auth.is_none() {
+            log::warn!(
+                "HTTP proxy listens on {} without --http-auth, so anybody who can reach it can use it",
+                CONNECT_CONFIG.http_listen
+            );
+        }
         let _socks2h = smolscale::spawn(Compat::new(crate::socks2http::run_tokio(
             CONNECT_CONFIG.http_listen,
             {
@@ -116,12 +249,16 @@ static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
                 addr.set_ip("127.0.0.1".parse().unwrap());
                 addr
             },
+            CONNECT_CONFIG.http_idempotent_retries,
+            CONNECT_CONFIG.tcp_keepalive,
+            CONNECT_CONFIG.http_auth.clone(),
         )));
 
         // socks5 proxy
         let socks5_fut = smolscale::spawn(socks5::socks5_loop(
             CONNECT_CONFIG.socks5_listen,
             CONNECT_CONFIG.exclude_prc,
+            CONNECT_CONFIG.prelogin,
         ));
         // dns
         let dns_fut = smolscale::spawn(dns::dns_loop(CONNECT_CONFIG.dns_listen));
@@ -136,11 +273,27 @@ static CONNECT_TASK: Lazy<Task<Infallible>> = Lazy::new(|| {
             smolscale::spawn(select_all(port_forwarders)).await;
         }
 
-        Lazy::force(&stats::STATS_THREAD);
-
         // ready, set, go!
-        Lazy::force(&vpn::VPN_SHUFFLE_TASK);
         socks5_fut.race(dns_fut).await.unwrap();
         panic!("something died")
     })
 });
+
+/// Exits the process with a JSON failure report if the tunnel doesn't come up within the deadline.
+async fn enforce_connect_deadline(deadline: Duration) {
+    let connected = async {
+        while !TUNNEL.status().connected() {
+            smol::Timer::after(Duration::from_millis(100)).await;
+        }
+    };
+    if connected.timeout(deadline).await.is_none() {
+        let report = serde_json::json!({
+            "error": "connect_deadline_exceeded",
+            "deadline_secs": deadline.as_secs_f64(),
+            "last_error": LAST_TUNNEL_ERROR.lock().clone(),
+        });
+        log::error!("could not connect within {:?}", deadline);
+        println!("{}", report);
+        std::process::exit(2);
+    }
+}
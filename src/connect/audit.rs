@@ -0,0 +1,89 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::CONNECT_CONFIG;
+
+/// One line of the audit log. Every entry commits to the hash of the previous one, so that removing or editing entries breaks the chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AuditEntry {
+    timestamp: u64,
+    origin: String,
+    action: String,
+    detail: String,
+    prev_hash: String,
+    hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for field in [
+            &self.timestamp.to_string(),
+            &self.origin,
+            &self.action,
+            &self.detail,
+            &self.prev_hash,
+        ] {
+            hasher.update(&(field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+/// The open audit log, together with the hash of its last entry. None if there is no audit log, or it cannot be opened.
+static AUDIT_LOG: Lazy<Option<Mutex<(File, String)>>> = Lazy::new(|| {
+    let path = CONNECT_CONFIG.audit_log.as_ref()?;
+    match open_audit_log(path) {
+        Ok(log) => Some(Mutex::new(log)),
+        Err(err) => {
+            log::warn!("cannot open audit log {:?}, not auditing: {:?}", path, err);
+            None
+        }
+    }
+});
+
+/// Opens the audit log for appending, creating it if needed, and finds the hash of its last entry.
+fn open_audit_log(path: &Path) -> anyhow::Result<(File, String)> {
+    let last_hash = File::open(path)
+        .ok()
+        .and_then(|f| BufReader::new(f).lines().filter_map(|l| l.ok()).last())
+        .and_then(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+        .map(|entry| entry.hash)
+        .unwrap_or_default();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok((file, last_hash))
+}
+
+/// Records a control-plane action into the audit log, if one is configured.
+pub fn audit(origin: &str, action: &str, detail: &str) {
+    if let Some(log) = AUDIT_LOG.as_ref() {
+        let mut log = log.lock();
+        let mut entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            origin: origin.into(),
+            action: action.into(),
+            detail: detail.into(),
+            prev_hash: log.1.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        let line = serde_json::to_string(&entry).unwrap();
+        if let Err(err) = writeln!(log.0, "{}", line).and_then(|_| log.0.flush()) {
+            log::error!("cannot write to audit log: {:?}", err);
+            return;
+        }
+        log.1 = entry.hash;
+    }
+}
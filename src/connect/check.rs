@@ -0,0 +1,461 @@
+use std::{net::SocketAddr, path::Path};
+
+use colored::Colorize;
+
+use crate::{
+    config::{ConnectOpt, VpnMode},
+    l10n::{tr, tr_args},
+};
+
+/// Validates the connect configuration without connecting, printing every check and what would happen. Returns whether everything passed.
+pub fn check_config(cfg: &ConnectOpt) -> bool {
+    let mut all_ok = true;
+    let mut report = |what: &str, res: anyhow::Result<()>| match res {
+        Ok(()) => println!("[{}] {}", " ok ".green(), what),
+        Err(err) => {
+            all_ok = false;
+            println!("[{}] {}: {}", "FAIL".red(), what, err)
+        }
+    };
+
+    report(&tr("check-credentials"), check_credentials(cfg));
+    if let Some(regex) = &cfg.force_protocol {
+        report(
+            "--force-protocol",
+            regex::Regex::new(regex).map(|_| ()).map_err(|e| e.into()),
+        );
+    }
+    if !cfg.bypass_app.is_empty() || !cfg.force_app.is_empty() {
+        report(
+            "--bypass-app/--force-app",
+            if cfg!(target_os = "linux")
+                && (cfg.vpn_mode == Some(VpnMode::TunRoute)
+                    || (cfg.vpn_mode == Some(VpnMode::Tun) && cfg.tun_default_route))
+            {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!(
+                    "per-app split tunneling needs Linux and --vpn-mode tun-route, or tun with --tun-default-route"
+                ))
+            },
+        );
+    }
+    if cfg.share_hotspot {
+        report(
+            "--share-hotspot",
+            if (cfg!(windows) && cfg.vpn_mode == Some(VpnMode::WinDivert))
+                || (cfg!(target_os = "macos")
+                    && (cfg.vpn_mode == Some(VpnMode::TunRoute)
+                        || (cfg.vpn_mode == Some(VpnMode::Tun) && cfg.tun_default_route)))
+            {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!(
+                    "hotspot sharing needs --vpn-mode windivert on Windows, or tun-route or tun with --tun-default-route on macOS"
+                ))
+            },
+        );
+    }
+    if let Some(path) = &cfg.domain_rules {
+        report(
+            "--domain-rules",
+            std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| super::split_tunnel::parse_domain_rules(&contents))
+                .map(|_| ()),
+        );
+    }
+    if let Some(url) = &cfg.doh_upstream {
+        report("--doh-upstream", super::doh::DohPool::new(url).map(|_| ()));
+    }
+    report(
+        &tr("check-credential-cache"),
+        check_writable(&cfg.auth.credential_cache),
+    );
+
+    if let Some(creds) = &cfg.http_auth {
+        report(
+            "--http-auth",
+            if creds.contains(':') {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("must be given as USERNAME:PASSWORD"))
+            },
+        );
+    }
+    for (what, res) in capability_checks(cfg)
+        .into_iter()
+        .chain(ephemeral_checks(cfg))
+        .chain(conflict_checks(cfg))
+        .chain(syntax_checks(cfg))
+    {
+        report(&what, res);
+    }
+
+    println!();
+    println!("{}", tr("check-would-connect"));
+    let exit = cfg
+        .override_connect
+        .clone()
+        .or_else(|| cfg.exit_server.clone())
+        .unwrap_or_else(|| {
+            tr_args(
+                "check-exit-automatic",
+                &[("strategy", &format!("{:?}", cfg.exit_select))],
+            )
+        });
+    println!("  {}", tr_args("check-summary-exit", &[("exit", &exit)]));
+    let bridges = tr(if cfg.use_bridges {
+        "check-yes"
+    } else {
+        "check-auto"
+    });
+    println!(
+        "  {}",
+        tr_args("check-summary-bridges", &[("bridges", &bridges)])
+    );
+    println!(
+        "  {}",
+        tr_args("check-summary-http", &[("addr", &cfg.http_listen)])
+    );
+    println!(
+        "  {}",
+        tr_args("check-summary-socks5", &[("addr", &cfg.socks5_listen)])
+    );
+    println!(
+        "  {}",
+        tr_args("check-summary-dns", &[("addr", &cfg.dns_listen)])
+    );
+    println!(
+        "  {}",
+        tr_args("check-summary-stats", &[("addr", &cfg.stats_listen)])
+    );
+    for desc in cfg.forward_ports.iter() {
+        if let Some((from, to)) = desc.split_once(":::") {
+            println!(
+                "  {}",
+                tr_args("check-summary-forward", &[("from", &from), ("to", &to)])
+            );
+        }
+    }
+    let vpn_mode = match cfg.vpn_mode {
+        Some(vpn_mode) => format!("{:?}", vpn_mode),
+        None => tr("check-vpn-disabled"),
+    };
+    println!("  {}", tr_args("check-summary-vpn", &[("mode", &vpn_mode)]));
+    all_ok
+}
+
+/// Checks up front that the OS lets us do everything the configuration needs, so that a taken port or a missing permission stops the daemon right away with a hint on how to fix it, rather than failing halfway through connecting. Returns every failure, one per line.
+pub fn preflight(cfg: &ConnectOpt) -> anyhow::Result<()> {
+    let failures = capability_checks(cfg)
+        .into_iter()
+        .chain(ephemeral_checks(cfg))
+        .chain(conflict_checks(cfg))
+        .chain(syntax_checks(cfg))
+        .filter_map(|(what, res)| res.err().map(|err| format!("{}: {}", what, err)))
+        .collect::<Vec<_>>();
+    if !failures.is_empty() {
+        anyhow::bail!("{}", failures.join("\n"))
+    }
+    Ok(())
+}
+
+/// The options that write files of their own, which ephemeral mode refuses rather than have them quietly write to disk.
+fn ephemeral_checks(cfg: &ConnectOpt) -> Vec<(String, anyhow::Result<()>)> {
+    [
+        ("--crash-log", cfg.crash_log.is_some()),
+        ("--audit-log", cfg.audit_log.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, given)| *given)
+    .map(|(flag, _)| (flag.to_string(), crate::storage::refuse_if_ephemeral(flag)))
+    .collect()
+}
+
+/// The options that are only parsed once connecting needs them, checked up front so that a malformed one refuses to start rather than fail halfway.
+fn syntax_checks(cfg: &ConnectOpt) -> Vec<(String, anyhow::Result<()>)> {
+    let covers = cfg.bridge_cover.iter().map(|spec| {
+        (
+            format!("--bridge-cover {}", spec),
+            crate::connect::tunnel::bridge_cover::parse_bridge_cover(spec).map(|_| ()),
+        )
+    });
+    // apps given as paths are cgroups, whose paths go into iptables rules
+    let cgroups = [
+        ("--bypass-app", &cfg.bypass_app),
+        ("--force-app", &cfg.force_app),
+    ]
+    .into_iter()
+    .flat_map(|(flag, apps)| apps.iter().map(move |app| (flag, app)))
+    .filter(|(_, app)| app.starts_with('/'))
+    .map(|(flag, app)| (format!("{} {}", flag, app), check_cgroup(app)));
+    covers.chain(cgroups).collect()
+}
+
+/// Checks that a cgroup path, relative to the cgroup root, names a cgroup that exists.
+fn check_cgroup(path: &str) -> anyhow::Result<()> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative.as_os_str().is_empty()
+        || relative
+            .components()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        anyhow::bail!("not a cgroup path")
+    }
+    if !Path::new("/sys/fs/cgroup").join(relative).is_dir() {
+        anyhow::bail!("no such cgroup under /sys/fs/cgroup")
+    }
+    Ok(())
+}
+
+/// The options that cannot be combined, checked up front so that connecting never has to give up on them halfway.
+fn conflict_checks(cfg: &ConnectOpt) -> Vec<(String, anyhow::Result<()>)> {
+    let mut checks = vec![];
+    if cfg.prelogin && (cfg.vpn_mode.is_some() || !cfg.forward_ports.is_empty()) {
+        checks.push((
+            "--prelogin".into(),
+            Err(anyhow::anyhow!(tr("check-prelogin-conflict"))),
+        ));
+    }
+    checks
+}
+
+/// The checks of what the OS has to allow: binding the listeners, and whatever the VPN mode, network namespace and kill switch need.
+fn capability_checks(cfg: &ConnectOpt) -> Vec<(String, anyhow::Result<()>)> {
+    let mut checks = vec![
+        (tr("check-http-listener"), check_tcp_listen(cfg.http_listen)),
+        (
+            tr("check-socks5-listener"),
+            check_tcp_listen(cfg.socks5_listen),
+        ),
+        (
+            tr("check-stats-listener"),
+            check_tcp_listen(cfg.stats_listen),
+        ),
+        (
+            tr("check-dns-listener"),
+            std::net::UdpSocket::bind(cfg.dns_listen)
+                .map(|_| ())
+                .map_err(|e| cannot_bind(cfg.dns_listen, e)),
+        ),
+    ];
+    if let Some(listen) = cfg.metrics_listen {
+        checks.push(("--metrics-listen".into(), check_tcp_listen(listen)));
+    }
+    // ephemeral mode refuses the audit log instead, and must not create it
+    if let Some(path) = cfg
+        .audit_log
+        .as_ref()
+        .filter(|_| !crate::storage::ephemeral())
+    {
+        checks.push((
+            "--audit-log".into(),
+            super::audit::open_audit_log(path).map(|_| ()),
+        ));
+    }
+    for desc in cfg.forward_ports.iter() {
+        checks.push((
+            tr_args("check-port-forward", &[("desc", &format!("{:?}", desc))]),
+            check_port_forward(desc),
+        ));
+    }
+    if let Some(netns) = cfg.netns.as_ref() {
+        checks.push((tr_args("check-netns", &[("name", netns)]), check_netns(cfg)));
+    }
+    if let Some(vpn_mode) = cfg.vpn_mode {
+        checks.push((
+            tr_args("check-vpn-mode", &[("mode", &format!("{:?}", vpn_mode))]),
+            check_vpn_mode(vpn_mode),
+        ));
+    }
+    if !cfg.tun_route6.is_empty() && cfg.tun_address6.is_none() {
+        checks.push((
+            "--tun-route6".into(),
+            Err(anyhow::anyhow!(
+                "IPv6 routes need an IPv6 address given by --tun-address6"
+            )),
+        ));
+    }
+    let tools = required_tools(cfg);
+    if !tools.is_empty() {
+        checks.push((tr("check-system-tools"), check_tools(&tools)));
+    }
+    checks
+}
+
+fn check_credentials(cfg: &ConnectOpt) -> anyhow::Result<()> {
+    if cfg.override_connect.is_none()
+        && (cfg.auth.username.is_empty() || cfg.auth.password.is_empty())
+    {
+        anyhow::bail!(tr("check-credentials-missing"))
+    }
+    Ok(())
+}
+
+/// Checks that the given file or directory could be written, without creating anything: the path itself if it exists, or else the nearest ancestor that does.
+fn check_writable(path: &Path) -> anyhow::Result<()> {
+    let existing = path
+        .ancestors()
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+        .ok_or_else(|| anyhow::anyhow!("no part of {:?} exists", path))?;
+    #[cfg(unix)]
+    let writable = {
+        use std::os::unix::ffi::OsStrExt;
+        let existing = std::ffi::CString::new(existing.as_os_str().as_bytes())?;
+        unsafe { libc::access(existing.as_ptr(), libc::W_OK) == 0 }
+    };
+    #[cfg(not(unix))]
+    let writable = !std::fs::metadata(existing)?.permissions().readonly();
+    if !writable {
+        anyhow::bail!("{:?} is not writable", existing)
+    }
+    Ok(())
+}
+
+fn check_tcp_listen(addr: SocketAddr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind(addr)
+        .map(|_| ())
+        .map_err(|e| cannot_bind(addr, e))
+}
+
+fn cannot_bind(addr: SocketAddr, err: std::io::Error) -> anyhow::Error {
+    let message = tr_args("check-cannot-bind", &[("addr", &addr), ("error", &err)]);
+    let hint = match err.kind() {
+        std::io::ErrorKind::AddrInUse => Some(tr("check-hint-addr-in-use")),
+        std::io::ErrorKind::PermissionDenied => Some(tr("check-hint-low-port")),
+        std::io::ErrorKind::AddrNotAvailable => Some(tr("check-hint-addr-not-local")),
+        _ => None,
+    };
+    match hint {
+        Some(hint) => anyhow::anyhow!("{}; {}", message, hint),
+        None => anyhow::anyhow!(message),
+    }
+}
+
+fn check_port_forward(desc: &str) -> anyhow::Result<()> {
+    let exploded = desc.split(":::").collect::<Vec<_>>();
+    if exploded.len() != 2 {
+        anyhow::bail!(tr("check-port-forward-syntax"))
+    }
+    let listen_addr: SocketAddr = exploded[0].parse()?;
+    check_tcp_listen(listen_addr)
+}
+
+fn check_netns(cfg: &ConnectOpt) -> anyhow::Result<()> {
+    if cfg.vpn_mode.is_some() {
+        anyhow::bail!(tr("check-netns-conflict"))
+    }
+    #[cfg(target_os = "linux")]
+    {
+        check_vpn_mode(VpnMode::TunNoRoute)
+    }
+    #[cfg(not(target_os = "linux"))]
+    anyhow::bail!(tr("check-linux-only"))
+}
+
+fn check_vpn_mode(vpn_mode: VpnMode) -> anyhow::Result<()> {
+    match vpn_mode {
+        VpnMode::InheritedFd => {
+            let fd = std::env::var("GEPH_VPN_FD")
+                .map_err(|_| anyhow::anyhow!(tr("check-vpn-fd-unset")))?;
+            fd.parse::<i32>()
+                .map_err(|_| anyhow::anyhow!(tr("check-vpn-fd-invalid")))?;
+            Ok(())
+        }
+        VpnMode::Tun if !cfg!(any(target_os = "linux", target_os = "macos")) => {
+            anyhow::bail!(tr("check-linux-macos-only"))
+        }
+        VpnMode::TunNoRoute | VpnMode::TunRoute | VpnMode::Tun => {
+            #[cfg(unix)]
+            {
+                #[cfg(target_os = "linux")]
+                if !std::path::Path::new("/dev/net/tun").exists() {
+                    anyhow::bail!(tr("check-no-dev-tun"))
+                }
+                // CAP_NET_ADMIN alone is not enough: routing and the kill switch shell out to sh, ip, iptables and nft, which don't inherit file capabilities
+                if unsafe { libc::geteuid() } != 0 {
+                    anyhow::bail!(tr("check-tun-needs-root"))
+                }
+                Ok(())
+            }
+            #[cfg(not(unix))]
+            anyhow::bail!(tr("check-unix-only"))
+        }
+        VpnMode::WinDivert => {
+            if cfg!(windows) {
+                Ok(())
+            } else {
+                anyhow::bail!(tr("check-windows-only"))
+            }
+        }
+        VpnMode::WinTun => {
+            #[cfg(windows)]
+            {
+                unsafe { wintun::load() }
+                    .map(|_| ())
+                    .map_err(|_| anyhow::anyhow!(tr("check-no-wintun")))
+            }
+            #[cfg(not(windows))]
+            anyhow::bail!(tr("check-windows-only"))
+        }
+        VpnMode::Stdio => Ok(()),
+    }
+}
+
+/// The external programs that routing and the kill switch shell out to, with the package each usually comes in.
+fn required_tools(cfg: &ConnectOpt) -> Vec<(&'static str, &'static str)> {
+    let mut tools = vec![];
+    if cfg!(target_os = "linux") {
+        let takes_default_route = cfg.vpn_mode == Some(VpnMode::TunRoute)
+            || (cfg.vpn_mode == Some(VpnMode::Tun) && cfg.tun_default_route);
+        let adds_routes = takes_default_route
+            || cfg.netns.is_some()
+            || (cfg.vpn_mode == Some(VpnMode::Tun)
+                && (!cfg.tun_route.is_empty() || cfg.tun_address6.is_some()));
+        if adds_routes {
+            tools.push(("ip", "iproute2"));
+        }
+        if takes_default_route {
+            tools.push(("iptables", "iptables"));
+            tools.push(("ip6tables", "iptables"));
+        }
+        if cfg.kill_switch {
+            tools.push(("nft", "nftables"));
+        }
+    }
+    if cfg!(target_os = "macos") && cfg.vpn_mode == Some(VpnMode::Tun) {
+        tools.push(("ifconfig", "macOS"));
+        if !cfg.tun_route.is_empty() || !cfg.tun_route6.is_empty() {
+            tools.push(("route", "macOS"));
+        }
+        if cfg.tun_default_route {
+            tools.push(("pfctl", "macOS"));
+        }
+    }
+    tools
+}
+
+/// Checks that the given programs are on the PATH, or in the sbin directories that the routing scripts add to it.
+fn check_tools(tools: &[(&str, &str)]) -> anyhow::Result<()> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let dirs = std::env::split_paths(&path)
+        .chain(["/usr/sbin", "/sbin"].into_iter().map(Into::into))
+        .collect::<Vec<std::path::PathBuf>>();
+    for (tool, package) in tools {
+        if !dirs.iter().any(|dir| dir.join(tool).is_file()) {
+            anyhow::bail!(tr_args(
+                "check-missing-tool",
+                &[("tool", tool), ("package", package)]
+            ))
+        }
+    }
+    Ok(())
+}
@@ -1,33 +1,66 @@
-use std::net::SocketAddr;
+use once_cell::sync::Lazy;
 use smol::{
     channel::{Receiver, Sender},
     prelude::*,
 };
 use smol_timeout::TimeoutExt;
 use sosistab2::MuxStream;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use std::time::Duration;
-use std::{sync::Arc, time::Instant};
+use std::time::Instant;
+
+use super::{doh::DohPool, gate, CONNECT_CONFIG, TUNNEL};
+
+/// The resolver behind the DNS listener, shared with the VPN, which sends every DNS query it sees here rather than letting it out as plaintext.
+pub static TUNNEL_RESOLVER: Lazy<TunnelResolver> = Lazy::new(|| {
+    TunnelResolver::new(CONNECT_CONFIG.doh_upstream.as_deref())
+        .expect("invalid DNS-over-HTTPS upstream")
+});
 
-use super::TUNNEL;
+/// Resolves DNS queries through the tunnel, with DNS-over-HTTPS to the upstream URL if there is one, or else with plain DNS over TCP.
+pub struct TunnelResolver {
+    pool: DnsPool,
+    doh: Option<DohPool>,
+}
+
+impl TunnelResolver {
+    fn new(doh_upstream: Option<&str>) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool: DnsPool::new(),
+            doh: doh_upstream.map(DohPool::new).transpose()?,
+        })
+    }
 
-/// Handle DNS requests from localhost
+    /// Does a DNS request, returning the raw response.
+    pub async fn request(&self, buff: &[u8]) -> Option<Vec<u8>> {
+        match &self.doh {
+            Some(doh) => doh.request(buff).await,
+            None => self.pool.request(buff).await,
+        }
+    }
+}
+
+/// Handle DNS requests from localhost, resolving them through the tunnel.
 pub async fn dns_loop(addr: SocketAddr) -> anyhow::Result<()> {
     let socket = smol::net::UdpSocket::bind(addr).await?;
     let mut buf = [0; 2048];
-    let pool = Arc::new(DnsPool::new());
     log::debug!("DNS loop started");
     loop {
         let (n, c_addr) = socket.recv_from(&mut buf).await?;
         let buff = buf[..n].to_vec();
         let socket = socket.clone();
-        let pool = pool.clone();
         smolscale::spawn(async move {
+            if !gate::admit() {
+                if let Some(resp) = servfail(&buff) {
+                    let _ = socket.send_to(&resp, c_addr).await;
+                }
+                return;
+            }
             let fut = || async {
-                socket
-                    .send_to(&pool.request(&buff).await?, c_addr)
-                    .await
-                    .ok()?;
+                let resp = TUNNEL_RESOLVER.request(&buff).await?;
+                socket.send_to(&resp, c_addr).await.ok()?;
                 Some(())
             };
             for _ in 0u32..5 {
@@ -40,50 +73,161 @@ pub async fn dns_loop(addr: SocketAddr) -> anyhow::Result<()> {
     }
 }
 
+/// A SERVFAIL answer to the query, for queries refused while the tunnel is down, so that the app fails right away rather than time out.
+fn servfail(query: &[u8]) -> Option<Vec<u8>> {
+    if query.get(4..6)? == [0, 0] {
+        return None;
+    }
+    // the header, then the first question: labels up to the root, then type and class
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let mut resp = query.get(..end + 4)?.to_vec();
+    // a response, keeping the opcode and recursion desired, then recursion available and SERVFAIL
+    resp[2] = 0x80 | (resp[2] & 0x79);
+    resp[3] = 0x82;
+    resp[4..12].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    Some(resp)
+}
+/// While earlier batches are still waiting on answers, queries that arrive within this long of the first one go out along with it, pipelined in one write on one stream, so that a burst of lookups costs one tunnel frame rather than one each.
+const BATCH_WINDOW: Duration = Duration::from_millis(3);
+
+/// At most this many queries go out together.
+const MAX_BATCH: usize = 32;
+
+static QUERIES: AtomicU64 = AtomicU64::new(0);
+static BATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Batches sent and still waiting on some of their answers.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many plain DNS queries went through the tunnel since startup, and in how many writes, which is fewer when bursts of queries were batched.
+pub fn dns_batch_stats() -> (u64, u64) {
+    (
+        QUERIES.load(Ordering::Relaxed),
+        BATCHES.load(Ordering::Relaxed),
+    )
+}
+
+type Query = (Vec<u8>, Sender<Vec<u8>>);
+
 /// A DNS connection pool
 pub struct DnsPool {
-    send_conn: Sender<(MuxStream, Instant)>,
-    recv_conn: Receiver<(MuxStream, Instant)>,
+    send_query: Sender<Query>,
+    _batcher: smol::Task<()>,
 }
 
 impl DnsPool {
     /// Create a new pool
     pub fn new() -> Self {
-        let (send_conn, recv_conn) = smol::channel::unbounded();
+        let (send_query, recv_query) = smol::channel::unbounded();
         Self {
-            send_conn,
-            recv_conn,
+            send_query,
+            _batcher: smolscale::spawn(batch_loop(recv_query)),
         }
     }
 
     /// Do a DNS request.
     pub async fn request(&self, buff: &[u8]) -> Option<Vec<u8>> {
         let dns_timeout = Duration::from_secs(10);
-        let mut conn = {
-            let lala = loop {
-                if let Ok((c, i)) = self.recv_conn.try_recv() {
-                    if i.elapsed().as_secs() < 5 {
-                        break Some(c);
-                    }
-                } else {
-                    break None;
+        // a query shorter than its header has no ID to tell its answer apart by
+        if buff.len() < 12 {
+            return None;
+        }
+        let (send_resp, recv_resp) = smol::channel::bounded(1);
+        self.send_query
+            .send((buff.to_vec(), send_resp))
+            .await
+            .ok()?;
+        recv_resp.recv().timeout(dns_timeout).await?.ok()
+    }
+}
+
+/// Gathers the queries arriving close together into batches, and sends each batch off on its own. A query arriving while nothing is in flight goes out right away, along with whatever is already queued, so that lone lookups are never held back; only during a burst are later queries held for [BATCH_WINDOW]. This is the only small-write batching the client does: compressing frames, or coalescing small writes of different connections into shared frames, would need the exit to understand them.
+async fn batch_loop(recv_query: Receiver<Query>) {
+    let (send_conn, recv_conn) = smol::channel::unbounded();
+    loop {
+        let first = match recv_query.recv().await {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match recv_query.try_recv() {
+                Ok(query) => batch.push(query),
+                Err(_) => break,
+            }
+        }
+        let deadline = Instant::now() + BATCH_WINDOW;
+        while batch.len() < MAX_BATCH && IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            match recv_query.recv().timeout(remaining).await {
+                Some(Ok(query)) => batch.push(query),
+                _ => break,
+            }
+        }
+        let send_conn = send_conn.clone();
+        let recv_conn = recv_conn.clone();
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        smolscale::spawn(async move {
+            if send_batch(batch, &send_conn, &recv_conn).await.is_none() {
+                log::debug!("DNS batch failed");
+            }
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+}
+
+/// Sends a batch of queries down one stream in a single write and hands each answer to whoever asked. DNS over TCP lets the answers come back in any order, so the queries are renumbered by their place in the batch, and the answers matched and given back their IDs by that.
+async fn send_batch(
+    batch: Vec<Query>,
+    send_conn: &Sender<(MuxStream, Instant)>,
+    recv_conn: &Receiver<(MuxStream, Instant)>,
+) -> Option<()> {
+    let dns_timeout = Duration::from_secs(10);
+    let mut conn = {
+        let lala = loop {
+            if let Ok((c, i)) = recv_conn.try_recv() {
+                if i.elapsed().as_secs() < 5 {
+                    break Some(c);
                 }
-            };
-            match lala {
-                Some(v) => v,
-                _ => TUNNEL
-                    .connect_stream("1.0.0.1:53")
-                    .timeout(dns_timeout)
-                    .await?
-                    .ok()?,
+            } else {
+                break None;
             }
         };
-        conn.write_all(&(buff.len() as u16).to_be_bytes())
-            .timeout(dns_timeout)
-            .await?
-            .ok()?;
-        conn.write_all(buff).timeout(dns_timeout).await?.ok()?;
-        conn.flush().timeout(dns_timeout).await?.ok()?;
+        match lala {
+            Some(v) => v,
+            _ => TUNNEL
+                .connect_stream("1.0.0.1:53")
+                .timeout(dns_timeout)
+                .await?
+                .ok()?,
+        }
+    };
+    let mut framed = Vec::with_capacity(batch.iter().map(|(q, _)| q.len() + 2).sum());
+    let mut waiting = Vec::with_capacity(batch.len());
+    for (idx, (mut query, send_resp)) in batch.into_iter().enumerate() {
+        let original_id = [query[0], query[1]];
+        query[..2].copy_from_slice(&(idx as u16).to_be_bytes());
+        framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&query);
+        waiting.push(Some((original_id, send_resp)));
+    }
+    QUERIES.fetch_add(waiting.len() as u64, Ordering::Relaxed);
+    BATCHES.fetch_add(1, Ordering::Relaxed);
+    conn.write_all(&framed).timeout(dns_timeout).await?.ok()?;
+    conn.flush().timeout(dns_timeout).await?.ok()?;
+    let mut outstanding = waiting.len();
+    while outstanding > 0 {
         let mut n_buf = [0; 2];
         conn.read_exact(&mut n_buf)
             .timeout(dns_timeout)
@@ -94,7 +238,37 @@ impl DnsPool {
             .timeout(dns_timeout)
             .await?
             .ok()?;
-        self.send_conn.try_send((conn, Instant::now())).unwrap();
-        Some(true_buf)
+        if true_buf.len() < 2 {
+            continue;
+        }
+        let idx = u16::from_be_bytes([true_buf[0], true_buf[1]]) as usize;
+        if let Some((original_id, send_resp)) = waiting.get_mut(idx).and_then(Option::take) {
+            true_buf[..2].copy_from_slice(&original_id);
+            let _ = send_resp.try_send(true_buf);
+            outstanding -= 1;
+        }
+    }
+    send_conn.try_send((conn, Instant::now())).unwrap();
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servfail_keeps_the_question() {
+        // a query for example.com A with recursion desired, and an EDNS record after the question
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let question_end = query.len();
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        let resp = servfail(&query).unwrap();
+        assert_eq!(
+            &resp[..12],
+            &[0x12, 0x34, 0x81, 0x82, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&resp[12..], &query[12..question_end]);
+        assert_eq!(servfail(&query[..20]), None);
     }
 }
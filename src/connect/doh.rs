@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_native_tls::TlsStream;
+use http_types::Url;
+use smol::channel::{Receiver, Sender};
+use smol_timeout::TimeoutExt;
+use sosistab2::MuxStream;
+
+use super::{http_wire::post_dns_message, TUNNEL};
+
+/// Pooled connections idle for longer than this are not reused, since the upstream has likely closed them.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DOH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A DNS-over-HTTPS upstream reached through the tunnel, with a pool of keep-alive connections to it.
+pub struct DohPool {
+    host: String,
+    port: u16,
+    path: String,
+    send_conn: Sender<(TlsStream<MuxStream>, Instant)>,
+    recv_conn: Receiver<(TlsStream<MuxStream>, Instant)>,
+}
+
+impl DohPool {
+    /// Creates a pool for the given upstream URL, like "https://cloudflare-dns.com/dns-query".
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(url).context("invalid DNS-over-HTTPS URL")?;
+        if url.scheme() != "https" {
+            anyhow::bail!("DNS-over-HTTPS URL must start with https://")
+        }
+        let (send_conn, recv_conn) = smol::channel::unbounded();
+        Ok(Self {
+            host: url
+                .host_str()
+                .context("DNS-over-HTTPS URL has no host")?
+                .to_string(),
+            port: url.port().unwrap_or(443),
+            path: url.path().to_string(),
+            send_conn,
+            recv_conn,
+        })
+    }
+
+    /// Does a DNS request, returning the raw response.
+    pub async fn request(&self, buff: &[u8]) -> Option<Vec<u8>> {
+        match self.request_inner(buff).timeout(DOH_TIMEOUT).await? {
+            Ok(resp) => Some(resp),
+            Err(err) => {
+                log::debug!("DNS-over-HTTPS request failed: {:?}", err);
+                None
+            }
+        }
+    }
+
+    async fn request_inner(&self, buff: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut conn = loop {
+            match self.recv_conn.try_recv() {
+                Ok((c, i)) if i.elapsed() < IDLE_TIMEOUT => break c,
+                Ok(_) => continue,
+                Err(_) => {
+                    let stream = TUNNEL
+                        .connect_stream(&format!("{}:{}", self.host, self.port))
+                        .await?;
+                    break async_native_tls::TlsConnector::new()
+                        .connect(&self.host, stream)
+                        .await
+                        .context("TLS handshake with DNS-over-HTTPS upstream failed")?;
+                }
+            }
+        };
+        let (head, body) = post_dns_message(&mut conn, &self.host, &self.path, buff, true).await?;
+        if !head.closes() {
+            let _ = self.send_conn.try_send((conn, Instant::now()));
+        }
+        Ok(body)
+    }
+}
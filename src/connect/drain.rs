@@ -0,0 +1,118 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use event_listener::Event;
+
+use super::{audit::audit, tunnel::control::stop_tunnel, usage_log::flush_usage, CONNECT_CONFIG};
+
+static ACTIVE_STREAMS: AtomicUsize = AtomicUsize::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static DRAIN_EVENT: Event = Event::new();
+
+/// Counts a proxied stream as open for as long as it lives, so that shutdown can wait for it.
+pub struct StreamGuard {
+    _private: (),
+}
+
+impl StreamGuard {
+    pub fn new() -> Self {
+        ACTIVE_STREAMS.fetch_add(1, Ordering::SeqCst);
+        Self { _private: () }
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        ACTIVE_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits until shutdown starts. Listeners should stop accepting connections once this returns.
+pub async fn wait_draining() {
+    loop {
+        let listener = DRAIN_EVENT.listen();
+        if DRAINING.load(Ordering::SeqCst) {
+            return;
+        }
+        listener.await;
+    }
+}
+
+/// Stops accepting new connections, gives open streams up to the drain timeout to finish, then exits the process. Calling this again exits immediately.
+pub fn drain_and_exit(code: i32) {
+    if DRAINING.swap(true, Ordering::SeqCst) {
+        log::warn!("exiting without waiting for the remaining streams");
+        std::process::exit(code);
+    }
+    DRAIN_EVENT.notify(usize::MAX);
+    let timeout = CONNECT_CONFIG.drain_timeout;
+    audit(
+        "shutdown",
+        "drain",
+        &format!(
+            "{} streams, timeout {:?}",
+            ACTIVE_STREAMS.load(Ordering::SeqCst),
+            timeout
+        ),
+    );
+    smolscale::spawn(async move {
+        // give whoever asked for the shutdown a moment to hear back
+        smol::Timer::after(Duration::from_millis(300)).await;
+        wait_streams(timeout).await;
+        std::process::exit(code);
+    })
+    .detach();
+}
+
+/// Stops the way [drain_and_exit] does, but tears down the tunnel and flushes the usage log instead of exiting, for embedders like the iOS app that must not have their process killed. Returns once done; nothing can be started back up afterwards.
+pub async fn stop() {
+    DRAINING.store(true, Ordering::SeqCst);
+    DRAIN_EVENT.notify(usize::MAX);
+    audit(
+        "shutdown",
+        "stop",
+        &format!("{} streams", ACTIVE_STREAMS.load(Ordering::SeqCst)),
+    );
+    wait_streams(CONNECT_CONFIG.drain_timeout).await;
+    stop_tunnel();
+    if let Err(err) = flush_usage().await {
+        log::warn!("cannot record usage: {:?}", err);
+    }
+}
+
+/// Waits for open streams to finish, for up to the given timeout.
+async fn wait_streams(timeout: Duration) {
+    let start = Instant::now();
+    loop {
+        let remaining = ACTIVE_STREAMS.load(Ordering::SeqCst);
+        if remaining == 0 {
+            log::info!("all streams drained");
+            break;
+        }
+        if start.elapsed() >= timeout {
+            log::warn!("drain timed out, closing {} streams", remaining);
+            break;
+        }
+        log::info!(
+            "draining: waiting for {} streams, {:.0}s left",
+            remaining,
+            timeout.saturating_sub(start.elapsed()).as_secs_f64()
+        );
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Drains on SIGINT and SIGTERM. A second signal exits immediately.
+#[cfg(unix)]
+pub fn drain_on_signals() {
+    std::thread::spawn(|| {
+        let mut signals = signal_hook::iterator::Signals::new([libc::SIGINT, libc::SIGTERM])
+            .expect("did not register signal handler properly");
+        for signal in signals.forever() {
+            log::info!("received signal {}, shutting down", signal);
+            drain_and_exit(0);
+        }
+    });
+}
@@ -0,0 +1,184 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use smol::channel::{Receiver, Sender};
+
+use super::CONNECT_CONFIG;
+
+/// How many records may wait for the analysis socket before new ones are dropped.
+const BACKLOG: usize = 1024;
+
+/// The current version of the [FlowRecord] schema.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Destination ports at or above this are registered or ephemeral ports, which can single out a service, and are reported as 0.
+const MAX_REPORTED_PORT: u16 = 1024;
+
+/// Key for hashing destination hosts, fresh for every run, so that hashes can be compared within one run but not linked across runs or reversed by hashing likely hosts.
+static HOST_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// Metadata of one proxied connection, mirrored to the --flow-mirror socket when it closes. Records are written as newline-delimited JSON objects with these fields:
+///
+/// - `v`: the schema version, currently 2
+/// - `proto`: the transport protocol, currently always "tcp"
+/// - `src_net`: the network of the application that opened the connection, as its address truncated to a /24 (IPv4) or /48 (IPv6) prefix
+/// - `dst_host_hash`: the destination as the application asked for it (a domain or an IP address), hashed with a key that changes every run
+/// - `dst_port`: the destination port if below 1024, otherwise 0
+/// - `route`: "tunnel" if the connection went through Geph, "direct" if it bypassed it
+/// - `start_unix_ms`, `duration_ms`: when the connection opened, and how long it lasted, in milliseconds
+/// - `sent_bytes`, `recv_bytes`: the bytes the application sent and received
+///
+/// No payloads, no raw addresses, and nothing about the Geph account, bridges or exits, are included.
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowRecord {
+    pub v: u32,
+    pub proto: &'static str,
+    pub src_net: String,
+    pub dst_host_hash: String,
+    pub dst_port: u16,
+    pub route: &'static str,
+    pub start_unix_ms: u64,
+    pub duration_ms: u64,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+}
+
+static RECORDS: Lazy<(Sender<FlowRecord>, Receiver<FlowRecord>)> =
+    Lazy::new(|| smol::channel::bounded(BACKLOG));
+
+/// Whether flows are being mirrored.
+pub fn enabled() -> bool {
+    CONNECT_CONFIG.flow_mirror.is_some()
+}
+
+/// Milliseconds since the Unix epoch, for [FlowRecord::start_unix_ms].
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The address truncated to its /24 (IPv4) or /48 (IPv6) network, for [FlowRecord::src_net].
+fn truncate(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
+
+/// The keyed hash of a destination host, for [FlowRecord::dst_host_hash].
+fn hash_host(host: &str) -> String {
+    blake3::keyed_hash(&HOST_KEY, host.to_ascii_lowercase().as_bytes()).to_hex()[..16].to_string()
+}
+
+/// Queues a record for the analysis socket. Records are dropped rather than slowing down traffic when the socket can't keep up.
+fn record(rec: FlowRecord) {
+    if RECORDS.0.try_send(rec).is_err() {
+        log::trace!("flow mirror backlog full, dropping a record");
+    }
+}
+
+/// Counts the bytes of one flow, mirroring its record when dropped, however the flow ended.
+pub struct FlowGuard {
+    src: SocketAddr,
+    dst_host: String,
+    dst_port: u16,
+    route: &'static str,
+    start: Instant,
+    start_time: SystemTime,
+    sent_bytes: AtomicU64,
+    recv_bytes: AtomicU64,
+}
+
+impl FlowGuard {
+    pub fn new(src: SocketAddr, dst_host: String, dst_port: u16, route: &'static str) -> Self {
+        Self {
+            src,
+            dst_host,
+            dst_port,
+            route,
+            start: Instant::now(),
+            start_time: SystemTime::now(),
+            sent_bytes: AtomicU64::new(0),
+            recv_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sent(&self, n: usize) {
+        self.sent_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn recv(&self, n: usize) {
+        self.recv_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for FlowGuard {
+    fn drop(&mut self) {
+        if !enabled() {
+            return;
+        }
+        record(FlowRecord {
+            v: SCHEMA_VERSION,
+            proto: "tcp",
+            src_net: truncate(self.src.ip()),
+            dst_host_hash: hash_host(&self.dst_host),
+            dst_port: if self.dst_port < MAX_REPORTED_PORT {
+                self.dst_port
+            } else {
+                0
+            },
+            route: self.route,
+            start_unix_ms: unix_ms(self.start_time),
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            recv_bytes: self.recv_bytes.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Writes queued records to the unix socket at the given path, reconnecting whenever the analysis program goes away. Never returns.
+#[cfg(unix)]
+pub async fn mirror_loop(path: PathBuf) {
+    use smol::prelude::*;
+    loop {
+        let mut conn = match smol::net::unix::UnixStream::connect(&path).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::debug!("cannot connect to flow mirror socket {:?}: {:?}", path, err);
+                smol::Timer::after(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        log::info!("mirroring flow metadata to {:?}", path);
+        loop {
+            let rec = match RECORDS.1.recv().await {
+                Ok(rec) => rec,
+                Err(_) => return,
+            };
+            let mut line = serde_json::to_vec(&rec).expect("cannot serialize flow record");
+            line.push(b'\n');
+            if let Err(err) = conn.write_all(&line).await {
+                log::debug!("flow mirror socket went away: {:?}", err);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn mirror_loop(_path: PathBuf) {
+    log::warn!("--flow-mirror needs unix sockets, which this platform lacks");
+}
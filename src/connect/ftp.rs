@@ -0,0 +1,225 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use anyhow::Context;
+use smol::{
+    io::{AsyncBufReadExt, BufReader},
+    prelude::*,
+};
+use smol_timeout::TimeoutExt;
+
+use super::{
+    stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
+    tunnel::{
+        activity::notify_activity,
+        downgrade::{copy_capped, throttle},
+    },
+    TUNNEL,
+};
+
+/// How long a passive-mode listener waits for the FTP client's data connection.
+const DATA_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The reply to a passive-mode request that cannot be pointed at a local listener, rather than one pointing the client at the server directly, around the tunnel.
+const PASSIVE_FAILED: &[u8] = b"425 Cannot open passive data connection.\r\n";
+
+/// Copies an FTP server's control replies to the client, pointing passive-mode replies (227 to PASV, 229 to EPSV) at a local listener on `listen_ip` that tunnels the data connection to the server. FTP clients then work through the proxies without proxying data connections themselves, and even when the server reports its private address. Once the server accepts AUTH TLS, the rest of the control connection is TLS and is passed through untouched, so passive replies on it cannot be rewritten.
+pub async fn copy_ftp_replies(
+    from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    server_host: &str,
+    listen_ip: IpAddr,
+    mut on_bytes: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let mut from = BufReader::new(from);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = from.read_until(b'\n', &mut line).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        throttle(n).await;
+        match rewrite_passive(&line, server_host, listen_ip).await {
+            Some(rewritten) => to.write_all(&rewritten).await?,
+            None => to.write_all(&line).await?,
+        }
+        on_bytes(n);
+        if line.starts_with(b"234 ") {
+            // the TLS handshake follows, along with whatever of it was already read ahead
+            return copy_capped(from, to, on_bytes).await;
+        }
+    }
+}
+
+/// Rewrites a passive-mode reply to point at a fresh local listener, or returns None for any other line. A passive-mode reply that cannot be rewritten becomes a failure, and is never passed through.
+async fn rewrite_passive(line: &[u8], server_host: &str, listen_ip: IpAddr) -> Option<Vec<u8>> {
+    let line = std::str::from_utf8(line).ok()?;
+    if !line.starts_with("227") && !line.starts_with("229") {
+        return None;
+    }
+    match passive_reply(line, server_host, listen_ip).await {
+        Ok(reply) => Some(reply),
+        Err(err) => {
+            log::debug!(
+                "cannot rewrite FTP passive reply {:?}: {:?}",
+                line.trim(),
+                err
+            );
+            Some(PASSIVE_FAILED.to_vec())
+        }
+    }
+}
+
+async fn passive_reply(
+    line: &str,
+    server_host: &str,
+    listen_ip: IpAddr,
+) -> anyhow::Result<Vec<u8>> {
+    let (port, reported) = if line.starts_with("227") {
+        let (ip, port) = parse_pasv(line).context("unparseable 227 reply")?;
+        (port, Some(ip))
+    } else {
+        (parse_epsv(line).context("unparseable 229 reply")?, None)
+    };
+    // PASV replies can only carry IPv4 addresses, and a client that sent PASV expects nothing else
+    let listen_v4 = match listen_ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    };
+    if reported.is_some() && listen_v4.is_none() {
+        anyhow::bail!("a 227 reply cannot point at an IPv6 listener")
+    }
+    // servers behind NAT often report their private address, which is useless from outside
+    let target = match reported {
+        Some(ip) if is_public(ip) => format!("{}:{}", ip, port),
+        _ => match server_host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]:{}", ip, port),
+            Err(_) => format!("{}:{}", server_host, port),
+        },
+    };
+    let listener = smol::net::TcpListener::bind((listen_ip, 0)).await?;
+    let local_port = listener.local_addr()?.port();
+    log::debug!(
+        "FTP passive data connection to {} goes through local port {}",
+        target,
+        local_port
+    );
+    smolscale::spawn(async move {
+        if let Err(err) = tunnel_data(listener, &target).await {
+            log::debug!("FTP data connection to {} failed: {:?}", target, err)
+        }
+    })
+    .detach();
+    let reply = match listen_v4 {
+        Some(ip) if reported.is_some() => {
+            let [a, b, c, d] = ip.octets();
+            format!(
+                "227 Entering Passive Mode ({},{},{},{},{},{}).\r\n",
+                a,
+                b,
+                c,
+                d,
+                local_port >> 8,
+                local_port & 0xff
+            )
+        }
+        _ => format!(
+            "229 Entering Extended Passive Mode (|||{}|)\r\n",
+            local_port
+        ),
+    };
+    Ok(reply.into_bytes())
+}
+
+/// Accepts one data connection and relays it through the tunnel.
+async fn tunnel_data(listener: smol::net::TcpListener, target: &str) -> anyhow::Result<()> {
+    let (client, _) = listener
+        .accept()
+        .timeout(DATA_ACCEPT_TIMEOUT)
+        .await
+        .context("client never made the data connection")??;
+    drop(listener);
+    let remote = TUNNEL.connect_stream(target).await?;
+    smol::future::race(
+        copy_capped(remote.clone(), client.clone(), |n| {
+            STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+            notify_activity();
+        }),
+        copy_capped(client, remote, |n| {
+            STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+            notify_activity();
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Parses the address and port out of a 227 reply, like "227 Entering Passive Mode (192,0,2,7,19,137)". Some servers leave out the parentheses, so this looks for six comma-separated numbers anywhere after the code.
+fn parse_pasv(line: &str) -> Option<(Ipv4Addr, u16)> {
+    let numbers = line
+        .get(3..)?
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .find(|token| token.matches(',').count() == 5)?
+        .split(',')
+        .map(|n| n.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let ip = Ipv4Addr::new(numbers[0], numbers[1], numbers[2], numbers[3]);
+    Some((ip, u16::from_be_bytes([numbers[4], numbers[5]])))
+}
+
+/// Parses the port out of a 229 reply, like "229 Entering Extended Passive Mode (|||6446|)".
+fn parse_epsv(line: &str) -> Option<u16> {
+    let start = line.find("|||")? + 3;
+    let len = line[start..].find('|')?;
+    line[start..start + len].parse().ok()
+}
+
+fn is_public(ip: Ipv4Addr) -> bool {
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passive_replies() {
+        assert_eq!(
+            parse_pasv("227 Entering Passive Mode (192,0,2,7,19,137).\r\n"),
+            Some((Ipv4Addr::new(192, 0, 2, 7), 5001))
+        );
+        assert_eq!(
+            parse_pasv("227 Entering Passive Mode 10,0,0,1,4,1\r\n"),
+            Some((Ipv4Addr::new(10, 0, 0, 1), 1025))
+        );
+        assert_eq!(parse_pasv("227 Entering Passive Mode (1,2,3)\r\n"), None);
+        assert_eq!(
+            parse_epsv("229 Entering Extended Passive Mode (|||6446|)\r\n"),
+            Some(6446)
+        );
+        assert_eq!(parse_epsv("229 Extended Passive Mode OK\r\n"), None);
+    }
+
+    #[test]
+    fn unrewritable_passive_replies_fail() {
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let pasv = b"227 Entering Passive Mode (192,0,2,7,19,137).\r\n";
+        assert_eq!(
+            smol::block_on(rewrite_passive(pasv, "example.com", v6)),
+            Some(PASSIVE_FAILED.to_vec())
+        );
+        assert_eq!(
+            smol::block_on(rewrite_passive(b"229 Garbled\r\n", "example.com", v6)),
+            Some(PASSIVE_FAILED.to_vec())
+        );
+        assert_eq!(
+            smol::block_on(rewrite_passive(b"230 Logged in\r\n", "example.com", v6)),
+            None
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::atomic::Ordering;
+
+use crate::config::ListenerGate;
+
+use super::{stats::STATS_SESSIONS, tunnel::listen_status, CONNECT_CONFIG, TUNNEL};
+
+/// With --listener-gate delay-bind, waits until the tunnel is up for the first time, so that apps see nothing listening rather than connections that hang. Only the proxy listeners wait; the control and stats APIs are up all along.
+pub async fn wait_bind_allowed() {
+    if CONNECT_CONFIG.listener_gate != ListenerGate::DelayBind {
+        return;
+    }
+    log::info!("waiting for the tunnel to come up before listening");
+    loop {
+        let listener = listen_status();
+        if TUNNEL.status().connected() {
+            return;
+        }
+        listener.await;
+    }
+}
+
+/// Whether new tunneled connections are refused whenever the tunnel drops again after having been up. --fail-closed asks for that, and --fail-open, like giving neither, holds them until it is back.
+fn fail_closed() -> bool {
+    CONNECT_CONFIG.fail_closed && !CONNECT_CONFIG.fail_open
+}
+
+/// Whether a new tunneled connection should go ahead, waiting for the tunnel if need be, rather than be refused right away. Before the tunnel first comes up, this follows --listener-gate; once it has been up, --fail-closed refuses connections whenever it is down again.
+pub fn admit() -> bool {
+    if TUNNEL.status().connected() {
+        return true;
+    }
+    if STATS_SESSIONS.load(Ordering::Relaxed) == 0 {
+        CONNECT_CONFIG.listener_gate != ListenerGate::Reject
+    } else {
+        !fail_closed()
+    }
+}
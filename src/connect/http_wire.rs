@@ -0,0 +1,126 @@
+use anyhow::Context;
+use smol::prelude::*;
+
+/// Response heads longer than this are refused.
+const MAX_HEAD: usize = 8192;
+
+/// DNS messages are at most this long.
+const MAX_DNS_MESSAGE: usize = 65535;
+
+/// The head of an HTTP/1.1 response, for the few places that speak HTTP by hand: DNS-over-HTTPS and the WebSocket handshake.
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    pub status_line: String,
+    pub status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    /// The value of the first header with the given name, which is matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.header("content-length")?.parse().ok()
+    }
+
+    /// Whether the server closes the connection after this response.
+    pub fn closes(&self) -> bool {
+        self.header("connection")
+            .map(|v| v.eq_ignore_ascii_case("close"))
+            .unwrap_or(false)
+    }
+}
+
+/// Reads and parses an HTTP response head, up to and including the blank line, leaving the body unread.
+pub async fn read_head(conn: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<ResponseHead> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            anyhow::bail!("response headers too long")
+        }
+        let mut byte = [0u8];
+        conn.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default().to_string();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("malformed status line {:?}", status_line))?;
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect();
+    Ok(ResponseHead {
+        status_line,
+        status,
+        headers,
+    })
+}
+
+/// Sends a DNS message to a DNS-over-HTTPS server and returns the response head, along with the answer. Only responses with a content length are understood, which is what DoH servers send.
+pub async fn post_dns_message(
+    conn: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    host: &str,
+    path: &str,
+    query: &[u8],
+    keep_alive: bool,
+) -> anyhow::Result<(ResponseHead, Vec<u8>)> {
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n{}\r\n",
+        path,
+        host,
+        query.len(),
+        if keep_alive { "" } else { "Connection: close\r\n" }
+    );
+    conn.write_all(request.as_bytes()).await?;
+    conn.write_all(query).await?;
+    conn.flush().await?;
+    let head = read_head(conn).await?;
+    if head.status != 200 {
+        anyhow::bail!("DNS-over-HTTPS server said {}", head.status_line)
+    }
+    let length = head
+        .content_length()
+        .context("DNS-over-HTTPS response has no content length")?;
+    if length > MAX_DNS_MESSAGE {
+        anyhow::bail!("DNS-over-HTTPS response too long")
+    }
+    let mut body = vec![0u8; length];
+    conn.read_exact(&mut body).await?;
+    Ok((head, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_heads() {
+        let mut wire: &[u8] =
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nconnection:  Close\r\n\r\nhello";
+        let head = smol::block_on(read_head(&mut wire)).unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_length(), Some(5));
+        assert!(head.closes());
+        assert_eq!(wire, b"hello");
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut wire: &[u8] = b"SSH-2.0-OpenSSH\r\n\r\n";
+        assert!(smol::block_on(read_head(&mut wire)).is_err());
+        let mut endless: &[u8] = &[b'a'; MAX_HEAD + 10];
+        assert!(smol::block_on(read_head(&mut endless)).is_err());
+    }
+}
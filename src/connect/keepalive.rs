@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use super::{power::stretch, CONNECT_CONFIG};
+
+/// Turns on TCP keepalives for a proxied connection if `--tcp-keepalive` is set, so that idle sessions aren't dropped by NATs along the way. The idle time is stretched on battery, as of when the connection is made.
+pub fn set_keepalive(stream: &smol::net::TcpStream) {
+    if let Some(idle) = CONNECT_CONFIG.tcp_keepalive {
+        if let Err(err) = set_keepalive_raw(stream, stretch(idle)) {
+            log::debug!("could not set TCP keepalive: {:?}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_keepalive_raw(stream: &smol::net::TcpStream, idle: Duration) -> std::io::Result<()> {
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    // borrow the descriptor without taking ownership, so that it isn't closed when the Socket is dropped
+    let socket = unsafe { socket2::Socket::from_raw_fd(stream.as_raw_fd()) };
+    let result = socket.set_keepalive(Some(idle));
+    socket.into_raw_fd();
+    result
+}
+
+#[cfg(windows)]
+fn set_keepalive_raw(stream: &smol::net::TcpStream, idle: Duration) -> std::io::Result<()> {
+    use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket};
+    // borrow the socket without taking ownership, so that it isn't closed when the Socket is dropped
+    let socket = unsafe { socket2::Socket::from_raw_socket(stream.as_raw_socket()) };
+    let result = socket.set_keepalive(Some(idle));
+    socket.into_raw_socket();
+    result
+}
@@ -0,0 +1,263 @@
+use std::{
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(target_os = "linux")]
+use crate::config::VpnMode;
+#[cfg(windows)]
+use crate::storage;
+
+#[cfg(any(target_os = "linux", windows))]
+use super::CONNECT_CONFIG;
+use super::{tunnel::listen_status, TUNNEL};
+
+/// Whether the blocking rules are in place.
+static ENGAGED: AtomicBool = AtomicBool::new(false);
+
+/// Gets ready to engage the kill switch: puts back what a run that crashed while engaged left behind, sets up what tells Geph's own traffic apart, and releases on exit. Called before the tunnel dials anything, since on Linux only sockets opened afterwards are told apart.
+pub fn prepare() {
+    #[cfg(windows)]
+    if storage::read(&backup_path()).is_ok() {
+        log::warn!("releasing a kill switch left engaged by an earlier run");
+        if let Err(err) = release_commands().iter().try_for_each(|cmd| run(cmd)) {
+            log::error!("could not release the leftover kill switch: {:?}", err);
+        }
+        let _ = storage::remove_file(&backup_path());
+    }
+    #[cfg(target_os = "linux")]
+    if let Err(err) = enter_own_cgroup() {
+        log::error!("cannot move into a cgroup of our own: {:?}", err);
+    }
+    shutdown_hooks::add_shutdown_hook(release_on_exit);
+}
+
+/// Watches the tunnel, blocking every other program's egress from when it drops after having connected until it recovers, so that nothing leaks out directly during reconnects. Geph's own traffic (by cgroup on Linux, by user on macOS, by executable on Windows) and loopback stay allowed, so that it can reconnect. Never returns.
+pub async fn kill_switch_loop() {
+    let mut was_connected = false;
+    loop {
+        let listener = listen_status();
+        let connected = TUNNEL.status().connected();
+        if connected == was_connected {
+            listener.await;
+            continue;
+        }
+        was_connected = connected;
+        let res = if connected {
+            smol::unblock(release).await
+        } else {
+            smol::unblock(engage).await
+        };
+        match res {
+            Ok(()) if connected => log::info!("tunnel is back, kill switch released"),
+            Ok(()) => log::warn!("tunnel dropped, kill switch engaged"),
+            Err(err) => log::error!("kill switch failed: {:?}", err),
+        }
+    }
+}
+
+fn engage() -> anyhow::Result<()> {
+    if ENGAGED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    for cmd in engage_commands()? {
+        run(&cmd)?;
+    }
+    Ok(())
+}
+
+fn release() -> anyhow::Result<()> {
+    if !ENGAGED.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    for cmd in release_commands() {
+        run(&cmd)?;
+    }
+    #[cfg(windows)]
+    storage::remove_file(&backup_path())?;
+    Ok(())
+}
+
+extern "C" fn release_on_exit() {
+    if let Err(err) = release() {
+        log::error!("could not release the kill switch: {:?}", err);
+    }
+    #[cfg(target_os = "linux")]
+    leave_own_cgroup();
+}
+
+/// Where the unified (v2) cgroup hierarchy is mounted.
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The cgroup Geph moves itself into, whose sockets the kill switch lets out. Letting out a user instead would let out every other program of that user, which for VPN mode is root.
+#[cfg(target_os = "linux")]
+const OWN_CGROUP: &str = "geph-killswitch";
+
+/// The cgroup Geph was in before moving into its own, relative to the cgroup root.
+#[cfg(target_os = "linux")]
+static ORIGINAL_CGROUP: once_cell::sync::Lazy<parking_lot::Mutex<Option<String>>> =
+    once_cell::sync::Lazy::new(Default::default);
+
+#[cfg(target_os = "linux")]
+fn enter_own_cgroup() -> anyhow::Result<()> {
+    // the unified hierarchy is the line starting with "0::"
+    let current = std::fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::").map(|path| path.to_string()))
+        .ok_or_else(|| anyhow::anyhow!("not on the unified (v2) cgroup hierarchy"))?;
+    let dir = format!("{}/{}", CGROUP_ROOT, OWN_CGROUP);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        format!("{}/cgroup.procs", dir),
+        std::process::id().to_string(),
+    )?;
+    *ORIGINAL_CGROUP.lock() = Some(current);
+    Ok(())
+}
+
+/// Moves back to where Geph started out, so that its own cgroup can be removed.
+#[cfg(target_os = "linux")]
+fn leave_own_cgroup() {
+    if let Some(original) = ORIGINAL_CGROUP.lock().take() {
+        let procs = format!("{}{}/cgroup.procs", CGROUP_ROOT, original);
+        let _ = std::fs::write(procs, std::process::id().to_string());
+        let _ = std::fs::remove_dir(format!("{}/{}", CGROUP_ROOT, OWN_CGROUP));
+    }
+}
+
+/// An nftables table of its own, so that releasing it never touches anybody else's rules.
+#[cfg(target_os = "linux")]
+fn engage_commands() -> anyhow::Result<Vec<String>> {
+    if ORIGINAL_CGROUP.lock().is_none() {
+        anyhow::bail!("Geph is not in a cgroup of its own, so its traffic cannot be let out alone")
+    }
+    Ok(vec![
+        "nft add table inet geph_killswitch".into(),
+        "nft add chain inet geph_killswitch output '{ type filter hook output priority 0; policy accept; }'".into(),
+        "nft add rule inet geph_killswitch output oifname lo accept".into(),
+        format!(
+            "nft add rule inet geph_killswitch output oifname {} accept",
+            tun_name()
+        ),
+        format!(
+            "nft add rule inet geph_killswitch output socket cgroupv2 level 1 '\"{}\"' accept",
+            OWN_CGROUP
+        ),
+        "nft add rule inet geph_killswitch output drop".into(),
+    ])
+}
+
+/// The TUN device that traffic is allowed out through.
+#[cfg(target_os = "linux")]
+fn tun_name() -> &'static str {
+    if CONNECT_CONFIG.vpn_mode == Some(VpnMode::Tun) {
+        &CONNECT_CONFIG.tun_name
+    } else {
+        "tun-geph"
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn release_commands() -> Vec<String> {
+    vec!["nft delete table inet geph_killswitch".into()]
+}
+
+/// A pf anchor under "com.apple", which both the stock pf.conf and tun-route's ruleset evaluate.
+#[cfg(target_os = "macos")]
+fn engage_commands() -> anyhow::Result<Vec<String>> {
+    let iname = default_net::get_default_interface()
+        .map_err(|e| anyhow::anyhow!("cannot get default interface: {}", e))?
+        .name;
+    Ok(vec![
+        format!(
+            "printf '%s\\n' 'block drop out quick on {} all user != {}' | pfctl -a com.apple/geph-killswitch -f -",
+            iname,
+            whoami::username()
+        ),
+        // pf may not be enabled yet, in which case this fails harmlessly
+        "pfctl -e || true".into(),
+    ])
+}
+
+#[cfg(target_os = "macos")]
+fn release_commands() -> Vec<String> {
+    vec!["pfctl -a com.apple/geph-killswitch -F rules".into()]
+}
+
+/// Windows Firewall (the front end to WFP) blocking all outbound traffic except Geph's executable, through a rule of its own and the profiles' default outbound action. Only the default outbound actions are backed up, and only if no backup is there yet, so that a backup left by a crash while engaged is never overwritten with the blocking ones. Releasing touches nothing else, keeping whatever changed in between.
+#[cfg(windows)]
+fn engage_commands() -> anyhow::Result<Vec<String>> {
+    if storage::read(&backup_path()).is_err() {
+        let actions = crate::connect::vpn::encrypted_dns::powershell(
+            "Get-NetFirewallProfile | ForEach-Object { $_.Name + '=' + $_.DefaultOutboundAction }",
+        )?;
+        storage::write(&backup_path(), actions)?;
+    }
+    let exe = std::env::current_exe()?;
+    Ok(vec![
+        format!(
+            "netsh advfirewall firewall add rule name={} dir=out action=allow program=\"{}\" enable=yes",
+            RULE_NAME,
+            exe.display()
+        ),
+        "powershell -NoProfile -NonInteractive -Command \"Set-NetFirewallProfile -All -DefaultOutboundAction Block\"".into(),
+    ])
+}
+
+#[cfg(windows)]
+fn release_commands() -> Vec<String> {
+    let backup = storage::read(&backup_path()).unwrap_or_default();
+    let mut commands: Vec<String> = String::from_utf8_lossy(&backup)
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .map(|(profile, action)| {
+            format!(
+                "powershell -NoProfile -NonInteractive -Command \"Set-NetFirewallProfile -Name {} -DefaultOutboundAction {}\"",
+                profile, action
+            )
+        })
+        .collect();
+    commands.push(format!(
+        "netsh advfirewall firewall delete rule name={}",
+        RULE_NAME
+    ));
+    commands
+}
+
+/// The name of the firewall rule that lets Geph out.
+#[cfg(windows)]
+const RULE_NAME: &str = "geph-killswitch";
+
+/// Where the profiles' default outbound actions are backed up while engaged, next to the usage log, so that the next run can put them back after a crash.
+#[cfg(windows)]
+fn backup_path() -> std::path::PathBuf {
+    CONNECT_CONFIG
+        .usage_path
+        .with_extension("killswitch-restore.txt")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn engage_commands() -> anyhow::Result<Vec<String>> {
+    anyhow::bail!("the kill switch is not supported on this platform")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn release_commands() -> Vec<String> {
+    vec![]
+}
+
+fn run(cmd: &str) -> anyhow::Result<()> {
+    log::debug!("running {}", cmd);
+    #[cfg(windows)]
+    let status = Command::new("cmd").arg("/C").arg(cmd).status()?;
+    #[cfg(not(windows))]
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("export PATH=$PATH:/usr/sbin/:/sbin/; {}", cmd))
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {}", cmd, status)
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::{process::Command, time::Duration};
+
+use crate::{
+    l10n::{tr, tr_args},
+    plain_output::plain_status,
+};
+
+use super::{tunnel::ConnectionStatus, CONNECT_CONFIG, TUNNEL};
+
+/// Shows a desktop notification, if notifications are enabled. Failures are only logged, since the notification machinery is often missing on headless boxes.
+pub fn notify(title: &str, body: &str) {
+    if !CONNECT_CONFIG.notifications {
+        return;
+    }
+    let mut cmd = notification_command(title, body);
+    smolscale::spawn(smol::unblock(move || {
+        if let Err(err) = cmd.status() {
+            log::debug!("could not show notification: {:?}", err);
+        }
+    }))
+    .detach();
+}
+
+/// Watches the tunnel, notifying whenever it connects or drops, and printing every status change in plain output mode. Never returns.
+pub async fn notify_loop() {
+    let mut was_connected = false;
+    let mut last_status = None;
+    loop {
+        let status = TUNNEL.status();
+        if last_status.as_ref() != Some(&status) {
+            plain_status(&match &status {
+                ConnectionStatus::Connecting => "connecting".to_string(),
+                ConnectionStatus::Verifying => "verifying".to_string(),
+                ConnectionStatus::Connected { protocol, address } => {
+                    format!("connected via {} to {}", protocol, address)
+                }
+            });
+            last_status = Some(status.clone());
+        }
+        match &status {
+            ConnectionStatus::Connected { protocol, address } if !was_connected => notify(
+                &tr("notify-connected"),
+                &tr_args(
+                    "notify-connected-body",
+                    &[("protocol", protocol), ("address", address)],
+                ),
+            ),
+            ConnectionStatus::Connecting | ConnectionStatus::Verifying if was_connected => {
+                notify(&tr("notify-disconnected"), &tr("notify-disconnected-body"))
+            }
+            _ => {}
+        }
+        was_connected = status.connected();
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn notification_command(title: &str, body: &str) -> Command {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut cmd = Command::new("osascript");
+    cmd.arg("-e").arg(format!(
+        "display notification {} with title {}",
+        quote(body),
+        quote(title)
+    ));
+    cmd
+}
+
+#[cfg(windows)]
+fn notification_command(title: &str, body: &str) -> Command {
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+    let script = format!(
+        "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; \
+        $t = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+        $x = $t.GetElementsByTagName('text'); \
+        $x.Item(0).AppendChild($t.CreateTextNode({})) > $null; \
+        $x.Item(1).AppendChild($t.CreateTextNode({})) > $null; \
+        [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('Geph').Show([Windows.UI.Notifications.ToastNotification]::new($t))",
+        quote(title),
+        quote(body)
+    );
+    let mut cmd = Command::new("powershell");
+    cmd.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+    cmd
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn notification_command(title: &str, body: &str) -> Command {
+    let mut cmd = Command::new("notify-send");
+    cmd.args(["--app-name=Geph", title, body]);
+    cmd
+}
@@ -0,0 +1,81 @@
+use std::{fmt::Write, net::Ipv4Addr};
+
+use super::{
+    split_tunnel::{domain_rules, split_rules, Route},
+    CONNECT_CONFIG,
+};
+
+/// The paths the HTTP listener serves the PAC file at. "/wpad.dat" is where WPAD auto-discovery looks.
+pub const PAC_PATHS: &[&str] = &["/proxy.pac", "/wpad.dat"];
+
+/// Generates a proxy auto-config file pointing at our proxies, as reached through `host` (the listener address the client used). Destinations that Geph would connect to directly anyway, because of --domain-rules, --bypass-subnet or --force-subnet, are encoded so that the browser skips the proxy for them. Everything else, including what --exclude-prc sends directly, still goes to the proxy, which applies every rule itself. There is deliberately no DIRECT fallback, so that nothing leaks while Geph is down.
+pub fn pac_file(host: &str) -> String {
+    let proxy = format!(
+        "PROXY {}:{}; SOCKS5 {}:{}",
+        host,
+        CONNECT_CONFIG.http_listen.port(),
+        host,
+        CONNECT_CONFIG.socks5_listen.port()
+    );
+    let route = |route: Route| match route {
+        Route::Direct => "\"DIRECT\"",
+        Route::Tunnel => "proxy",
+    };
+    let mut pac = String::new();
+    let _ = writeln!(pac, "function FindProxyForURL(url, host) {{");
+    let _ = writeln!(pac, "  var proxy = \"{}\";", proxy);
+    let _ = writeln!(
+        pac,
+        "  if (isPlainHostName(host) || host == \"localhost\") return \"DIRECT\";"
+    );
+    let _ = writeln!(
+        pac,
+        "  if (/^[0-9]+\\.[0-9]+\\.[0-9]+\\.[0-9]+$/.test(host)) {{"
+    );
+    // only literal IP addresses are matched against subnets, since isInNet would resolve domains outside Geph, and only IPv4 ones, which is all isInNet understands
+    for rule in split_rules()
+        .iter()
+        .filter(|rule| rule.subnet.addr.is_ipv4())
+    {
+        let _ = writeln!(
+            pac,
+            "    if (isInNet(host, \"{}\", \"{}\")) return {};",
+            rule.subnet.addr,
+            netmask(rule.subnet.prefix_len),
+            route(rule.route)
+        );
+    }
+    for (net, prefix_len) in [
+        ("10.0.0.0", 8),
+        ("172.16.0.0", 12),
+        ("192.168.0.0", 16),
+        ("127.0.0.0", 8),
+    ] {
+        let _ = writeln!(
+            pac,
+            "    if (isInNet(host, \"{}\", \"{}\")) return \"DIRECT\";",
+            net,
+            netmask(prefix_len)
+        );
+    }
+    let _ = writeln!(pac, "    return proxy;");
+    let _ = writeln!(pac, "  }}");
+    for rule in domain_rules() {
+        let cond = if rule.wildcard {
+            format!(
+                "host == \"{}\" || dnsDomainIs(host, \".{}\")",
+                rule.domain, rule.domain
+            )
+        } else {
+            format!("host == \"{}\"", rule.domain)
+        };
+        let _ = writeln!(pac, "  if ({}) return {};", cond, route(rule.route));
+    }
+    let _ = writeln!(pac, "  return proxy;");
+    let _ = writeln!(pac, "}}");
+    pac
+}
+
+fn netmask(prefix_len: u32) -> Ipv4Addr {
+    u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0).into()
+}
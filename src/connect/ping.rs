@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use geph4_protocol::client_exit::CLIENT_EXIT_PSEUDOHOST;
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+
+use super::{tunnel::control::current_exit, TUNNEL};
+
+/// A probe unanswered for this long counts as lost.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Round-trip times to the exit, as measured by [ping_exit].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PingReport {
+    /// The exit probed, if connected through the binder.
+    pub exit: Option<String>,
+    pub sent: u32,
+    pub received: u32,
+    /// The fraction of probes lost, from 0 to 1.
+    pub loss: f64,
+    /// None if every probe was lost.
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+}
+
+/// Probes the exit of the current session `count` times, `interval` apart. Each probe opens a stream to the exit itself, which it answers without reaching out anywhere, so this measures the tunnel alone. The tunnel should be up already, or the first probes are lost waiting for it.
+pub async fn ping_exit(count: u32, interval: Duration) -> PingReport {
+    let mut rtts = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            smol::Timer::after(interval).await;
+        }
+        let start = Instant::now();
+        match TUNNEL
+            .connect_stream(CLIENT_EXIT_PSEUDOHOST)
+            .timeout(PROBE_TIMEOUT)
+            .await
+        {
+            Some(Ok(_)) => {
+                let rtt = start.elapsed().as_secs_f64() * 1000.0;
+                log::debug!("exit probe {} answered in {:.1} ms", i + 1, rtt);
+                rtts.push(rtt);
+            }
+            Some(Err(err)) => log::debug!("exit probe {} failed: {:?}", i + 1, err),
+            None => log::debug!("exit probe {} timed out", i + 1),
+        }
+    }
+    rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let received = rtts.len() as u32;
+    PingReport {
+        exit: current_exit(),
+        sent: count,
+        received,
+        loss: if count == 0 {
+            0.0
+        } else {
+            1.0 - received as f64 / count as f64
+        },
+        min_ms: rtts.first().copied(),
+        avg_ms: (received > 0).then(|| rtts.iter().sum::<f64>() / received as f64),
+        p95_ms: (received > 0).then(|| rtts[(rtts.len() * 95 + 99) / 100 - 1]),
+    }
+}
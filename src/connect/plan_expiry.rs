@@ -0,0 +1,145 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use geph4_protocol::binder::protocol::Level;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::l10n::{tr, tr_args};
+
+use super::{
+    notify::notify,
+    tunnel::{downgrade::is_downgraded, TunnelStatus},
+    CACHED_BINDER_CLIENT, TUNNEL_STATUS_CALLBACK,
+};
+
+/// How often the cached account info is looked at again.
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How close the user's plan is to expiring. Plans are bought as time, so this is also how the account's balance running low is warned about. Later variants are more urgent.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Ord, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanWarning {
+    /// Expires within 7 days.
+    ExpiresSoon,
+    /// Expires within a day.
+    ExpiresTomorrow,
+    /// Already expired, but the binder still hands out Plus tokens, so the plan keeps working for now.
+    ExpiredGrace,
+}
+
+/// The plan expiry as last seen in the cached account info.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlanStatus {
+    /// When the plan expires, or None for free accounts.
+    pub expires_unix: Option<i64>,
+    /// Whole days left on the plan, which is the account's balance, or None for free accounts. Negative once the plan expired.
+    #[serde(default)]
+    pub days_left: Option<i64>,
+    /// The current warning, if any.
+    pub warning: Option<PlanWarning>,
+    /// Whether the plan expired mid-session, so that the tunnel now runs at the free plan's speed.
+    #[serde(default)]
+    pub downgraded: bool,
+}
+
+/// The latest plan status.
+static PLAN_STATUS: Lazy<Mutex<PlanStatus>> = Lazy::new(Default::default);
+
+/// The plan status, as served to the stats API and the iOS FFI.
+pub fn plan_status() -> PlanStatus {
+    PlanStatus {
+        downgraded: is_downgraded(),
+        ..PLAN_STATUS.lock().clone()
+    }
+}
+
+/// Computes the warning for a plan expiring at the given time. How long an expired plan keeps working is up to the binder, so it counts as in its grace period for as long as the binder still hands out Plus tokens.
+pub fn plan_warning(expires_unix: i64, now_unix: i64, level: Level) -> Option<PlanWarning> {
+    let remaining = expires_unix - now_unix;
+    if remaining <= 0 {
+        // once the grace period is over, the downgrade is reported instead
+        (level == Level::Plus).then_some(PlanWarning::ExpiredGrace)
+    } else if remaining <= 86400 {
+        Some(PlanWarning::ExpiresTomorrow)
+    } else if remaining <= 7 * 86400 {
+        Some(PlanWarning::ExpiresSoon)
+    } else {
+        None
+    }
+}
+
+/// Periodically checks the plan expiry in the cached account info, reporting a [TunnelStatus::PlanExpiry] and notifying whenever the warning escalates. Never returns.
+pub async fn plan_expiry_loop() {
+    let mut last_warning = None;
+    loop {
+        match CACHED_BINDER_CLIENT.get_auth_token().await {
+            Ok((user_info, token)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as i64;
+                let expires_unix = user_info.subscription.map(|sub| sub.expires_unix);
+                let warning = expires_unix.and_then(|exp| plan_warning(exp, now, token.level));
+                *PLAN_STATUS.lock() = PlanStatus {
+                    expires_unix,
+                    days_left: expires_unix.map(|exp| (exp - now).div_euclid(86400)),
+                    warning,
+                    downgraded: false,
+                };
+                if warning > last_warning {
+                    if let (Some(warning), Some(expires_unix)) = (warning, expires_unix) {
+                        report(warning, expires_unix);
+                    }
+                }
+                // a renewal resets the warnings, so that the next expiry gets warned about again
+                last_warning = warning;
+            }
+            Err(err) => log::debug!("could not check plan expiry: {:?}", err),
+        }
+        smol::Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+fn report(warning: PlanWarning, expires_unix: i64) {
+    let date = chrono::NaiveDateTime::from_timestamp_opt(expires_unix, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    log::warn!("plan expiry warning {:?}, expires on {}", warning, date);
+    TUNNEL_STATUS_CALLBACK.read()(TunnelStatus::PlanExpiry {
+        warning,
+        expires_unix,
+    });
+    let body = match warning {
+        PlanWarning::ExpiresSoon => tr_args("notify-plan-expires-soon", &[("date", &date)]),
+        PlanWarning::ExpiresTomorrow => tr_args("notify-plan-expires-tomorrow", &[("date", &date)]),
+        PlanWarning::ExpiredGrace => tr("notify-plan-expired"),
+    };
+    notify(&tr("notify-plan"), &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalates_warnings() {
+        let now = 1_700_000_000;
+        let day = 86400;
+        assert_eq!(plan_warning(now + 30 * day, now, Level::Plus), None);
+        assert_eq!(
+            plan_warning(now + 7 * day, now, Level::Plus),
+            Some(PlanWarning::ExpiresSoon)
+        );
+        assert_eq!(
+            plan_warning(now + day / 2, now, Level::Plus),
+            Some(PlanWarning::ExpiresTomorrow)
+        );
+        // however long ago it expired, the grace period lasts as long as the binder says
+        assert_eq!(
+            plan_warning(now - 30 * day, now, Level::Plus),
+            Some(PlanWarning::ExpiredGrace)
+        );
+        assert_eq!(plan_warning(now - 60, now, Level::Free), None);
+    }
+}
@@ -1,6 +1,14 @@
 use std::net::SocketAddr;
 
-use super::TUNNEL;
+use smol::future::FutureExt;
+
+use super::{
+    drain::{wait_draining, StreamGuard},
+    gate,
+    keepalive::set_keepalive,
+    tunnel::downgrade::copy_capped,
+    TUNNEL,
+};
 
 /// Forwards ports using a particular description.
 pub async fn port_forwarder(desc: String) {
@@ -10,14 +18,36 @@ pub async fn port_forwarder(desc: String) {
         .await
         .expect("could not listen for port forwarding");
     loop {
-        let (conn, _) = listener.accept().await.unwrap();
+        let accepted = async { Some(listener.accept().await) }
+            .or(async {
+                wait_draining().await;
+                None
+            })
+            .await;
+        let (conn, _) = match accepted {
+            Some(accepted) => accepted.unwrap(),
+            None => {
+                log::info!(
+                    "port forwarder {} no longer accepting connections",
+                    listen_addr
+                );
+                drop(listener);
+                return smol::future::pending().await;
+            }
+        };
+        // closing right away is all a raw TCP forward can do to refuse
+        if !gate::admit() {
+            continue;
+        }
+        set_keepalive(&conn);
 
         let remote_addr = exploded[1].to_owned();
         smolscale::spawn(async move {
+            let _guard = StreamGuard::new();
             let remote = TUNNEL.connect_stream(&remote_addr).await.ok()?;
             smol::future::race(
-                smol::io::copy(remote.clone(), conn.clone()),
-                smol::io::copy(conn, remote),
+                copy_capped(remote.clone(), conn.clone(), |_| ()),
+                copy_capped(conn, remote, |_| ()),
             )
             .await
             .ok()
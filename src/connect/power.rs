@@ -0,0 +1,168 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::PowerProfile;
+
+use super::CONNECT_CONFIG;
+
+/// What the device runs on, as told by the platform, so that background work can be cut down when energy is scarce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PowerState {
+    /// Plugged in: everything runs at its usual pace.
+    Charging,
+    /// On battery: periodic work is spaced out.
+    Battery,
+    /// In the system's low power mode: periodic work is spaced out further, and background probing and stats sampling pause.
+    LowPower,
+}
+
+impl PowerState {
+    /// How much periodic timers are stretched in this state.
+    pub fn stretch_factor(self) -> u32 {
+        match self {
+            Self::Charging => 1,
+            Self::Battery => 2,
+            Self::LowPower => 6,
+        }
+    }
+}
+
+impl FromStr for PowerState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "charging" => Ok(Self::Charging),
+            "battery" => Ok(Self::Battery),
+            "low_power" => Ok(Self::LowPower),
+            other => anyhow::bail!("unrecognized power state {}", other),
+        }
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Charging => "charging",
+            Self::Battery => "battery",
+            Self::LowPower => "low_power",
+        })
+    }
+}
+
+/// How the daemon spent its time in each power state, to verify what the state changes save.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PowerStats {
+    pub state: String,
+    /// Seconds spent in each state, including the current one so far.
+    pub seconds: BTreeMap<String, f64>,
+    /// Timer wakeups of the periodic loops in each state. Divided by the seconds, this gives the wakeup rate.
+    pub wakeups: BTreeMap<String, u64>,
+    /// Background probes (like self-checks and warm spare refreshes) skipped because of low power mode.
+    pub probes_skipped: u64,
+    /// Stats samples skipped because of low power mode.
+    pub samples_skipped: u64,
+}
+
+struct Power {
+    state: PowerState,
+    since: Instant,
+    seconds: BTreeMap<PowerState, f64>,
+    wakeups: BTreeMap<PowerState, u64>,
+    probes_skipped: u64,
+    samples_skipped: u64,
+}
+
+static POWER: Lazy<Mutex<Power>> = Lazy::new(|| {
+    Mutex::new(Power {
+        state: PowerState::Charging,
+        since: Instant::now(),
+        seconds: BTreeMap::new(),
+        wakeups: BTreeMap::new(),
+        probes_skipped: 0,
+        samples_skipped: 0,
+    })
+});
+
+/// The power state last set, assumed to be charging until told otherwise.
+pub fn power_state() -> PowerState {
+    POWER.lock().state
+}
+
+/// Switches to the given power state. Loops that are already waiting pick it up on their next wakeup.
+pub fn set_power_state(state: PowerState) {
+    let mut power = POWER.lock();
+    if power.state == state {
+        return;
+    }
+    let elapsed = power.since.elapsed().as_secs_f64();
+    let previous = power.state;
+    *power.seconds.entry(previous).or_default() += elapsed;
+    power.state = state;
+    power.since = Instant::now();
+    log::info!("power state changed from {} to {}", previous, state);
+}
+
+/// The power profile picked with --power-profile.
+pub fn power_profile() -> PowerProfile {
+    CONNECT_CONFIG.power_profile
+}
+
+/// A random wait before retrying a failed pipe connection, within the bounds of the power profile, so that pipes don't all retry in lockstep.
+pub fn retry_jitter() -> Duration {
+    let (low, high) = power_profile().retry_jitter();
+    Duration::from_secs_f64(rand::thread_rng().gen_range(low.as_secs_f64(), high.as_secs_f64()))
+}
+
+/// The given interval, stretched for the current power state.
+pub fn stretch(interval: Duration) -> Duration {
+    interval * power_state().stretch_factor()
+}
+
+/// Counts a wakeup of a periodic loop, then tells whether it may do optional background work, like probing or sampling stats. It may not in low power mode.
+pub fn background_wakeup() -> bool {
+    let mut power = POWER.lock();
+    let state = power.state;
+    *power.wakeups.entry(state).or_default() += 1;
+    state != PowerState::LowPower
+}
+
+/// Records a background probe skipped because of low power mode.
+pub fn record_probe_skipped() {
+    POWER.lock().probes_skipped += 1;
+}
+
+/// Records a stats sample skipped because of low power mode.
+pub fn record_sample_skipped() {
+    POWER.lock().samples_skipped += 1;
+}
+
+/// The time spent and wakeups made in each power state so far.
+pub fn power_stats() -> PowerStats {
+    let power = POWER.lock();
+    let mut seconds = power.seconds.clone();
+    *seconds.entry(power.state).or_default() += power.since.elapsed().as_secs_f64();
+    PowerStats {
+        state: power.state.to_string(),
+        seconds: seconds
+            .into_iter()
+            .map(|(state, secs)| (state.to_string(), secs))
+            .collect(),
+        wakeups: power
+            .wakeups
+            .iter()
+            .map(|(state, count)| (state.to_string(), *count))
+            .collect(),
+        probes_skipped: power.probes_skipped,
+        samples_skipped: power.samples_skipped,
+    }
+}
@@ -0,0 +1,32 @@
+/// Domains reachable in pre-login mode: the Geph website and account pages, plus the payment processors the payment flow redirects to.
+const PRELOGIN_ALLOWLIST: &[&str] = &[
+    "geph.io",
+    "geph.org",
+    "gephgui.org",
+    "stripe.com",
+    "stripe.network",
+    "js.stripe.com",
+    "paypal.com",
+    "paypalobjects.com",
+    "alipay.com",
+    "alipayobjects.com",
+];
+
+/// Returns true if the given host (with or without a port) may be reached in pre-login mode.
+pub fn is_prelogin_allowed(host: &str) -> bool {
+    let host = host
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(host)
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    // explode by dots, then check every suffix
+    let exploded: Vec<_> = host.split('.').collect();
+    for i in 0..exploded.len() {
+        let candidate = exploded[i..].join(".");
+        if PRELOGIN_ALLOWLIST.contains(&candidate.as_str()) {
+            return true;
+        }
+    }
+    false
+}
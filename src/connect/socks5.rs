@@ -2,64 +2,126 @@ use std::{sync::atomic::Ordering, time::Duration};
 
 use anyhow::Context;
 use futures_util::TryFutureExt;
+use once_cell::sync::OnceCell;
 use psl::Psl;
+use smol::prelude::*;
 use smol_timeout::TimeoutExt;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::{
     china,
     connect::{
-        stats::{STATS_RECV_BYTES, STATS_SEND_BYTES},
-        tunnel::activity::notify_activity,
-        TUNNEL,
+        drain::{wait_draining, StreamGuard},
+        flow_mirror::FlowGuard,
+        ftp, gate,
+        keepalive::set_keepalive,
+        prelogin::is_prelogin_allowed,
+        split_tunnel::{route_for, route_for_host, Route},
+        stats::{
+            add_class_bytes, classify, record_sni, SniSniffer, STATS_RECV_BYTES, STATS_SEND_BYTES,
+        },
+        tunnel::{activity::notify_activity, downgrade::copy_capped},
+        CONNECT_CONFIG, TUNNEL,
     },
 };
 
 /// Handles a socks5 client from localhost
-async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> anyhow::Result<()> {
+async fn handle_socks5(
+    s5client: smol::net::TcpStream,
+    exclude_prc: bool,
+    prelogin: bool,
+) -> anyhow::Result<()> {
+    let _guard = StreamGuard::new();
     s5client.set_nodelay(true)?;
+    set_keepalive(&s5client);
     use socksv5::v5::*;
     let _handshake = read_handshake(s5client.clone()).await?;
     write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
     let request = read_request(s5client.clone()).await?;
     let port = request.port;
-    let v4addr: Option<Ipv4Addr>;
+    let ipaddr: Option<IpAddr>;
+    let mut hostname: Option<String> = None;
     let addr: String = match &request.host {
         SocksV5Host::Domain(dom) => {
-            v4addr = String::from_utf8_lossy(dom).parse().ok();
-            format!("{}:{}", String::from_utf8_lossy(dom), request.port)
+            let dom = String::from_utf8_lossy(dom);
+            // some clients send address literals, IPv6 ones possibly in brackets, as domains
+            ipaddr = dom
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .ok();
+            match ipaddr {
+                Some(ip) => SocketAddr::new(ip, request.port).to_string(),
+                None => {
+                    hostname = Some(dom.to_string());
+                    format!("{}:{}", dom, request.port)
+                }
+            }
         }
         SocksV5Host::Ipv4(v4) => {
-            let v4addr_inner = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
-            SocketAddr::V4(SocketAddrV4::new(
-                {
-                    v4addr = Some(v4addr_inner);
-                    v4addr.unwrap()
-                },
-                request.port,
-            ))
-            .to_string()
+            let ip = IpAddr::from(Ipv4Addr::from(*v4));
+            ipaddr = Some(ip);
+            SocketAddr::new(ip, request.port).to_string()
         }
-        _ => anyhow::bail!("not supported"),
+        SocksV5Host::Ipv6(v6) => {
+            let ip = IpAddr::from(Ipv6Addr::from(*v6));
+            ipaddr = Some(ip);
+            SocketAddr::new(ip, request.port).to_string()
+        }
+    };
+    let v4addr = match ipaddr {
+        Some(IpAddr::V4(v4)) => Some(v4),
+        _ => None,
     };
 
-    let is_private = if let Some(v4addr) = v4addr {
-        v4addr.is_private() || v4addr.is_loopback()
-    } else {
-        !psl::List
+    if prelogin && !is_prelogin_allowed(&addr) {
+        log::debug!("pre-login mode refusing {}", addr);
+        write_request_status(
+            s5client.clone(),
+            SocksV5RequestStatus::ConnectionNotAllowed,
+            request.host,
+            port,
+        )
+        .await?;
+        anyhow::bail!("{} is not reachable in pre-login mode", addr)
+    }
+
+    let is_private = match ipaddr {
+        Some(IpAddr::V4(v4addr)) => v4addr.is_private() || v4addr.is_loopback(),
+        Some(IpAddr::V6(v6addr)) => is_private_v6(v6addr),
+        None => !psl::List
             .suffix(addr.split(':').next().unwrap().as_bytes())
             .map(|suf| suf.typ().is_some())
-            .unwrap_or_default()
+            .unwrap_or_default(),
     };
 
     // true if the connection should not go through geph
-    let must_direct = is_private
-        || (exclude_prc
-            && (china::is_chinese_host(addr.split(':').next().unwrap())
-                || v4addr.map(china::is_chinese_ip).unwrap_or(false)));
+    let route = match (ipaddr, hostname.as_deref()) {
+        (Some(ipaddr), _) => route_for(ipaddr),
+        (None, Some(hostname)) => route_for_host(hostname),
+        (None, None) => None,
+    };
+    let must_direct = match route {
+        Some(route) => route == Route::Direct,
+        None => {
+            is_private
+                || (exclude_prc
+                    && (hostname
+                        .as_deref()
+                        .map(china::is_chinese_host)
+                        .unwrap_or(false)
+                        || v4addr.map(china::is_chinese_ip).unwrap_or(false)))
+        }
+    };
+    let dst_host = hostname
+        .clone()
+        .or_else(|| ipaddr.map(|ip| ip.to_string()))
+        .unwrap_or_default();
+    let src = s5client.peer_addr()?;
     if must_direct {
         log::debug!("bypassing {}", addr);
         let conn = smol::net::TcpStream::connect(&addr).await?;
+        set_keepalive(&conn);
         write_request_status(
             s5client.clone(),
             SocksV5RequestStatus::Success,
@@ -67,12 +129,23 @@ async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> any
             port,
         )
         .await?;
+        let flow = FlowGuard::new(src, dst_host, port, "direct");
         smol::future::race(
-            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |_| ()),
-            geph4_aioutils::copy_with_stats(s5client.clone(), conn.clone(), |_| ()),
+            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| flow.recv(n)),
+            geph4_aioutils::copy_with_stats(s5client.clone(), conn.clone(), |n| flow.sent(n)),
         )
         .await?;
     } else {
+        if !gate::admit() {
+            write_request_status(
+                s5client.clone(),
+                SocksV5RequestStatus::ServerFailure,
+                request.host,
+                port,
+            )
+            .await?;
+            anyhow::bail!("refusing {} while the tunnel is down", addr)
+        }
         let conn = TUNNEL
             .connect_stream(&addr)
             .timeout(Duration::from_secs(120))
@@ -85,13 +158,48 @@ async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> any
             port,
         )
         .await?;
+        // without a hostname, the class is settled once the SNI turns up in what the client sends
+        let sniff = (port == 443 || port == 8443) && hostname.is_none();
+        let sniffed_class = OnceCell::new();
+        let provisional_class = classify(port, hostname.as_deref());
+        let class = || sniffed_class.get().copied().unwrap_or(provisional_class);
+        let listen_ip = s5client.local_addr()?.ip();
+        let flow = FlowGuard::new(src, dst_host.clone(), port, "tunnel");
+        let on_recv = |n| {
+            flow.recv(n);
+            STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+            add_class_bytes(class(), n as u64);
+            notify_activity();
+        };
+        let download = if port == 21 {
+            ftp::copy_ftp_replies(
+                conn.clone(),
+                s5client.clone(),
+                &dst_host,
+                listen_ip,
+                on_recv,
+            )
+            .boxed()
+        } else {
+            copy_capped(conn.clone(), s5client.clone(), on_recv).boxed()
+        };
+        let upload = SniSniffer::new(s5client, |sni: Option<String>| {
+            if CONNECT_CONFIG.log_sni {
+                if let Some(sni) = sni.as_ref() {
+                    log::info!("tunneled TLS connection to {} with SNI {}", addr, sni);
+                    record_sni(sni);
+                }
+            }
+            if sniff {
+                let _ = sniffed_class.set(classify(port, sni.as_deref()));
+            }
+        });
         smol::future::race(
-            geph4_aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| {
-                STATS_RECV_BYTES.fetch_add(n as u64, Ordering::Relaxed);
-                notify_activity();
-            }),
-            geph4_aioutils::copy_with_stats(s5client, conn, |n| {
+            download,
+            copy_capped(upload, conn, |n| {
+                flow.sent(n);
                 STATS_SEND_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+                add_class_bytes(class(), n as u64);
                 notify_activity();
             }),
         )
@@ -100,19 +208,39 @@ async fn handle_socks5(s5client: smol::net::TcpStream, exclude_prc: bool) -> any
     Ok(())
 }
 
-pub async fn socks5_loop(socks5_listen: SocketAddr, exclude_prc: bool) -> anyhow::Result<()> {
+/// Whether an IPv6 address is loopback, link-local or unique local (fc00::/7), and so never reachable through Geph.
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback() || first & 0xffc0 == 0xfe80 || first & 0xfe00 == 0xfc00
+}
+
+pub async fn socks5_loop(
+    socks5_listen: SocketAddr,
+    exclude_prc: bool,
+    prelogin: bool,
+) -> anyhow::Result<()> {
     let socks5_listener = smol::net::TcpListener::bind(socks5_listen)
         .await
         .context("cannot bind socks5")?;
     log::debug!("socks5 started");
     loop {
-        let (s5client, _) = socks5_listener
-            .accept()
-            .await
-            .context("cannot accept socks5")?;
+        let accepted = async { Some(socks5_listener.accept().await) }
+            .or(async {
+                wait_draining().await;
+                None
+            })
+            .await;
+        let (s5client, _) = match accepted {
+            Some(accepted) => accepted.context("cannot accept socks5")?,
+            None => {
+                log::info!("socks5 no longer accepting connections");
+                drop(socks5_listener);
+                return smol::future::pending().await;
+            }
+        };
 
         smolscale::spawn(
-            async move { handle_socks5(s5client, exclude_prc).await }
+            async move { handle_socks5(s5client, exclude_prc, prelogin).await }
                 .map_err(|e| log::debug!("socks5 died with: {:?}", e)),
         )
         .detach()
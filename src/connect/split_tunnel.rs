@@ -0,0 +1,156 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use itertools::Itertools;
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::config::Subnet;
+
+use super::CONNECT_CONFIG;
+
+/// Where traffic to a destination goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    Tunnel,
+}
+
+/// A --bypass-subnet or --force-subnet rule.
+#[derive(Clone, Copy, Debug)]
+pub struct SplitRule {
+    pub subnet: Subnet,
+    pub route: Route,
+}
+
+/// The split tunneling rules, most specific first, so that the first match is the one that applies. Between equally specific rules, forcing through the tunnel wins.
+static SPLIT_RULES: Lazy<Vec<SplitRule>> = Lazy::new(|| {
+    let force = CONNECT_CONFIG.force_subnet.iter().map(|&subnet| SplitRule {
+        subnet,
+        route: Route::Tunnel,
+    });
+    let bypass = CONNECT_CONFIG
+        .bypass_subnet
+        .iter()
+        .map(|&subnet| SplitRule {
+            subnet,
+            route: Route::Direct,
+        });
+    force
+        .chain(bypass)
+        .sorted_by_key(|rule| std::cmp::Reverse(rule.subnet.prefix_len))
+        .collect()
+});
+
+/// All the split tunneling rules, most specific first.
+pub fn split_rules() -> &'static [SplitRule] {
+    &SPLIT_RULES
+}
+
+/// A rule from the --domain-rules file.
+#[derive(Clone, Debug)]
+pub struct DomainRule {
+    /// The domain, lowercase and without any "*." prefix.
+    pub domain: String,
+    /// Whether subdomains match too.
+    pub wildcard: bool,
+    pub route: Route,
+}
+
+impl DomainRule {
+    fn matches(&self, host: &str) -> bool {
+        host == self.domain
+            || (self.wildcard
+                && host
+                    .strip_suffix(&self.domain)
+                    .map(|prefix| prefix.ends_with('.'))
+                    .unwrap_or(false))
+    }
+
+    /// How specific the rule is: longer domains are more specific, and an exact match beats a wildcard on the same domain.
+    fn specificity(&self) -> (usize, bool) {
+        (self.domain.len(), !self.wildcard)
+    }
+}
+
+/// Parses a domain rules file.
+pub fn parse_domain_rules(contents: &str) -> anyhow::Result<Vec<DomainRule>> {
+    let mut rules = vec![];
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (route, pattern) = line.split_once(char::is_whitespace).with_context(|| {
+            format!(
+                "line {}: expected \"direct\" or \"tunnel\" and a domain",
+                lineno + 1
+            )
+        })?;
+        let route = match route {
+            "direct" => Route::Direct,
+            "tunnel" => Route::Tunnel,
+            x => anyhow::bail!("line {}: unrecognized route {}", lineno + 1, x),
+        };
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let (domain, wildcard) = match pattern.strip_prefix("*.") {
+            Some(domain) => (domain.to_string(), true),
+            None => (pattern, false),
+        };
+        if domain.is_empty() || domain.contains('*') {
+            anyhow::bail!("line {}: invalid domain pattern", lineno + 1)
+        }
+        rules.push(DomainRule {
+            domain,
+            wildcard,
+            route,
+        });
+    }
+    Ok(rules)
+}
+
+/// The domain rules, most specific first.
+static DOMAIN_RULES: OnceCell<Vec<DomainRule>> = OnceCell::new();
+
+/// Loads the rules given by --domain-rules. Called at startup, so that a missing or malformed rules file stops the daemon right away rather than the first connection that needs the rules.
+pub fn load_domain_rules() -> anyhow::Result<()> {
+    let rules = match &CONNECT_CONFIG.domain_rules {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read domain rules {:?}", path))?;
+            parse_domain_rules(&contents)
+                .with_context(|| format!("cannot parse domain rules {:?}", path))?
+                .into_iter()
+                .sorted_by_key(|rule| std::cmp::Reverse(rule.specificity()))
+                .collect()
+        }
+        None => vec![],
+    };
+    log::debug!("{} domain rules loaded", rules.len());
+    let _ = DOMAIN_RULES.set(rules);
+    Ok(())
+}
+
+/// All the domain rules, most specific first.
+pub fn domain_rules() -> &'static [DomainRule] {
+    DOMAIN_RULES
+        .get()
+        .map(|rules| rules.as_slice())
+        .unwrap_or(&[])
+}
+
+/// Where traffic to the given domain goes, if a domain rule says so. This is decided before the domain is resolved, so that directly-routed domains are never looked up through Geph and vice versa.
+pub fn route_for_host(host: &str) -> Option<Route> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    domain_rules()
+        .iter()
+        .find(|rule| rule.matches(&host))
+        .map(|rule| rule.route)
+}
+
+/// Where traffic to the given address goes, if a split tunneling rule says so.
+pub fn route_for(ip: IpAddr) -> Option<Route> {
+    SPLIT_RULES
+        .iter()
+        .find(|rule| rule.subnet.contains(ip))
+        .map(|rule| rule.route)
+}
@@ -1,4 +1,10 @@
+mod control_auth;
+mod control_socket;
 mod gatherer;
+mod local_tls;
+mod metrics;
+mod scopes;
+mod traffic;
 
 use std::{
     convert::Infallible,
@@ -12,30 +18,76 @@ use itertools::Itertools;
 use smol_str::SmolStr;
 
 use self::gatherer::StatsGatherer;
+pub use control_auth::init_control_tokens;
+pub use control_socket::control_socket_loop;
 pub use gatherer::StatItem;
-use nanorpc::nanorpc_derive;
+pub use metrics::metrics_loop;
 use nanorpc::RpcService;
+use nanorpc::{nanorpc_derive, JrpcRequest};
 use once_cell::sync::Lazy;
+pub use scopes::{init_scopes, scoped_stats, start_session, ScopedStats, StatScope};
 use serde::{Deserialize, Serialize};
+pub use traffic::{add_class_bytes, classify, record_sni, SniSniffer, TrafficClass};
 
-use super::{CONNECT_CONFIG, TUNNEL};
+use crate::{
+    binder_stats::{self, BinderCallStats},
+    log_levels::{log_levels, set_log_level},
+    usage::UsageLog,
+};
+
+use super::{
+    audit::audit,
+    drain::drain_and_exit,
+    ping::{ping_exit, PingReport},
+    plan_expiry::{plan_status, PlanStatus},
+    power::{power_stats, set_power_state, PowerStats},
+    tunnel::{
+        bridge_history::{bridge_history, BridgeStanding},
+        control::{change_exit, current_exit, request_reconnect},
+        exit_select::{preview_exit, ExitPreview},
+        pipe_info::{pipe_info, PipeInfo},
+        postmortem::{last_postmortem, PostMortem},
+        selfcheck::{SelfCheckStatus, SELFCHECK_STATUS},
+        ConnectionStatus,
+    },
+    usage_log::{flush_usage, with_usage_db},
+    CACHED_BINDER_CLIENT, CONNECT_CONFIG, TUNNEL,
+};
 
 /// The main stats-serving thread.
 pub static STATS_THREAD: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
     std::thread::spawn(|| loop {
-        let server = tiny_http::Server::http(CONNECT_CONFIG.stats_listen).unwrap();
+        let server = if CONNECT_CONFIG.stats_tls {
+            let ssl_config = local_tls::load_or_generate(CONNECT_CONFIG.stats_listen.ip())
+                .expect("cannot prepare local TLS certificate");
+            tiny_http::Server::https(CONNECT_CONFIG.stats_listen, ssl_config).unwrap()
+        } else {
+            tiny_http::Server::http(CONNECT_CONFIG.stats_listen).unwrap()
+        };
         for mut request in server.incoming_requests() {
             smolscale::spawn(async move {
-                if let Ok(key) = std::env::var("GEPH_RPC_KEY") {
-                    if !request.url().contains(&key) {
-                        anyhow::bail!("missing rpc key")
-                    }
-                }
+                let granted = control_auth::granted_scope(&request);
                 let mut s = String::new();
                 request.as_reader().read_to_string(&mut s)?;
-                let resp = StatsControlService(DummyImpl)
-                    .respond_raw(serde_json::from_str(&s)?)
-                    .await;
+                let jrpc: JrpcRequest = serde_json::from_str(&s)?;
+                if granted < Some(control_auth::required_scope(&jrpc.method)) {
+                    request.respond(tiny_http::Response::empty(403))?;
+                    anyhow::bail!("insufficient scope for {}", jrpc.method)
+                }
+                if control_auth::required_scope(&jrpc.method) == control_auth::Scope::Control {
+                    audit(
+                        &format!(
+                            "control-api {}",
+                            request
+                                .remote_addr()
+                                .map(|addr| addr.to_string())
+                                .unwrap_or_default()
+                        ),
+                        &jrpc.method,
+                        &serde_json::to_string(&jrpc.params)?,
+                    );
+                }
+                let resp = StatsControlService(DummyImpl).respond_raw(jrpc).await;
                 request.respond(tiny_http::Response::from_data(serde_json::to_vec(&resp)?))?;
                 anyhow::Ok(())
             })
@@ -55,6 +107,46 @@ pub struct BasicStats {
     pub address: SmolStr,
 }
 
+/// A snapshot of the tunnel's state, for frontends that render a status screen without going through the control API, like the iOS app.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    /// "connecting", "verifying" or "connected", as in the connection_state call.
+    pub state: String,
+    /// The exit the tunnel is connected to, if connected through the binder.
+    pub exit: Option<String>,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    /// The latest end-to-end ping through the tunnel.
+    pub rtt_ms: Option<f64>,
+    /// The protocol of every connected pipe.
+    pub protocols: Vec<String>,
+}
+
+/// Takes a [StatusSnapshot] of the tunnel.
+pub fn status_snapshot() -> StatusSnapshot {
+    let status = TUNNEL.status();
+    StatusSnapshot {
+        state: match status {
+            ConnectionStatus::Connecting => "connecting",
+            ConnectionStatus::Verifying => "verifying",
+            ConnectionStatus::Connected { .. } => "connected",
+        }
+        .into(),
+        exit: current_exit(),
+        sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed),
+        recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed),
+        rtt_ms: if status.connected() {
+            STATS_GATHERER
+                .all_items()
+                .last()
+                .map(|item| item.ping.as_secs_f64() * 1000.0)
+        } else {
+            None
+        },
+        protocols: pipe_info().into_iter().map(|pipe| pipe.protocol).collect(),
+    }
+}
+
 #[derive(Copy, Clone)]
 struct DummyImpl;
 
@@ -68,6 +160,82 @@ pub trait StatsControlProtocol {
         TUNNEL.status().connected()
     }
 
+    /// Obtains the state of the daemon: "connecting", "verifying" (solving a proof-of-work puzzle for the binder), or "connected".
+    async fn connection_state(&self) -> String {
+        match TUNNEL.status() {
+            ConnectionStatus::Connecting => "connecting",
+            ConnectionStatus::Verifying => "verifying",
+            ConnectionStatus::Connected { .. } => "connected",
+        }
+        .into()
+    }
+
+    /// Obtains the exit the tunnel is connected to, if connected through the binder.
+    async fn current_exit(&self) -> Option<String> {
+        current_exit()
+    }
+
+    /// Tears down the current session and connects afresh.
+    async fn reconnect(&self) -> bool {
+        request_reconnect();
+        true
+    }
+
+    /// Reconnects to the given exit (or the most similar one, as for --exit-server) for the rest of this run.
+    async fn change_exit(&self, exit: String) -> bool {
+        change_exit(exit);
+        true
+    }
+
+    /// Measures latency to the given exit alongside the current one, without switching, and says whether switching looks worth it. Takes a few seconds. None if the exit is unknown or there is no binder to ask, as with --override-connect.
+    async fn preview_exit(&self, exit: String) -> Option<ExitPreview> {
+        if CONNECT_CONFIG.override_connect.is_some() {
+            return None;
+        }
+        match preview_exit(&CACHED_BINDER_CLIENT, current_exit().as_deref(), &exit).await {
+            Ok(preview) => Some(preview),
+            Err(err) => {
+                log::warn!("cannot preview exit {}: {:?}", exit, err);
+                None
+            }
+        }
+    }
+
+    /// Sets the log level of a module and its submodules, like "geph4client::connect::tunnel", to "off", "error", "warn", "info", "debug" or "trace" until restart, or back to the level given at start with "default".
+    async fn set_log_level(&self, module: String, level: String) -> bool {
+        match set_log_level(&module, &level) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("cannot set log level: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Obtains the log levels set at runtime, by module.
+    async fn log_levels(&self) -> Vec<(String, String)> {
+        log_levels()
+    }
+
+    /// Sets the power state, "charging", "battery" or "low_power", which decides how often the daemon wakes up for background work.
+    async fn set_power_state(&self, state: String) -> bool {
+        match state.parse() {
+            Ok(state) => {
+                set_power_state(state);
+                true
+            }
+            Err(err) => {
+                log::warn!("cannot set power state: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Obtains the time spent, wakeups made, and background work skipped in each power state.
+    async fn power_stats(&self) -> PowerStats {
+        power_stats()
+    }
+
     /// Obtains statistics.
     async fn basic_stats(&self) -> BasicStats {
         loop {
@@ -85,6 +253,48 @@ pub trait StatsControlProtocol {
         }
     }
 
+    /// Obtains the bytes sent and received and the number of sessions in the given scope: "lifetime" (every run, as recorded in the usage log), "boot" (this run of the daemon), or "session" (since the tunnel last came up).
+    async fn scoped_stats(&self, scope: StatScope) -> ScopedStats {
+        scopes::scoped_stats(scope)
+    }
+
+    /// Obtains the bytes sent and received and the number of sessions recorded on disk for each day, overall and per exit, including this run's so far. Meant for users on metered plans.
+    async fn usage_log(&self) -> UsageLog {
+        if let Err(err) = flush_usage().await {
+            log::warn!("cannot record usage: {:?}", err)
+        }
+        smol::unblock(|| with_usage_db(|db| db.log()))
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("cannot read usage log: {:?}", err);
+                UsageLog::default()
+            })
+    }
+
+    /// Measures round-trip times to the exit of the current session over `count` probes (at most 100), `interval_ms` apart, reporting min/avg/p95 and loss. None if the tunnel isn't up.
+    async fn ping_exit(&self, count: u32, interval_ms: u64) -> Option<PingReport> {
+        if !TUNNEL.status().connected() {
+            return None;
+        }
+        Some(ping_exit(count.min(100), Duration::from_millis(interval_ms)).await)
+    }
+
+    /// Obtains how every bridge used from the current network fared (connect success rate, handshake time and download rate), most recently used first. Bridges with a good history are tried first on the next connect.
+    async fn bridge_history(&self) -> Vec<BridgeStanding> {
+        bridge_history()
+    }
+
+    /// Resets the counters of the given scope to zero, returning whether that worked.
+    async fn reset_stats(&self, scope: StatScope) -> bool {
+        match scopes::reset_scope(scope) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("cannot reset {:?} stats: {:?}", scope, err);
+                false
+            }
+        }
+    }
+
     /// Obtains time-series statistics.
     async fn timeseries_stats(&self, series: Timeseries) -> Vec<(u64, f32)> {
         let s = STATS_GATHERER.all_items();
@@ -138,13 +348,44 @@ pub trait StatsControlProtocol {
         }
     }
 
+    /// Obtains the total bytes proxied through the tunnel in each traffic category (web, video, messaging, other), guessed from ports and hostnames.
+    async fn traffic_classes(&self) -> Vec<(TrafficClass, u64)> {
+        traffic::class_bytes()
+    }
+
+    /// Obtains how many tunneled TLS connections went to each SNI, most frequent first. Always empty unless --log-sni is on.
+    async fn sni_connections(&self) -> Vec<(String, u64)> {
+        traffic::sni_connections()
+    }
+
+    /// Obtains binder cache hit/miss counts per cache key, and call counts and latency percentiles per binder RPC method.
+    async fn binder_stats(&self) -> Vec<(String, BinderCallStats)> {
+        binder_stats::binder_stats()
+    }
+
+    /// Obtains when the plan expires, and whether the user should be warned about it.
+    async fn plan_status(&self) -> PlanStatus {
+        plan_status()
+    }
+
+    /// Obtains how each connected pipe was negotiated: its transport, TLS path, whether it sends a real SNI, and how long its handshake took.
+    async fn pipes(&self) -> Vec<PipeInfo> {
+        pipe_info()
+    }
+
+    /// Obtains what the latest session to end, in this run or an earlier one, looked like when it did: its duration and traffic, its pipes, the error that ended it, and network changes shortly before.
+    async fn last_postmortem(&self) -> Option<PostMortem> {
+        last_postmortem()
+    }
+
+    /// Obtains the results of the end-to-end self-checks.
+    async fn self_check(&self) -> SelfCheckStatus {
+        SELFCHECK_STATUS.lock().clone()
+    }
+
     /// Turns off the daemon.
     async fn kill(&self) -> bool {
-        smolscale::spawn(async {
-            smol::Timer::after(Duration::from_millis(300)).await;
-            std::process::exit(0);
-        })
-        .detach();
+        drain_and_exit(0);
         true
     }
 }
@@ -162,3 +403,6 @@ pub static STATS_GATHERER: Lazy<StatsGatherer> = Lazy::new(Default::default);
 pub static STATS_SEND_BYTES: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
 
 pub static STATS_RECV_BYTES: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
+
+/// How many times the tunnel came up since the daemon started.
+pub static STATS_SESSIONS: AtomicU64 = AtomicU64::new(0);
@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use http_types::Url;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{connect::CONNECT_CONFIG, storage};
+
+/// The scope of access a token grants to the control API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Reading status and statistics.
+    ReadOnly,
+    /// Anything, including actions that change or stop the tunnel.
+    Control,
+}
+
+/// The tokens guarding the control API, persisted in a file readable only by the current user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ControlTokens {
+    read_only: String,
+    control: String,
+}
+
+static CONTROL_TOKENS: OnceCell<ControlTokens> = OnceCell::new();
+
+/// Loads the control API tokens, generating them if there are none yet. Called at startup, so that a token file that cannot be written stops the daemon right away rather than the control API later on.
+pub fn init_control_tokens() -> anyhow::Result<()> {
+    CONTROL_TOKENS.get_or_try_init(|| load_or_generate(&CONNECT_CONFIG.control_token_path))?;
+    Ok(())
+}
+
+fn load_or_generate(path: &Path) -> anyhow::Result<ControlTokens> {
+    if let Ok(existing) = storage::read(path) {
+        if let Ok(tokens) = serde_json::from_slice(&existing) {
+            return Ok(tokens);
+        }
+    }
+    let random_token = || hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let tokens = ControlTokens {
+        read_only: random_token(),
+        control: random_token(),
+    };
+    if let Some(parent) = path.parent() {
+        storage::create_dir_all(parent)?;
+    }
+    storage::write_private(path, serde_json::to_vec_pretty(&tokens)?)?;
+    log::info!("generated control API tokens at {:?}", path);
+    Ok(tokens)
+}
+
+/// Returns the scope a request is allowed, based on the token in its `Authorization: Bearer` header or in its `token` query parameter. The legacy `GEPH_RPC_KEY` environment variable, if set, is accepted as a full-control token.
+pub fn granted_scope(request: &tiny_http::Request) -> Option<Scope> {
+    let bearer = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .map(|s| s.trim().to_string());
+    let presented = bearer.or_else(|| url_token(request.url()))?;
+    let matches = |token: &str| {
+        ring::constant_time::verify_slices_are_equal(presented.as_bytes(), token.as_bytes()).is_ok()
+    };
+    if let Ok(key) = std::env::var("GEPH_RPC_KEY") {
+        if matches(&key) {
+            return Some(Scope::Control);
+        }
+    }
+    let tokens = CONTROL_TOKENS.get()?;
+    if matches(&tokens.control) {
+        Some(Scope::Control)
+    } else if matches(&tokens.read_only) {
+        Some(Scope::ReadOnly)
+    } else {
+        None
+    }
+}
+
+/// The `token` query parameter of a request's URL, which is just the path and query.
+fn url_token(url: &str) -> Option<String> {
+    let url = Url::parse("http://localhost").ok()?.join(url).ok()?;
+    let mut tokens = url.query_pairs().filter(|(k, _)| k == "token");
+    let (_, token) = tokens.next()?;
+    // more than one is ambiguous
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some(token.into_owned())
+}
+
+/// Returns the scope needed to call the given control API method.
+pub fn required_scope(method: &str) -> Scope {
+    match method {
+        "kill" | "reset_stats" | "reconnect" | "change_exit" | "set_log_level"
+        | "set_power_state" => Scope::Control,
+        _ => Scope::ReadOnly,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_from_the_query() {
+        assert_eq!(url_token("/?token=abc").as_deref(), Some("abc"));
+        assert_eq!(url_token("/metrics?x=1&token=abc").as_deref(), Some("abc"));
+        assert_eq!(url_token("/abc"), None);
+        assert_eq!(url_token("/?xtoken=abc"), None);
+        assert_eq!(url_token("/?token=abc&token=def"), None);
+    }
+}
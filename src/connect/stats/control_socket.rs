@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+#[cfg(unix)]
+use nanorpc::{JrpcRequest, RpcService};
+
+#[cfg(unix)]
+use crate::connect::audit::audit;
+
+#[cfg(unix)]
+use super::{control_auth, DummyImpl, StatsControlService};
+
+/// Serves the control API as newline-delimited JSON-RPC on a unix socket at the given path, readable and writable only by the current user. Anybody who can open the socket gets full control, so no tokens are needed. Never returns.
+#[cfg(unix)]
+pub async fn control_socket_loop(path: PathBuf) -> anyhow::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    // the socket is bound and locked down inside a directory only we can enter, then moved into place, so that nobody can connect before it is locked down
+    let staging = path.with_extension(format!("staging-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("control.sock");
+    let listener = smol::net::unix::UnixListener::bind(&staged)?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+    let _ = std::fs::remove_file(&path);
+    std::fs::rename(&staged, &path)?;
+    let _ = std::fs::remove_dir(&staging);
+    log::info!("control API listening on {:?}", path);
+    loop {
+        let (conn, _) = listener.accept().await?;
+        smolscale::spawn(async move {
+            if let Err(err) = serve_connection(conn).await {
+                log::debug!("control socket connection ended: {:?}", err);
+            }
+        })
+        .detach();
+    }
+}
+
+#[cfg(unix)]
+async fn serve_connection(conn: smol::net::unix::UnixStream) -> anyhow::Result<()> {
+    use smol::{io::BufReader, prelude::*};
+
+    let mut lines = BufReader::new(conn.clone()).lines();
+    let mut conn = conn;
+    while let Some(line) = lines.next().await {
+        let jrpc: JrpcRequest = serde_json::from_str(&line?)?;
+        if control_auth::required_scope(&jrpc.method) == control_auth::Scope::Control {
+            audit(
+                "control-socket",
+                &jrpc.method,
+                &serde_json::to_string(&jrpc.params)?,
+            );
+        }
+        let resp = StatsControlService(DummyImpl).respond_raw(jrpc).await;
+        let mut out = serde_json::to_vec(&resp)?;
+        out.push(b'\n');
+        conn.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn control_socket_loop(_path: PathBuf) -> anyhow::Result<()> {
+    anyhow::bail!("--control-socket needs unix sockets, which this platform lacks; use the HTTP control API at --stats-listen instead")
+}
@@ -0,0 +1,91 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use anyhow::Context;
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{
+        extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName},
+        X509NameBuilder, X509,
+    },
+};
+
+use crate::storage;
+
+/// Directory where the locally generated certificate lives. It is kept across runs so that it only needs to be installed into the OS trust store once.
+fn local_tls_dir() -> PathBuf {
+    let mut dir = dirs::config_dir().unwrap();
+    dir.push("geph4-local-tls");
+    dir
+}
+
+/// Loads the local certificate and private key (both PEM-encoded), generating and persisting them if they don't exist yet.
+pub fn load_or_generate(listen_ip: IpAddr) -> anyhow::Result<tiny_http::SslConfig> {
+    let dir = local_tls_dir();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    if let (Ok(certificate), Ok(private_key)) =
+        (storage::read(&cert_path), storage::read(&key_path))
+    {
+        return Ok(tiny_http::SslConfig {
+            certificate,
+            private_key,
+        });
+    }
+    let (certificate, private_key) = generate(listen_ip)?;
+    storage::create_dir_all(&dir)?;
+    storage::write(&cert_path, &certificate)?;
+    storage::write_private(&key_path, &private_key)?;
+    log::info!(
+        "generated a local TLS certificate at {:?}; add it to your OS trust store to avoid browser warnings",
+        cert_path
+    );
+    Ok(tiny_http::SslConfig {
+        certificate,
+        private_key,
+    })
+}
+
+/// Generates a self-signed certificate valid for localhost and the given IP address.
+fn generate(listen_ip: IpAddr) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "Geph local status page")?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = {
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+        serial.to_asn1_integer()?
+    };
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(825)?.as_ref())?;
+    builder.append_extension(BasicConstraints::new().critical().build()?)?;
+    builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    let san = {
+        let mut san = SubjectAlternativeName::new();
+        san.dns("localhost").ip("127.0.0.1");
+        if !listen_ip.is_unspecified() {
+            san.ip(&listen_ip.to_string());
+        }
+        san.build(&builder.x509v3_context(None, None))?
+    };
+    builder.append_extension(san)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let certificate = builder.build().to_pem()?;
+    let private_key = key
+        .private_key_to_pem_pkcs8()
+        .context("cannot encode private key")?;
+    Ok((certificate, private_key))
+}
@@ -0,0 +1,390 @@
+use std::{fmt::Write, net::SocketAddr, sync::atomic::Ordering};
+
+use once_cell::sync::Lazy;
+
+use crate::connect::{
+    dns::dns_batch_stats,
+    power::power_stats,
+    tunnel::{
+        control::current_exit,
+        pipe_health::pipe_health,
+        pipe_info::pipe_info,
+        repair_stats::{repair_stats, RepairStats, REPLACE_BUCKETS},
+        roaming::network_generation,
+    },
+    vpn::{dedup_stats, dns_intercept_stats, ipv6_refused, pool_stats},
+    TUNNEL,
+};
+
+use super::{control_auth, STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES, STATS_SESSIONS};
+
+/// Key for the pipe ids in metric labels, fresh for every run, so that the labels identify pipes without revealing which bridges they go to.
+static PIPE_ID_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// Serves tunnel metrics in the Prometheus text format on every path of the given address, so that connection quality can be graphed over time. Like the rest of the control API, it needs at least the read-only control token, as a bearer token or as the `token` query parameter. Never returns unless the address cannot be bound.
+pub fn metrics_loop(listen: SocketAddr) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(listen).map_err(|e| anyhow::anyhow!(e))?;
+    log::info!("metrics listening on {}", listen);
+    for request in server.incoming_requests() {
+        if control_auth::granted_scope(&request).is_none() {
+            let _ = request.respond(tiny_http::Response::empty(401));
+            continue;
+        }
+        let header =
+            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                .unwrap();
+        let response = tiny_http::Response::from_string(render()).with_header(header);
+        if let Err(err) = request.respond(response) {
+            log::debug!("could not send metrics: {:?}", err);
+        }
+    }
+    Ok(())
+}
+
+/// A short id standing in for a pipe's endpoint in labels.
+fn pipe_id(endpoint: &str) -> String {
+    blake3::keyed_hash(&PIPE_ID_KEY, endpoint.as_bytes()).to_hex()[..8].to_string()
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    metric(
+        &mut out,
+        "geph_connected",
+        "gauge",
+        "Whether the tunnel is connected.",
+        &[(String::new(), TUNNEL.status().connected() as u8 as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_sent_bytes_total",
+        "counter",
+        "Bytes sent through the tunnel since startup.",
+        &[(
+            String::new(),
+            STATS_SEND_BYTES.load(Ordering::Relaxed) as f64,
+        )],
+    );
+    metric(
+        &mut out,
+        "geph_recv_bytes_total",
+        "counter",
+        "Bytes received through the tunnel since startup.",
+        &[(
+            String::new(),
+            STATS_RECV_BYTES.load(Ordering::Relaxed) as f64,
+        )],
+    );
+    metric(
+        &mut out,
+        "geph_sessions_total",
+        "counter",
+        "Sessions established since startup; every one after the first is a reconnect.",
+        &[(String::new(), STATS_SESSIONS.load(Ordering::Relaxed) as f64)],
+    );
+    if let Some(item) = STATS_GATHERER.all_items().last() {
+        metric(
+            &mut out,
+            "geph_ping_seconds",
+            "gauge",
+            "Round-trip time of the latest end-to-end ping through the tunnel.",
+            &[(String::new(), item.ping.as_secs_f64())],
+        );
+    }
+    if let Some(exit) = current_exit() {
+        metric(
+            &mut out,
+            "geph_exit_info",
+            "gauge",
+            "The exit the current session goes to.",
+            &[(format!("exit=\"{}\"", escape(&exit)), 1.0)],
+        );
+    }
+
+    let pipes = pipe_info();
+    metric(
+        &mut out,
+        "geph_pipes",
+        "gauge",
+        "Pipes connected in the current session.",
+        &[(String::new(), pipes.len() as f64)],
+    );
+    let labels = |protocol: &str, endpoint: &str| {
+        format!(
+            "protocol=\"{}\",pipe=\"{}\"",
+            escape(protocol),
+            pipe_id(endpoint)
+        )
+    };
+    let reconnects: Vec<_> = pipes
+        .iter()
+        .map(|p| (labels(&p.protocol, &p.endpoint), p.reconnects as f64))
+        .collect();
+    metric(
+        &mut out,
+        "geph_pipe_reconnects_total",
+        "counter",
+        "Times each pipe had to reconnect.",
+        &reconnects,
+    );
+    let handshakes: Vec<_> = pipes
+        .iter()
+        .map(|p| (labels(&p.protocol, &p.endpoint), p.handshake_ms / 1000.0))
+        .collect();
+    metric(
+        &mut out,
+        "geph_pipe_handshake_seconds",
+        "gauge",
+        "How long each pipe's latest handshake took.",
+        &handshakes,
+    );
+
+    let health = pipe_health();
+    let rtts: Vec<_> = health
+        .iter()
+        .filter_map(|h| {
+            h.latency
+                .map(|l| (labels(&h.protocol, &h.endpoint), l.as_secs_f64()))
+        })
+        .collect();
+    metric(
+        &mut out,
+        "geph_pipe_rtt_seconds",
+        "gauge",
+        "Smoothed time from a send to the next reply on each pipe.",
+        &rtts,
+    );
+    let stalls: Vec<_> = health
+        .iter()
+        .map(|h| (labels(&h.protocol, &h.endpoint), h.recent_stalls as f64))
+        .collect();
+    metric(
+        &mut out,
+        "geph_pipe_recent_stalls",
+        "gauge",
+        "Sends left unanswered for over five seconds on each pipe in the last ten minutes, a stand-in for packet loss.",
+        &stalls,
+    );
+    let demoted: Vec<_> = health
+        .iter()
+        .map(|h| (labels(&h.protocol, &h.endpoint), h.demoted as u8 as f64))
+        .collect();
+    metric(
+        &mut out,
+        "geph_pipe_demoted",
+        "gauge",
+        "Whether each pipe was demoted for poor health.",
+        &demoted,
+    );
+
+    let repairs = repair_stats();
+    let by_protocol = |value: fn(&RepairStats) -> u64| -> Vec<(String, f64)> {
+        repairs
+            .iter()
+            .map(|(protocol, stats)| {
+                (
+                    format!("protocol=\"{}\"", escape(protocol)),
+                    value(stats) as f64,
+                )
+            })
+            .collect()
+    };
+    metric(
+        &mut out,
+        "geph_repair_dead_pipes_total",
+        "counter",
+        "Pipes that died or were demoted, by protocol.",
+        &by_protocol(|s| s.dead_detected),
+    );
+    metric(
+        &mut out,
+        "geph_repair_attempts_total",
+        "counter",
+        "Reconnects and replacement pipes tried, by protocol.",
+        &by_protocol(|s| s.replacements_attempted),
+    );
+    metric(
+        &mut out,
+        "geph_repair_successes_total",
+        "counter",
+        "Reconnects and replacement pipes that connected, by protocol.",
+        &by_protocol(|s| s.replacements_succeeded),
+    );
+    metric(
+        &mut out,
+        "geph_repair_failures_total",
+        "counter",
+        "Replacement pipes that did not connect, by protocol.",
+        &by_protocol(|s| s.replacements_failed),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP geph_repair_replace_seconds Time from finding a dead pipe to its replacement connecting."
+    );
+    let _ = writeln!(out, "# TYPE geph_repair_replace_seconds histogram");
+    for (protocol, stats) in repairs.iter() {
+        let protocol = escape(protocol);
+        let mut cumulative = 0;
+        for (bound, count) in REPLACE_BUCKETS.iter().zip(stats.replace_buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "geph_repair_replace_seconds_bucket{{protocol=\"{}\",le=\"{}\"}} {}",
+                protocol, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "geph_repair_replace_seconds_bucket{{protocol=\"{}\",le=\"+Inf\"}} {}",
+            protocol, stats.replacements_succeeded
+        );
+        let _ = writeln!(
+            out,
+            "geph_repair_replace_seconds_sum{{protocol=\"{}\"}} {}",
+            protocol, stats.replace_seconds_sum
+        );
+        let _ = writeln!(
+            out,
+            "geph_repair_replace_seconds_count{{protocol=\"{}\"}} {}",
+            protocol, stats.replacements_succeeded
+        );
+    }
+
+    let power = power_stats();
+    metric(
+        &mut out,
+        "geph_power_state",
+        "gauge",
+        "The power state the platform last reported.",
+        &[(format!("state=\"{}\"", escape(&power.state)), 1.0)],
+    );
+    metric(
+        &mut out,
+        "geph_power_seconds_total",
+        "counter",
+        "Time spent in each power state.",
+        &power
+            .seconds
+            .iter()
+            .map(|(state, secs)| (format!("state=\"{}\"", escape(state)), *secs))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        &mut out,
+        "geph_power_wakeups_total",
+        "counter",
+        "Timer wakeups of the periodic loops in each power state.",
+        &power
+            .wakeups
+            .iter()
+            .map(|(state, count)| (format!("state=\"{}\"", escape(state)), *count as f64))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        &mut out,
+        "geph_power_probes_skipped_total",
+        "counter",
+        "Background probes skipped in low power mode.",
+        &[(String::new(), power.probes_skipped as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_power_samples_skipped_total",
+        "counter",
+        "Stats samples skipped in low power mode.",
+        &[(String::new(), power.samples_skipped as f64)],
+    );
+
+    let (allocated, reused) = pool_stats();
+    metric(
+        &mut out,
+        "geph_vpn_buffers_allocated_total",
+        "counter",
+        "VPN packet buffers allocated because the pool had none free.",
+        &[(String::new(), allocated as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_vpn_buffers_reused_total",
+        "counter",
+        "VPN packets carried in a buffer reused from the pool.",
+        &[(String::new(), reused as f64)],
+    );
+    let (answered, failed, refused_tcp) = dns_intercept_stats();
+    metric(
+        &mut out,
+        "geph_vpn_dns_intercepted_total",
+        "counter",
+        "DNS packets seen in the VPN and kept from leaving as plaintext, by what became of them.",
+        &[
+            ("outcome=\"answered\"".into(), answered as f64),
+            ("outcome=\"failed\"".into(), failed as f64),
+            ("outcome=\"refused_tcp\"".into(), refused_tcp as f64),
+        ],
+    );
+    metric(
+        &mut out,
+        "geph_vpn_ipv6_refused_total",
+        "counter",
+        "IPv6 packets seen in the VPN, which the tunnel cannot carry, refused with an ICMPv6 error.",
+        &[(String::new(), ipv6_refused() as f64)],
+    );
+    let (suppressed, suppressed_bytes) = dedup_stats();
+    metric(
+        &mut out,
+        "geph_vpn_duplicates_suppressed_total",
+        "counter",
+        "Exact duplicate UDP packets that came down the VPN, as some middleboxes duplicate UDP, and were dropped.",
+        &[(String::new(), suppressed as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_vpn_duplicate_bytes_suppressed_total",
+        "counter",
+        "Bytes taken up by the duplicate packets dropped.",
+        &[(String::new(), suppressed_bytes as f64)],
+    );
+    let (dns_queries, dns_batches) = dns_batch_stats();
+    metric(
+        &mut out,
+        "geph_dns_queries_total",
+        "counter",
+        "Plain DNS queries sent through the tunnel.",
+        &[(String::new(), dns_queries as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_dns_batches_total",
+        "counter",
+        "Writes the plain DNS queries went out in, fewer than the queries when bursts were batched together.",
+        &[(String::new(), dns_batches as f64)],
+    );
+    metric(
+        &mut out,
+        "geph_network_changes_total",
+        "counter",
+        "Times the device moved to another network, each reconnecting every pipe.",
+        &[(String::new(), network_generation() as f64)],
+    );
+    out
+}
+
+/// Writes one metric family, whose samples are given as label sets (without braces, empty for none) and values.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,162 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connect::{usage_log::with_usage_db, CONNECT_CONFIG},
+    storage,
+    usage::{DayUsage, UsageLog},
+};
+
+use super::{STATS_RECV_BYTES, STATS_SEND_BYTES, STATS_SESSIONS};
+
+/// What a set of counters counts since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatScope {
+    /// Every run of the daemon, as recorded in the usage log, since the counters were last reset.
+    Lifetime,
+    /// This run of the daemon.
+    Boot,
+    /// The tunnel's current (or last) connection.
+    Session,
+}
+
+/// Traffic counters for one [StatScope].
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ScopedStats {
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    /// How many times the tunnel came up.
+    pub sessions: u64,
+    /// When counting started, or None for lifetime counters that were never reset.
+    pub since_unix: Option<u64>,
+}
+
+/// The counter values a scope's counters are relative to.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Baseline {
+    usage: DayUsage,
+    since_unix: Option<u64>,
+}
+
+impl Baseline {
+    fn at(usage: DayUsage) -> Self {
+        Self {
+            usage,
+            since_unix: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            ),
+        }
+    }
+}
+
+/// The usage of every earlier run, read from the usage log before this run starts adding to it.
+static EARLIER_USAGE: Lazy<DayUsage> = Lazy::new(|| {
+    let log = with_usage_db(|db| db.log()).unwrap_or_else(|err| {
+        log::warn!("cannot read usage log for lifetime stats: {:?}", err);
+        UsageLog::default()
+    });
+    log.days
+        .values()
+        .fold(DayUsage::default(), |acc, day| DayUsage {
+            sent_bytes: acc.sent_bytes + day.sent_bytes,
+            recv_bytes: acc.recv_bytes + day.recv_bytes,
+            sessions: acc.sessions + day.sessions,
+        })
+});
+
+static LIFETIME_BASELINE: Lazy<Mutex<Baseline>> = Lazy::new(|| {
+    let baseline = storage::read(&baseline_path())
+        .ok()
+        .and_then(|bts| serde_json::from_slice(&bts).ok())
+        .unwrap_or_default();
+    Mutex::new(baseline)
+});
+
+static BOOT_BASELINE: Lazy<Mutex<Baseline>> =
+    Lazy::new(|| Mutex::new(Baseline::at(DayUsage::default())));
+
+static SESSION_BASELINE: Lazy<Mutex<Baseline>> = Lazy::new(Default::default);
+
+/// Where a lifetime reset is remembered, next to the usage log.
+fn baseline_path() -> PathBuf {
+    CONNECT_CONFIG.usage_path.with_extension("baseline.json")
+}
+
+/// The counters of this run.
+fn boot_usage() -> DayUsage {
+    DayUsage {
+        sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed),
+        recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed),
+        sessions: STATS_SESSIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// The counters of every run, including this one.
+fn lifetime_usage() -> DayUsage {
+    let boot = boot_usage();
+    DayUsage {
+        sent_bytes: EARLIER_USAGE.sent_bytes + boot.sent_bytes,
+        recv_bytes: EARLIER_USAGE.recv_bytes + boot.recv_bytes,
+        sessions: EARLIER_USAGE.sessions + boot.sessions,
+    }
+}
+
+fn absolute_usage(scope: StatScope) -> DayUsage {
+    match scope {
+        StatScope::Lifetime => lifetime_usage(),
+        StatScope::Boot | StatScope::Session => boot_usage(),
+    }
+}
+
+fn baseline(scope: StatScope) -> &'static Mutex<Baseline> {
+    match scope {
+        StatScope::Lifetime => &LIFETIME_BASELINE,
+        StatScope::Boot => &BOOT_BASELINE,
+        StatScope::Session => &SESSION_BASELINE,
+    }
+}
+
+/// Pins down where the boot and lifetime counters start. Must be called before any traffic, and before the usage log is first flushed.
+pub fn init_scopes() {
+    Lazy::force(&EARLIER_USAGE);
+    Lazy::force(&LIFETIME_BASELINE);
+    Lazy::force(&BOOT_BASELINE);
+}
+
+/// Starts the session counters afresh. Called whenever the tunnel comes up.
+pub fn start_session() {
+    *SESSION_BASELINE.lock() = Baseline::at(boot_usage());
+}
+
+/// Obtains the counters for the given scope.
+pub fn scoped_stats(scope: StatScope) -> ScopedStats {
+    let now = absolute_usage(scope);
+    let baseline = *baseline(scope).lock();
+    ScopedStats {
+        sent_bytes: now.sent_bytes.saturating_sub(baseline.usage.sent_bytes),
+        recv_bytes: now.recv_bytes.saturating_sub(baseline.usage.recv_bytes),
+        sessions: now.sessions.saturating_sub(baseline.usage.sessions),
+        since_unix: baseline.since_unix,
+    }
+}
+
+/// Resets the counters for the given scope to zero. A lifetime reset is remembered across runs, and leaves the usage log itself untouched.
+pub fn reset_scope(scope: StatScope) -> anyhow::Result<()> {
+    let fresh = Baseline::at(absolute_usage(scope));
+    if scope == StatScope::Lifetime {
+        storage::write(&baseline_path(), serde_json::to_vec(&fresh)?)?;
+    }
+    *baseline(scope).lock() = fresh;
+    Ok(())
+}
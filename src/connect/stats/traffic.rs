@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::AsyncRead;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// A coarse category of proxied traffic, guessed from the port and hostname only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TrafficClass {
+    Web,
+    Video,
+    Messaging,
+    Other,
+}
+
+impl TrafficClass {
+    const ALL: [TrafficClass; 4] = [
+        TrafficClass::Web,
+        TrafficClass::Video,
+        TrafficClass::Messaging,
+        TrafficClass::Other,
+    ];
+}
+
+const VIDEO_DOMAINS: &[&str] = &[
+    "youtube.com",
+    "googlevideo.com",
+    "ytimg.com",
+    "netflix.com",
+    "nflxvideo.net",
+    "twitch.tv",
+    "ttvnw.net",
+    "vimeo.com",
+    "vimeocdn.com",
+    "tiktok.com",
+    "tiktokcdn.com",
+    "bilibili.com",
+    "bilivideo.com",
+    "dailymotion.com",
+    "aparat.com",
+];
+
+const MESSAGING_DOMAINS: &[&str] = &[
+    "whatsapp.com",
+    "whatsapp.net",
+    "telegram.org",
+    "t.me",
+    "signal.org",
+    "discord.com",
+    "discord.gg",
+    "discordapp.com",
+    "messenger.com",
+    "line.me",
+    "slack.com",
+    "wechat.com",
+    "skype.com",
+];
+
+/// XMPP, WhatsApp and Google push notifications.
+const MESSAGING_PORTS: &[u16] = &[5222, 5223, 5228];
+
+const WEB_PORTS: &[u16] = &[80, 443, 8080, 8443];
+
+/// Classifies a connection by its destination port and, when known, its hostname (from the SOCKS5 request or the TLS SNI).
+pub fn classify(port: u16, host: Option<&str>) -> TrafficClass {
+    let matches = |domains: &[&str]| {
+        host.map(|host| {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            domains
+                .iter()
+                .any(|d| host == *d || host.ends_with(&format!(".{}", d)))
+        })
+        .unwrap_or(false)
+    };
+    if matches(VIDEO_DOMAINS) {
+        TrafficClass::Video
+    } else if matches(MESSAGING_DOMAINS) || MESSAGING_PORTS.contains(&port) {
+        TrafficClass::Messaging
+    } else if WEB_PORTS.contains(&port) {
+        TrafficClass::Web
+    } else {
+        TrafficClass::Other
+    }
+}
+
+static CLASS_BYTES: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Counts bytes, in either direction, against a traffic class.
+pub fn add_class_bytes(class: TrafficClass, bytes: u64) {
+    CLASS_BYTES[class as usize].fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Total bytes proxied so far in each traffic class.
+pub fn class_bytes() -> Vec<(TrafficClass, u64)> {
+    TrafficClass::ALL
+        .iter()
+        .map(|class| (*class, CLASS_BYTES[*class as usize].load(Ordering::Relaxed)))
+        .collect()
+}
+
+static SNI_CONNECTIONS: Lazy<Mutex<BTreeMap<String, u64>>> = Lazy::new(Default::default);
+
+/// Counts a tunneled TLS connection to the given SNI. Only called when `--log-sni` is on.
+pub fn record_sni(sni: &str) {
+    *SNI_CONNECTIONS.lock().entry(sni.to_string()).or_default() += 1;
+}
+
+/// How many tunneled TLS connections went to each SNI, most frequent first.
+pub fn sni_connections() -> Vec<(String, u64)> {
+    let mut counts: Vec<_> = SNI_CONNECTIONS
+        .lock()
+        .iter()
+        .map(|(sni, n)| (sni.clone(), *n))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1));
+    counts
+}
+
+/// Extracts the server name from a TLS ClientHello, if the buffer starts with one.
+pub fn parse_sni(buf: &[u8]) -> Option<String> {
+    let mut r = Reader(buf);
+    // record header: handshake, version, length
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.skip(4)?;
+    // handshake header: client hello, length
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.skip(3)?;
+    // client version and random
+    r.skip(2 + 32)?;
+    let session_id = r.u8()? as usize;
+    r.skip(session_id)?;
+    let ciphers = r.u16()? as usize;
+    r.skip(ciphers)?;
+    let compression = r.u8()? as usize;
+    r.skip(compression)?;
+    let mut extensions = Reader(r.take_u16_prefixed()?);
+    while let Some(ext_type) = extensions.u16() {
+        let mut ext = Reader(extensions.take_u16_prefixed()?);
+        if ext_type != 0 {
+            continue;
+        }
+        let mut names = Reader(ext.take_u16_prefixed()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.take_u16_prefixed()?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// The most of a ClientHello looked at for the SNI, which is as long as a TLS record can be.
+const MAX_HELLO: usize = 5 + (1 << 14);
+
+/// Relays what a client sends while looking for the SNI in the ClientHello it starts with, so that looking never holds the connection up. Calls back once, with the SNI or with None if there turns out to be none.
+pub struct SniSniffer<R, F> {
+    inner: R,
+    seen: Vec<u8>,
+    on_sni: Option<F>,
+}
+
+impl<R, F: FnOnce(Option<String>)> SniSniffer<R, F> {
+    pub fn new(inner: R, on_sni: F) -> Self {
+        Self {
+            inner,
+            seen: Vec::new(),
+            on_sni: Some(on_sni),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin, F: FnOnce(Option<String>) + Unpin> AsyncRead for SniSniffer<R, F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let n = futures_util::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(on_sni) = this.on_sni.take() {
+            this.seen.extend_from_slice(&buf[..n]);
+            match sniffed(&this.seen, n == 0) {
+                Some(sni) => {
+                    this.seen = Vec::new();
+                    on_sni(sni)
+                }
+                None => this.on_sni = Some(on_sni),
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// What the start of a client's stream says about the SNI: None while more of it is needed, or else the SNI, if any.
+fn sniffed(seen: &[u8], eof: bool) -> Option<Option<String>> {
+    if let Some(sni) = parse_sni(seen) {
+        return Some(Some(sni));
+    }
+    // not TLS, or the whole hello is here and has no SNI
+    let record_len = match seen {
+        [0x16, _, _, hi, lo, ..] => u16::from_be_bytes([*hi, *lo]) as usize + 5,
+        [0x16, ..] | [] => MAX_HELLO,
+        _ => 0,
+    };
+    if eof || seen.len() >= record_len.min(MAX_HELLO) {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn take_u16_prefixed(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::AsyncReadExt;
+
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut name = vec![0];
+        name.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        name.extend_from_slice(sni.as_bytes());
+        let mut ext = (name.len() as u16).to_be_bytes().to_vec();
+        ext.extend_from_slice(&name);
+        let mut extensions = vec![0, 0];
+        extensions.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&ext);
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        // no session id, one cipher suite, no compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![0x01, 0];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sniff(stream: Vec<u8>) -> (Vec<u8>, Vec<Option<String>>) {
+        let mut calls = vec![];
+        let mut relayed = vec![];
+        smol::block_on(
+            SniSniffer::new(futures_util::io::Cursor::new(stream), |sni| calls.push(sni))
+                .read_to_end(&mut relayed),
+        )
+        .unwrap();
+        (relayed, calls)
+    }
+
+    #[test]
+    fn finds_the_sni() {
+        let mut stream = client_hello("example.com");
+        stream.extend_from_slice(b"application data");
+        let (relayed, calls) = sniff(stream.clone());
+        assert_eq!(relayed, stream);
+        assert_eq!(calls, vec![Some("example.com".to_string())]);
+    }
+
+    #[test]
+    fn gives_up_on_other_protocols() {
+        let (relayed, calls) = sniff(b"GET / HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(relayed, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(calls, vec![None]);
+        let (_, calls) = sniff(vec![]);
+        assert_eq!(calls, vec![None]);
+    }
+
+    #[test]
+    fn waits_for_the_whole_hello() {
+        let hello = client_hello("example.com");
+        assert_eq!(sniffed(&hello[..3], false), None);
+        assert_eq!(sniffed(&hello[..20], false), None);
+        assert_eq!(sniffed(&hello[..20], true), Some(None));
+    }
+}
@@ -15,6 +15,8 @@ use smol::{
 };
 use sosistab2::Pipe;
 
+use super::roaming::{network_generation, wait_network_change};
+
 pub struct AutoconnectPipe<P: Pipe> {
     protocol: String,
     peer_metadata: String,
@@ -107,10 +109,17 @@ async fn autoconnect_loop<P: Pipe>(
         Up(Bytes),
         Down(Bytes),
         Replaced(P),
+        Roamed(u64),
     }
     let mut current_pipe = init_pipe;
     let mut replace_task: Option<(Receiver<P>, Task<()>)> = None;
     let recreate = Arc::new(recreate);
+    // flapping detection: how many times in a row the pipe died soon after being replaced
+    let mut last_replaced: Option<Instant> = None;
+    let mut flaps: u32 = 0;
+    // after moving to another network, the old pipe may still trickle in packets, which must not call off its replacement
+    let mut generation = network_generation();
+    let mut roaming = false;
     loop {
         let up_event = async {
             let up = recv_up.recv().await?;
@@ -131,37 +140,51 @@ async fn autoconnect_loop<P: Pipe>(
             }
         };
 
-        match up_event.or(replace_event.or(dn_event)).await {
+        let roam_event = async { anyhow::Ok(Event::Roamed(wait_network_change(generation).await)) };
+
+        match up_event.or(replace_event.or(roam_event.or(dn_event))).await {
             Ok(Event::Up(up)) => {
                 current_pipe.send(up).await;
                 if replace_task.is_none() {
-                    let (send, recv) = smol::channel::bounded(1);
-                    let protocol = protocol.clone();
-                    let endpoint = endpoint.clone();
-                    let recreate = recreate.clone();
-                    replace_task = Some((
-                        recv,
-                        smolscale::spawn(async move {
-                            smol::Timer::after(Duration::from_secs(5)).await;
-                            let start = Instant::now();
-                            log::debug!("reconnecting {protocol}/{endpoint}...");
-                            let replacement = recreate().await;
-                            log::debug!(
-                                "reconnected {protocol}/{endpoint} in {:?}!",
-                                start.elapsed()
-                            );
-                            let _ = send.try_send(replacement);
-                        }),
+                    replace_task = Some(spawn_replacement(
+                        recreate.clone(),
+                        protocol.clone(),
+                        endpoint.clone(),
+                        Duration::from_secs(5),
+                        quarantine_duration(flaps),
                     ));
                 }
             }
             Ok(Event::Down(dn)) => {
-                replace_task = None;
+                if !roaming {
+                    replace_task = None;
+                }
                 let _ = send_down.try_send(dn);
             }
+            Ok(Event::Roamed(new_generation)) => {
+                // the same session and the same bridge, only from the new network, and right away
+                log::debug!("network changed, reconnecting {protocol}/{endpoint} now");
+                generation = new_generation;
+                roaming = true;
+                flaps = 0;
+                last_replaced = None;
+                replace_task = Some(spawn_replacement(
+                    recreate.clone(),
+                    protocol.clone(),
+                    endpoint.clone(),
+                    Duration::ZERO,
+                    Duration::ZERO,
+                ));
+            }
             Ok(Event::Replaced(p)) => {
                 current_pipe = p;
                 replace_task = None;
+                roaming = false;
+                flaps = match last_replaced {
+                    Some(last) if last.elapsed() < FLAP_WINDOW => flaps + 1,
+                    _ => 0,
+                };
+                last_replaced = Some(Instant::now());
             }
             Err(err) => {
                 log::warn!("error: {:?}", err);
@@ -170,3 +193,48 @@ async fn autoconnect_loop<P: Pipe>(
         }
     }
 }
+
+/// Reconnects the pipe after the given delay and quarantine, handing over the replacement through the returned channel.
+fn spawn_replacement<P: Pipe>(
+    recreate: Arc<impl Fn() -> Task<P> + Send + Sync + 'static>,
+    protocol: String,
+    endpoint: String,
+    delay: Duration,
+    quarantine: Duration,
+) -> (Receiver<P>, Task<()>) {
+    let (send, recv) = smol::channel::bounded(1);
+    let task = smolscale::spawn(async move {
+        smol::Timer::after(delay).await;
+        if quarantine > Duration::ZERO {
+            log::debug!(
+                "{protocol}/{endpoint} is flapping, quarantining for {:?}",
+                quarantine
+            );
+            smol::Timer::after(quarantine).await;
+        }
+        // the fresh handshake done by recreate doubles as the quarantine probe
+        let start = Instant::now();
+        log::debug!("reconnecting {protocol}/{endpoint}...");
+        let replacement = recreate().await;
+        log::debug!(
+            "reconnected {protocol}/{endpoint} in {:?}!",
+            start.elapsed()
+        );
+        let _ = send.try_send(replacement);
+    });
+    (recv, task)
+}
+
+/// A pipe that dies again within this long of being replaced counts as flapping.
+const FLAP_WINDOW: Duration = Duration::from_secs(120);
+
+/// How long a pipe that flapped the given number of times in a row stays quarantined before being replaced again. Doubles with every flap, up to five minutes.
+fn quarantine_duration(flaps: u32) -> Duration {
+    if flaps == 0 {
+        Duration::ZERO
+    } else {
+        Duration::from_secs(5)
+            .saturating_mul(2u32.saturating_pow(flaps - 1))
+            .min(Duration::from_secs(300))
+    }
+}
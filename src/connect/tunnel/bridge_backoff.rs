@@ -0,0 +1,63 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol_str::SmolStr;
+
+/// Per-bridge failure tracking, shared across sessions so that a bridge that keeps failing stays backed off even when the tunnel restarts.
+pub static BRIDGE_BACKOFF: Lazy<BridgeBackoff> = Lazy::new(Default::default);
+
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Tracks consecutive failures of every bridge, and applies an exponential backoff to each.
+#[derive(Default)]
+pub struct BridgeBackoff {
+    failures: Mutex<HashMap<(SocketAddr, SmolStr), (u32, Instant)>>,
+}
+
+impl BridgeBackoff {
+    /// Records that connecting to a bridge failed.
+    pub fn record_failure(&self, desc: &BridgeDescriptor) {
+        let mut failures = self.failures.lock();
+        let entry = failures
+            .entry((desc.endpoint, desc.protocol.clone()))
+            .or_insert((0, Instant::now()));
+        entry.0 += 1;
+        entry.1 = Instant::now();
+    }
+
+    /// Records that connecting to a bridge succeeded, clearing its backoff.
+    pub fn record_success(&self, desc: &BridgeDescriptor) {
+        self.failures
+            .lock()
+            .remove(&(desc.endpoint, desc.protocol.clone()));
+    }
+
+    /// Returns when the bridge may be tried again, or None if it has no recent failures.
+    pub fn ready_at(&self, desc: &BridgeDescriptor) -> Option<Instant> {
+        let failures = self.failures.lock();
+        let (count, last) = failures.get(&(desc.endpoint, desc.protocol.clone()))?;
+        let backoff = BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(count.saturating_sub(1)))
+            .min(MAX_BACKOFF);
+        Some(*last + backoff)
+    }
+
+    /// Waits until the bridge may be tried again.
+    pub async fn wait_ready(&self, desc: &BridgeDescriptor) {
+        if let Some(ready_at) = self.ready_at(desc) {
+            smol::Timer::at(ready_at).await;
+        }
+    }
+
+    /// Sorts bridges so that untried ones come first, followed by the ones whose backoff ends soonest.
+    pub fn sort_by_readiness(&self, bridges: &mut [&BridgeDescriptor]) {
+        bridges.sort_by_key(|b| self.ready_at(b));
+    }
+}
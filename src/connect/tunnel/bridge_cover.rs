@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use smol::{net::TcpStream, prelude::*};
+use smol_timeout::TimeoutExt;
+
+use crate::connect::CONNECT_CONFIG;
+
+use super::dial_resolve::resolve_for_dial;
+
+/// How long a cover fetch may take in all, so that it never holds up the handshake it covers for long.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A step performed right before handshaking with a private bridge, so that the handshake doesn't come out of nowhere.
+#[derive(Clone, Debug)]
+pub enum BridgeCover {
+    /// Sends a small random UDP datagram to each of these ports on the bridge, in order.
+    Knock(Vec<u16>),
+    /// Fetches this HTTPS URL and discards the response.
+    Fetch {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+/// The cover steps given with --bridge-cover, by bridge address. Preflight has already refused malformed ones.
+static COVERS: Lazy<HashMap<IpAddr, BridgeCover>> = Lazy::new(|| {
+    CONNECT_CONFIG
+        .bridge_cover
+        .iter()
+        .filter_map(|spec| match parse_bridge_cover(spec) {
+            Ok(cover) => Some(cover),
+            Err(err) => {
+                log::warn!("ignoring bridge cover {}: {:?}", spec, err);
+                None
+            }
+        })
+        .collect()
+});
+
+/// The hosts that cover steps fetch from, which are dialed directly rather than through the tunnel.
+pub fn cover_hosts() -> Vec<(String, u16)> {
+    COVERS
+        .values()
+        .filter_map(|cover| match cover {
+            BridgeCover::Fetch { host, port, .. } => Some((host.clone(), *port)),
+            BridgeCover::Knock(_) => None,
+        })
+        .collect()
+}
+
+/// Parses a cover spec of the form IP=knock:PORT,PORT,... or IP=https://host[:port]/path.
+pub fn parse_bridge_cover(spec: &str) -> anyhow::Result<(IpAddr, BridgeCover)> {
+    let (ip, step) = spec
+        .split_once('=')
+        .context("bridge cover must be in form IP=knock:PORT,... or IP=https://...")?;
+    let ip: IpAddr = ip.parse().context("cannot parse bridge IP")?;
+    let cover = if let Some(ports) = step.strip_prefix("knock:") {
+        BridgeCover::Knock(
+            ports
+                .split(',')
+                .map(|p| p.trim().parse())
+                .collect::<Result<_, _>>()
+                .context("cannot parse knock ports")?,
+        )
+    } else if let Some(rest) = step.strip_prefix("https://") {
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("cannot parse HTTPS port")?),
+            None => (authority, 443),
+        };
+        BridgeCover::Fetch {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        }
+    } else {
+        anyhow::bail!("unknown bridge cover step {}", step)
+    };
+    Ok((ip, cover))
+}
+
+/// Performs the cover step for the bridge, if one was configured. Failures are only logged, since the cover step is not what the bridge itself checks.
+pub async fn cover_bridge(endpoint: SocketAddr) {
+    let cover = match COVERS.get(&endpoint.ip()) {
+        Some(cover) => cover.clone(),
+        None => return,
+    };
+    log::debug!("performing cover step {:?} for {}", cover, endpoint);
+    let result = match cover {
+        BridgeCover::Knock(ports) => knock(endpoint.ip(), &ports).await,
+        BridgeCover::Fetch { host, port, path } => fetch(&host, port, &path)
+            .timeout(FETCH_TIMEOUT)
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("timed out"))),
+    };
+    if let Err(err) = result {
+        log::warn!("cover step for {} failed: {:?}", endpoint, err);
+    }
+}
+
+async fn knock(ip: IpAddr, ports: &[u16]) -> anyhow::Result<()> {
+    let socket =
+        smol::net::UdpSocket::bind(if ip.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    for port in ports {
+        let junk: Vec<u8> = (0..rand::thread_rng().gen_range(8, 64))
+            .map(|_| rand::random())
+            .collect();
+        socket.send_to(&junk, (ip, *port)).await?;
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+async fn fetch(host: &str, port: u16, path: &str) -> anyhow::Result<()> {
+    // resolved like the bridges themselves, so that the system resolver isn't used where it mustn't be
+    let addr = resolve_for_dial(&format!("{}:{}", host, port)).await?;
+    let tcp = TcpStream::connect(addr).await?;
+    let mut tls = async_native_tls::TlsConnector::new()
+        .connect(host, tcp)
+        .await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    tls.write_all(request.as_bytes()).await?;
+    tls.flush().await?;
+    // read a little of the response, like a client that got what it wanted
+    let mut buf = [0u8; 4096];
+    let n = tls.read(&mut buf).await?;
+    log::trace!("cover fetch from {} read {} bytes", host, n);
+    Ok(())
+}
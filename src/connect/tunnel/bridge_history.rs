@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use event_listener::Event;
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{connect::CONNECT_CONFIG, storage};
+
+use super::roaming::network_generation;
+
+/// Each new outcome weighs this much more than the one before it, so that a bridge that stopped working from this network soon stops being preferred.
+const DECAY: f64 = 0.8;
+
+/// History older than this says nothing about how a bridge does now.
+const MAX_AGE: Duration = Duration::from_secs(30 * 86400);
+
+/// How many networks and how many bridges per network are remembered, dropping the least recently used first.
+const MAX_NETWORKS: usize = 16;
+const MAX_BRIDGES: usize = 256;
+
+/// How long changes to the history are gathered before they are saved together.
+const SAVE_DELAY: Duration = Duration::from_secs(10);
+
+/// How a bridge fared from one network.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct BridgeRecord {
+    /// Decayed counts of successful and failed connects.
+    successes: f64,
+    failures: f64,
+    /// Smoothed handshake time of successful connects.
+    rtt_ms: Option<f64>,
+    /// Bytes received and seconds spent connected, over every pipe to the bridge.
+    recv_bytes: u64,
+    connected_secs: f64,
+    last_used: u64,
+}
+
+impl BridgeRecord {
+    fn success_rate(&self) -> f64 {
+        self.successes / (self.successes + self.failures).max(f64::EPSILON)
+    }
+
+    fn connect_succeeded(&mut self, handshake: Duration) {
+        let ms = handshake.as_secs_f64() * 1000.0;
+        self.successes = self.successes * DECAY + 1.0;
+        self.failures *= DECAY;
+        self.rtt_ms = Some(match self.rtt_ms {
+            Some(old) => old * DECAY + ms * (1.0 - DECAY),
+            None => ms,
+        });
+    }
+
+    fn connect_failed(&mut self) {
+        self.successes *= DECAY;
+        self.failures = self.failures * DECAY + 1.0;
+    }
+
+    /// 0 if the bridge connected reliably lately, 1 if that's unclear, and 2 if it mostly failed.
+    fn rank(&self) -> u8 {
+        match self.success_rate() {
+            rate if rate >= 0.8 => 0,
+            rate if rate >= 0.5 => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Bridge records by network, then by protocol and endpoint.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct BridgeHistory {
+    networks: BTreeMap<String, BTreeMap<String, BridgeRecord>>,
+}
+
+impl BridgeHistory {
+    /// Updates the record of a bridge from a network at the given time, evicting the least recently used records and networks over the limits.
+    fn update(
+        &mut self,
+        network: String,
+        bridge: String,
+        now: u64,
+        f: impl FnOnce(&mut BridgeRecord),
+    ) {
+        let bridges = self.networks.entry(network).or_default();
+        let record = bridges.entry(bridge).or_default();
+        f(record);
+        record.last_used = now;
+        evict(bridges, MAX_BRIDGES, |r| r.last_used);
+        evict(&mut self.networks, MAX_NETWORKS, |bridges| {
+            bridges.values().map(|r| r.last_used).max().unwrap_or(0)
+        });
+    }
+
+    /// The rank and usual handshake time of a bridge from a network, as of the given time. See [bridge_standing].
+    fn standing(&self, network: &str, bridge: &str, now: u64) -> (u8, Option<Duration>) {
+        let record = self
+            .networks
+            .get(network)
+            .and_then(|bridges| bridges.get(bridge))
+            .filter(|r| r.last_used + MAX_AGE.as_secs() >= now);
+        match record {
+            None => (1, None),
+            Some(record) => (
+                record.rank(),
+                record.rtt_ms.map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+            ),
+        }
+    }
+}
+
+static HISTORY: Lazy<Mutex<BridgeHistory>> = Lazy::new(|| {
+    let history = storage::read(&history_path())
+        .ok()
+        .and_then(|bts| serde_json::from_slice(&bts).ok())
+        .unwrap_or_default();
+    Mutex::new(history)
+});
+
+/// Whether the history changed since it was last saved.
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Wakes [SAVER] whenever the history changes.
+static CHANGED: Event = Event::new();
+
+/// Saves the history in the background, at most once every [SAVE_DELAY], so that recording an outcome never waits on the disk.
+static SAVER: Lazy<()> = Lazy::new(|| smolscale::spawn(save_loop()).detach());
+
+async fn save_loop() {
+    loop {
+        let listener = CHANGED.listen();
+        if !DIRTY.load(Ordering::SeqCst) {
+            listener.await;
+            continue;
+        }
+        smol::Timer::after(SAVE_DELAY).await;
+        DIRTY.store(false, Ordering::SeqCst);
+        let history = HISTORY.lock().clone();
+        let result = smol::unblock(move || {
+            let path = history_path();
+            let tmp = path.with_extension("tmp");
+            storage::write(&tmp, serde_json::to_vec(&history)?)?;
+            storage::rename(&tmp, &path)
+        })
+        .await;
+        if let Err(err) = result {
+            log::warn!("cannot save bridge history: {:?}", err)
+        }
+    }
+}
+
+/// Where bridge history is kept, next to the usage log.
+fn history_path() -> PathBuf {
+    CONNECT_CONFIG.usage_path.with_extension("bridges.json")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The network ID, as of the network generation it was worked out in.
+static NETWORK_ID: Lazy<Mutex<Option<(u64, String)>>> = Lazy::new(Default::default);
+
+/// Tells networks apart by the default interface and the router behind it. Only a hash is kept, so that the file doesn't say where the user has been.
+fn network_id() -> String {
+    let generation = network_generation();
+    let mut cached = NETWORK_ID.lock();
+    if let Some((seen, id)) = cached.as_ref() {
+        if *seen == generation {
+            return id.clone();
+        }
+    }
+    let id = match default_net::get_default_interface() {
+        Ok(iface) => format!(
+            "{}/{:?}",
+            iface.name,
+            iface.gateway.map(|gw| (gw.ip_addr, gw.mac_addr))
+        ),
+        Err(_) => "unknown".into(),
+    };
+    let id = blake3::hash(id.as_bytes()).to_hex()[..16].to_string();
+    *cached = Some((generation, id.clone()));
+    id
+}
+
+fn bridge_key(desc: &BridgeDescriptor) -> String {
+    format!("{} {}", desc.protocol, desc.endpoint)
+}
+
+/// Updates the record of the bridge on the current network, leaving the saving to [SAVER].
+fn update(desc: &BridgeDescriptor, f: impl FnOnce(&mut BridgeRecord)) {
+    let network = network_id();
+    HISTORY
+        .lock()
+        .update(network, bridge_key(desc), unix_now(), f);
+    DIRTY.store(true, Ordering::SeqCst);
+    Lazy::force(&SAVER);
+    CHANGED.notify(1);
+}
+
+/// Drops the least recently used entries until at most `max` are left.
+fn evict<V>(map: &mut BTreeMap<String, V>, max: usize, last_used: impl Fn(&V) -> u64) {
+    while map.len() > max {
+        let oldest = map
+            .iter()
+            .min_by_key(|(_, v)| last_used(v))
+            .map(|(k, _)| k.clone());
+        match oldest {
+            Some(oldest) => map.remove(&oldest),
+            None => return,
+        };
+    }
+}
+
+/// Records a successful connect to the bridge from the current network, which took the given handshake time.
+pub fn record_connect_success(desc: &BridgeDescriptor, handshake: Duration) {
+    update(desc, |record| record.connect_succeeded(handshake))
+}
+
+/// Records a failed connect to the bridge from the current network.
+pub fn record_connect_failure(desc: &BridgeDescriptor) {
+    update(desc, |record| record.connect_failed())
+}
+
+/// Records how much a pipe to the bridge received over how long it was connected.
+pub fn record_traffic(desc: &BridgeDescriptor, recv_bytes: u64, connected: Duration) {
+    update(desc, |record| {
+        record.recv_bytes += recv_bytes;
+        record.connected_secs += connected.as_secs_f64();
+    })
+}
+
+/// What the history says about a bridge from the current network, for sorting: 0 if it connected reliably lately, 1 if there is no recent history, and 2 if it mostly failed. Also gives its usual handshake time, if known.
+pub fn bridge_standing(desc: &BridgeDescriptor) -> (u8, Option<Duration>) {
+    let network = network_id();
+    HISTORY
+        .lock()
+        .standing(&network, &bridge_key(desc), unix_now())
+}
+
+/// A summary of the history of one bridge from the current network, for debugging.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BridgeStanding {
+    pub bridge: String,
+    pub success_rate: f64,
+    pub rtt_ms: Option<f64>,
+    /// Average download rate while connected, in bytes per second.
+    pub throughput: Option<f64>,
+    pub last_used_unix: u64,
+}
+
+/// The history of every bridge used from the current network, most recently used first.
+pub fn bridge_history() -> Vec<BridgeStanding> {
+    let network = network_id();
+    let history = HISTORY.lock();
+    let mut standings: Vec<BridgeStanding> = history
+        .networks
+        .get(&network)
+        .into_iter()
+        .flatten()
+        .map(|(bridge, record)| BridgeStanding {
+            bridge: bridge.clone(),
+            success_rate: record.success_rate(),
+            rtt_ms: record.rtt_ms,
+            throughput: (record.connected_secs > 0.0)
+                .then(|| record.recv_bytes as f64 / record.connected_secs),
+            last_used_unix: record.last_used,
+        })
+        .collect();
+    standings.sort_by_key(|s| std::cmp::Reverse(s.last_used_unix));
+    standings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_outcomes_outweigh_old_ones() {
+        let mut record = BridgeRecord::default();
+        for _ in 0..10 {
+            record.connect_succeeded(Duration::from_millis(100));
+        }
+        assert_eq!(record.rank(), 0);
+        for _ in 0..4 {
+            record.connect_failed();
+        }
+        assert_eq!(record.rank(), 2);
+        record.connect_succeeded(Duration::from_millis(300));
+        let rtt = record.rtt_ms.unwrap();
+        assert!(rtt > 100.0 && rtt < 300.0);
+    }
+
+    #[test]
+    fn standing_expires() {
+        let mut history = BridgeHistory::default();
+        history.update("net".into(), "b".into(), 1000, |r| {
+            r.connect_succeeded(Duration::from_millis(50))
+        });
+        let (rank, rtt) = history.standing("net", "b", 1000);
+        assert_eq!(rank, 0);
+        assert!(rtt.is_some());
+        assert_eq!(history.standing("other", "b", 1000), (1, None));
+        assert_eq!(
+            history.standing("net", "b", 1001 + MAX_AGE.as_secs()),
+            (1, None)
+        );
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted() {
+        let mut history = BridgeHistory::default();
+        for i in 0..=MAX_BRIDGES as u64 {
+            history.update("net".into(), format!("b{}", i), i, |r| r.connect_failed());
+        }
+        let bridges = &history.networks["net"];
+        assert_eq!(bridges.len(), MAX_BRIDGES);
+        assert!(!bridges.contains_key("b0"));
+        for i in 0..=MAX_NETWORKS as u64 {
+            history.update(format!("n{}", i), "b".into(), 1000 + i, |r| {
+                r.connect_failed()
+            });
+        }
+        assert_eq!(history.networks.len(), MAX_NETWORKS);
+        assert!(!history.networks.contains_key("net"));
+        assert!(!history.networks.contains_key("n0"));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol_str::SmolStr;
+use smol_timeout::TimeoutExt;
+
+use super::{bridge_backoff::BRIDGE_BACKOFF, bridge_history::bridge_standing};
+
+/// Round-trip estimates for bridges, from their earlier handshakes, or from TCP connect probes when an exit is previewed.
+pub static BRIDGE_RTT: Lazy<Mutex<HashMap<(SocketAddr, SmolStr), Duration>>> =
+    Lazy::new(Default::default);
+
+/// How long probing an exit's bridges may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Records a round-trip estimate for a bridge.
+pub fn record_rtt(desc: &BridgeDescriptor, rtt: Duration) {
+    BRIDGE_RTT
+        .lock()
+        .insert((desc.endpoint, desc.protocol.clone()), rtt);
+}
+
+fn known_rtt(desc: &BridgeDescriptor) -> Option<Duration> {
+    BRIDGE_RTT
+        .lock()
+        .get(&(desc.endpoint, desc.protocol.clone()))
+        .copied()
+}
+
+/// Measures the TCP connect time to TCP-based bridges. Other bridges keep whatever estimate they already have.
+async fn probe(desc: &BridgeDescriptor) {
+    if !desc.protocol.contains("tls") && !desc.protocol.contains("wss") {
+        return;
+    }
+    let start = Instant::now();
+    if let Some(Ok(_)) = smol::net::TcpStream::connect(desc.endpoint)
+        .timeout(PROBE_TIMEOUT)
+        .await
+    {
+        record_rtt(desc, start.elapsed());
+    }
+}
+
+/// The round-trip estimate of the fastest of the given bridges, probing those that have none yet. Only an exit preview, which the user asks for, probes: dialing goes by earlier handshakes alone, since probing every bridge before each dial would make for a burst of connections that is easy to tell apart.
+pub async fn best_rtt(bridges: &[&BridgeDescriptor]) -> Option<Duration> {
+    let estimate = |b: &BridgeDescriptor| known_rtt(b).or_else(|| bridge_standing(b).1);
+    let mut probes: FuturesUnordered<_> = bridges
+        .iter()
+        .filter(|b| estimate(b).is_none())
+        .map(|b| probe(b))
+        .collect();
+    async { while probes.next().await.is_some() {} }
+        .timeout(PROBE_TIMEOUT)
+        .await;
+    drop(probes);
+    bridges.iter().filter_map(|b| estimate(b)).min()
+}
+
+/// Sorts the bridges so that bridges not in backoff come first, then those that connected reliably from this network lately, then those never tried from it, fastest first within each group. Bridges without an estimate go after the measured ones, in their original order. Nothing is probed.
+pub fn sort_by_rtt(bridges: &mut [&BridgeDescriptor]) {
+    let now = Instant::now();
+    bridges.sort_by_cached_key(|b| {
+        let backed_off = BRIDGE_BACKOFF.ready_at(b).map(|t| t > now).unwrap_or(false);
+        // bridges that worked well from this network before go first, and their usual handshake time stands in for the latest one
+        let (standing, usual_rtt) = bridge_standing(b);
+        let rtt = known_rtt(b).or(usual_rtt);
+        (backed_off, standing, rtt.is_none(), rtt)
+    });
+    if let Some(fastest) = bridges.first() {
+        log::debug!(
+            "{} bridges ({}) sorted, fastest {} at {:?}",
+            bridges.len(),
+            fastest.protocol,
+            fastest.endpoint,
+            known_rtt(fastest)
+        );
+    }
+}
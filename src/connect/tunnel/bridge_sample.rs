@@ -0,0 +1,39 @@
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use itertools::Itertools;
+
+/// Restricts a session to a stable sample of at most `k` bridges per protocol, so that a client only ever dials a small, fixed part of the bridge set, and needs to ask the binder for fresh bridges less often.
+///
+/// The binder has no way to sample on its side, so the sample is taken from the full list: every bridge is ranked by a hash of the client's salt and the bridge's IP address, and the lowest-ranked ones are kept. The same client therefore dials the same bridges across reconnects and bridge list refreshes, while different clients dial different bridges. What this hides is limited to the client's traffic: anyone who can log in, a censor included, still gets the whole list from the binder.
+#[derive(Clone, Debug)]
+pub struct BridgeSampler {
+    k: usize,
+    salt: [u8; 32],
+}
+
+impl BridgeSampler {
+    /// Creates a sampler keeping `k` bridges per protocol, ranked with the given client-stable salt.
+    pub fn new(k: usize, salt: [u8; 32]) -> Self {
+        Self { k, salt }
+    }
+
+    /// Samples the given bridges. Direct bridges are the exits themselves, whose addresses aren't secret, so they are all kept.
+    pub fn sample(&self, bridges: Vec<BridgeDescriptor>) -> Vec<BridgeDescriptor> {
+        let (direct, hidden): (Vec<_>, Vec<_>) = bridges.into_iter().partition(|b| b.is_direct);
+        let sampled = hidden
+            .into_iter()
+            .into_group_map_by(|b| b.protocol.clone())
+            .into_values()
+            .flat_map(|group| {
+                group
+                    .into_iter()
+                    .sorted_by_key(|b| self.rank(b))
+                    .take(self.k)
+            });
+        direct.into_iter().chain(sampled).collect()
+    }
+
+    fn rank(&self, bridge: &BridgeDescriptor) -> [u8; 32] {
+        // only the IP, so that a bridge changing ports stays in the sample
+        *blake3::keyed_hash(&self.salt, bridge.endpoint.ip().to_string().as_bytes()).as_bytes()
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::connect::usage_log::exit_changing;
+
+/// The exit picked at runtime through the control API, overriding --exit-server.
+static EXIT_OVERRIDE: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// The exit the current session goes to, if it was picked through the binder.
+static CURRENT_EXIT: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+static RECONNECT_EVENT: Event = Event::new();
+
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Tears down the current session, so that the tunnel connects afresh.
+pub fn request_reconnect() {
+    RECONNECT_EVENT.notify(usize::MAX);
+}
+
+/// Tears down the current session for good, without connecting again. There is no starting back up within the same process.
+pub fn stop_tunnel() {
+    STOPPED.store(true, Ordering::SeqCst);
+    request_reconnect();
+}
+
+/// Whether [stop_tunnel] was called.
+pub(super) fn stopped() -> bool {
+    STOPPED.load(Ordering::SeqCst)
+}
+
+/// Switches to the given exit (or a similar one, as for --exit-server) by reconnecting.
+pub fn change_exit(exit: String) {
+    *EXIT_OVERRIDE.lock() = Some(exit);
+    request_reconnect();
+}
+
+/// The exit asked for through [change_exit], if any.
+pub fn exit_override() -> Option<String> {
+    EXIT_OVERRIDE.lock().clone()
+}
+
+/// The exit the current session goes to, if connected through the binder.
+pub fn current_exit() -> Option<String> {
+    CURRENT_EXIT.lock().clone()
+}
+
+pub(super) fn set_current_exit(exit: Option<String>) {
+    let mut current = CURRENT_EXIT.lock();
+    if *current != exit {
+        exit_changing(current.clone());
+    }
+    *current = exit;
+}
+
+/// Waits until the tunnel is stopped for good.
+pub(super) async fn wait_stopped() {
+    loop {
+        let listener = RECONNECT_EVENT.listen();
+        if stopped() {
+            return;
+        }
+        listener.await;
+    }
+}
+
+/// Waits until a reconnect is requested.
+pub(super) async fn wait_reconnect() -> anyhow::Result<()> {
+    RECONNECT_EVENT.listen().await;
+    anyhow::bail!("reconnect requested")
+}
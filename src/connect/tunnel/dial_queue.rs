@@ -0,0 +1,87 @@
+use std::{collections::HashSet, future::Future, net::SocketAddr};
+
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use parking_lot::Mutex;
+use smol::lock::Semaphore;
+use smol_str::SmolStr;
+
+/// Bounds how many bridge dials run at once within a session, and makes sure the same bridge is never dialed twice simultaneously.
+pub struct DialQueue {
+    semaphore: Semaphore,
+    in_flight: Mutex<HashSet<(SocketAddr, SmolStr)>>,
+}
+
+impl DialQueue {
+    /// Creates a new queue allowing at most `max_concurrent` simultaneous dials.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrent),
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Runs the given dial to a bridge once a slot is free. Returns None, without running it, if that bridge is already being dialed.
+    pub async fn dial<T>(
+        &self,
+        desc: &BridgeDescriptor,
+        dial: impl Future<Output = T>,
+    ) -> Option<T> {
+        let key = (desc.endpoint, desc.protocol.clone());
+        if !self.in_flight.lock().insert(key.clone()) {
+            log::debug!("not dialing {} / {} twice", desc.protocol, desc.endpoint);
+            return None;
+        }
+        scopeguard::defer!({
+            self.in_flight.lock().remove(&key);
+        });
+        let _permit = self.semaphore.acquire().await;
+        Some(dial.await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn bridge() -> BridgeDescriptor {
+        BridgeDescriptor {
+            is_direct: false,
+            protocol: "sosistab2-obfsudp".into(),
+            endpoint: "192.0.2.1:1234".parse().unwrap(),
+            sosistab_key: Default::default(),
+            exit_hostname: "exit".into(),
+            alloc_group: "free".into(),
+            update_time: 0,
+            exit_signature: Default::default(),
+        }
+    }
+
+    #[test]
+    fn duplicate_dials_do_not_run() {
+        smol::block_on(async {
+            let queue = DialQueue::new(4);
+            let bridge = bridge();
+            let (release, wait) = smol::channel::bounded::<()>(1);
+            let first = queue.dial(&bridge, async move {
+                let _ = wait.recv().await;
+                true
+            });
+            let second = async {
+                let ran = AtomicBool::new(false);
+                let result = queue
+                    .dial(&bridge, async { ran.store(true, Ordering::Relaxed) })
+                    .await;
+                drop(release);
+                (result, ran.load(Ordering::Relaxed))
+            };
+            let (first, (second, ran)) = smol::future::zip(first, second).await;
+            assert_eq!(first, Some(true));
+            assert_eq!(second, None);
+            assert!(!ran);
+            // once the first dial is done, the bridge can be dialed again
+            assert_eq!(queue.dial(&bridge, async { 1 }).await, Some(1));
+        })
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::net::TcpStream;
+use smol_timeout::TimeoutExt;
+
+use crate::connect::{http_wire::post_dns_message, CONNECT_CONFIG};
+
+/// DoH resolvers reached by IP address, so that finding them takes no DNS of its own. Their certificates are checked against the hostname.
+const BOOTSTRAP_DOH: &[(&str, &str)] = &[
+    ("1.1.1.1:443", "cloudflare-dns.com"),
+    ("8.8.8.8:443", "dns.google"),
+    ("9.9.9.9:443", "dns.quad9.net"),
+];
+
+const DOH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long system resolver answers are cached, since they come without a TTL.
+const SYSTEM_TTL: Duration = Duration::from_secs(300);
+
+/// DoH answers are cached for their TTL, but within these bounds.
+const MIN_TTL: Duration = Duration::from_secs(60);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// The resolver that dialing uses.
+static DIAL_RESOLVER: Lazy<DialResolver> =
+    Lazy::new(|| DialResolver::new(Box::new(SystemLookup), Box::new(DohLookup)));
+
+/// The addresses of the bootstrap DoH resolvers, which dialing reaches directly.
+pub fn bootstrap_resolvers() -> impl Iterator<Item = IpAddr> {
+    BOOTSTRAP_DOH
+        .iter()
+        .map(|(addr, _)| addr.parse::<SocketAddr>().unwrap().ip())
+}
+
+/// Resolves an endpoint's host:port to dial it. In VPN mode or with the kill switch the system resolver is never used, since its answers may come from a poisoned local network, or its queries may leak outside the tunnel or be blocked by the kill switch; only DoH to a fixed set of resolvers is.
+pub async fn resolve_for_dial(host_port: &str) -> anyhow::Result<SocketAddr> {
+    let strict = CONNECT_CONFIG.vpn_mode.is_some() || CONNECT_CONFIG.kill_switch;
+    DIAL_RESOLVER.resolve(host_port, strict).await
+}
+
+/// A way to look up a hostname, returning its addresses and how long they may be cached.
+#[async_trait]
+trait Lookup: Send + Sync {
+    async fn lookup(&self, host: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)>;
+}
+
+struct SystemLookup;
+
+#[async_trait]
+impl Lookup for SystemLookup {
+    async fn lookup(&self, host: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)> {
+        let addrs = smol::net::resolve((host, 0)).await?;
+        Ok((addrs.into_iter().map(|a| a.ip()).collect(), SYSTEM_TTL))
+    }
+}
+
+struct DohLookup;
+
+#[async_trait]
+impl Lookup for DohLookup {
+    async fn lookup(&self, host: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)> {
+        let mut last_err = anyhow::anyhow!("no bootstrap resolvers");
+        for (addr, name) in BOOTSTRAP_DOH {
+            for qtype in [TYPE_A, TYPE_AAAA] {
+                match doh_query(addr, name, host, qtype)
+                    .timeout(DOH_TIMEOUT)
+                    .await
+                    .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")))
+                {
+                    Ok(answers) if !answers.is_empty() => {
+                        let ttl = answers.iter().map(|(_, ttl)| *ttl).min().unwrap_or(0);
+                        let ttl = Duration::from_secs(ttl as u64).clamp(MIN_TTL, MAX_TTL);
+                        return Ok((answers.into_iter().map(|(ip, _)| ip).collect(), ttl));
+                    }
+                    Ok(_) => continue,
+                    Err(err) => {
+                        log::debug!("DoH lookup of {} through {} failed: {:?}", host, name, err);
+                        last_err = err;
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// Resolves hostnames for dialing, caching the answers.
+struct DialResolver {
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+    system: Box<dyn Lookup>,
+    doh: Box<dyn Lookup>,
+}
+
+impl DialResolver {
+    fn new(system: Box<dyn Lookup>, doh: Box<dyn Lookup>) -> Self {
+        Self {
+            cache: Default::default(),
+            system,
+            doh,
+        }
+    }
+
+    /// Resolves host:port, through DoH only if `strict`. Literal addresses are returned as they are.
+    async fn resolve(&self, host_port: &str, strict: bool) -> anyhow::Result<SocketAddr> {
+        if let Ok(addr) = host_port.parse() {
+            return Ok(addr);
+        }
+        let (host, port) = host_port
+            .rsplit_once(':')
+            .context("endpoint not in form host:port")?;
+        let port: u16 = port.parse().context("invalid port")?;
+        let key = format!("{}/{}", host, strict);
+        let cached = self
+            .cache
+            .lock()
+            .get(&key)
+            .filter(|(_, expiry)| *expiry > Instant::now())
+            .map(|(ips, _)| ips.clone());
+        let ips = match cached {
+            Some(ips) => ips,
+            None => {
+                let (ips, ttl) = if strict {
+                    self.doh.lookup(host).await
+                } else {
+                    self.system.lookup(host).await
+                }
+                .with_context(|| format!("cannot resolve {}", host))?;
+                self.cache
+                    .lock()
+                    .insert(key, (ips.clone(), Instant::now() + ttl));
+                ips
+            }
+        };
+        let ip = ips.first().context("host resolved to no addresses")?;
+        Ok(SocketAddr::new(*ip, port))
+    }
+}
+
+/// Does one DoH query, directly rather than through the tunnel, which may not be up yet.
+async fn doh_query(
+    addr: &str,
+    name: &str,
+    host: &str,
+    qtype: u16,
+) -> anyhow::Result<Vec<(IpAddr, u32)>> {
+    let id = fastrand::u16(..);
+    let query = build_query(id, host, qtype)?;
+    let tcp = TcpStream::connect(addr).await?;
+    let mut conn = async_native_tls::TlsConnector::new()
+        .connect(name, tcp)
+        .await
+        .context("TLS handshake with bootstrap resolver failed")?;
+    let (_, body) = post_dns_message(&mut conn, name, "/dns-query", &query, false).await?;
+    parse_response(&body, id, qtype)
+}
+
+/// Builds a recursive DNS query for one name and record type.
+fn build_query(id: u16, host: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(host.len() + 18);
+    out.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    out.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid hostname {}", host)
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&qtype.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    Ok(out)
+}
+
+/// Extracts the addresses of the given type, with their TTLs, from a DNS response to the query with the given ID.
+fn parse_response(resp: &[u8], id: u16, qtype: u16) -> anyhow::Result<Vec<(IpAddr, u32)>> {
+    let u16_at = |i: usize| -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(
+            resp.get(i..i + 2).context("truncated")?.try_into()?,
+        ))
+    };
+    if u16_at(0)? != id {
+        anyhow::bail!("response ID does not match the query")
+    }
+    let rcode = u16_at(2)? & 0xf;
+    if rcode != 0 {
+        anyhow::bail!("resolver returned rcode {}", rcode)
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut i = 12;
+    for _ in 0..questions {
+        i = skip_name(resp, i)? + 4;
+    }
+    let mut out = vec![];
+    for _ in 0..answers {
+        i = skip_name(resp, i)?;
+        let rtype = u16_at(i)?;
+        let ttl = u32::from_be_bytes(resp.get(i + 4..i + 8).context("truncated")?.try_into()?);
+        let rdlen = u16_at(i + 8)? as usize;
+        let rdata = resp.get(i + 10..i + 10 + rdlen).context("truncated")?;
+        i += 10 + rdlen;
+        if rtype != qtype {
+            // most likely a CNAME on the way to the addresses
+            continue;
+        }
+        let ip = match rdata.len() {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(rdata)?)),
+            16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(rdata)?)),
+            _ => anyhow::bail!("malformed address record"),
+        };
+        out.push((ip, ttl));
+    }
+    Ok(out)
+}
+
+/// Returns the index just past the (possibly compressed) name starting at `i`.
+fn skip_name(resp: &[u8], mut i: usize) -> anyhow::Result<usize> {
+    loop {
+        let len = *resp.get(i).context("truncated")?;
+        if len == 0 {
+            return Ok(i + 1);
+        } else if len & 0xc0 == 0xc0 {
+            return Ok(i + 2);
+        }
+        i += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every lookup with the same address, counting the lookups.
+    struct FixedLookup(IpAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl Lookup for FixedLookup {
+        async fn lookup(&self, _host: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok((vec![self.0], MIN_TTL))
+        }
+    }
+
+    fn resolver(
+        poisoned: IpAddr,
+        real: IpAddr,
+    ) -> (DialResolver, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        let system_calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let resolver = DialResolver::new(
+            Box::new(FixedLookup(poisoned, system_calls.clone())),
+            Box::new(FixedLookup(real, Default::default())),
+        );
+        (resolver, system_calls)
+    }
+
+    #[test]
+    fn strict_mode_ignores_poisoned_local_dns() {
+        let poisoned: IpAddr = "10.10.34.35".parse().unwrap();
+        let real: IpAddr = "203.0.113.7".parse().unwrap();
+        let (resolver, system_calls) = resolver(poisoned, real);
+        smol::block_on(async {
+            for _ in 0..2 {
+                let addr = resolver
+                    .resolve("bridge.example.com:443", true)
+                    .await
+                    .unwrap();
+                assert_eq!(addr, SocketAddr::new(real, 443));
+            }
+            assert_eq!(system_calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+            // outside of strict mode, the system resolver is used as before, with its own cache entry
+            let addr = resolver
+                .resolve("bridge.example.com:443", false)
+                .await
+                .unwrap();
+            assert_eq!(addr, SocketAddr::new(poisoned, 443));
+            assert_eq!(system_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn literal_addresses_skip_resolution() {
+        let (resolver, system_calls) = resolver(
+            "10.10.34.35".parse().unwrap(),
+            "203.0.113.7".parse().unwrap(),
+        );
+        let addr = smol::block_on(resolver.resolve("198.51.100.1:8443", false)).unwrap();
+        assert_eq!(addr, "198.51.100.1:8443".parse().unwrap());
+        assert_eq!(system_calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    /// A response to `query` with a CNAME and then an A record, both using name compression.
+    fn response(query: &[u8], ip: [u8; 4]) -> Vec<u8> {
+        let mut resp = query.to_vec();
+        resp[2] = 0x81;
+        resp[3] = 0x80;
+        resp[7] = 2;
+        // CNAME pointing at the question name
+        resp.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 30, 0, 2, 0xc0, 12]);
+        resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4]);
+        resp.extend_from_slice(&ip);
+        resp
+    }
+
+    #[test]
+    fn parses_answers() {
+        let query = build_query(0x1234, "bridge.example.com", TYPE_A).unwrap();
+        let resp = response(&query, [203, 0, 113, 7]);
+        let answers = parse_response(&resp, 0x1234, TYPE_A).unwrap();
+        assert_eq!(answers, vec![("203.0.113.7".parse().unwrap(), 3600)]);
+    }
+
+    #[test]
+    fn rejects_spoofed_and_truncated_responses() {
+        let query = build_query(0x1234, "bridge.example.com", TYPE_A).unwrap();
+        let resp = response(&query, [10, 10, 34, 35]);
+        assert!(parse_response(&resp, 0x4321, TYPE_A).is_err());
+        assert!(parse_response(&resp[..resp.len() - 2], 0x1234, TYPE_A).is_err());
+    }
+}
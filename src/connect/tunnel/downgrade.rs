@@ -0,0 +1,112 @@
+use std::{
+    num::NonZeroU32,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use geph4_protocol::binder::{client::CachedBinderClient, protocol::Level};
+use governor::{
+    clock::DefaultClock,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use once_cell::sync::Lazy;
+use smol::prelude::*;
+
+use crate::{
+    config::CacheRefreshGuard,
+    connect::{notify::notify, CONNECT_CONFIG},
+    l10n::tr,
+};
+
+use super::{TunnelCtx, TunnelStatus};
+
+/// Whether the plan expired mid-session, so that the free-tier cap applies.
+static DOWNGRADED: AtomicBool = AtomicBool::new(false);
+
+/// The free-tier cap in KiB/s, from --free-cap-kib, and its limiter. Without it, the free exit the session moves to enforces its own limit.
+static FREE_CAP: Lazy<
+    Option<(
+        NonZeroU32,
+        RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
+    )>,
+> = Lazy::new(|| {
+    CONNECT_CONFIG.free_cap_kib.map(|cap| {
+        (
+            cap,
+            RateLimiter::direct(Quota::per_second(cap).allow_burst(cap)),
+        )
+    })
+});
+
+/// Whether the session was downgraded to the free plan.
+pub fn is_downgraded() -> bool {
+    DOWNGRADED.load(Ordering::Relaxed)
+}
+
+/// Waits until the given number of bytes may be transferred under the free-tier cap. Returns right away unless the session was downgraded and there is a cap.
+pub async fn throttle(bytes: usize) {
+    if !is_downgraded() {
+        return;
+    }
+    if let Some((cap, limiter)) = FREE_CAP.as_ref() {
+        let kib = ((bytes + 1023) / 1024).clamp(1, cap.get() as usize) as u32;
+        let _ = limiter.until_n_ready(NonZeroU32::new(kib).unwrap()).await;
+    }
+}
+
+/// Copies from one stream to the other like `copy_with_stats`, but keeping to the free-tier cap when the plan expired mid-session.
+pub async fn copy_capped(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    mut on_bytes: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let mut buf = [0u8; 16384];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        throttle(n).await;
+        to.write_all(&buf[..n]).await?;
+        on_bytes(n);
+    }
+}
+
+/// Called with the level of the token a session authenticated with. A Plus token means the plan was renewed, lifting the cap.
+pub fn note_level(level: Level) {
+    if level == Level::Plus && DOWNGRADED.swap(false, Ordering::Relaxed) {
+        log::info!("plan renewed, lifting the free-tier cap");
+    }
+}
+
+/// Handles an exit refusing our token, which is what happens when a Plus plan expires while the token is still cached. If a freshly fetched token turns out to be a free one, switches to free mode, so that the next session picks a free exit with the new token instead of failing authentication over and over. Returns whether that happened.
+pub async fn handle_rejected_token(
+    ctx: &TunnelCtx,
+    ccache: &CachedBinderClient,
+) -> anyhow::Result<bool> {
+    let (user_info, token) = ccache.get_auth_token().await?;
+    if token.level != Level::Plus {
+        return Ok(false);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let expired = user_info
+        .subscription
+        .map(|sub| sub.expires_unix <= now)
+        .unwrap_or(true);
+    if !expired {
+        return Ok(false);
+    }
+    let fresh = {
+        let _guard = CacheRefreshGuard::new();
+        ccache.get_auth_token().await?.1
+    };
+    if fresh.level != Level::Free {
+        return Ok(false);
+    }
+    log::warn!("plan expired mid-session, continuing on the free plan");
+    DOWNGRADED.store(true, Ordering::Relaxed);
+    (ctx.status_callback)(TunnelStatus::PlanDowngraded);
+    notify(&tr("notify-downgraded"), &tr("notify-downgraded-body"));
+    Ok(true)
+}
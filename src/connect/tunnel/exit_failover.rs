@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{
+    connect::notify::notify,
+    l10n::{tr, tr_args},
+};
+
+use super::{control::stopped, roaming::network_generation};
+
+/// An exit whose sessions fail this many times in a row is considered dead.
+const MAX_FAILURES: u32 = 3;
+
+/// How long a dead exit is left out of exit selection.
+const AVOID_DURATION: Duration = Duration::from_secs(600);
+
+/// A session that stayed up this long shows that the exit was alive, so its eventual failure counts as a fresh first failure.
+const STABLE_SESSION: Duration = Duration::from_secs(120);
+
+#[derive(Default)]
+struct ExitHealth {
+    failures: u32,
+    avoid_until: Option<Instant>,
+}
+
+static EXIT_HEALTH: Lazy<Mutex<HashMap<String, ExitHealth>>> = Lazy::new(Default::default);
+
+/// The exit the latest session went to.
+static LAST_EXIT: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// Whether the given exit recently failed too often, so that exit selection should pick another one if it can.
+pub fn is_avoided(hostname: &str) -> bool {
+    EXIT_HEALTH
+        .lock()
+        .get(hostname)
+        .and_then(|h| h.avoid_until)
+        .map(|until| until > Instant::now())
+        .unwrap_or(false)
+}
+
+/// One session to an exit, whose end counts against that exit only when [ExitSession::failed] is called. Sessions ended by a reconnect request, by stopping the tunnel or by a network change say nothing about the exit, and are not counted.
+pub struct ExitSession {
+    hostname: Option<String>,
+    connected_at: Option<Instant>,
+    network: u64,
+}
+
+impl ExitSession {
+    /// Starts tracking a session to the given exit, if the session goes to one.
+    pub fn new(hostname: Option<&str>) -> Self {
+        Self {
+            hostname: hostname.map(|h| h.to_string()),
+            connected_at: None,
+            network: network_generation(),
+        }
+    }
+
+    /// Marks the session as authenticated and carrying traffic.
+    pub fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// Counts the end of this session as a failure of its exit, unless the tunnel was stopped or the device moved to another network meanwhile.
+    pub fn failed(&self) {
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname,
+            None => return,
+        };
+        if stopped() || network_generation() != self.network {
+            return;
+        }
+        let mut map = EXIT_HEALTH.lock();
+        let health = map.entry(hostname.clone()).or_default();
+        let stable = self
+            .connected_at
+            .map(|t| t.elapsed() > STABLE_SESSION)
+            .unwrap_or(false);
+        health.failures = if stable { 1 } else { health.failures + 1 };
+        if health.failures >= MAX_FAILURES {
+            log::warn!(
+                "exit {} failed {} times in a row, avoiding it unless pinned",
+                hostname,
+                health.failures
+            );
+            health.failures = 0;
+            health.avoid_until = Some(Instant::now() + AVOID_DURATION);
+        }
+    }
+}
+
+/// Notes the exit a new session goes to, notifying the user when it replaces one that was given up on.
+pub(super) fn note_selected(hostname: &str) {
+    let previous = LAST_EXIT.lock().replace(hostname.to_string());
+    if let Some(previous) = previous {
+        if previous != hostname && is_avoided(&previous) {
+            log::warn!("failing over from exit {} to {}", previous, hostname);
+            notify(
+                &tr("notify-failover"),
+                &tr_args(
+                    "notify-failover-body",
+                    &[("from", &previous), ("to", hostname)],
+                ),
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::future::join_all;
+use geph4_protocol::binder::{client::CachedBinderClient, protocol::ExitDescriptor};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ExitSelect;
+
+use super::{bridge_probe::best_rtt, exit_failover::is_avoided};
+
+/// Exits reporting a load above this are considered overloaded.
+pub const OVERLOADED_THRESHOLD: f64 = 0.8;
+
+/// How many of the most similar exits the weighted strategy picks among.
+const WEIGHTED_TOP_K: usize = 3;
+
+/// How many of the most similar exits the lowest-latency and lowest-load strategies consider.
+const CANDIDATES: usize = 5;
+
+/// How much lower a previewed exit's latency must be for switching to it to be recommended.
+const PREVIEW_MIN_IMPROVEMENT: f64 = 0.8;
+
+/// The exit the sticky strategy picked, kept for every later reconnect.
+static STICKY_EXIT: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// Penalty, in units of hostname edit distance, applied to an exit with the given load. Exits below [OVERLOADED_THRESHOLD] are not penalized at all, while a fully loaded exit is penalized as if its name differed by two characters, so that a less busy sibling (e.g. `us-hio-02` rather than `us-hio-01`) wins.
+pub fn load_penalty(load: f64) -> f64 {
+    if load > OVERLOADED_THRESHOLD {
+        (load.min(1.0) - OVERLOADED_THRESHOLD) * 10.0
+    } else {
+        0.0
+    }
+}
+
+/// Selects an exit similar to the requested one using the given strategy, penalizing overloaded exits unless `ignore_load` is set. An exact hostname match is always honored, even if that exit has been failing, since the user pinned it.
+pub async fn select_exit(
+    ccache: &CachedBinderClient,
+    destination_exit: &str,
+    ignore_load: bool,
+    strategy: ExitSelect,
+) -> anyhow::Result<ExitDescriptor> {
+    let token = ccache.get_auth_token().await?.1;
+    let summary = ccache.get_summary().await?;
+    let mut exits = summary.exits;
+    exits.retain(|e| e.allowed_levels.contains(&token.level));
+    if let Some(exact) = exits.iter().find(|e| e.hostname == destination_exit) {
+        return Ok(exact.clone());
+    }
+    // exits that keep failing are skipped, as long as there is anything else to pick
+    if exits.iter().any(|e| !is_avoided(&e.hostname)) {
+        exits.retain(|e| !is_avoided(&e.hostname));
+    }
+    if strategy == ExitSelect::Sticky {
+        let sticky = STICKY_EXIT.lock().clone();
+        if let Some(sticky) = sticky.and_then(|h| exits.iter().find(|e| e.hostname == h)) {
+            return Ok(sticky.clone());
+        }
+    }
+    // shuffle exits so that ties are broken randomly
+    exits.shuffle(&mut rand::thread_rng());
+    let score = |exit: &ExitDescriptor| {
+        let distance = strsim::damerau_levenshtein(&exit.hostname, destination_exit) as f64;
+        if ignore_load {
+            distance
+        } else {
+            distance + load_penalty(exit.load)
+        }
+    };
+    exits.sort_by(|a, b| score(a).total_cmp(&score(b)));
+    let candidates = &exits[..CANDIDATES.min(exits.len())];
+    let selected = match strategy {
+        ExitSelect::Closest => exits.get(0).cloned(),
+        ExitSelect::Sticky => {
+            let selected = exits.get(0).cloned();
+            *STICKY_EXIT.lock() = selected.as_ref().map(|e| e.hostname.to_string());
+            selected
+        }
+        ExitSelect::LowestLoad => candidates
+            .iter()
+            .min_by(|a, b| a.load.total_cmp(&b.load))
+            .cloned(),
+        ExitSelect::LowestLatency => {
+            let latencies =
+                join_all(candidates.iter().map(|exit| exit_latency(ccache, exit))).await;
+            candidates
+                .iter()
+                .zip(latencies)
+                .inspect(|(exit, latency)| {
+                    log::debug!("exit {} latency {:?}", exit.hostname, latency)
+                })
+                // exits that could not be probed go last, in order of similarity
+                .sorted_by_key(|(_, latency)| (latency.is_none(), *latency))
+                .map(|(exit, _)| exit.clone())
+                .next()
+        }
+        ExitSelect::Weighted => exits
+            .get(..WEIGHTED_TOP_K.min(exits.len()))
+            .and_then(|top| {
+                top.choose_weighted(&mut rand::thread_rng(), |exit| {
+                    if ignore_load {
+                        1.0
+                    } else {
+                        (1.0 - exit.load).max(0.05)
+                    }
+                })
+                .ok()
+            })
+            .cloned(),
+    }
+    .context("no exits found at all lol")?;
+    if selected.load > OVERLOADED_THRESHOLD {
+        log::warn!(
+            "selected exit {} is overloaded (load {:.2})",
+            selected.hostname,
+            selected.load
+        );
+    }
+    Ok(selected)
+}
+
+/// How one exit measured up in a [preview_exit].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExitMeasurement {
+    pub hostname: String,
+    /// The round-trip time to the fastest of the exit's bridges, or None if none of them could be measured.
+    pub latency_ms: Option<f64>,
+    /// The load the binder reports, from 0 to 1. Measuring throughput would mean switching, so this stands in for how much spare throughput the exit has.
+    pub load: f64,
+}
+
+/// A comparison of another exit against the current one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExitPreview {
+    /// None when not connected to any exit.
+    pub current: Option<ExitMeasurement>,
+    pub candidate: ExitMeasurement,
+    /// Whether the candidate is clearly faster and not overloaded, so that switching is worth it.
+    pub recommended: bool,
+}
+
+/// Measures the given exit alongside the current one, without touching the tunnel.
+pub async fn preview_exit(
+    ccache: &CachedBinderClient,
+    current: Option<&str>,
+    candidate: &str,
+) -> anyhow::Result<ExitPreview> {
+    let exits = ccache.get_summary().await?.exits;
+    let find = |hostname: &str| {
+        exits
+            .iter()
+            .find(|e| e.hostname == hostname)
+            .cloned()
+            .with_context(|| format!("no exit named {}", hostname))
+    };
+    let candidate = find(candidate)?;
+    let current = current.map(find).transpose()?;
+    let (candidate, current) =
+        futures_util::future::join(measure_exit(ccache, &candidate), async {
+            match &current {
+                Some(current) => Some(measure_exit(ccache, current).await),
+                None => None,
+            }
+        })
+        .await;
+    let recommended = candidate.load <= OVERLOADED_THRESHOLD
+        && match (candidate.latency_ms, current.as_ref().map(|c| c.latency_ms)) {
+            (None, _) => false,
+            (Some(_), None | Some(None)) => true,
+            (Some(new), Some(Some(old))) => new < old * PREVIEW_MIN_IMPROVEMENT,
+        };
+    Ok(ExitPreview {
+        current,
+        candidate,
+        recommended,
+    })
+}
+
+async fn measure_exit(ccache: &CachedBinderClient, exit: &ExitDescriptor) -> ExitMeasurement {
+    ExitMeasurement {
+        hostname: exit.hostname.to_string(),
+        latency_ms: exit_latency(ccache, exit)
+            .await
+            .map(|latency| latency.as_secs_f64() * 1000.0),
+        load: exit.load,
+    }
+}
+
+/// Estimates the latency to the exit by way of its bridges, the way traffic would actually take, or None if none of them can be measured. Nothing is sent to the exit itself, which would show whoever watches the network every exit the client considers: the bridges come from the binder, and are measured from their earlier handshakes, or else probed with a TCP connect.
+async fn exit_latency(ccache: &CachedBinderClient, exit: &ExitDescriptor) -> Option<Duration> {
+    let bridges = match ccache.get_bridges_v2(&exit.hostname, false).await {
+        Ok(bridges) => bridges,
+        Err(err) => {
+            log::debug!("cannot get bridges of {}: {:?}", exit.hostname, err);
+            return None;
+        }
+    };
+    // direct "bridges" are the exit itself
+    let bridges = bridges.iter().filter(|b| !b.is_direct).collect_vec();
+    best_rtt(&bridges).await
+}
@@ -5,19 +5,55 @@ use itertools::Itertools;
 use native_tls::TlsConnector;
 use rand::Rng;
 use regex::Regex;
+use smol::channel::{Receiver, Sender};
 use smol_str::SmolStr;
 use smol_timeout::TimeoutExt;
 use sosistab2::{Multiplex, MuxPublic, MuxSecret, ObfsTlsPipe, ObfsUdpPipe, ObfsUdpPublic, Pipe};
 
-use crate::connect::tunnel::{autoconnect::AutoconnectPipe, TunnelStatus};
+use crate::{
+    connect::{
+        audit::audit,
+        power::{power_profile, retry_jitter, stretch},
+        tunnel::{
+            autoconnect::AutoconnectPipe,
+            bridge_backoff::BRIDGE_BACKOFF,
+            bridge_cover::cover_bridge,
+            bridge_history::{record_connect_failure, record_connect_success},
+            bridge_probe::{record_rtt, sort_by_rtt},
+            control::exit_override,
+            dial_queue::DialQueue,
+            dial_resolve::resolve_for_dial,
+            exit_failover::note_selected,
+            exit_select::select_exit,
+            held_pipes::HeldPipes,
+            pipe_health::HealthBoard,
+            pipe_info::PipeRecord,
+            quic::{QuicKey, QuicPipe},
+            repair_stats,
+            warm_spare::{spare_loop, take_spare, Target},
+            wss::{WssKey, WssPipe},
+            TunnelStatus,
+        },
+    },
+    crypto::CRYPTO_REPORT,
+};
 
 use super::{BinderTunnelParams, EndpointSource, TunnelCtx};
 use anyhow::Context;
-use std::{collections::BTreeSet, net::SocketAddr, sync::Weak};
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Weak,
+    },
+    time::Instant,
+};
 
 use std::{convert::TryFrom, sync::Arc, time::Duration};
 
-pub fn parse_independent_endpoint(endpoint: &str) -> anyhow::Result<(SocketAddr, [u8; 32])> {
+/// Parses an independent obfsudp endpoint of the form PK@host:port, where host may be a domain name.
+pub async fn parse_independent_endpoint(endpoint: &str) -> anyhow::Result<(SocketAddr, [u8; 32])> {
     // parse endpoint addr
     let pk_and_url = endpoint.split('@').collect::<Vec<_>>();
     let server_pk = <[u8; 32]>::try_from(
@@ -26,167 +62,489 @@ pub fn parse_independent_endpoint(endpoint: &str) -> anyhow::Result<(SocketAddr,
     )
     .ok()
     .context("cannot parse server pk")?;
-    let server_addr: SocketAddr = pk_and_url
-        .get(1)
-        .context("URL not in form PK@host:port")?
-        .parse()
-        .context("cannot parse host:port")?;
+    let host_port = pk_and_url.get(1).context("URL not in form PK@host:port")?;
+    let server_addr = resolve_for_dial(host_port).await?;
     Ok((server_addr, server_pk))
 }
 
-pub(crate) async fn get_session(ctx: TunnelCtx) -> anyhow::Result<Arc<sosistab2::Multiplex>> {
+/// Parses an independent endpoint of the form protocol:KEY@host:port, where KEY is the hex of the key material as the binder would list it for a bridge of that protocol, into a descriptor that is dialed like any bridge.
+async fn parse_independent_bridge(endpoint: &str) -> anyhow::Result<BridgeDescriptor> {
+    let (protocol_key, host_port) = endpoint
+        .split_once('@')
+        .context("URL not in form protocol:KEY@host:port")?;
+    let (protocol, key) = protocol_key
+        .split_once(':')
+        .context("URL not in form protocol:KEY@host:port")?;
+    Ok(BridgeDescriptor {
+        is_direct: true,
+        protocol: protocol.into(),
+        endpoint: resolve_for_dial(host_port).await?,
+        sosistab_key: hex::decode(key).context("KEY is not hex")?.into(),
+        exit_hostname: "".into(),
+        alloc_group: "".into(),
+        update_time: 0,
+        exit_signature: Default::default(),
+    })
+}
+
+/// Connects a session, returning it together with the exit it goes to, if it was picked through the binder.
+pub(crate) async fn get_session(
+    ctx: TunnelCtx,
+) -> anyhow::Result<(Arc<sosistab2::Multiplex>, Option<String>)> {
     match &ctx.endpoint {
-        EndpointSource::Independent { endpoint } => {
-            let (addr, raw_key) = parse_independent_endpoint(endpoint)?;
-            let obfs_pk = ObfsUdpPublic::from_bytes(raw_key);
+        EndpointSource::Independent { endpoint, exit_key } => {
+            // pinning the exit's end-to-end key keeps a private bridge in between from standing in for it
+            let exit_key = exit_key
+                .as_deref()
+                .map(|key| {
+                    let key =
+                        <[u8; 32]>::try_from(hex::decode(key).context("exit key is not hex")?)
+                            .ok()
+                            .context("exit key must be 32 bytes")?;
+                    anyhow::Ok(MuxPublic::from_bytes(key))
+                })
+                .transpose()?;
             let sessid = rand::thread_rng().gen::<u128>().to_string();
-            let mplex = Multiplex::new(MuxSecret::generate(), None);
+            let mplex = Multiplex::new(MuxSecret::generate(), exit_key);
+            let has_protocol = endpoint
+                .split_once('@')
+                .map(|(key, _)| key.contains(':'))
+                .unwrap_or(false);
+            if has_protocol {
+                let bridge = parse_independent_bridge(endpoint).await?;
+                log::info!(
+                    "connecting directly to independent endpoint at {} ({})",
+                    bridge.endpoint,
+                    bridge.protocol
+                );
+                for _ in 0..4 {
+                    mplex.add_pipe(
+                        dial_pipe(bridge.clone(), &sessid)
+                            .timeout(power_profile().connect_timeout())
+                            .await
+                            .context("timed out connecting to independent endpoint")??,
+                    );
+                }
+                return Ok((Arc::new(mplex), None));
+            }
+            let (addr, raw_key) = parse_independent_endpoint(endpoint).await?;
+            log::info!("connecting directly to independent exit at {}", addr);
+            let obfs_pk = ObfsUdpPublic::from_bytes(raw_key);
             for _ in 0..4 {
-                let pipe = ObfsUdpPipe::connect(addr, obfs_pk, &sessid).await?;
+                let pipe = ObfsUdpPipe::connect(addr, obfs_pk, &sessid)
+                    .timeout(power_profile().connect_timeout())
+                    .await
+                    .context("timed out connecting to independent exit")??;
                 let sessid = sessid.clone();
                 let pipe = AutoconnectPipe::new(pipe, move || {
                     let sessid = sessid.clone();
                     smolscale::spawn(async move {
                         loop {
                             if let Some(Ok(pipe)) = ObfsUdpPipe::connect(addr, obfs_pk, &sessid)
-                                .timeout(Duration::from_secs(10))
+                                .timeout(power_profile().connect_timeout())
                                 .await
                             {
                                 return pipe;
                             }
-                            smol::Timer::after(Duration::from_secs(1)).await;
+                            smol::Timer::after(retry_jitter()).await;
                         }
                     })
                 });
                 mplex.add_pipe(pipe);
             }
-            Ok(Arc::new(mplex))
+            Ok((Arc::new(mplex), None))
         }
         EndpointSource::Binder(binder_tunnel_params) => {
-            let selected_exit = binder_tunnel_params
-                .ccache
-                .get_closest_exit(&binder_tunnel_params.exit_server.clone().unwrap_or_default())
-                .await
-                .context("cannot get closest exit")?;
-            log::info!("using exit {}", selected_exit.hostname);
-            let bridges = binder_tunnel_params
-                .ccache
-                .get_bridges_v2(&selected_exit.hostname, false)
-                .await
-                .context("cannot get bridges")?;
-            if bridges.is_empty() {
-                anyhow::bail!("no sosistab2 routes to {}", selected_exit.hostname)
-            }
-            log::debug!("{} routes", bridges.len());
-            // The bridge descriptor is laid out in a rather weird format: the "sosistab_key" field is a bincode-encode tuple of the first-level cookie, and the end-to-end MuxPublic key.
-            // we assume we have at least one obfsudp key
-            let e2e_key: MuxPublic = {
-                let mut seen = None;
-                for bridge in bridges.iter() {
-                    if bridge.protocol == "sosistab2-obfsudp" {
-                        if let Ok(val) =
-                            bincode::deserialize::<(ObfsUdpPublic, MuxPublic)>(&bridge.sosistab_key)
-                        {
-                            seen = Some(val.1)
-                        }
-                    }
+            let requested = exit_override()
+                .or_else(|| binder_tunnel_params.exit_server.clone())
+                .unwrap_or_default();
+            let target = match take_spare(&requested) {
+                Some(target) => {
+                    log::info!("promoting the warm spare for {}", target.exit.hostname);
+                    target
                 }
-                seen.context("cannot deduce the sosistab2 MuxPublic of this exit")?
+                None => prepare_target(binder_tunnel_params, &requested).await?,
             };
-            let multiplex = Arc::new(sosistab2::Multiplex::new(
-                MuxSecret::generate(),
-                Some(e2e_key),
-            ));
+            log::debug!("{} routes", target.bridges.len());
+            note_selected(&target.exit.hostname);
+            let Target {
+                exit: selected_exit,
+                bridges,
+                multiplex,
+                sess_id,
+                health,
+                on_demote,
+                demoted,
+                predialed,
+            } = target;
+            if binder_tunnel_params.warm_spare {
+                multiplex.add_drop_friend(smolscale::spawn(spare_loop(
+                    ctx.clone(),
+                    binder_tunnel_params.clone(),
+                    requested,
+                    selected_exit.clone(),
+                )));
+            }
+            let mut bridges = sample_bridges(binder_tunnel_params, bridges);
+            // a promoted spare already has a pipe to this one
+            bridges.retain(|b| Some(b) != predialed.as_ref());
             // add *all* the bridges!
-            let sess_id = format!("sess-{}", rand::thread_rng().gen::<u128>());
+            health.make_current();
+            let pipes = SessionPipes {
+                sess_id,
+                dial_queue: Arc::new(DialQueue::new(MAX_CONCURRENT_DIALS)),
+                health,
+                on_demote,
+                held: Default::default(),
+            };
+            multiplex.add_drop_friend(smolscale::spawn(pipes.health.clone().scoring_loop()));
+            // weak here to prevent a reference cycle!
+            let weak_multiplex = Arc::downgrade(&multiplex);
             {
                 let ctx = ctx.clone();
-                let multiplex = multiplex.clone();
-                let sess_id = sess_id.clone();
-                smolscale::spawn(async move {
-                    add_bridges(&ctx, &sess_id, &multiplex, &bridges).await;
-                })
-                .detach();
+                let weak_multiplex = weak_multiplex.clone();
+                let pipes = pipes.clone();
+                multiplex.add_drop_friend(smolscale::spawn(async move {
+                    if let Some(multiplex) = weak_multiplex.upgrade() {
+                        add_bridges(&ctx, &pipes, &multiplex, &bridges, pipes_per_protocol).await;
+                    }
+                }));
             }
 
-            // weak here to prevent a reference cycle!
-            let weak_multiplex = Arc::downgrade(&multiplex);
+            multiplex.add_drop_friend(smolscale::spawn(replace_demoted(
+                ctx.clone(),
+                binder_tunnel_params.clone(),
+                selected_exit.clone(),
+                pipes.clone(),
+                demoted,
+                weak_multiplex.clone(),
+            )));
+            let hostname = selected_exit.hostname.to_string();
             multiplex.add_drop_friend(smolscale::spawn(replace_dead(
                 ctx.clone(),
                 binder_tunnel_params.clone(),
                 selected_exit,
-                sess_id,
+                pipes,
                 weak_multiplex,
             )));
 
-            Ok(multiplex)
+            Ok((multiplex, Some(hostname)))
         }
     }
 }
 
-async fn add_bridges(
-    ctx: &TunnelCtx,
-    sess_id: &str,
-    mplex: &Multiplex,
-    bridges: &[BridgeDescriptor],
-) {
-    // we pick only the 3 best out of every protocol
+/// Picks the exit and fetches its bridges through the binder.
+async fn prepare_target(params: &BinderTunnelParams, requested: &str) -> anyhow::Result<Target> {
+    let selected_exit = select_exit(
+        &params.ccache,
+        requested,
+        params.ignore_load,
+        params.exit_select,
+    )
+    .await
+    .context("cannot get closest exit")?;
+    log::info!("using exit {}", selected_exit.hostname);
+    audit("tunnel", "select_exit", &selected_exit.hostname);
+    let bridges = params
+        .ccache
+        .get_bridges_v2(&selected_exit.hostname, false)
+        .await
+        .context("cannot get bridges")?;
+    Target::new(selected_exit, bridges)
+}
+
+/// Applies --bridge-sample, if given.
+fn sample_bridges(
+    params: &BinderTunnelParams,
+    bridges: Vec<BridgeDescriptor>,
+) -> Vec<BridgeDescriptor> {
+    match &params.bridge_sampler {
+        Some(sampler) => sampler.sample(bridges),
+        None => bridges,
+    }
+}
+
+/// How many bridges a warm spare tries before giving up on dialing ahead.
+const PREDIAL_TRIES: usize = 3;
+
+/// Dials one pipe into a spare target's multiplex, fastest bridge first, so that the spare can carry traffic the moment it is promoted. Returns the bridge the pipe goes to.
+pub(super) async fn predial(ctx: &TunnelCtx, target: &Target) -> anyhow::Result<BridgeDescriptor> {
+    let bridges = match &ctx.endpoint {
+        EndpointSource::Binder(params) => sample_bridges(params, target.bridges.clone()),
+        EndpointSource::Independent { .. } => target.bridges.clone(),
+    };
+    let mut bridges = bridges.iter().filter(|b| allowed(ctx, b)).collect_vec();
+    sort_by_rtt(&mut bridges);
+    for bridge in bridges.into_iter().take(PREDIAL_TRIES) {
+        match dial_pipe(bridge.clone(), &target.sess_id).await {
+            Ok(pipe) => {
+                target.multiplex.add_pipe(target.health.track(
+                    pipe,
+                    bridge.clone(),
+                    target.on_demote.clone(),
+                ));
+                return Ok(bridge.clone());
+            }
+            Err(err) => log::debug!(
+                "warm spare could not dial {} ({}): {:?}",
+                bridge.endpoint,
+                bridge.protocol,
+                err
+            ),
+        }
+    }
+    anyhow::bail!("none of the spare's bridges could be dialed")
+}
+
+/// Whether the bridge may be dialed at all, given --use-bridges and --force-protocol.
+fn allowed(ctx: &TunnelCtx, bridge: &BridgeDescriptor) -> bool {
+    if let EndpointSource::Binder(params) = &ctx.endpoint {
+        if params.use_bridges && bridge.is_direct {
+            return false;
+        }
+        if let Some(regex) = &params.force_protocol {
+            let compiled = Regex::new(regex).expect("invalid protocol force");
+            if !compiled.is_match(&bridge.protocol) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Maximum number of bridges being dialed at once within a session.
+const MAX_CONCURRENT_DIALS: usize = 8;
+
+/// Per-session state shared by everything that adds pipes to the multiplex.
+#[derive(Clone)]
+struct SessionPipes {
+    sess_id: String,
+    dial_queue: Arc<DialQueue>,
+    health: Arc<HealthBoard>,
+    /// Demoted pipes report their bridge here, so that a replacement gets dialed.
+    on_demote: Sender<BridgeDescriptor>,
+    held: Arc<HeldPipes>,
+}
+
+/// Until the first pipe connects, dials of a protocol start one at a time, this long apart, so that the most promising bridge gets a head start rather than competing with every other handshake for the same bandwidth.
+const DIAL_STAGGER: Duration = Duration::from_millis(250);
+
+/// Dials up to `keep(protocol)` pipes of every protocol among the given bridges, returning how many ended up in the multiplex. With --transport-priority, only the listed protocols are dialed, one at a time in order of preference, stopping at the first that connects.
+///
+/// Dialing is a race in the manner of happy eyeballs: each protocol starts with its best bridge, and the next only joins a stagger later, unless the ones before have failed. The first pipe to connect goes into the multiplex at once, unblocking the tunnel, and the remaining pipes are then all dialed in the background. Once a protocol has all its pipes, one more is dialed and held back, to replace the first of them that gets demoted.
+async fn add_bridges<'a>(
+    ctx: &'a TunnelCtx,
+    pipes: &'a SessionPipes,
+    mplex: &'a Multiplex,
+    bridges: &'a [BridgeDescriptor],
+    keep: impl Fn(&str) -> usize,
+) -> usize {
+    // we pick only the few best out of every protocol
     let protocols: BTreeSet<SmolStr> = bridges.iter().map(|b| b.protocol.clone()).collect();
-    let mut outer = FuturesUnordered::new();
-    for protocol in protocols {
-        let bridges = bridges
+    let race_start = Instant::now();
+    let first_up = AtomicBool::new(false);
+    let first_up = &first_up;
+    // returns how many pipes of the protocol ended up in the multiplex
+    let add_protocol = |protocol: &str, keep: usize| {
+        let mut bridges = bridges
             .iter()
             .filter(|s| s.protocol == protocol)
             .collect_vec();
-        outer.push(async {
-            let uo = FuturesUnordered::new();
-            for bridge in bridges {
-                if let EndpointSource::Binder(params) = &ctx.endpoint {
-                    if params.use_bridges && bridge.is_direct {
-                        continue;
+        // untried bridges first, then those that have been failing the least recently
+        BRIDGE_BACKOFF.sort_by_readiness(&mut bridges);
+        let protocol = SmolStr::from(protocol);
+        async move {
+            bridges.retain(|bridge| allowed(ctx, bridge));
+            sort_by_rtt(&mut bridges);
+            // returns whether the bridge ended up in the multiplex
+            let dial = |bridge: &'a BridgeDescriptor| async move {
+                for _ in 0..10 {
+                    BRIDGE_BACKOFF.wait_ready(bridge).await;
+                    let start = std::time::Instant::now();
+                    let result = pipes
+                        .dial_queue
+                        .dial(
+                            bridge,
+                            connect_once(ctx.clone(), bridge.clone(), &pipes.sess_id),
+                        )
+                        .await;
+                    match result {
+                        // somebody else is already dialing this bridge, so it is not ours to count
+                        None => return false,
+                        Some(Ok(pipe)) => {
+                            log::debug!("add pipe {} / {}", pipe.protocol(), pipe.peer_addr());
+                            BRIDGE_BACKOFF.record_success(bridge);
+                            record_connect_success(bridge, start.elapsed());
+                            mplex.add_pipe(pipes.health.track(
+                                pipe,
+                                bridge.clone(),
+                                pipes.on_demote.clone(),
+                            ));
+                            return true;
+                        }
+                        Some(Err(err)) => {
+                            log::warn!(
+                                "pipe creation failed for {} ({}): {:?}",
+                                bridge.endpoint,
+                                bridge.protocol,
+                                err
+                            );
+                            BRIDGE_BACKOFF.record_failure(bridge);
+                            record_connect_failure(bridge);
+                        }
                     }
-                    if let Some(regex) = &params.force_protocol {
-                        let compiled = Regex::new(regex).expect("invalid protocol force");
-                        if !compiled.is_match(&bridge.protocol) {
-                            continue;
+                }
+                false
+            };
+            // dial only the fastest few, falling back to the rest as those fail
+            let mut remaining = bridges.into_iter().peekable();
+            let mut dialing = FuturesUnordered::new();
+            let mut added = 0;
+            let mut next_start = Instant::now();
+            while added < keep {
+                while added + dialing.len() < keep
+                    && (dialing.is_empty()
+                        || first_up.load(Ordering::Relaxed)
+                        || Instant::now() >= next_start)
+                {
+                    match remaining.next() {
+                        Some(bridge) => {
+                            dialing.push(dial(bridge));
+                            next_start = Instant::now() + DIAL_STAGGER;
                         }
+                        None => break,
                     }
                 }
-                uo.push(async {
-                    for _ in 0..10 {
-                        match connect_once(ctx.clone(), bridge.clone(), sess_id).await {
-                            Ok(pipe) => {
-                                log::debug!("add pipe {} / {}", pipe.protocol(), pipe.peer_addr());
-                                mplex.add_pipe(pipe);
-                                return;
-                            }
-                            Err(err) => {
-                                log::warn!(
-                                    "pipe creation failed for {} ({}): {:?}",
-                                    bridge.endpoint,
-                                    bridge.protocol,
-                                    err
-                                );
-                                smol::Timer::after(Duration::from_secs(1)).await;
-                            }
+                let staggered = !first_up.load(Ordering::Relaxed)
+                    && added + dialing.len() < keep
+                    && remaining.peek().is_some();
+                let stagger = async {
+                    if staggered {
+                        smol::Timer::at(next_start).await;
+                    } else {
+                        smol::future::pending::<()>().await;
+                    }
+                    None
+                };
+                // either a dial finished, or it is time to start another
+                match smol::future::or(async { Some(dialing.next().await) }, stagger).await {
+                    Some(Some(true)) => {
+                        added += 1;
+                        if !first_up.swap(true, Ordering::Relaxed) {
+                            log::info!(
+                                "first pipe ({}) up after {:?}, dialing the rest in the background",
+                                protocol,
+                                race_start.elapsed()
+                            );
                         }
                     }
-                })
+                    Some(Some(false)) => {}
+                    Some(None) => break,
+                    None => {}
+                }
+            }
+            drop(dialing);
+            if added > 0 {
+                hold_spare(pipes, remaining).await;
+            }
+            added
+        }
+    };
+    let priority = match &ctx.endpoint {
+        EndpointSource::Binder(params) => params.transport_priority.as_slice(),
+        EndpointSource::Independent { .. } => &[],
+    };
+    if priority.is_empty() {
+        let mut outer: FuturesUnordered<_> = protocols
+            .iter()
+            .map(|protocol| add_protocol(protocol, keep(protocol)))
+            .collect();
+        let mut added = 0;
+        while let Some(n) = outer.next().await {
+            added += n;
+        }
+        added
+    } else {
+        for pref in priority {
+            if !protocols.contains(pref.protocol.as_str()) {
+                continue;
+            }
+            let keep = pref
+                .limit
+                .map(|limit| limit.min(keep(&pref.protocol)))
+                .unwrap_or_else(|| keep(&pref.protocol));
+            let added = add_protocol(&pref.protocol, keep).await;
+            if added > 0 {
+                return added;
+            }
+            log::warn!(
+                "no {} pipes could connect, falling back to the next protocol",
+                pref.protocol
+            );
+        }
+        0
+    }
+}
+
+/// Dials the first of the given bridges that connects, all of one protocol, and holds its pipe back as the protocol's spare, unless one is held already.
+async fn hold_spare<'a>(
+    pipes: &SessionPipes,
+    bridges: impl IntoIterator<Item = &'a BridgeDescriptor>,
+) {
+    for bridge in bridges {
+        if !pipes.held.wants(&bridge.protocol) {
+            return;
+        }
+        BRIDGE_BACKOFF.wait_ready(bridge).await;
+        let start = std::time::Instant::now();
+        match pipes
+            .dial_queue
+            .dial(bridge, dial_pipe(bridge.clone(), &pipes.sess_id))
+            .await
+        {
+            None => {}
+            Some(Ok(pipe)) => {
+                log::debug!(
+                    "holding spare pipe {} / {}",
+                    bridge.protocol,
+                    bridge.endpoint
+                );
+                BRIDGE_BACKOFF.record_success(bridge);
+                record_connect_success(bridge, start.elapsed());
+                pipes.held.hold(bridge.clone(), pipe);
+                return;
             }
-            let mut stream = uo.take(3);
-            while stream.next().await.is_some() {}
-        });
+            Some(Err(err)) => {
+                log::debug!(
+                    "spare pipe to {} ({}) failed: {:?}",
+                    bridge.endpoint,
+                    bridge.protocol,
+                    err
+                );
+                BRIDGE_BACKOFF.record_failure(bridge);
+                record_connect_failure(bridge);
+            }
+        }
     }
-    while outer.next().await.is_some() {}
+}
+
+/// How many pipes of the given protocol to keep, which depends on the crypto backend picked for this machine.
+fn pipes_per_protocol(protocol: &str) -> usize {
+    CRYPTO_REPORT.backend.pipes_for(protocol)
 }
 
 async fn connect_udp(desc: BridgeDescriptor, meta: String) -> anyhow::Result<ObfsUdpPipe> {
     let keys: (ObfsUdpPublic, MuxPublic) =
         bincode::deserialize(&desc.sosistab_key).context("cannot decode keys")?;
-    ObfsUdpPipe::connect(desc.endpoint, keys.0, &meta)
-        .timeout(Duration::from_secs(10))
+    cover_bridge(desc.endpoint).await;
+    let start = std::time::Instant::now();
+    let pipe = ObfsUdpPipe::connect(desc.endpoint, keys.0, &meta)
+        .timeout(power_profile().connect_timeout())
         .await
-        .context("pipe connection timeout")?
+        .context("pipe connection timeout")??;
+    // the handshake time stands in for a probe, which would be one more connection for an observer to see
+    record_rtt(&desc, start.elapsed());
+    Ok(pipe)
 }
 
 async fn connect_tls(desc: BridgeDescriptor, meta: String) -> anyhow::Result<ObfsTlsPipe> {
@@ -198,6 +556,8 @@ async fn connect_tls(desc: BridgeDescriptor, meta: String) -> anyhow::Result<Obf
         .max_protocol_version(None)
         .use_sni(false);
     let fake_domain = format!("{}.com", eff_wordlist::short::random_word());
+    cover_bridge(desc.endpoint).await;
+    let start = std::time::Instant::now();
     let connection = ObfsTlsPipe::connect(
         desc.endpoint,
         &fake_domain,
@@ -205,33 +565,102 @@ async fn connect_tls(desc: BridgeDescriptor, meta: String) -> anyhow::Result<Obf
         desc.sosistab_key.clone(),
         &meta,
     )
-    .timeout(Duration::from_secs(10))
+    .timeout(power_profile().connect_timeout())
     .await
     .context("pipe connection timeout")??;
+    record_rtt(&desc, start.elapsed());
     Ok(connection)
 }
 
+async fn connect_quic(desc: BridgeDescriptor, meta: String) -> anyhow::Result<QuicPipe> {
+    let key: QuicKey = bincode::deserialize(&desc.sosistab_key).context("cannot decode keys")?;
+    cover_bridge(desc.endpoint).await;
+    let fake_domain = format!("{}.com", eff_wordlist::short::random_word());
+    let start = std::time::Instant::now();
+    let pipe = QuicPipe::connect(desc.endpoint, &fake_domain, &key, &meta)
+        .timeout(power_profile().connect_timeout())
+        .await
+        .context("pipe connection timeout")??;
+    record_rtt(&desc, start.elapsed());
+    Ok(pipe)
+}
+
+async fn connect_wss(desc: BridgeDescriptor, meta: String) -> anyhow::Result<WssPipe> {
+    let key: WssKey = bincode::deserialize(&desc.sosistab_key).context("cannot decode keys")?;
+    cover_bridge(desc.endpoint).await;
+    let start = std::time::Instant::now();
+    let pipe = WssPipe::connect(desc.endpoint, &key, &meta)
+        .timeout(power_profile().connect_timeout())
+        .await
+        .context("pipe connection timeout")??;
+    record_rtt(&desc, start.elapsed());
+    Ok(pipe)
+}
+
+/// Connects a single, non-reconnecting pipe to the given bridge, without going through the tunnel machinery. Used for testing bridges.
+#[cfg(not(feature = "router"))]
+pub async fn connect_bridge_pipe(desc: &BridgeDescriptor) -> anyhow::Result<Box<dyn Pipe>> {
+    let meta = format!("bridgetest-{}", rand::thread_rng().gen::<u64>());
+    let pipe: Box<dyn Pipe> = match desc.protocol.as_str() {
+        "sosistab2-obfsudp" => Box::new(connect_udp(desc.clone(), meta).await?),
+        "sosistab2-obfstls" => Box::new(connect_tls(desc.clone(), meta).await?),
+        "sosistab2-wss" => Box::new(connect_wss(desc.clone(), meta).await?),
+        "sosistab2-quic" => Box::new(connect_quic(desc.clone(), meta).await?),
+        other => {
+            anyhow::bail!("unknown protocol {other}")
+        }
+    };
+    Ok(pipe)
+}
+
+/// Connects a single pipe to the given bridge and returns how long that took.
+#[cfg(not(feature = "router"))]
+pub async fn test_bridge(desc: &BridgeDescriptor) -> anyhow::Result<Duration> {
+    let start = std::time::Instant::now();
+    connect_bridge_pipe(desc).await?;
+    Ok(start.elapsed())
+}
+
 async fn autoconnect_with<P: Pipe, F: Future<Output = anyhow::Result<P>> + Send + 'static>(
     f: impl Fn() -> F + Send + Sync + 'static,
 ) -> anyhow::Result<AutoconnectPipe<P>> {
+    let start = std::time::Instant::now();
     let connection = f().await?;
     let protocol = connection.protocol().to_string();
     let endpoint = connection.peer_addr();
+    // lives as long as the pipe, since only the reconnect closure holds it
+    let record = Arc::new(PipeRecord::new(
+        &protocol,
+        endpoint.clone(),
+        start.elapsed(),
+    ));
     let f = Arc::new(f);
     Ok(AutoconnectPipe::new(connection, move || {
         let protocol = protocol.clone();
         let endpoint = endpoint.clone();
         let f = f.clone();
+        let record = record.clone();
         smolscale::spawn(async move {
+            let died = std::time::Instant::now();
+            repair_stats::record_dead(&protocol);
             for wait in 0u64.. {
+                let start = std::time::Instant::now();
+                repair_stats::record_attempt(&protocol);
                 match f().await {
-                    Ok(val) => return val,
-                    Err(err) => log::warn!(
-                        "problem reconnecting to {} / {}: {:?}",
-                        protocol,
-                        endpoint,
-                        err
-                    ),
+                    Ok(val) => {
+                        record.reconnected(start.elapsed());
+                        repair_stats::record_success(&protocol, died.elapsed());
+                        return val;
+                    }
+                    Err(err) => {
+                        repair_stats::record_failure(&protocol);
+                        log::warn!(
+                            "problem reconnecting to {} / {}: {:?}",
+                            protocol,
+                            endpoint,
+                            err
+                        )
+                    }
                 }
                 smol::Timer::after(Duration::from_secs_f64(1.5f64.powf(wait as f64))).await;
             }
@@ -250,7 +679,11 @@ async fn connect_once(
         addr: desc.endpoint,
         protocol: desc.protocol.clone(),
     });
-    let desc = desc.clone();
+    dial_pipe(desc, meta).await
+}
+
+/// Dials a reconnecting pipe to the given bridge, without reporting it as the tunnel's progress.
+async fn dial_pipe(desc: BridgeDescriptor, meta: &str) -> anyhow::Result<Box<dyn Pipe>> {
     let meta = meta.to_string();
     let inner: Box<dyn Pipe> = match desc.protocol.as_str() {
         "sosistab2-obfsudp" => {
@@ -261,6 +694,14 @@ async fn connect_once(
             let desc = desc.clone();
             Box::new(autoconnect_with(move || connect_tls(desc.clone(), meta.clone())).await?)
         }
+        "sosistab2-wss" => {
+            let desc = desc.clone();
+            Box::new(autoconnect_with(move || connect_wss(desc.clone(), meta.clone())).await?)
+        }
+        "sosistab2-quic" => {
+            let desc = desc.clone();
+            Box::new(autoconnect_with(move || connect_quic(desc.clone(), meta.clone())).await?)
+        }
         other => {
             anyhow::bail!("unknown protocol {other}")
         }
@@ -268,20 +709,87 @@ async fn connect_once(
     Ok(inner)
 }
 
+/// Replaces a pipe whenever it is demoted for poor health, with the spare held for its protocol if there is one, or else by dialing a fresh bridge of the same protocol.
+async fn replace_demoted(
+    ctx: TunnelCtx,
+    binder_tunnel_params: BinderTunnelParams,
+    selected_exit: ExitDescriptor,
+    pipes: SessionPipes,
+    demoted: Receiver<BridgeDescriptor>,
+    weak_multiplex: Weak<Multiplex>,
+) {
+    while let Ok(bridge) = demoted.recv().await {
+        let start = std::time::Instant::now();
+        repair_stats::record_dead(&bridge.protocol);
+        repair_stats::record_attempt(&bridge.protocol);
+        // so that the replacement is some other bridge, if there is one
+        BRIDGE_BACKOFF.record_failure(&bridge);
+        let fallible_part = async {
+            let bridges = binder_tunnel_params
+                .ccache
+                .get_bridges_v2(&selected_exit.hostname, false)
+                .await?;
+            let multiplex = weak_multiplex.upgrade().context("multiplex is dead")?;
+            let candidates = sample_bridges(&binder_tunnel_params, bridges)
+                .into_iter()
+                .filter(|br| br.protocol == bridge.protocol && br.endpoint != bridge.endpoint)
+                .collect_vec();
+            // the bridges to hold a fresh spare from, once a held one was promoted
+            match pipes.held.take(&bridge.protocol) {
+                Some((spare, pipe)) => {
+                    log::debug!(
+                        "promoting spare pipe to {} in place of {}",
+                        spare.endpoint,
+                        bridge.endpoint
+                    );
+                    multiplex.add_pipe(pipes.health.track(pipe, spare, pipes.on_demote.clone()));
+                    anyhow::Ok(Some(candidates))
+                }
+                None => {
+                    if add_bridges(&ctx, &pipes, &multiplex, &candidates, |_| 1).await == 0 {
+                        anyhow::bail!("no {} bridge could connect", bridge.protocol)
+                    }
+                    anyhow::Ok(None)
+                }
+            }
+        };
+        match fallible_part.await {
+            Ok(refill) => {
+                repair_stats::record_success(&bridge.protocol, start.elapsed());
+                if let Some(candidates) = refill {
+                    let mut candidates =
+                        candidates.iter().filter(|b| allowed(&ctx, b)).collect_vec();
+                    sort_by_rtt(&mut candidates);
+                    hold_spare(&pipes, candidates).await;
+                }
+            }
+            Err(err) => {
+                repair_stats::record_failure(&bridge.protocol);
+                log::warn!("error replacing demoted pipe: {:?}", err)
+            }
+        }
+    }
+}
+
 async fn replace_dead(
     ctx: TunnelCtx,
     binder_tunnel_params: BinderTunnelParams,
     selected_exit: ExitDescriptor,
-    sess_id: String,
+    pipes: SessionPipes,
     weak_multiplex: Weak<Multiplex>,
 ) {
     let ccache = binder_tunnel_params.ccache.clone();
     let mut previous_bridges: Option<Vec<BridgeDescriptor>> = None;
     loop {
-        smol::Timer::after(Duration::from_secs(300)).await;
+        smol::Timer::after(stretch(power_profile().rebalance_interval())).await;
         loop {
             let fallible_part = async {
-                let bridges = ccache.get_bridges_v2(&selected_exit.hostname, true).await?;
+                // a sampled session keeps using the same few bridges, so the cached list does
+                let force_refresh = binder_tunnel_params.bridge_sampler.is_none();
+                let bridges = ccache
+                    .get_bridges_v2(&selected_exit.hostname, force_refresh)
+                    .await?;
+                let bridges = sample_bridges(&binder_tunnel_params, bridges);
                 let multiplex = weak_multiplex.upgrade().context("multiplex is dead")?;
                 if let Some(previous_bridges) = previous_bridges.replace(bridges.clone()) {
                     let new_bridges = bridges
@@ -292,7 +800,7 @@ async fn replace_dead(
                                 .any(|pipe| pipe.endpoint == br.endpoint)
                         })
                         .collect_vec();
-                    add_bridges(&ctx, &sess_id, &multiplex, &new_bridges).await;
+                    add_bridges(&ctx, &pipes, &multiplex, &new_bridges, pipes_per_protocol).await;
                 }
                 anyhow::Ok(())
             };
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use parking_lot::Mutex;
+use smol_str::SmolStr;
+use sosistab2::Pipe;
+
+/// Dialed pipes held out of a session's multiplex, at most one per protocol, so that a demoted pipe can be replaced at once rather than after a fresh handshake.
+///
+/// Holding a pipe back is harmless: it belongs to the session on the exit's side, but the exit only ever answers on the pipe the client last sent on, so nothing arrives on a pipe that hasn't been promoted yet.
+#[derive(Default)]
+pub struct HeldPipes {
+    held: Mutex<HashMap<SmolStr, (BridgeDescriptor, Box<dyn Pipe>)>>,
+}
+
+impl HeldPipes {
+    /// Whether a pipe of this protocol is still wanted.
+    pub fn wants(&self, protocol: &str) -> bool {
+        !self.held.lock().contains_key(protocol)
+    }
+
+    /// Holds a freshly dialed pipe back. If one of its protocol is already held, the new one is dropped instead.
+    pub fn hold(&self, bridge: BridgeDescriptor, pipe: Box<dyn Pipe>) {
+        self.held
+            .lock()
+            .entry(bridge.protocol.clone())
+            .or_insert((bridge, pipe));
+    }
+
+    /// Takes the held pipe of this protocol, if there is one.
+    pub fn take(&self, protocol: &str) -> Option<(BridgeDescriptor, Box<dyn Pipe>)> {
+        self.held.lock().remove(protocol)
+    }
+}
@@ -1,4 +1,5 @@
 use bytes::Bytes;
+use event_listener::{Event, EventListener};
 use geph4_protocol::binder::client::CachedBinderClient;
 use parking_lot::RwLock;
 use smol::channel::{Receiver, Sender};
@@ -14,20 +15,48 @@ use std::{
     time::Duration,
 };
 use tunnel_actor::tunnel_actor;
+
+use crate::config::{ExitSelect, TransportPriority};
+
+use super::plan_expiry::PlanWarning;
 pub mod activity;
+mod bridge_backoff;
+pub(crate) mod bridge_history;
+pub(crate) mod bridge_cover;
+mod bridge_probe;
+pub mod bridge_sample;
+pub mod control;
+mod dial_queue;
+pub(crate) mod dial_resolve;
+pub mod downgrade;
+mod exit_failover;
+pub mod exit_select;
 pub mod getsess;
+mod held_pipes;
+pub mod selfcheck;
 
 mod autoconnect;
 mod delay;
+pub mod pipe_health;
+pub mod pipe_info;
+pub mod postmortem;
+mod quic;
+pub mod repair_stats;
+pub mod roaming;
 pub mod tunnel_actor;
+mod warm_spare;
+pub(crate) mod wss;
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
-use self::activity::notify_activity;
+use self::{activity::notify_activity, bridge_sample::BridgeSampler};
 
 #[derive(Clone)]
 pub enum EndpointSource {
-    Independent { endpoint: String },
+    Independent {
+        endpoint: String,
+        exit_key: Option<String>,
+    },
     Binder(BinderTunnelParams),
 }
 
@@ -36,8 +65,13 @@ pub struct BinderTunnelParams {
     pub ccache: Arc<CachedBinderClient>,
     pub exit_server: Option<String>,
     pub use_bridges: bool,
-    pub force_bridge: Option<Ipv4Addr>,
+    pub force_bridge: Option<IpAddr>,
     pub force_protocol: Option<String>,
+    pub transport_priority: Vec<TransportPriority>,
+    pub bridge_sampler: Option<BridgeSampler>,
+    pub ignore_load: bool,
+    pub exit_select: ExitSelect,
+    pub warm_spare: bool,
 }
 
 #[derive(Clone)]
@@ -59,12 +93,21 @@ pub(crate) struct TunnelCtx {
 pub enum TunnelStatus {
     /// Just about to connect to a given address, with the given protocol
     PreConnect { addr: SocketAddr, protocol: SmolStr },
+    /// The user's plan is about to expire, or just did. Only reported when the warning gets more urgent.
+    PlanExpiry {
+        warning: PlanWarning,
+        expires_unix: i64,
+    },
+    /// The plan expired mid-session, and the tunnel carries on in free mode, at the free plan's speed.
+    PlanDowngraded,
 }
 
 /// A ConnectionStatus shows the status of the tunnel.
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub enum ConnectionStatus {
     Connecting,
+    /// Solving a proof-of-work puzzle the binder asked for before it would let us log in.
+    Verifying,
     Connected { protocol: SmolStr, address: SmolStr },
 }
 
@@ -74,6 +117,18 @@ impl ConnectionStatus {
     }
 }
 
+/// Fires whenever the tunnel connects or drops.
+static STATUS_EVENT: Event = Event::new();
+
+/// Resolves once the tunnel connects or drops. Listen before checking the status, so that a change in between is not missed.
+pub fn listen_status() -> EventListener {
+    STATUS_EVENT.listen()
+}
+
+fn notify_status() {
+    STATUS_EVENT.notify(usize::MAX);
+}
+
 /// A tunnel starts and keeps alive the best sosistab session it can under given constraints.
 /// A sosistab Session is *a single end-to-end connection between a client and a server.*
 /// This can be thought of as analogous to TcpStream, except all reads and writes are datagram-based and unreliable.
@@ -131,7 +186,11 @@ impl ClientTunnel {
     /// Returns the current connection status.
     pub fn status(&self) -> ConnectionStatus {
         if self.client_ip_addr.load(Ordering::Relaxed) == 0 {
-            ConnectionStatus::Connecting
+            if crate::puzzle::is_solving() {
+                ConnectionStatus::Verifying
+            } else {
+                ConnectionStatus::Connecting
+            }
         } else {
             self.connect_status.read().clone()
         }
@@ -0,0 +1,420 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use geph4_protocol::binder::protocol::BridgeDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender};
+use sosistab2::Pipe;
+
+use super::bridge_history::record_traffic;
+
+/// How long a send can go without any reply before the pipe counts as stalled, and the longest wait for a reply that still counts as a latency sample.
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stalls older than this no longer count against a pipe.
+const STALL_WINDOW: Duration = Duration::from_secs(600);
+
+/// A pipe that stalled this many times within [STALL_WINDOW] is demoted.
+const MAX_STALLS: usize = 3;
+
+/// A pipe whose reply latency stays above this many times the session median, for [SLOW_CHECKS] checks in a row, is demoted.
+const SLOW_FACTOR: f64 = 3.0;
+const SLOW_CHECKS: u32 = 3;
+
+/// Reply latencies below this are never considered slow, however fast the other pipes are.
+const SLOW_FLOOR: Duration = Duration::from_millis(500);
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Health {
+    protocol: String,
+    endpoint: String,
+    /// When the oldest send that hasn't seen a reply yet went out.
+    outstanding: Option<Instant>,
+    /// Whether the current outstanding send was already counted as a stall.
+    stall_counted: bool,
+    stalls: VecDeque<Instant>,
+    /// Smoothed time from a send to the next reply, a rough stand-in for the pipe's RTT.
+    latency: Option<Duration>,
+    /// When any pipe of the session last received something, shared by the whole [HealthBoard].
+    session_recv: Arc<Mutex<Option<Instant>>>,
+    slow_checks: u32,
+    demoted: bool,
+    /// Closed on demotion, waking up the pipe's pending receive.
+    demote_signal: Sender<()>,
+}
+
+impl Health {
+    fn demote(&mut self) {
+        self.demoted = true;
+        self.demote_signal.close();
+    }
+
+    fn on_send(&mut self) {
+        if self.outstanding.is_none() {
+            self.outstanding = Some(Instant::now());
+            self.stall_counted = false;
+        }
+    }
+
+    fn on_recv(&mut self) {
+        *self.session_recv.lock() = Some(Instant::now());
+        if let Some(sent) = self.outstanding.take() {
+            // anything later is more likely something the exit had to say than a reply
+            let sample = sent.elapsed();
+            if sample <= STALL_TIMEOUT {
+                self.latency = Some(match self.latency {
+                    Some(old) => old.mul_f64(0.8) + sample.mul_f64(0.2),
+                    None => sample,
+                });
+            }
+        }
+    }
+
+    /// Whether a send has gone unanswered for too long right now. Not every send is answered, as in a one-way upload, so that only counts if the session still got replies on other pipes after it should have been answered.
+    fn stalled(&self) -> bool {
+        self.outstanding
+            .map(|sent| {
+                let deadline = sent + STALL_TIMEOUT;
+                Instant::now() > deadline
+                    && self
+                        .session_recv
+                        .lock()
+                        .map(|recv| recv > deadline)
+                        .unwrap_or(false)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Counts a stall if the outstanding send has gone unanswered for too long, and forgets old stalls.
+    fn update_stalls(&mut self) {
+        if !self.stall_counted && self.stalled() {
+            self.stall_counted = true;
+            self.stalls.push_back(Instant::now());
+        }
+        while self
+            .stalls
+            .front()
+            .map(|t| t.elapsed() > STALL_WINDOW)
+            .unwrap_or(false)
+        {
+            self.stalls.pop_front();
+        }
+    }
+}
+
+/// The health of one pipe of the current session, as seen by its [HealthBoard].
+#[derive(Clone, Debug)]
+pub struct PipeHealth {
+    pub protocol: String,
+    pub endpoint: String,
+    /// The smoothed reply latency, if the pipe got any reply yet.
+    pub latency: Option<Duration>,
+    /// Sends that went unanswered for too long within the last ten minutes, the closest thing we have to a loss count.
+    pub recent_stalls: usize,
+    pub demoted: bool,
+}
+
+/// The board of the newest session.
+static CURRENT_BOARD: Lazy<Mutex<Weak<HealthBoard>>> = Lazy::new(Default::default);
+
+/// Lists the health of every pipe of the current session.
+pub fn pipe_health() -> Vec<PipeHealth> {
+    let board = CURRENT_BOARD.lock().upgrade();
+    board
+        .map(|board| {
+            board
+                .pipes
+                .lock()
+                .iter()
+                .filter_map(|p| p.upgrade())
+                .map(|p| {
+                    let p = p.lock();
+                    PipeHealth {
+                        protocol: p.protocol.clone(),
+                        endpoint: p.endpoint.clone(),
+                        latency: p.latency,
+                        recent_stalls: p.stalls.len(),
+                        demoted: p.demoted,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Demotes the pipe of the current session with the given protocol and endpoint, so that the multiplex stops using it, but only if it is stalled right now, with a send unanswered for too long, and is not the last healthy one. Returns whether it was demoted.
+pub fn demote_stalled_pipe(protocol: &str, endpoint: &str) -> bool {
+    let board = match CURRENT_BOARD.lock().upgrade() {
+        Some(board) => board,
+        None => return false,
+    };
+    let pipes: Vec<Arc<Mutex<Health>>> = board
+        .pipes
+        .lock()
+        .iter()
+        .filter_map(|p| p.upgrade())
+        .collect();
+    if pipes.iter().filter(|p| !p.lock().demoted).count() < 2 {
+        return false;
+    }
+    for pipe in pipes {
+        let mut pipe = pipe.lock();
+        if !pipe.demoted && pipe.protocol == protocol && pipe.endpoint == endpoint {
+            if !pipe.stalled() {
+                return false;
+            }
+            pipe.demote();
+            return true;
+        }
+    }
+    false
+}
+
+/// Keeps track of the health of every pipe in a session, demoting chronically bad ones.
+#[derive(Default)]
+pub struct HealthBoard {
+    pipes: Mutex<Vec<Weak<Mutex<Health>>>>,
+    last_recv: Arc<Mutex<Option<Instant>>>,
+}
+
+impl HealthBoard {
+    /// Makes this the board of the current session, which [pipe_health] then reports on.
+    pub fn make_current(self: &Arc<Self>) {
+        *CURRENT_BOARD.lock() = Arc::downgrade(self);
+    }
+
+    /// Wraps a pipe so that its health is tracked. Once demoted, the pipe fails its receives so that the multiplex clears it out, and its bridge is sent on `on_demote` so that a replacement can be dialed.
+    pub fn track(
+        &self,
+        pipe: Box<dyn Pipe>,
+        bridge: BridgeDescriptor,
+        on_demote: Sender<BridgeDescriptor>,
+    ) -> HealthPipe {
+        let (demote_signal, demoted) = smol::channel::bounded(1);
+        let health = Arc::new(Mutex::new(Health {
+            protocol: bridge.protocol.to_string(),
+            endpoint: bridge.endpoint.to_string(),
+            outstanding: None,
+            stall_counted: false,
+            stalls: VecDeque::new(),
+            latency: None,
+            session_recv: self.last_recv.clone(),
+            slow_checks: 0,
+            demoted: false,
+            demote_signal,
+        }));
+        self.pipes.lock().push(Arc::downgrade(&health));
+        HealthPipe {
+            inner: pipe,
+            health,
+            demoted,
+            reported: AtomicBool::new(false),
+            bridge,
+            on_demote,
+            recv_bytes: AtomicU64::new(0),
+            created: Instant::now(),
+        }
+    }
+
+    /// Periodically scores every pipe, demoting those that stall too often or are much slower than the rest. At least one pipe is always left alone.
+    pub async fn scoring_loop(self: Arc<Self>) {
+        loop {
+            smol::Timer::after(CHECK_INTERVAL).await;
+            let pipes: Vec<Arc<Mutex<Health>>> = {
+                let mut pipes = self.pipes.lock();
+                pipes.retain(|p| p.strong_count() > 0);
+                pipes.iter().filter_map(|p| p.upgrade()).collect()
+            };
+            let mut latencies: Vec<Duration> = pipes
+                .iter()
+                .filter_map(|p| {
+                    let p = p.lock();
+                    if p.demoted {
+                        None
+                    } else {
+                        p.latency
+                    }
+                })
+                .collect();
+            latencies.sort_unstable();
+            let median = latencies.get(latencies.len() / 2).copied();
+            let mut healthy = pipes.iter().filter(|p| !p.lock().demoted).count();
+            for pipe in pipes.iter() {
+                let mut pipe = pipe.lock();
+                if pipe.demoted {
+                    continue;
+                }
+                pipe.update_stalls();
+                let slow = match (pipe.latency, median) {
+                    (Some(latency), Some(median)) => {
+                        latency > SLOW_FLOOR && latency > median.mul_f64(SLOW_FACTOR)
+                    }
+                    _ => false,
+                };
+                pipe.slow_checks = if slow { pipe.slow_checks + 1 } else { 0 };
+                if healthy > 1
+                    && (pipe.stalls.len() >= MAX_STALLS || pipe.slow_checks >= SLOW_CHECKS)
+                {
+                    log::debug!(
+                        "demoting pipe with {} stalls, latency {:?} (median {:?})",
+                        pipe.stalls.len(),
+                        pipe.latency,
+                        median
+                    );
+                    pipe.demote();
+                    healthy -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// A pipe whose health is tracked by a [HealthBoard].
+pub struct HealthPipe {
+    inner: Box<dyn Pipe>,
+    health: Arc<Mutex<Health>>,
+    demoted: Receiver<()>,
+    reported: AtomicBool,
+    bridge: BridgeDescriptor,
+    on_demote: Sender<BridgeDescriptor>,
+    recv_bytes: AtomicU64,
+    created: Instant,
+}
+
+impl Drop for HealthPipe {
+    fn drop(&mut self) {
+        record_traffic(
+            &self.bridge,
+            self.recv_bytes.load(Ordering::Relaxed),
+            self.created.elapsed(),
+        );
+    }
+}
+
+#[async_trait]
+impl Pipe for HealthPipe {
+    async fn send(&self, to_send: Bytes) {
+        {
+            let mut health = self.health.lock();
+            if health.demoted {
+                return;
+            }
+            health.on_send();
+        }
+        self.inner.send(to_send).await
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let demoted = async {
+            // never returns Ok, since nothing is ever sent on it
+            let _ = self.demoted.recv().await;
+            if !self.reported.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "pipe {} / {} demoted for poor health",
+                    self.bridge.protocol,
+                    self.bridge.endpoint
+                );
+                let _ = self.on_demote.try_send(self.bridge.clone());
+            }
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "pipe demoted for poor health",
+            ))
+        };
+        let received = async {
+            let msg = self.inner.recv().await?;
+            self.health.lock().on_recv();
+            self.recv_bytes
+                .fetch_add(msg.len() as u64, Ordering::Relaxed);
+            Ok(msg)
+        };
+        smol::future::race(demoted, received).await
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.inner.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(session_recv: &Arc<Mutex<Option<Instant>>>) -> Health {
+        Health {
+            protocol: "sosistab2-obfsudp".into(),
+            endpoint: "127.0.0.1:1".into(),
+            outstanding: None,
+            stall_counted: false,
+            stalls: VecDeque::new(),
+            latency: None,
+            session_recv: session_recv.clone(),
+            slow_checks: 0,
+            demoted: false,
+            demote_signal: smol::channel::bounded(1).0,
+        }
+    }
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn one_way_upload_is_no_stall() {
+        let session_recv = Arc::new(Mutex::new(Some(ago(20))));
+        let mut pipe = health(&session_recv);
+        pipe.on_send();
+        pipe.outstanding = Some(ago(10));
+        pipe.update_stalls();
+        assert!(!pipe.stalled());
+        assert!(pipe.stalls.is_empty());
+    }
+
+    #[test]
+    fn unanswered_while_others_answer_is_a_stall() {
+        let session_recv = Arc::new(Mutex::new(None));
+        let mut pipe = health(&session_recv);
+        let mut other = health(&session_recv);
+        pipe.on_send();
+        pipe.outstanding = Some(ago(10));
+        other.on_recv();
+        pipe.update_stalls();
+        pipe.update_stalls();
+        assert!(pipe.stalled());
+        assert_eq!(pipe.stalls.len(), 1);
+        // the reply ends the stall, but is too late to be a latency sample
+        pipe.on_recv();
+        assert!(!pipe.stalled());
+        assert_eq!(pipe.latency, None);
+    }
+
+    #[test]
+    fn replies_are_latency_samples() {
+        let session_recv = Arc::new(Mutex::new(None));
+        let mut pipe = health(&session_recv);
+        pipe.on_send();
+        pipe.outstanding = Some(ago(1));
+        pipe.on_recv();
+        let latency = pipe.latency.unwrap();
+        assert!(latency >= Duration::from_secs(1) && latency < STALL_TIMEOUT);
+        assert!(session_recv.lock().is_some());
+    }
+}
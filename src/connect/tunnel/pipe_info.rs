@@ -0,0 +1,258 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{AsyncRead, AsyncWrite};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// How a connected pipe was negotiated, for bridge operators debugging client compatibility.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipeInfo {
+    /// The sosistab2 protocol, like "sosistab2-obfstls".
+    pub protocol: String,
+    pub endpoint: String,
+    /// What carries the pipe: "udp", "tcp" or "quic".
+    pub transport: String,
+    /// The TLS version: "none" for obfsudp; "1.3" for QUIC, which only speaks TLS 1.3; for WebSocket, whatever the bridge's ServerHello picked; and for obfstls, whatever the platform's TLS library tops out at, since sosistab2 does that handshake out of our sight.
+    pub tls: String,
+    /// Whether the real server name is sent in the clear. obfstls sends none, and QUIC a random fake one.
+    pub sni: bool,
+    /// How long the latest handshake took.
+    pub handshake_ms: f64,
+    /// When the latest handshake finished.
+    pub connected_unix: u64,
+    /// How many times the pipe had to reconnect.
+    pub reconnects: u64,
+}
+
+static PIPES: Lazy<Mutex<BTreeMap<u64, PipeInfo>>> = Lazy::new(Default::default);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The TLS version last negotiated with each endpoint, for the pipes whose handshake we can watch.
+static NEGOTIATED_TLS: Lazy<Mutex<HashMap<String, &'static str>>> = Lazy::new(Default::default);
+
+/// The TLS version obfstls ends up with. Schannel and Secure Transport, as native-tls drives them, stop at TLS 1.2; OpenSSL speaks TLS 1.3, as do the bridges, which all run OpenSSL.
+const OBFSTLS_VERSION: &str = if cfg!(any(windows, target_vendor = "apple")) {
+    "1.2"
+} else {
+    "1.3"
+};
+
+/// Lists every connected pipe of the current session.
+pub fn pipe_info() -> Vec<PipeInfo> {
+    PIPES.lock().values().cloned().collect()
+}
+
+/// Keeps a pipe listed in [pipe_info] until dropped.
+pub struct PipeRecord {
+    id: u64,
+}
+
+impl PipeRecord {
+    pub fn new(protocol: &str, endpoint: String, handshake: Duration) -> Self {
+        let (transport, tls, sni) = match protocol {
+            "sosistab2-obfsudp" => ("udp", "none", false),
+            "sosistab2-obfstls" => ("tcp", OBFSTLS_VERSION, false),
+            "sosistab2-wss" => ("tcp", negotiated_tls(&endpoint), true),
+            "sosistab2-quic" => ("quic", "1.3", false),
+            _ => ("unknown", "unknown", false),
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        PIPES.lock().insert(
+            id,
+            PipeInfo {
+                protocol: protocol.to_string(),
+                endpoint,
+                transport: transport.into(),
+                tls: tls.into(),
+                sni,
+                handshake_ms: handshake.as_secs_f64() * 1000.0,
+                connected_unix: now_unix(),
+                reconnects: 0,
+            },
+        );
+        Self { id }
+    }
+
+    /// Records a successful reconnect, which took the given handshake time.
+    pub fn reconnected(&self, handshake: Duration) {
+        if let Some(info) = PIPES.lock().get_mut(&self.id) {
+            if info.protocol == "sosistab2-wss" {
+                info.tls = negotiated_tls(&info.endpoint).into();
+            }
+            info.handshake_ms = handshake.as_secs_f64() * 1000.0;
+            info.connected_unix = now_unix();
+            info.reconnects += 1;
+        }
+    }
+}
+
+impl Drop for PipeRecord {
+    fn drop(&mut self) {
+        PIPES.lock().remove(&self.id);
+    }
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn negotiated_tls(endpoint: &str) -> &'static str {
+    NEGOTIATED_TLS
+        .lock()
+        .get(endpoint)
+        .copied()
+        .unwrap_or("unknown")
+}
+
+/// Wraps the TCP stream under a TLS handshake, to see which version the server picks.
+pub struct TlsVersionSniffer<S> {
+    inner: S,
+    seen: Vec<u8>,
+}
+
+/// The most of the server's first record we look at, which is as long as a TLS record can be.
+const MAX_RECORD: usize = 5 + (1 << 14);
+
+impl<S> TlsVersionSniffer<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Records the version the server picked as the one negotiated with the given endpoint. Call this once the handshake is done.
+    pub fn record(&self, endpoint: String) {
+        let version = server_hello_version(&self.seen).unwrap_or("unknown");
+        NEGOTIATED_TLS.lock().insert(endpoint, version);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsVersionSniffer<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let n = futures_util::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let wanted = MAX_RECORD.saturating_sub(this.seen.len()).min(n);
+        this.seen.extend_from_slice(&buf[..wanted]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsVersionSniffer<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Reads the version a server picked out of its first record, which must be a ServerHello. TLS 1.3 picks its version in the supported_versions extension, with the legacy version field stuck at 1.2.
+fn server_hello_version(record: &[u8]) -> Option<&'static str> {
+    const HANDSHAKE: u8 = 0x16;
+    const SERVER_HELLO: u8 = 0x02;
+    const SUPPORTED_VERSIONS: u16 = 0x002b;
+    let u16_at = |i: usize| Some(u16::from_be_bytes([*record.get(i)?, *record.get(i + 1)?]));
+    if *record.first()? != HANDSHAKE || *record.get(5)? != SERVER_HELLO {
+        return None;
+    }
+    let mut version = u16_at(9)?;
+    // the legacy version and the random
+    let mut i = 9 + 2 + 32;
+    let session_id_len = *record.get(i)? as usize;
+    // the session id, the cipher suite and the compression method
+    i += 1 + session_id_len + 2 + 1;
+    if let Some(extensions_len) = u16_at(i) {
+        let end = i + 2 + extensions_len as usize;
+        i += 2;
+        while i + 4 <= end {
+            let kind = u16_at(i)?;
+            let len = u16_at(i + 2)? as usize;
+            if kind == SUPPORTED_VERSIONS {
+                version = u16_at(i + 4)?;
+            }
+            i += 4 + len;
+        }
+    }
+    match version {
+        0x0304 => Some("1.3"),
+        0x0303 => Some("1.2"),
+        0x0302 => Some("1.1"),
+        0x0301 => Some("1.0"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_hello(legacy_version: [u8; 2], extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend_from_slice(&legacy_version);
+        body.extend_from_slice(&[7; 32]);
+        // a 32-byte session id, a cipher suite and no compression
+        body.push(32);
+        body.extend_from_slice(&[9; 32]);
+        body.extend_from_slice(&[0x13, 0x01, 0]);
+        if !extensions.is_empty() {
+            body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+            body.extend_from_slice(extensions);
+        }
+        let mut handshake = vec![0x02, 0];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 3, 3];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn tls12_server_hello() {
+        // only the renegotiation_info extension
+        let record = server_hello([3, 3], &[0xff, 0x01, 0, 1, 0]);
+        assert_eq!(server_hello_version(&record), Some("1.2"));
+        assert_eq!(
+            server_hello_version(&server_hello([3, 3], &[])),
+            Some("1.2")
+        );
+    }
+
+    #[test]
+    fn tls13_server_hello() {
+        // key_share, then supported_versions picking TLS 1.3
+        let record = server_hello([3, 3], &[0, 0x33, 0, 4, 0, 0x1d, 0, 0, 0, 0x2b, 0, 2, 3, 4]);
+        assert_eq!(server_hello_version(&record), Some("1.3"));
+    }
+
+    #[test]
+    fn not_a_server_hello() {
+        assert_eq!(server_hello_version(&[0x15, 3, 3, 0, 2, 2, 40]), None);
+        assert_eq!(server_hello_version(&[]), None);
+    }
+}
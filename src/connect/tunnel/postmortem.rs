@@ -0,0 +1,183 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connect::{
+        stats::{scoped_stats, StatScope},
+        CONNECT_CONFIG,
+    },
+    storage,
+};
+
+use super::pipe_health::pipe_health;
+
+/// Network changes this long before a session ended go into its post-mortem.
+const NEAR_END: Duration = Duration::from_secs(120);
+
+/// How many network changes are remembered.
+const MAX_NETWORK_EVENTS: usize = 32;
+
+/// What a session looked like when it ended, for diagnosing disconnects after the fact.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PostMortem {
+    /// Unix timestamps of when the session came up and ended.
+    pub started: Option<u64>,
+    pub ended: u64,
+    pub duration_secs: Option<u64>,
+    pub exit: Option<String>,
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    /// The session's pipes as of the end.
+    pub pipes: Vec<PipeState>,
+    /// The error that ended the session, outermost first.
+    pub error_chain: Vec<String>,
+    /// Changes of the network in the two minutes before the end.
+    pub network_events: Vec<NetworkEvent>,
+}
+
+/// One pipe of an ended session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipeState {
+    pub protocol: String,
+    pub endpoint: String,
+    pub latency_ms: Option<f64>,
+    pub recent_stalls: usize,
+    pub demoted: bool,
+}
+
+/// A change of the network the daemon runs on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NetworkEvent {
+    pub time: u64,
+    pub description: String,
+}
+
+static NETWORK_EVENTS: Lazy<Mutex<VecDeque<NetworkEvent>>> = Lazy::new(Default::default);
+
+static LAST_POSTMORTEM: Lazy<Mutex<Option<PostMortem>>> = Lazy::new(|| {
+    let last = storage::read(&postmortem_path())
+        .ok()
+        .and_then(|bts| serde_json::from_slice(&bts).ok());
+    Mutex::new(last)
+});
+
+/// Whether [LAST_POSTMORTEM] changed since it was last saved.
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Wakes [SAVER] whenever a post-mortem is recorded.
+static CHANGED: Event = Event::new();
+
+/// Saves the latest post-mortem in the background, so that recording one never waits on the disk.
+static SAVER: Lazy<()> = Lazy::new(|| smolscale::spawn(save_loop()).detach());
+
+async fn save_loop() {
+    loop {
+        let listener = CHANGED.listen();
+        if !DIRTY.swap(false, Ordering::SeqCst) {
+            listener.await;
+            continue;
+        }
+        let postmortem = LAST_POSTMORTEM.lock().clone();
+        let result = smol::unblock(move || {
+            let path = postmortem_path();
+            let tmp = path.with_extension("tmp");
+            storage::write(&tmp, serde_json::to_vec_pretty(&postmortem)?)?;
+            storage::rename(&tmp, &path)
+        })
+        .await;
+        if let Err(err) = result {
+            log::warn!("cannot save session post-mortem: {:?}", err)
+        }
+    }
+}
+
+/// Where the latest post-mortem is kept, next to the usage log.
+fn postmortem_path() -> PathBuf {
+    CONNECT_CONFIG.usage_path.with_extension("postmortem.json")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The post-mortem of the latest session to end, in this run or an earlier one.
+pub fn last_postmortem() -> Option<PostMortem> {
+    LAST_POSTMORTEM.lock().clone()
+}
+
+/// Records the post-mortem of the session that just ended with the given result, including sessions that never got past connecting or authenticating, which are recorded as not `connected`. Must be called before the session's multiplex is dropped, so that its pipes are still there to look at.
+pub(super) fn record_postmortem(exit: Option<&str>, connected: bool, result: &anyhow::Result<()>) {
+    let ended = unix_now();
+    // until it connects, the session scope still counts the session before
+    let session = if connected {
+        scoped_stats(StatScope::Session)
+    } else {
+        Default::default()
+    };
+    let pipes = pipe_health()
+        .into_iter()
+        .map(|p| PipeState {
+            protocol: p.protocol,
+            endpoint: p.endpoint,
+            latency_ms: p.latency.map(|l| l.as_secs_f64() * 1000.0),
+            recent_stalls: p.recent_stalls,
+            demoted: p.demoted,
+        })
+        .collect();
+    let error_chain = match result {
+        Ok(()) => vec![],
+        Err(err) => err.chain().map(|e| e.to_string()).collect(),
+    };
+    let network_events = NETWORK_EVENTS
+        .lock()
+        .iter()
+        .filter(|e| e.time + NEAR_END.as_secs() >= ended)
+        .cloned()
+        .collect();
+    let postmortem = PostMortem {
+        started: session.since_unix,
+        ended,
+        duration_secs: session.since_unix.map(|s| ended.saturating_sub(s)),
+        exit: exit.map(|e| e.to_string()),
+        sent_bytes: session.sent_bytes,
+        recv_bytes: session.recv_bytes,
+        pipes,
+        error_chain,
+        network_events,
+    };
+    log::info!(
+        "session to {:?} ended after {:?}s: {:?}",
+        postmortem.exit,
+        postmortem.duration_secs,
+        postmortem.error_chain
+    );
+    *LAST_POSTMORTEM.lock() = Some(postmortem);
+    DIRTY.store(true, Ordering::SeqCst);
+    Lazy::force(&SAVER);
+    CHANGED.notify(1);
+}
+
+/// Records a change of the network, to go into the post-mortem of a session that ends soon after.
+pub fn record_network_event(description: String) {
+    log::debug!("network change: {}", description);
+    let mut events = NETWORK_EVENTS.lock();
+    events.push_back(NetworkEvent {
+        time: unix_now(),
+        description,
+    });
+    while events.len() > MAX_NETWORK_EVENTS {
+        events.pop_front();
+    }
+}
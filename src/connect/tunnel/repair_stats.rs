@@ -0,0 +1,66 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+/// Upper bounds, in seconds, of the buckets that replacement times are counted in.
+pub const REPLACE_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// What the repair loop did for one protocol since startup.
+#[derive(Clone, Debug, Default)]
+pub struct RepairStats {
+    /// Pipes that died, which are then reconnected, or were demoted for poor health, which are then replaced.
+    pub dead_detected: u64,
+    /// Reconnects and replacement dials tried, several of which may go to one dead pipe.
+    pub replacements_attempted: u64,
+    pub replacements_succeeded: u64,
+    pub replacements_failed: u64,
+    /// How many successful replacements took at most the matching [REPLACE_BUCKETS] bound, not cumulative; replacements slower than every bound are only in the sum and count.
+    pub replace_buckets: Vec<u64>,
+    pub replace_seconds_sum: f64,
+}
+
+static REPAIR_STATS: Lazy<Mutex<BTreeMap<String, RepairStats>>> = Lazy::new(Default::default);
+
+/// The repair loop's counters, by protocol.
+pub fn repair_stats() -> BTreeMap<String, RepairStats> {
+    REPAIR_STATS.lock().clone()
+}
+
+fn with_protocol(protocol: &str, f: impl FnOnce(&mut RepairStats)) {
+    let mut stats = REPAIR_STATS.lock();
+    let stats = stats
+        .entry(protocol.to_string())
+        .or_insert_with(|| RepairStats {
+            replace_buckets: vec![0; REPLACE_BUCKETS.len()],
+            ..Default::default()
+        });
+    f(stats)
+}
+
+/// Records that a pipe died or was demoted.
+pub(super) fn record_dead(protocol: &str) {
+    with_protocol(protocol, |stats| stats.dead_detected += 1)
+}
+
+/// Records that a reconnect or a replacement is being tried.
+pub(super) fn record_attempt(protocol: &str) {
+    with_protocol(protocol, |stats| stats.replacements_attempted += 1)
+}
+
+/// Records a reconnect or replacement that connected, and how long after the dead pipe was found.
+pub(super) fn record_success(protocol: &str, took: Duration) {
+    let secs = took.as_secs_f64();
+    with_protocol(protocol, |stats| {
+        stats.replacements_succeeded += 1;
+        stats.replace_seconds_sum += secs;
+        if let Some(bucket) = REPLACE_BUCKETS.iter().position(|bound| secs <= *bound) {
+            stats.replace_buckets[bucket] += 1;
+        }
+    })
+}
+
+/// Records a reconnect or replacement that did not connect.
+pub(super) fn record_failure(protocol: &str) {
+    with_protocol(protocol, |stats| stats.replacements_failed += 1)
+}
@@ -0,0 +1,199 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use event_listener::Event;
+use smol::prelude::*;
+
+use crate::connect::{power::stretch, CONNECT_CONFIG};
+
+use super::postmortem::record_network_event;
+
+/// How often the network is checked for changes, when nothing says it changed.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to let the network settle after a hint that it changed, since addresses and routes come and go in bursts.
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Bumped whenever the device moves to another network, like from Wi-Fi to LTE.
+static NETWORK_GENERATION: AtomicU64 = AtomicU64::new(0);
+static NETWORK_CHANGED: Event = Event::new();
+
+/// Woken by platform notifications that the network may have changed.
+static NETWORK_HINT: Event = Event::new();
+
+/// How many times the device moved to another network since startup.
+pub fn network_generation() -> u64 {
+    NETWORK_GENERATION.load(Ordering::SeqCst)
+}
+
+/// Waits until the device moves to another network than the one of the given generation, returning the new generation.
+pub(super) async fn wait_network_change(seen: u64) -> u64 {
+    loop {
+        let listener = NETWORK_CHANGED.listen();
+        let current = network_generation();
+        if current != seen {
+            return current;
+        }
+        listener.await;
+    }
+}
+
+/// Tells the network watcher to check the network right away, for platforms that notice changes themselves (like NWPathMonitor on Apple devices).
+pub fn network_hint() {
+    NETWORK_HINT.notify(usize::MAX);
+}
+
+/// The local addresses that traffic to the internet leaves from, sorted, or none without a network: those of the physical interfaces with a default gateway, or failing that, their global IPv6 addresses, since on IPv6-only networks no gateway may be found. Geph's own TUN device is never one of them, though in VPN mode the route to the internet goes into it.
+fn outbound_addrs() -> Vec<IpAddr> {
+    let physical: Vec<_> = default_net::get_interfaces()
+        .into_iter()
+        .filter(|iface| {
+            iface.name != CONNECT_CONFIG.tun_name
+                && !iface
+                    .ipv4
+                    .iter()
+                    .any(|net| net.addr == CONNECT_CONFIG.tun_address || net.addr.is_loopback())
+        })
+        .collect();
+    let mut addrs: Vec<IpAddr> = physical
+        .iter()
+        .filter(|iface| iface.gateway.is_some())
+        .flat_map(|iface| {
+            let v4 = iface.ipv4.iter().map(|net| IpAddr::V4(net.addr));
+            let v6 = iface.ipv6.iter().map(|net| net.addr).filter(is_global_v6);
+            v4.chain(v6.map(IpAddr::V6)).collect::<Vec<_>>()
+        })
+        .collect();
+    if addrs.is_empty() {
+        addrs = physical
+            .iter()
+            .flat_map(|iface| iface.ipv6.iter().map(|net| net.addr))
+            .filter(is_global_v6)
+            .map(IpAddr::V6)
+            .collect();
+    }
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs
+}
+
+/// Whether an IPv6 address can reach the internet: not loopback, link-local or unique local.
+fn is_global_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || first & 0xffc0 == 0xfe80
+        || first & 0xfe00 == 0xfc00)
+}
+
+fn describe(addrs: &[IpAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Watches for the network going away, coming back or changing address, recording each change. Whenever the device ends up on another network, every pipe reconnects from it at once, keeping its session, rather than waiting to notice that the old path died. Changes are noticed through netlink on Linux, hints from the platform elsewhere, and polling as a backstop. Never returns.
+pub async fn network_watch_loop() {
+    #[cfg(target_os = "linux")]
+    if let Err(err) = netlink_hints() {
+        log::warn!(
+            "cannot watch netlink for network changes, polling instead: {:?}",
+            err
+        );
+    }
+    let mut last = outbound_addrs();
+    loop {
+        let hint = NETWORK_HINT.listen();
+        async {
+            smol::Timer::after(stretch(NETWORK_POLL_INTERVAL)).await;
+        }
+        .or(async {
+            hint.await;
+            smol::Timer::after(SETTLE_DELAY).await;
+        })
+        .await;
+        let current = smol::unblock(outbound_addrs).await;
+        if current == last {
+            continue;
+        }
+        record_network_event(match (last.is_empty(), current.is_empty()) {
+            (false, false) => format!(
+                "local addresses changed from {} to {}",
+                describe(&last),
+                describe(&current)
+            ),
+            (true, _) => format!(
+                "network came back with local addresses {}",
+                describe(&current)
+            ),
+            (false, true) => format!("network lost (local addresses were {})", describe(&last)),
+        });
+        let reconnect = !current.is_empty();
+        last = current;
+        // with no network at all, there is nothing to reconnect from yet
+        if reconnect {
+            log::info!("network changed, reconnecting all pipes");
+            NETWORK_GENERATION.fetch_add(1, Ordering::SeqCst);
+            NETWORK_CHANGED.notify(usize::MAX);
+        }
+    }
+}
+
+/// Turns every link, address and route change the kernel announces into a network hint, on a thread of its own.
+#[cfg(target_os = "linux")]
+fn netlink_hints() -> anyhow::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = (libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_ROUTE) as u32;
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if bound < 0 {
+        let err = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err.into());
+    }
+    std::thread::Builder::new()
+        .name("netlink".into())
+        .spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                let n =
+                    unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+                // ENOBUFS means messages were lost, which still says something changed
+                if n < 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOBUFS) {
+                    log::warn!(
+                        "netlink watch failed: {:?}",
+                        std::io::Error::last_os_error()
+                    );
+                    unsafe { libc::close(fd) };
+                    return;
+                }
+                network_hint();
+            }
+        })?;
+    Ok(())
+}
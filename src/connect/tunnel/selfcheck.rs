@@ -0,0 +1,119 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+use sosistab2::Multiplex;
+
+use crate::connect::power::{background_wakeup, record_probe_skipped, stretch};
+
+use super::activity::wait_activity;
+
+/// Where the self-check stream goes. The exit forwards it like any other stream, so a correct reply proves that the exit actually forwards traffic.
+const SELFCHECK_HOST: &str = "1.0.0.1:53";
+
+/// After this many consecutive failures, the exit is reported as not forwarding.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// The result of the end-to-end self-checks.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SelfCheckStatus {
+    /// Unix timestamp of the last successful check.
+    pub last_success: Option<u64>,
+    /// Round-trip latency of the last successful check, in milliseconds.
+    pub last_latency_ms: Option<f32>,
+    /// How many checks in a row have failed.
+    pub consecutive_failures: u32,
+    /// Whether the tunnel seems up but the exit isn't forwarding traffic.
+    pub exit_not_forwarding: bool,
+}
+
+pub static SELFCHECK_STATUS: Lazy<Mutex<SelfCheckStatus>> = Lazy::new(Default::default);
+
+/// Periodically checks that streams through the tunnel are actually forwarded end-to-end, except in low power mode. Never returns.
+pub(crate) async fn selfcheck_loop(mux: Arc<Multiplex>) -> anyhow::Result<()> {
+    loop {
+        if background_wakeup() {
+            check_and_record(&mux).await;
+        } else {
+            record_probe_skipped();
+        }
+
+        let timer = smol::Timer::after(stretch(Duration::from_secs(60)));
+        wait_activity(Duration::from_secs(600)).await;
+        timer.await;
+    }
+}
+
+/// Runs one self-check and updates [SELFCHECK_STATUS] with its result.
+async fn check_and_record(mux: &Multiplex) {
+    match selfcheck_once(mux).timeout(Duration::from_secs(30)).await {
+        Some(Ok(latency)) => {
+            log::debug!("** self-check completed in {:?} **", latency);
+            let mut status = SELFCHECK_STATUS.lock();
+            status.last_success = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            );
+            status.last_latency_ms = Some(latency.as_secs_f32() * 1000.0);
+            status.consecutive_failures = 0;
+            status.exit_not_forwarding = false;
+        }
+        res => {
+            let err = match res {
+                Some(Err(err)) => err,
+                _ => anyhow::anyhow!("timed out"),
+            };
+            let mut status = SELFCHECK_STATUS.lock();
+            status.consecutive_failures += 1;
+            if status.consecutive_failures >= FAILURE_THRESHOLD {
+                status.exit_not_forwarding = true;
+                log::error!(
+                    "self-check failed {} times in a row, the exit does not seem to forward traffic: {:?}",
+                    status.consecutive_failures,
+                    err
+                );
+            } else {
+                log::warn!("self-check failed: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Sends a DNS query with a random ID through the tunnel and verifies that the reply echoes the ID and the question back intact.
+async fn selfcheck_once(mux: &Multiplex) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    let mut conn = mux.open_conn(SELFCHECK_HOST).await?;
+    let id: u16 = rand::thread_rng().gen();
+    let question: &[u8] = b"\x07example\x03com\x00\x00\x01\x00\x01";
+    let mut query = Vec::with_capacity(12 + question.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    query.extend_from_slice(question);
+    conn.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    conn.write_all(&query).await?;
+    conn.flush().await?;
+
+    let mut len_buf = [0u8; 2];
+    conn.read_exact(&mut len_buf).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    conn.read_exact(&mut response).await?;
+    let echoed_id = response.get(..2).context("reply too short")?;
+    let echoed_question = response
+        .get(12..12 + question.len())
+        .context("reply too short")?;
+    if echoed_id != id.to_be_bytes() || echoed_question != question {
+        anyhow::bail!("reply does not match the query")
+    }
+    Ok(start.elapsed())
+}
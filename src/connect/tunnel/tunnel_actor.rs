@@ -1,11 +1,21 @@
 use crate::connect::{
-    stats::{StatItem, STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES},
+    power::{background_wakeup, power_profile, record_sample_skipped, stretch},
+    stats::{
+        start_session, StatItem, STATS_GATHERER, STATS_RECV_BYTES, STATS_SEND_BYTES, STATS_SESSIONS,
+    },
     tunnel::{ConnectionStatus, EndpointSource},
 };
 
 use super::{
     activity::{notify_activity, wait_activity},
+    control::{set_current_exit, stopped, wait_reconnect, wait_stopped},
+    downgrade::{handle_rejected_token, note_level, throttle},
+    exit_failover::ExitSession,
     getsess::get_session,
+    notify_status,
+    pipe_health::demote_stalled_pipe,
+    postmortem::record_postmortem,
+    selfcheck::selfcheck_loop,
     TunnelCtx,
 };
 use anyhow::Context;
@@ -18,6 +28,8 @@ use geph4_protocol::{
 use std::{net::Ipv4Addr, time::SystemTime};
 
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 
 use smol::{
     channel::{Receiver, Sender},
@@ -33,12 +45,41 @@ use std::{
     time::Instant,
 };
 
+/// How long one attempt at opening a stream may take.
+const OPEN_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How many times opening a stream is tried before the session is given up on. A stalled pipe is demoted between attempts, so that the next goes over another.
+const MAX_OPEN_ATTEMPTS: usize = 3;
+
+/// The last error that made the tunnel restart, if any.
+pub static LAST_TUNNEL_ERROR: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// The latest error that reconnecting cannot fix, like the exit refusing our credentials, cleared once a session connects.
+pub static FATAL_TUNNEL_ERROR: Lazy<Mutex<Option<String>>> = Lazy::new(Default::default);
+
+/// The exit refused our authentication token.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid authentication token")]
+pub(crate) struct TokenRejected;
+
 /// Background task of a TunnelManager
 pub(crate) async fn tunnel_actor(ctx: TunnelCtx) -> anyhow::Result<()> {
     loop {
-        // Run until a failure happens, log the error, then restart
-        if let Err(err) = tunnel_actor_once(ctx.clone()).await {
+        // Run until a failure happens, log the error, then restart. A stop
+        // cuts any stage short, including get_session and authentication.
+        let result = tunnel_actor_once(ctx.clone())
+            .or(async {
+                wait_stopped().await;
+                anyhow::bail!("tunnel stopped")
+            })
+            .await;
+        if stopped() {
+            log::info!("tunnel stopped");
+            return Ok(());
+        }
+        if let Err(err) = result {
             log::warn!("tunnel_actor restarting: {:?}", err);
+            *LAST_TUNNEL_ERROR.lock() = Some(format!("{:?}", err));
             smol::Timer::after(Duration::from_secs(1)).await;
         }
     }
@@ -46,69 +87,134 @@ pub(crate) async fn tunnel_actor(ctx: TunnelCtx) -> anyhow::Result<()> {
 
 async fn print_stats_loop(mux: Arc<Multiplex>) {
     for _ctr in 0u64.. {
-        if let Some(pipe) = mux.last_recv_pipe() {
-            log::info!("RECV-CONN {} / PROT {} ", pipe.peer_addr(), pipe.protocol(),);
+        if background_wakeup() {
+            if let Some(pipe) = mux.last_recv_pipe() {
+                log::info!("RECV-CONN {} / PROT {} ", pipe.peer_addr(), pipe.protocol(),);
+            }
         }
-        smol::Timer::after(Duration::from_secs(30)).await;
+        smol::Timer::after(stretch(Duration::from_secs(30))).await;
     }
 }
 
 async fn tunnel_actor_once(ctx: TunnelCtx) -> anyhow::Result<()> {
-    let ctx1 = ctx.clone();
     ctx.vpn_client_ip.store(0, Ordering::SeqCst);
     notify_activity();
 
-    let tunnel_mux = get_session(ctx.clone()).await?;
+    let (tunnel_mux, exit) = match get_session(ctx.clone()).await {
+        Ok(session) => session,
+        Err(err) => {
+            let result = Err(err);
+            record_postmortem(None, false, &result);
+            return result;
+        }
+    };
+    set_current_exit(exit.clone());
+    scopeguard::defer!(set_current_exit(None));
+    let mut connected = false;
+    let result = run_session(ctx, &tunnel_mux, exit.as_deref(), &mut connected).await;
+    record_postmortem(exit.as_deref(), connected, &result);
+    result
+}
+
+/// Authenticates the session and runs it until it fails, setting `connected` once it is up.
+async fn run_session(
+    ctx: TunnelCtx,
+    tunnel_mux: &Arc<Multiplex>,
+    exit: Option<&str>,
+    connected: &mut bool,
+) -> anyhow::Result<()> {
+    let ctx1 = ctx.clone();
+    // failures of the exit itself count against it, so that a dead exit gets failed over from
+    let mut exit_session = ExitSession::new(exit);
 
     if let EndpointSource::Binder(binder_tunnel_params) = ctx.endpoint.clone() {
         // authenticate
         let token = binder_tunnel_params.ccache.get_auth_token().await?.1;
-        let ipv4 = authenticate_session(&tunnel_mux, &token)
+        let ipv4 = match authenticate_session(tunnel_mux, &token)
             .timeout(Duration::from_secs(60))
             .await
-            .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
+        {
+            Some(Ok(ipv4)) => ipv4,
+            Some(Err(err)) => {
+                // an expired plan shows up as the exit refusing our still-cached Plus token
+                if handle_rejected_token(&ctx, &binder_tunnel_params.ccache).await? {
+                    anyhow::bail!("plan expired, reconnecting on the free plan")
+                }
+                if err.downcast_ref::<TokenRejected>().is_some() {
+                    *FATAL_TUNNEL_ERROR.lock() = Some(err.to_string());
+                    notify_status();
+                } else {
+                    exit_session.failed();
+                }
+                return Err(err);
+            }
+            None => {
+                exit_session.failed();
+                anyhow::bail!("authentication timed out")
+            }
+        };
+        note_level(token.level);
         log::info!("VPN private IP assigned: {ipv4}");
         ctx.vpn_client_ip.store(ipv4.into(), Ordering::SeqCst);
     } else {
         ctx.vpn_client_ip.store(12345, Ordering::SeqCst);
     }
 
+    exit_session.connected();
+    *connected = true;
+
+    start_session();
+    STATS_SESSIONS.fetch_add(1, Ordering::Relaxed);
     log::info!("TUNNEL_ACTOR MAIN LOOP!");
+    *FATAL_TUNNEL_ERROR.lock() = None;
     *ctx.connect_status.write() = ConnectionStatus::Connected {
         protocol: "sosistab2".into(),
         address: "dynamic".into(),
     };
+    notify_status();
     let ctx2 = ctx.clone();
     scopeguard::defer!({
         *ctx2.connect_status.write() = ConnectionStatus::Connecting;
+        notify_status();
     });
 
+    let mut reconnect_requested = false;
     let (send_death, recv_death) = smol::channel::unbounded();
     let _lala = smolscale::spawn(print_stats_loop(tunnel_mux.clone()));
-    connection_handler_loop(ctx1.clone(), tunnel_mux.clone(), send_death)
+    let result = connection_handler_loop(ctx1.clone(), tunnel_mux.clone(), send_death)
         .or(async {
             // kill the whole session if any one connection fails
             let e = recv_death.recv().await.context("death received")?;
             anyhow::bail!(e)
         })
         .or(watchdog_loop(ctx1.clone(), tunnel_mux.clone()))
+        .or(selfcheck_loop(tunnel_mux.clone()))
+        .or(async {
+            let result = wait_reconnect().await;
+            reconnect_requested = true;
+            result
+        })
         .or(vpn_loop(
             tunnel_mux.clone(),
             ctx.send_vpn_incoming,
             ctx.recv_vpn_outgoing,
         ))
-        .await
+        .await;
+    if result.is_err() && !reconnect_requested {
+        exit_session.failed();
+    }
+    result
 }
 
 /// authenticates a muxed session
-async fn authenticate_session(
+pub(crate) async fn authenticate_session(
     session: &sosistab2::Multiplex,
     token: &BlindToken,
 ) -> anyhow::Result<Ipv4Addr> {
     let tport = MuxStreamTransport::new(session.open_conn(CLIENT_EXIT_PSEUDOHOST).await?);
     let client = ClientExitClient::from(tport);
     if !client.validate(token.clone()).await? {
-        anyhow::bail!("invalid authentication token")
+        return Err(TokenRejected.into());
     }
     let addr = client
         .get_vpn_ipv4()
@@ -156,6 +262,7 @@ async fn vpn_loop(
     let uploop = async {
         loop {
             let to_send = recv_outgoing.recv().await?;
+            throttle(to_send.len()).await;
             wire.send_urel(stdcode::serialize(&vec![to_send])?.into())
                 .await?;
         }
@@ -165,6 +272,7 @@ async fn vpn_loop(
             let received = wire.recv_urel().await?;
             let received: Vec<Bytes> = stdcode::deserialize(&received)?;
             for received in received {
+                throttle(received.len()).await;
                 send_incoming.send(received).await?;
             }
         }
@@ -189,41 +297,54 @@ async fn connection_handler_loop(
         notify_activity();
         smolscale::spawn(async move {
             let start = Instant::now();
-            let remote = mux
-                .open_conn(&conn_host)
-                .timeout(Duration::from_secs(60))
-                .await;
-            match remote {
-                Some(Ok(remote)) => {
-                    log::debug!(
-                        "opened connection to {} in {} ms",
-                        conn_host,
-                        start.elapsed().as_millis(),
-                    );
-
-                    conn_reply.send(remote).await.context("conn_reply failed")?;
-                    Ok::<(), anyhow::Error>(())
+            let mut attempt = 1;
+            let err = loop {
+                // the pipe most likely to carry the open, going by where replies last came from
+                let carrier = mux.last_recv_pipe();
+                let err = match mux
+                    .open_conn(&conn_host)
+                    .timeout(OPEN_ATTEMPT_TIMEOUT)
+                    .await
+                {
+                    Some(Ok(remote)) => {
+                        log::debug!(
+                            "opened connection to {} in {} ms",
+                            conn_host,
+                            start.elapsed().as_millis(),
+                        );
+                        conn_reply.send(remote).await.context("conn_reply failed")?;
+                        return Ok::<(), anyhow::Error>(());
+                    }
+                    Some(Err(err)) => anyhow::anyhow!(
+                        "conn open error {} in {}s",
+                        err,
+                        start.elapsed().as_secs_f64()
+                    ),
+                    None => {
+                        anyhow::anyhow!("conn timeout in {}s", start.elapsed().as_secs_f64())
+                    }
+                };
+                if attempt >= MAX_OPEN_ATTEMPTS {
+                    break err;
                 }
-                Some(Err(err)) => {
-                    send_death
-                        .send(anyhow::anyhow!(
-                            "conn open error {} in {}s",
+                // a pipe dying mid-handshake shouldn't fail the connection when others are alive, but a failed open alone is no sign of that, since the exit or the destination may just be slow
+                match carrier {
+                    Some(pipe) if demote_stalled_pipe(pipe.protocol(), &pipe.peer_addr()) => {
+                        log::debug!(
+                            "{} (attempt {}), retrying {} without stalled {} / {}",
                             err,
-                            start.elapsed().as_secs_f64()
-                        ))
-                        .await?;
-                    Ok(())
+                            attempt,
+                            conn_host,
+                            pipe.protocol(),
+                            pipe.peer_addr()
+                        );
+                    }
+                    _ => log::debug!("{} (attempt {}), retrying {}", err, attempt, conn_host),
                 }
-                None => {
-                    send_death
-                        .send(anyhow::anyhow!(
-                            "conn timeout in {}s",
-                            start.elapsed().as_secs_f64()
-                        ))
-                        .await?;
-                    Ok(())
-                }
-            }
+                attempt += 1;
+            };
+            send_death.send(err).await?;
+            Ok(())
         })
         .detach();
     }
@@ -247,20 +368,25 @@ async fn watchdog_loop(
         } else {
             let ping = start.elapsed();
             let pipe = tunnel_mux.last_recv_pipe().context("no pipe")?;
-            let item = StatItem {
-                time: SystemTime::now(),
-                endpoint: pipe.peer_addr().into(),
-                protocol: pipe.protocol().into(),
-                ping,
-                send_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed),
-                recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed),
-            };
-            STATS_GATHERER.push(item.clone());
+            if background_wakeup() {
+                let item = StatItem {
+                    time: SystemTime::now(),
+                    endpoint: pipe.peer_addr().into(),
+                    protocol: pipe.protocol().into(),
+                    ping,
+                    send_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed),
+                    recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed),
+                };
+                STATS_GATHERER.push(item.clone());
+            } else {
+                record_sample_skipped();
+            }
             log::debug!("** watchdog completed in {:?} **", ping);
         }
 
-        let timer = smol::Timer::after(Duration::from_secs(10));
-        wait_activity(Duration::from_secs(600)).await;
+        // the watchdog doubles as the tunnel's keepalive, so it keeps running in low power mode, only less often
+        let timer = smol::Timer::after(stretch(power_profile().keepalive_interval()));
+        wait_activity(power_profile().idle_timeout()).await;
         timer.await;
     }
 }
@@ -0,0 +1,154 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use geph4_protocol::binder::protocol::{BridgeDescriptor, ExitDescriptor};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
+use smol::channel::{Receiver, Sender};
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, MuxPublic, MuxSecret};
+
+use crate::connect::power::{background_wakeup, record_probe_skipped, stretch};
+
+use super::{
+    exit_failover::is_avoided, getsess::predial, pipe_health::HealthBoard,
+    tunnel_actor::authenticate_session, BinderTunnelParams, TunnelCtx,
+};
+
+/// How often the spare's bridge list is fetched afresh.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A spare older than this is not promoted, since its bridges may well have changed.
+const MAX_AGE: Duration = Duration::from_secs(600);
+
+/// How long the spare gets to authenticate with the exit.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Everything a session needs before it can start dialing: the exit, its bridges, a multiplex keyed to it, and the bookkeeping for the pipes dialed into it.
+pub(super) struct Target {
+    pub exit: ExitDescriptor,
+    pub bridges: Vec<BridgeDescriptor>,
+    pub multiplex: Arc<Multiplex>,
+    /// The metadata every pipe of the session is dialed with.
+    pub sess_id: String,
+    pub health: Arc<HealthBoard>,
+    /// Demoted pipes report their bridge here, so that a replacement gets dialed.
+    pub on_demote: Sender<BridgeDescriptor>,
+    pub demoted: Receiver<BridgeDescriptor>,
+    /// The bridge of the pipe a spare already has in its multiplex.
+    pub predialed: Option<BridgeDescriptor>,
+}
+
+impl Target {
+    /// Builds a target, failing if the bridges don't tell us the exit's end-to-end key.
+    pub fn new(exit: ExitDescriptor, bridges: Vec<BridgeDescriptor>) -> anyhow::Result<Self> {
+        if bridges.is_empty() {
+            anyhow::bail!("no sosistab2 routes to {}", exit.hostname)
+        }
+        let e2e_key = e2e_key(&bridges)?;
+        let (on_demote, demoted) = smol::channel::unbounded();
+        Ok(Self {
+            exit,
+            bridges,
+            multiplex: Arc::new(Multiplex::new(MuxSecret::generate(), Some(e2e_key))),
+            sess_id: format!("sess-{}", rand::thread_rng().gen::<u128>()),
+            health: Default::default(),
+            on_demote,
+            demoted,
+            predialed: None,
+        })
+    }
+}
+
+/// The bridge descriptor is laid out in a rather weird format: the "sosistab_key" field is a bincode-encoded tuple of the first-level cookie and the end-to-end MuxPublic key. We assume at least one obfsudp bridge.
+fn e2e_key(bridges: &[BridgeDescriptor]) -> anyhow::Result<MuxPublic> {
+    bridges
+        .iter()
+        .filter(|bridge| bridge.protocol == "sosistab2-obfsudp")
+        .filter_map(|bridge| {
+            bincode::deserialize::<(sosistab2::ObfsUdpPublic, MuxPublic)>(&bridge.sosistab_key).ok()
+        })
+        .last()
+        .ok_or_else(|| anyhow::anyhow!("cannot deduce the sosistab2 MuxPublic of this exit"))
+}
+
+struct Spare {
+    /// The --exit-server (or change_exit) value the spare was prepared for.
+    requested: String,
+    target: Target,
+    prepared: Instant,
+}
+
+static SPARE: Lazy<Mutex<Option<Spare>>> = Lazy::new(Default::default);
+
+/// Takes the spare for the given requested exit, if there is a fresh one whose exit isn't being failed over from.
+pub(super) fn take_spare(requested: &str) -> Option<Target> {
+    let mut spare = SPARE.lock();
+    match spare.take() {
+        Some(s)
+            if s.requested == requested
+                && s.prepared.elapsed() < MAX_AGE
+                && !is_avoided(&s.target.exit.hostname) =>
+        {
+            Some(s.target)
+        }
+        _ => None,
+    }
+}
+
+/// Keeps a standby session to the given exit, with a fresh bridge list and one pipe already dialed and authenticated, so that when every pipe of the current session dies at once, the next session can carry traffic right away instead of waiting on the binder and a handshake. Pauses in low power mode. Meant to be dropped along with the session it stands by for.
+pub(super) async fn spare_loop(
+    ctx: TunnelCtx,
+    params: BinderTunnelParams,
+    requested: String,
+    exit: ExitDescriptor,
+) {
+    // the session just fetched the bridges, so the cached list is fresh enough at first
+    let mut force_refresh = false;
+    loop {
+        if !background_wakeup() {
+            record_probe_skipped();
+            smol::Timer::after(stretch(REFRESH_INTERVAL)).await;
+            continue;
+        }
+        let prepare = async {
+            let bridges = params
+                .ccache
+                .get_bridges_v2(&exit.hostname, force_refresh)
+                .await?;
+            let mut target = Target::new(exit.clone(), bridges)?;
+            target.predialed = Some(predial(&ctx, &target).await?);
+            let token = params.ccache.get_auth_token().await?.1;
+            authenticate_session(&target.multiplex, &token)
+                .timeout(AUTH_TIMEOUT)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("spare authentication timed out"))??;
+            anyhow::Ok(target)
+        };
+        match prepare.await {
+            Ok(target) => {
+                log::debug!(
+                    "warm spare for {} ready with {} bridges, connected through {}",
+                    exit.hostname,
+                    target.bridges.len(),
+                    target
+                        .predialed
+                        .as_ref()
+                        .map(|b| b.protocol.as_str())
+                        .unwrap_or_default()
+                );
+                *SPARE.lock() = Some(Spare {
+                    requested: requested.clone(),
+                    target,
+                    prepared: Instant::now(),
+                })
+            }
+            Err(err) => log::warn!("could not prepare warm spare: {:?}", err),
+        }
+        force_refresh = true;
+        smol::Timer::after(stretch(REFRESH_INTERVAL)).await;
+    }
+}
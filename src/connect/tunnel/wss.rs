@@ -0,0 +1,207 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use async_native_tls::TlsStream;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use smol::{
+    channel::{Receiver, Sender},
+    net::TcpStream,
+};
+use sosistab2::Pipe;
+
+use crate::connect::{http_wire::read_head, tunnel::pipe_info::TlsVersionSniffer};
+
+/// The key material of a sosistab2-wss bridge, bincode-encoded in the descriptor's sosistab_key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WssKey {
+    /// The domain the bridge has a real certificate for, used for SNI and the Host header.
+    pub hostname: String,
+    /// The path of the WebSocket endpoint.
+    pub path: String,
+    /// A shared secret that the bridge checks before speaking sosistab2.
+    pub cookie: Bytes,
+}
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+type Stream = async_dup::Arc<async_dup::Mutex<TlsStream<TlsVersionSniffer<TcpStream>>>>;
+
+/// A pipe carried over a genuine TLS + WebSocket connection, which looks like any browser's WebSocket to networks that only allow web traffic.
+pub struct WssPipe {
+    inner: Stream,
+    send_write: Sender<(u8, Bytes)>,
+    peer_addr: SocketAddr,
+    peer_metadata: String,
+    _task: smol::Task<anyhow::Result<()>>,
+}
+
+impl WssPipe {
+    /// Connects to a bridge, validating its certificate for the key's hostname, and upgrades to a WebSocket.
+    pub async fn connect(
+        remote_addr: SocketAddr,
+        key: &WssKey,
+        peer_metadata: &str,
+    ) -> anyhow::Result<Self> {
+        let tcp = TcpStream::connect(remote_addr).await?;
+        tcp.set_nodelay(true)?;
+        let mut tls = async_native_tls::TlsConnector::new()
+            .connect(&key.hostname, TlsVersionSniffer::new(tcp))
+            .await
+            .context("TLS handshake failed")?;
+        tls.get_ref().record(remote_addr.to_string());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            key.path,
+            key.hostname,
+            base64::encode(rand::random::<[u8; 16]>())
+        );
+        tls.write_all(request.as_bytes()).await?;
+        let response = read_head(&mut tls).await?;
+        if response.status != 101 {
+            anyhow::bail!("WebSocket upgrade refused: {}", response.status_line)
+        }
+        // the cookie and our metadata go in the first message, just like obfstls sends them first
+        let mut hello = key.cookie.to_vec();
+        hello.extend_from_slice(&(peer_metadata.len() as u32).to_be_bytes());
+        hello.extend_from_slice(peer_metadata.as_bytes());
+        write_frame(&mut tls, OPCODE_BINARY, &hello).await?;
+        tls.flush().await?;
+
+        let inner = async_dup::Arc::new(async_dup::Mutex::new(tls));
+        let (send_write, recv_write) = smol::channel::bounded(100);
+        let _task = smolscale::spawn(send_loop(recv_write, inner.clone()));
+        Ok(Self {
+            inner,
+            send_write,
+            peer_addr: remote_addr,
+            peer_metadata: peer_metadata.into(),
+            _task,
+        })
+    }
+}
+
+async fn send_loop(recv_write: Receiver<(u8, Bytes)>, mut inner: Stream) -> anyhow::Result<()> {
+    loop {
+        let (opcode, payload) = recv_write.recv().await?;
+        write_frame(&mut inner, opcode, &payload).await?;
+        inner.flush().await?;
+    }
+}
+
+#[async_trait]
+impl Pipe for WssPipe {
+    async fn send(&self, to_send: Bytes) {
+        let _ = self.send_write.try_send((OPCODE_BINARY, to_send));
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let mut message = Vec::new();
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.inner.clone()).await?;
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(message.into());
+                    }
+                }
+                OPCODE_PING => {
+                    let _ = self.send_write.send((OPCODE_PONG, payload.into())).await;
+                }
+                OPCODE_CLOSE => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "WebSocket closed by bridge",
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn protocol(&self) -> &str {
+        "sosistab2-wss"
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.to_string()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+}
+
+/// Writes a single masked frame, as clients must.
+async fn write_frame(
+    w: &mut (impl futures_util::AsyncWrite + Unpin),
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = rand::random();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    w.write_all(&frame).await
+}
+
+/// Reads a single frame, returning whether it is final, its opcode and its payload.
+async fn read_frame(
+    r: &mut (impl futures_util::AsyncRead + Unpin),
+) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    r.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            r.read_exact(&mut len).await?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            r.read_exact(&mut len).await?;
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    if len > 1 << 20 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "WebSocket frame too large",
+        ));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        r.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    if masked {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    Ok((fin, opcode, payload))
+}
@@ -0,0 +1,92 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use once_cell::sync::Lazy;
+use smol::lock::Mutex;
+
+use crate::usage::{DayUsage, UsageDb};
+
+use super::{
+    stats::{STATS_RECV_BYTES, STATS_SEND_BYTES, STATS_SESSIONS},
+    tunnel::control::current_exit,
+    CONNECT_CONFIG,
+};
+
+/// How often the counters are added to the usage log on disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The counters as of the last successful flush.
+static FLUSHED: Lazy<Mutex<DayUsage>> = Lazy::new(Default::default);
+
+/// The counters as of every exit change since the last flush, each with the exit that was current until then.
+static EXIT_CHANGES: Lazy<parking_lot::Mutex<Vec<(Option<String>, DayUsage)>>> =
+    Lazy::new(Default::default);
+
+/// The usage database, opened on first use and kept open, which in ephemeral mode is the only copy of it.
+static USAGE_DB: Lazy<parking_lot::Mutex<Option<UsageDb>>> = Lazy::new(Default::default);
+
+/// Runs `f` on the usage database, opening it first if need be. Blocks on the disk.
+pub fn with_usage_db<T>(f: impl FnOnce(&UsageDb) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let mut db = USAGE_DB.lock();
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => db.insert(UsageDb::open(&CONNECT_CONFIG.usage_path)?),
+    };
+    f(db)
+}
+
+fn counters() -> DayUsage {
+    DayUsage {
+        sent_bytes: STATS_SEND_BYTES.load(Ordering::Relaxed),
+        recv_bytes: STATS_RECV_BYTES.load(Ordering::Relaxed),
+        sessions: STATS_SESSIONS.load(Ordering::Relaxed),
+    }
+}
+
+/// Notes that the current exit is about to change from the given one, so that the usage until now is charged to it rather than to the next one.
+pub fn exit_changing(from: Option<String>) {
+    EXIT_CHANGES.lock().push((from, counters()));
+}
+
+/// Periodically adds the bytes and sessions since the last flush to today's totals in the usage log. Never returns.
+pub async fn usage_loop() {
+    loop {
+        smol::Timer::after(FLUSH_INTERVAL).await;
+        if let Err(err) = flush_usage().await {
+            log::warn!("cannot record usage: {:?}", err)
+        }
+    }
+}
+
+/// Adds the bytes and sessions since the last flush to today's totals in the usage log, each charged to the exit that was current when they were counted.
+pub async fn flush_usage() -> anyhow::Result<()> {
+    // held across the write, so that two flushes never count the same bytes
+    let mut last = FLUSHED.lock().await;
+    let changes = std::mem::take(&mut *EXIT_CHANGES.lock());
+    let now = counters();
+    let mut segments = changes.clone();
+    segments.push((current_exit(), now));
+    let mut start = *last;
+    let mut deltas = Vec::with_capacity(segments.len());
+    for (exit, end) in segments {
+        let delta = DayUsage {
+            sent_bytes: end.sent_bytes - start.sent_bytes,
+            recv_bytes: end.recv_bytes - start.recv_bytes,
+            sessions: end.sessions - start.sessions,
+        };
+        if delta.sent_bytes + delta.recv_bytes + delta.sessions > 0 {
+            deltas.push((exit, delta));
+        }
+        start = end;
+    }
+    if deltas.is_empty() {
+        return Ok(());
+    }
+    let result = smol::unblock(move || with_usage_db(|db| db.add_today(&deltas))).await;
+    if result.is_err() {
+        // keep the exit changes for the next try
+        EXIT_CHANGES.lock().splice(0..0, changes);
+    }
+    result?;
+    *last = now;
+    Ok(())
+}
@@ -1,20 +1,55 @@
+#[cfg(target_os = "linux")]
+pub(crate) mod linux_netns;
+
+#[cfg(target_os = "linux")]
+mod linux_app_split;
+
 #[cfg(target_os = "linux")]
 mod linux_routing;
 
+#[cfg(target_os = "linux")]
+mod linux_tun;
+
 #[cfg(target_os = "macos")]
 mod macos_routing;
 
+#[cfg(target_os = "macos")]
+mod macos_utun;
+
+#[cfg(any(windows, target_os = "macos"))]
+pub(crate) mod encrypted_dns;
+
+#[cfg(any(windows, target_os = "macos"))]
+mod hotspot;
+
+#[cfg(any(windows, target_os = "macos"))]
+mod system_dns;
+
+mod dedup;
+pub use dedup::dedup_stats;
+
+mod dns_intercept;
+pub use dns_intercept::dns_intercept_stats;
+
+mod ipv6_refuse;
+pub use ipv6_refuse::ipv6_refused;
+
+mod mtu_blackhole;
+
+mod packet_pool;
+pub use packet_pool::{pool_stats, PooledPacket};
+
 #[cfg(windows)]
 mod windows_routing;
 
+#[cfg(windows)]
+mod windows_wintun;
+
 use std::{
     convert::Infallible, io::BufWriter, num::NonZeroU32, sync::Arc, thread::JoinHandle,
     time::Duration,
 };
-use std::{
-    io::BufReader,
-    sync::atomic::{AtomicU32, Ordering},
-};
+use std::{io::BufReader, sync::atomic::Ordering};
 
 use std::io::{Read, Write};
 
@@ -25,17 +60,17 @@ use anyhow::Context;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Bytes;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 
 use geph_nat::GephNat;
 use governor::{Quota, RateLimiter};
 use once_cell::sync::Lazy;
-use pnet_packet::ip::IpNextHeaderProtocols;
-use pnet_packet::MutablePacket;
 use pnet_packet::{
-    ipv4::Ipv4Packet,
-    tcp::{TcpFlags, TcpPacket},
-    Packet,
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    tcp::{MutableTcpPacket, TcpFlags, TcpPacket},
+    udp::MutableUdpPacket,
+    MutablePacket, Packet,
 };
 use smol::prelude::*;
 
@@ -57,10 +92,10 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
                 loop {
                     let n = up_file.read(&mut bts).expect("vpn up thread failed");
 
-                    let to_send = Bytes::copy_from_slice(&bts[..n]);
+                    let to_send = &bts[..n];
                     #[cfg(target_os = "macos")]
                     let to_send = if to_send.len() >= 4 {
-                        to_send.slice(4..)
+                        &to_send[4..]
                     } else {
                         continue;
                     };
@@ -76,9 +111,13 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
                 log::trace!("vpn dn {}", bts.len());
                 #[cfg(target_os = "macos")]
                 {
+                    // utun wants the address family in front, as a big-endian u32
                     let mut buf = [0u8; 65536];
                     buf[4..][..bts.len()].copy_from_slice(&bts);
-                    buf[3] = 0x02;
+                    buf[3] = match bts.first().map(|b| b >> 4) {
+                        Some(6) => libc::AF_INET6 as u8,
+                        _ => libc::AF_INET as u8,
+                    };
                     let _ = down_file.write(&buf[..bts.len() + 4]);
                 }
                 #[cfg(not(target_os = "macos"))]
@@ -92,17 +131,38 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
     std::thread::Builder::new()
         .name("vpn".into())
         .spawn(|| {
+            #[cfg(any(windows, target_os = "macos"))]
+            system_dns::restore_leftover();
+            if let Some(netns) = CONNECT_CONFIG.netns.as_ref() {
+                #[cfg(target_os = "linux")]
+                {
+                    let device = ::tun::platform::Device::new(
+                        ::tun::Configuration::default()
+                            .name("tun-geph")
+                            .mtu(16384)
+                            .up(),
+                    )
+                    .expect("could not initialize TUN device");
+                    linux_netns::setup_netns(netns, "tun-geph");
+                    return unsafe { fd_vpn_loop(device.as_raw_fd()) };
+                }
+                #[cfg(not(target_os = "linux"))]
+                {
+                    panic!("cannot use network namespace {} outside Linux", netns)
+                }
+            }
             match CONNECT_CONFIG.vpn_mode {
                 Some(VpnMode::Stdio) => {
                     // every packet is prepended with u16le length
                     std::thread::spawn(|| {
                         let mut stdin = BufReader::new(std::io::stdin().lock());
                         // upload
+                        let mut buffer = Vec::new();
                         loop {
                             let len = stdin.read_u16::<LittleEndian>().unwrap() as usize;
-                            let mut buffer = vec![0u8; len];
+                            buffer.resize(len, 0);
                             stdin.read_exact(&mut buffer).unwrap();
-                            vpn_upload(buffer.into())
+                            vpn_upload(&buffer)
                         }
                     });
                     // download
@@ -168,7 +228,7 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
                         if CONNECT_CONFIG.vpn_mode == Some(VpnMode::TunRoute) {
                             #[cfg(target_os = "linux")]
                             {
-                                linux_routing::setup_routing();
+                                linux_routing::setup_routing("tun-geph");
                             }
                             #[cfg(target_os = "macos")]
                             {
@@ -183,6 +243,34 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
                         panic!("cannot use tun modes on non-Unix systems")
                     }
                 }
+                Some(VpnMode::Tun) => {
+                    #[cfg(target_os = "linux")]
+                    {
+                        let device = linux_tun::setup_tun().expect("could not set up TUN device");
+                        unsafe { fd_vpn_loop(device.as_raw_fd()) }
+                    }
+                    #[cfg(target_os = "macos")]
+                    {
+                        let device =
+                            macos_utun::setup_utun().expect("could not set up utun device");
+                        unsafe { fd_vpn_loop(device.as_raw_fd()) }
+                    }
+                    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+                    {
+                        panic!("cannot use tun mode outside Linux and macOS")
+                    }
+                }
+                Some(VpnMode::WinTun) => {
+                    #[cfg(windows)]
+                    {
+                        windows_wintun::start_wintun()
+                    }
+
+                    #[cfg(not(windows))]
+                    {
+                        panic!("cannot use wintun mode outside windows")
+                    }
+                }
                 Some(VpnMode::WinDivert) => {
                     #[cfg(windows)]
                     {
@@ -206,15 +294,15 @@ pub static VPN_SHUFFLE_TASK: Lazy<JoinHandle<Infallible>> = Lazy::new(|| {
         .unwrap()
 });
 
-/// Uploads a packet through the global VPN
-pub fn vpn_upload(pkt: Bytes) {
+/// Uploads a packet through the global VPN, copying it into a pooled buffer
+pub fn vpn_upload(pkt: &[u8]) {
     Lazy::force(&VPN_TASK);
     STATS_SEND_BYTES.fetch_add(pkt.len() as u64, Ordering::Relaxed);
-    let _ = UP_CHANNEL.0.try_send(pkt);
+    let _ = UP_CHANNEL.0.try_send(PooledPacket::copy_from(pkt));
 }
 
 /// Downloads a packet through the global VPN
-pub async fn vpn_download() -> Bytes {
+pub async fn vpn_download() -> PooledPacket {
     log::trace!("called vpn_download");
     Lazy::force(&VPN_TASK);
     let pkt = DOWN_CHANNEL.1.recv_async().await.unwrap();
@@ -222,18 +310,26 @@ pub async fn vpn_download() -> Bytes {
     pkt
 }
 
+/// Downloads a packet through the global VPN, if one is waiting
+pub fn vpn_try_download() -> Option<PooledPacket> {
+    Lazy::force(&VPN_TASK);
+    let pkt = DOWN_CHANNEL.1.try_recv().ok()?;
+    STATS_RECV_BYTES.fetch_add(pkt.len() as u64, Ordering::Relaxed);
+    Some(pkt)
+}
+
 /// Downloads a packet through the global VPN, blockingly
-pub fn vpn_download_blocking() -> Bytes {
+pub fn vpn_download_blocking() -> PooledPacket {
     Lazy::force(&VPN_TASK);
     let pkt = DOWN_CHANNEL.1.recv().unwrap();
     STATS_RECV_BYTES.fetch_add(pkt.len() as u64, Ordering::Relaxed);
     pkt
 }
 
-// Up and down channels
-static UP_CHANNEL: Lazy<(flume::Sender<Bytes>, flume::Receiver<Bytes>)> =
+// Up and down channels, whose packets go back to the pool once they're through
+static UP_CHANNEL: Lazy<(flume::Sender<PooledPacket>, flume::Receiver<PooledPacket>)> =
     Lazy::new(|| flume::bounded(10000));
-static DOWN_CHANNEL: Lazy<(flume::Sender<Bytes>, flume::Receiver<Bytes>)> =
+static DOWN_CHANNEL: Lazy<(flume::Sender<PooledPacket>, flume::Receiver<PooledPacket>)> =
     Lazy::new(|| flume::bounded(10000));
 
 static VPN_TASK: Lazy<std::thread::JoinHandle<()>> = Lazy::new(|| {
@@ -277,61 +373,115 @@ async fn vpn_up_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
             .allow_burst(NonZeroU32::new(100u32).unwrap()),
     );
     loop {
-        let mut bts = UP_CHANNEL.1.recv_async().await.unwrap().to_vec();
-        mangle_dns_up(&mut bts);
+        let mut bts = UP_CHANNEL.1.recv_async().await.unwrap();
+        if dns_intercept::intercept_dns(&bts) {
+            continue;
+        }
+        mtu_blackhole::inspect_up(&mut bts);
         // ACK decimation
         if ack_decimate(&bts).is_some() && limiter.check().is_err() {
             log::trace!("doing ack decimation!");
         } else {
-            let mangled_msg = nat.mangle_upstream_pkt(&bts);
-
-            if let Some(body) = mangled_msg {
-                TUNNEL.send_vpn(body).await?
+            // the tunnel only carries IPv4, with the address the exit assigned
+            if !is_ipv4(&bts) {
+                ipv6_refuse::refuse_ipv6(&bts);
+                continue;
+            }
+            if mangle_upstream(&nat, &mut bts).is_some() {
+                // the session keeps what it is given, so this is the one copy the up path makes
+                TUNNEL.send_vpn(Bytes::copy_from_slice(&bts)).await?
             };
         }
     }
 }
 
-static FAKE_DNS_SERVER: AtomicU32 = AtomicU32::new(0);
-static REAL_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
-
-fn mangle_dns_up(pkt: &mut [u8]) {
-    let pkt_dest: Option<Ipv4Addr> =
-        pnet_packet::ipv4::Ipv4Packet::new(pkt).map(|parsed| parsed.get_destination());
-    if let Some(_pkt_dest) = pkt_dest {
-        let mut mangled = false;
-        if let Some(mut ip_pkt) = pnet_packet::ipv4::MutableIpv4Packet::new(pkt) {
-            if let Some(udp_pkt) = pnet_packet::udp::MutableUdpPacket::new(ip_pkt.payload_mut()) {
-                if udp_pkt.get_destination() == 53 {
-                    mangled = true;
-                }
-            }
-            if mangled {
-                FAKE_DNS_SERVER.store(ip_pkt.get_destination().into(), Ordering::SeqCst);
-                ip_pkt.set_destination(REAL_DNS_SERVER);
-            }
+/// Whether the packet is IPv4, since pnet happily parses anything as whatever it is asked to.
+fn is_ipv4(pkt: &[u8]) -> bool {
+    pkt.first().map(|b| b >> 4) == Some(4)
+}
+
+/// Down loop for vpn
+async fn vpn_down_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
+    let mut dedup = dedup::Dedup::default();
+    loop {
+        let incoming = TUNNEL.recv_vpn().await.context("downstream failed")?;
+        if dedup.is_duplicate(&incoming) {
+            continue;
         }
-        if mangled {
-            fix_all_checksums(pkt);
+        // mangled in place in a pooled buffer, so the down path makes no allocations of its own
+        let mut pkt = PooledPacket::copy_from(&incoming);
+        if is_ipv4(&pkt) && mangle_downstream(&nat, &mut pkt).is_none() {
+            continue;
         }
+        mtu_blackhole::inspect_down(&mut pkt);
+        let _ = DOWN_CHANNEL.0.try_send(pkt);
     }
 }
 
-fn mangle_dns_dn(pkt: &mut [u8]) {
-    let mut mangled = false;
-    if let Some(mut ip_pkt) = pnet_packet::ipv4::MutableIpv4Packet::new(pkt) {
-        if let Some(udp_pkt) = pnet_packet::udp::MutableUdpPacket::new(ip_pkt.payload_mut()) {
-            if udp_pkt.get_source() == 53 {
-                mangled = true;
-            }
+/// Rewrites the source of an upstream IPv4 packet in place, as GephNat::mangle_upstream_pkt does into a fresh buffer.
+fn mangle_upstream(nat: &GephNat, bts: &mut [u8]) -> Option<()> {
+    let mut ip_layer = MutableIpv4Packet::new(bts)?;
+    let src_ip = ip_layer.get_source();
+    let dest_ip = ip_layer.get_destination();
+    match ip_layer.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => {
+            let mut tcp_layer = MutableTcpPacket::new(ip_layer.payload_mut())?;
+            let new_src = nat.rewrite_upstream_src(
+                SocketAddrV4::new(src_ip, tcp_layer.get_source()),
+                SocketAddrV4::new(dest_ip, tcp_layer.get_destination()),
+            );
+            tcp_layer.set_source(new_src.port());
+            ip_layer.set_source(*new_src.ip());
+        }
+        IpNextHeaderProtocols::Udp => {
+            let mut udp_layer = MutableUdpPacket::new(ip_layer.payload_mut())?;
+            let new_src = nat.rewrite_upstream_src(
+                SocketAddrV4::new(src_ip, udp_layer.get_source()),
+                SocketAddrV4::new(dest_ip, udp_layer.get_destination()),
+            );
+            udp_layer.set_source(new_src.port());
+            ip_layer.set_source(*new_src.ip());
         }
-        if mangled {
-            ip_pkt.set_source(FAKE_DNS_SERVER.load(Ordering::SeqCst).into())
+        _ => {
+            let fake = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+            let new_src = nat.rewrite_upstream_src(SocketAddrV4::new(src_ip, 0), fake);
+            ip_layer.set_source(*new_src.ip());
         }
     }
-    if mangled {
-        fix_all_checksums(pkt);
+    fix_all_checksums(bts)
+}
+
+/// Rewrites the destination of a downstream IPv4 packet in place, as GephNat::mangle_downstream_pkt does into a fresh buffer. Returns None for packets that belong to no mapping.
+fn mangle_downstream(nat: &GephNat, bts: &mut [u8]) -> Option<()> {
+    let mut ip_layer = MutableIpv4Packet::new(bts)?;
+    let src_ip = ip_layer.get_source();
+    let dest_ip = ip_layer.get_destination();
+    match ip_layer.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => {
+            let mut tcp_layer = MutableTcpPacket::new(ip_layer.payload_mut())?;
+            let new_dest = nat.rewrite_downstream_dest(
+                SocketAddrV4::new(dest_ip, tcp_layer.get_destination()),
+                SocketAddrV4::new(src_ip, tcp_layer.get_source()),
+            )?;
+            tcp_layer.set_destination(new_dest.port());
+            ip_layer.set_destination(*new_dest.ip());
+        }
+        IpNextHeaderProtocols::Udp => {
+            let mut udp_layer = MutableUdpPacket::new(ip_layer.payload_mut())?;
+            let new_dest = nat.rewrite_downstream_dest(
+                SocketAddrV4::new(dest_ip, udp_layer.get_destination()),
+                SocketAddrV4::new(src_ip, udp_layer.get_source()),
+            )?;
+            udp_layer.set_destination(new_dest.port());
+            ip_layer.set_destination(*new_dest.ip());
+        }
+        _ => {
+            let fake = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+            let new_dest = nat.rewrite_downstream_dest(SocketAddrV4::new(dest_ip, 0), fake)?;
+            ip_layer.set_destination(*new_dest.ip());
+        }
     }
+    fix_all_checksums(bts)
 }
 
 fn fix_all_checksums(bts: &mut [u8]) -> Option<()> {
@@ -361,21 +511,11 @@ fn fix_all_checksums(bts: &mut [u8]) -> Option<()> {
     Some(())
 }
 
-/// Down loop for vpn
-async fn vpn_down_loop(nat: Arc<GephNat>) -> anyhow::Result<()> {
-    loop {
-        let incoming = TUNNEL.recv_vpn().await.context("downstream failed")?;
-        let mangled_incoming = nat.mangle_downstream_pkt(&incoming);
-        if let Some(mangled_bts) = mangled_incoming {
-            let mut mangled_bts = mangled_bts.to_vec();
-            mangle_dns_dn(&mut mangled_bts);
-            let _ = DOWN_CHANNEL.0.try_send(mangled_bts.into());
-        }
-    }
-}
-
 /// returns ok if it's an ack that needs to be decimated
 fn ack_decimate(bts: &[u8]) -> Option<u16> {
+    if !is_ipv4(bts) {
+        return None;
+    }
     let parsed = Ipv4Packet::new(bts)?;
     // log::warn!("******** VPN UP: {:?}", parsed);
     let parsed = TcpPacket::new(parsed.payload())?;
@@ -0,0 +1,97 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Exact copies of a UDP packet arriving within this long of it are dropped. Middleboxes that duplicate UDP send the copies right behind the original.
+const DUP_WINDOW: Duration = Duration::from_millis(100);
+
+/// At most this many recent packets are remembered, however many arrive within the window.
+const MAX_REMEMBERED: usize = 8192;
+
+static SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+static SUPPRESSED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// How many duplicate packets came down the VPN and were dropped, and how many bytes they took up, since startup.
+pub fn dedup_stats() -> (u64, u64) {
+    (
+        SUPPRESSED.load(Ordering::Relaxed),
+        SUPPRESSED_BYTES.load(Ordering::Relaxed),
+    )
+}
+
+/// The IP protocol number of UDP.
+const UDP: u8 = 17;
+
+/// Whether the packet is UDP, over IPv4 or over IPv6 with no extension headers.
+fn is_udp(pkt: &[u8]) -> bool {
+    match pkt.first().map(|b| b >> 4) {
+        Some(4) => pkt.get(9) == Some(&UDP),
+        Some(6) => pkt.get(6) == Some(&UDP),
+        _ => false,
+    }
+}
+
+/// Remembers the UDP packets that came down the VPN lately, by a hash of their bytes.
+#[derive(Default)]
+pub struct Dedup {
+    seen: HashSet<u64>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Dedup {
+    /// Whether the packet is an exact copy of a UDP packet that came down within the window, counting it if so. Anything but UDP always passes: identical TCP segments are legitimate, like the duplicate ACKs that trigger fast retransmit, which go out back to back and can be byte for byte the same.
+    pub fn is_duplicate(&mut self, pkt: &[u8]) -> bool {
+        if !is_udp(pkt) {
+            return false;
+        }
+        let now = Instant::now();
+        while let Some(&(hash, when)) = self.order.front() {
+            if now.duration_since(when) < DUP_WINDOW && self.order.len() < MAX_REMEMBERED {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&hash);
+        }
+        let mut hasher = DefaultHasher::new();
+        pkt.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.seen.contains(&hash) {
+            SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+            SUPPRESSED_BYTES.fetch_add(pkt.len() as u64, Ordering::Relaxed);
+            return true;
+        }
+        self.seen.insert(hash);
+        self.order.push_back((hash, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare IPv4 header carrying the given protocol, followed by a few payload bytes.
+    fn packet(protocol: u8) -> Vec<u8> {
+        let mut pkt = vec![0x45, 0, 0, 24, 0, 0, 0x40, 0, 64, protocol, 0, 0];
+        pkt.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        pkt.extend_from_slice(&[1, 2, 3, 4]);
+        pkt
+    }
+
+    #[test]
+    fn drops_duplicate_udp() {
+        let mut dedup = Dedup::default();
+        assert!(!dedup.is_duplicate(&packet(UDP)));
+        assert!(dedup.is_duplicate(&packet(UDP)));
+    }
+
+    #[test]
+    fn passes_identical_tcp_segments() {
+        let mut dedup = Dedup::default();
+        assert!(!dedup.is_duplicate(&packet(6)));
+        assert!(!dedup.is_duplicate(&packet(6)));
+    }
+}
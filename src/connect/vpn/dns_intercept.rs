@@ -0,0 +1,213 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use pnet_packet::{
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    tcp::{MutableTcpPacket, TcpFlags, TcpPacket},
+    udp::{MutableUdpPacket, UdpPacket},
+    Packet,
+};
+
+use crate::connect::dns::TUNNEL_RESOLVER;
+
+use super::{PooledPacket, DOWN_CHANNEL};
+
+static ANSWERED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static REFUSED_TCP: AtomicU64 = AtomicU64::new(0);
+
+/// How many DNS queries seen in the VPN were answered through the tunnel resolver, how many it could not answer, and how many DNS-over-TCP packets were refused with a reset, since startup.
+pub fn dns_intercept_stats() -> (u64, u64, u64) {
+    (
+        ANSWERED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        REFUSED_TCP.load(Ordering::Relaxed),
+    )
+}
+
+/// A UDP datagram to port 53, with what is needed to answer it.
+struct Query {
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    payload: PooledPacket,
+}
+
+/// Takes the packet if it is DNS, whatever server it is addressed to, so that no DNS leaves the VPN as plaintext. UDP queries are answered through the tunnel resolver as if by the server they were sent to. DNS over TCP, which clients fall back to for truncated answers, is refused with a reset, so that they give up on it right away rather than time out; answers through the tunnel resolver are never truncated anyway. Returns whether the packet was taken.
+pub fn intercept_dns(pkt: &[u8]) -> bool {
+    match pkt.first().map(|b| b >> 4) {
+        Some(4) => match Ipv4Packet::new(pkt) {
+            Some(ip) => intercept_transport(
+                ip.get_next_level_protocol(),
+                IpAddr::from(ip.get_source()),
+                IpAddr::from(ip.get_destination()),
+                ip.payload(),
+            ),
+            None => false,
+        },
+        Some(6) => match Ipv6Packet::new(pkt) {
+            Some(ip) => intercept_transport(
+                ip.get_next_header(),
+                IpAddr::from(ip.get_source()),
+                IpAddr::from(ip.get_destination()),
+                ip.payload(),
+            ),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Looks at the transport layer in place, copying out only what is DNS.
+fn intercept_transport(
+    protocol: IpNextHeaderProtocol,
+    src: IpAddr,
+    dst: IpAddr,
+    transport: &[u8],
+) -> bool {
+    match protocol {
+        IpNextHeaderProtocols::Udp => {
+            let udp = match UdpPacket::new(transport) {
+                Some(udp) if udp.get_destination() == 53 => udp,
+                _ => return false,
+            };
+            let query = Query {
+                src,
+                dst,
+                src_port: udp.get_source(),
+                payload: PooledPacket::copy_from(udp.payload()),
+            };
+            smolscale::spawn(answer(query)).detach();
+            true
+        }
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(transport) {
+            Some(tcp) if tcp.get_destination() == 53 => {
+                REFUSED_TCP.fetch_add(1, Ordering::Relaxed);
+                // never answer a reset with a reset
+                if tcp.get_flags() & TcpFlags::RST == 0 {
+                    if let Some(pkt) = build_reset(dst, src, &tcp) {
+                        let _ = DOWN_CHANNEL.0.try_send(PooledPacket::copy_from(&pkt));
+                    }
+                }
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+async fn answer(query: Query) {
+    let response = match TUNNEL_RESOLVER.request(&query.payload).await {
+        Some(response) => response,
+        None => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            log::debug!("could not resolve DNS query to {} in the VPN", query.dst);
+            return;
+        }
+    };
+    // the answer comes back from the server the query was addressed to
+    let pkt = match build_reply(query.dst, query.src, query.src_port, &response) {
+        Some(pkt) => pkt,
+        None => return,
+    };
+    ANSWERED.fetch_add(1, Ordering::Relaxed);
+    let _ = DOWN_CHANNEL.0.try_send(PooledPacket::copy_from(&pkt));
+}
+
+/// Builds a UDP packet from port 53 of `src` to `dst`, with checksums.
+fn build_reply(src: IpAddr, dst: IpAddr, dst_port: u16, payload: &[u8]) -> Option<Vec<u8>> {
+    let mut segment = vec![0u8; 8 + payload.len()];
+    let mut udp = MutableUdpPacket::new(&mut segment)?;
+    udp.set_source(53);
+    udp.set_destination(dst_port);
+    udp.set_length(u16::try_from(8 + payload.len()).ok()?);
+    udp.set_payload(payload);
+    let checksum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pnet_packet::udp::ipv4_checksum(&udp.to_immutable(), &src, &dst)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            pnet_packet::udp::ipv6_checksum(&udp.to_immutable(), &src, &dst)
+        }
+        _ => return None,
+    };
+    udp.set_checksum(checksum);
+    wrap_ip(src, dst, IpNextHeaderProtocols::Udp, &segment)
+}
+
+/// Builds the reset that refuses the given TCP segment, sent from `src` back to `dst`, as RFC 793 has a closed port do.
+fn build_reset(src: IpAddr, dst: IpAddr, incoming: &TcpPacket) -> Option<Vec<u8>> {
+    let mut segment = vec![0u8; 20];
+    let mut tcp = MutableTcpPacket::new(&mut segment)?;
+    tcp.set_source(incoming.get_destination());
+    tcp.set_destination(incoming.get_source());
+    tcp.set_data_offset(5);
+    let flags = incoming.get_flags();
+    if flags & TcpFlags::ACK != 0 {
+        tcp.set_sequence(incoming.get_acknowledgement());
+        tcp.set_flags(TcpFlags::RST);
+    } else {
+        // everything the segment takes up in sequence space is acknowledged
+        let len = incoming.payload().len() as u32
+            + u32::from(flags & TcpFlags::SYN != 0)
+            + u32::from(flags & TcpFlags::FIN != 0);
+        tcp.set_sequence(0);
+        tcp.set_acknowledgement(incoming.get_sequence().wrapping_add(len));
+        tcp.set_flags(TcpFlags::RST | TcpFlags::ACK);
+    }
+    let checksum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pnet_packet::tcp::ipv4_checksum(&tcp.to_immutable(), &src, &dst)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            pnet_packet::tcp::ipv6_checksum(&tcp.to_immutable(), &src, &dst)
+        }
+        _ => return None,
+    };
+    tcp.set_checksum(checksum);
+    wrap_ip(src, dst, IpNextHeaderProtocols::Tcp, &segment)
+}
+
+/// Puts a transport segment, checksum and all, into an IP packet from `src` to `dst`.
+pub(super) fn wrap_ip(
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: IpNextHeaderProtocol,
+    segment: &[u8],
+) -> Option<Vec<u8>> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut buf = vec![0u8; 20 + segment.len()];
+            let mut ip = MutableIpv4Packet::new(&mut buf)?;
+            ip.set_version(4);
+            ip.set_header_length(5);
+            ip.set_total_length(u16::try_from(20 + segment.len()).ok()?);
+            ip.set_ttl(64);
+            ip.set_next_level_protocol(protocol);
+            ip.set_source(src);
+            ip.set_destination(dst);
+            let checksum = pnet_packet::ipv4::checksum(&ip.to_immutable());
+            ip.set_checksum(checksum);
+            ip.set_payload(segment);
+            Some(buf)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut buf = vec![0u8; 40 + segment.len()];
+            let mut ip = MutableIpv6Packet::new(&mut buf)?;
+            ip.set_version(6);
+            ip.set_payload_length(u16::try_from(segment.len()).ok()?);
+            ip.set_next_header(protocol);
+            ip.set_hop_limit(64);
+            ip.set_source(src);
+            ip.set_destination(dst);
+            ip.set_payload(segment);
+            Some(buf)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::{IpAddr, ToSocketAddrs};
+
+use http_types::Url;
+
+use crate::connect::CONNECT_CONFIG;
+
+/// The address the system resolver is pointed at when there is no DNS-over-HTTPS upstream. The VPN answers DNS queries to any server through the tunnel resolver, so this is the resolver that answers plain DNS through the tunnel rather than one that matters.
+const PLAIN_TUNNEL_DNS: &str = "1.0.0.1";
+
+/// The DNS-over-HTTPS upstream given with --doh-upstream, as an address of its host and the URL template for it. Resolving the host here goes through the VPN, like any other query, and so through the tunnel.
+fn doh_server() -> Option<(IpAddr, String)> {
+    let template = CONNECT_CONFIG.doh_upstream.as_ref()?;
+    let resolved = Url::parse(template)
+        .map_err(anyhow::Error::from)
+        .and_then(|url| {
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow::anyhow!("no host"))?
+                .trim_matches(|c| c == '[' || c == ']')
+                .to_string();
+            let port = url.port().unwrap_or(443);
+            (host.as_str(), port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{} has no addresses", host))
+        });
+    match resolved {
+        Ok(addr) => Some((addr.ip(), template.clone())),
+        Err(err) => {
+            log::warn!(
+                "cannot resolve the DNS-over-HTTPS upstream {}: {:?}",
+                template,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Points the system resolver into the tunnel. If there is a DNS-over-HTTPS upstream, its server is also registered for DoH with auto-upgrade and no UDP fallback, and the default interface is pointed at it, so that the OS's encrypted DNS goes through the tunnel to the upstream rather than upgrading some other resolver. Registering needs Windows 11; everything changed is put back on exit, or at the next startup after a crash.
+#[cfg(windows)]
+pub fn register_encrypted_dns() {
+    let resolver = match doh_server() {
+        Some((server, template)) => {
+            let server = server.to_string();
+            match super::system_dns::register_doh_server(&server, &template) {
+                Ok(()) => log::info!("registered {} as an encrypted DNS resolver", server),
+                Err(err) => log::warn!(
+                    "could not register encrypted DNS (needs Windows 11): {:?}",
+                    err
+                ),
+            }
+            server
+        }
+        None => PLAIN_TUNNEL_DNS.to_string(),
+    };
+    super::system_dns::point_system_dns(&resolver);
+}
+
+#[cfg(windows)]
+pub(crate) fn powershell(script: &str) -> anyhow::Result<String> {
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim())
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Points the system resolver into the tunnel. If there is a DNS-over-HTTPS upstream, also writes a configuration profile that makes it the system's DoH resolver. macOS only installs DNS settings profiles with the user's approval, so this only tells the user where to find it.
+#[cfg(target_os = "macos")]
+pub fn register_encrypted_dns() {
+    let (server, template) = match doh_server() {
+        Some(doh) => doh,
+        None => {
+            super::system_dns::point_system_dns(PLAIN_TUNNEL_DNS);
+            return;
+        }
+    };
+    super::system_dns::point_system_dns(&server.to_string());
+    if crate::storage::ephemeral() {
+        log::info!("not writing an encrypted DNS profile in ephemeral mode");
+        return;
+    }
+    let path = dirs::config_dir()
+        .unwrap_or_default()
+        .join("geph4-encrypted-dns.mobileconfig");
+    let profile = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>DNSSettings</key>
+            <dict>
+                <key>DNSProtocol</key>
+                <string>HTTPS</string>
+                <key>ServerAddresses</key>
+                <array>
+                    <string>{dns}</string>
+                </array>
+                <key>ServerURL</key>
+                <string>{doh}</string>
+            </dict>
+            <key>PayloadDisplayName</key>
+            <string>Geph encrypted DNS</string>
+            <key>PayloadIdentifier</key>
+            <string>io.geph.encrypted-dns.settings</string>
+            <key>PayloadType</key>
+            <string>com.apple.dnsSettings.managed</string>
+            <key>PayloadUUID</key>
+            <string>8C2D0B5E-5B7A-4F4C-9A8E-2F1D6B3C7E10</string>
+            <key>PayloadVersion</key>
+            <integer>1</integer>
+        </dict>
+    </array>
+    <key>PayloadDisplayName</key>
+    <string>Geph encrypted DNS</string>
+    <key>PayloadIdentifier</key>
+    <string>io.geph.encrypted-dns</string>
+    <key>PayloadType</key>
+    <string>Configuration</string>
+    <key>PayloadUUID</key>
+    <string>4E9B7C21-0D3A-4B6E-8F52-A17C9D04E3B8</string>
+    <key>PayloadVersion</key>
+    <integer>1</integer>
+</dict>
+</plist>
+"#,
+        dns = server,
+        doh = template
+    );
+    match std::fs::write(&path, profile) {
+        Ok(()) => log::info!(
+            "to make DoH-capable apps resolve through the tunnel, install {:?} in System Settings > Privacy & Security > Profiles",
+            path
+        ),
+        Err(err) => log::warn!("could not write encrypted DNS profile: {:?}", err),
+    }
+}
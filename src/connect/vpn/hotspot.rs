@@ -0,0 +1,76 @@
+/// The subnet the OS hands out to devices sharing our connection. Windows' Internet Connection Sharing always uses 192.168.137.0/24, and macOS's Internet Sharing 192.168.2.0/24.
+#[cfg(windows)]
+pub const HOTSPOT_SUBNET: (&str, &str) = ("192.168.137.0", "192.168.137.255");
+#[cfg(target_os = "macos")]
+pub const HOTSPOT_SUBNET: &str = "192.168.2.0/24";
+
+/// The bridge interface macOS's Internet Sharing puts shared devices on.
+#[cfg(target_os = "macos")]
+pub const HOTSPOT_BRIDGE: &str = "bridge100";
+
+/// Turns on the Mobile Hotspot, sharing the current internet connection profile, and turns it back off on exit. Shared devices' traffic is forwarded through us, so the routing code must capture it; their DNS goes to the hotspot's own resolver, which resolves through the tunnel like any local query.
+#[cfg(windows)]
+pub fn start_hotspot() -> anyhow::Result<()> {
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'; {} \
+        [void]$m.StartTetheringAsync(); \
+        for ($n = 0; $n -lt 20 -and $m.TetheringOperationalState -ne 'On'; $n++) {{ Start-Sleep -Milliseconds 500 }}; \
+        if ($m.TetheringOperationalState -ne 'On') {{ throw 'Mobile Hotspot did not turn on' }}",
+        TETHERING_MANAGER
+    );
+    super::encrypted_dns::powershell(&script)?;
+    log::info!("turned on the Mobile Hotspot");
+    shutdown_hooks::add_shutdown_hook(stop_hotspot);
+    Ok(())
+}
+
+/// Looks up the tethering manager of the current internet connection profile as `$m`.
+#[cfg(windows)]
+const TETHERING_MANAGER: &str = "\
+    $p = [Windows.Networking.Connectivity.NetworkInformation, Windows.Networking.Connectivity, ContentType = WindowsRuntime]::GetInternetConnectionProfile(); \
+    $m = [Windows.Networking.NetworkOperators.NetworkOperatorTetheringManager, Windows.Networking.NetworkOperators, ContentType = WindowsRuntime]::CreateFromConnectionProfile($p);";
+
+#[cfg(windows)]
+extern "C" fn stop_hotspot() {
+    let script = format!("{} [void]$m.StopTetheringAsync()", TETHERING_MANAGER);
+    if let Err(err) = super::encrypted_dns::powershell(&script) {
+        log::warn!("could not turn off the Mobile Hotspot: {:?}", err);
+    }
+}
+
+/// Turns on Internet Sharing, sharing the default interface to whatever devices it was last set up for in System Settings, and turns it back off on exit. Shared devices' traffic is routed into the tunnel by pf; their DNS goes to the Mac's own resolver, which resolves through the tunnel like any local query.
+#[cfg(target_os = "macos")]
+pub fn start_hotspot(default_interface: &str) -> anyhow::Result<()> {
+    run(&format!(
+        "defaults write /Library/Preferences/SystemConfiguration/com.apple.nat NAT -dict-add Enabled -int 1 PrimaryInterface -dict Device {} Enabled -int 1",
+        default_interface
+    ))?;
+    run("launchctl load -w /System/Library/LaunchDaemons/com.apple.NetworkSharing.plist")?;
+    log::info!("turned on Internet Sharing from {}", default_interface);
+    shutdown_hooks::add_shutdown_hook(stop_hotspot);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+extern "C" fn stop_hotspot() {
+    let res = run("launchctl unload -w /System/Library/LaunchDaemons/com.apple.NetworkSharing.plist")
+        .and_then(|_| {
+            run("defaults write /Library/Preferences/SystemConfiguration/com.apple.nat NAT -dict-add Enabled -int 0")
+        });
+    if let Err(err) = res {
+        log::warn!("could not turn off Internet Sharing: {:?}", err);
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn run(cmd: &str) -> anyhow::Result<()> {
+    log::debug!("running {}", cmd);
+    let status = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(cmd)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {}", cmd, status)
+    }
+    Ok(())
+}
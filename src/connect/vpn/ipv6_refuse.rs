@@ -0,0 +1,70 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use pnet_packet::{
+    icmpv6::{Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
+    ip::IpNextHeaderProtocols,
+    ipv6::Ipv6Packet,
+    Packet,
+};
+
+use super::{dns_intercept::wrap_ip, PooledPacket, DOWN_CHANNEL};
+
+static REFUSED: AtomicU64 = AtomicU64::new(0);
+
+/// An ICMPv6 error may not make the packet carrying it larger than the minimum IPv6 MTU.
+const MIN_MTU: usize = 1280;
+
+/// How many IPv6 packets seen in the VPN were refused, since startup.
+pub fn ipv6_refused() -> u64 {
+    REFUSED.load(Ordering::Relaxed)
+}
+
+/// Refuses an IPv6 packet, which the tunnel cannot carry: the exit only hands out an IPv4 address, and sending IPv6 out unmangled would have the exit forward packets whose source address belongs to the client's own network. The packet is answered with "no route to destination", so that apps fall back to IPv4 right away rather than time out. ICMPv6 errors and multicast, like neighbor discovery, are never answered.
+pub fn refuse_ipv6(pkt: &[u8]) {
+    REFUSED.fetch_add(1, Ordering::Relaxed);
+    let ip = match Ipv6Packet::new(pkt) {
+        Some(ip) => ip,
+        None => return,
+    };
+    // nothing can be sent from a multicast address, or to an unspecified one
+    if ip.get_source().is_unspecified()
+        || ip.get_source().is_multicast()
+        || ip.get_destination().is_multicast()
+    {
+        return;
+    }
+    if ip.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+        match Icmpv6Packet::new(ip.payload()) {
+            Some(icmp) if icmp.get_icmpv6_type().0 >= 128 => {}
+            _ => return,
+        }
+    }
+    if let Some(reply) = build_unreachable(pkt, &ip) {
+        let _ = DOWN_CHANNEL.0.try_send(PooledPacket::copy_from(&reply));
+    }
+}
+
+fn build_unreachable(pkt: &[u8], ip: &Ipv6Packet) -> Option<Vec<u8>> {
+    // 4 bytes of type, code and checksum, 4 unused, then as much of the offending packet as fits
+    let quoted = &pkt[..pkt.len().min(MIN_MTU - 40 - 8)];
+    let mut segment = vec![0u8; 8 + quoted.len()];
+    let mut icmp = MutableIcmpv6Packet::new(&mut segment)?;
+    icmp.set_icmpv6_type(Icmpv6Types::DestinationUnreachable);
+    icmp.set_icmpv6_code(Icmpv6Code(0));
+    icmp.payload_mut()[4..].copy_from_slice(quoted);
+    let checksum = pnet_packet::icmpv6::checksum(
+        &icmp.to_immutable(),
+        &ip.get_destination(),
+        &ip.get_source(),
+    );
+    icmp.set_checksum(checksum);
+    wrap_ip(
+        IpAddr::V6(ip.get_destination()),
+        IpAddr::V6(ip.get_source()),
+        IpNextHeaderProtocols::Icmpv6,
+        &segment,
+    )
+}
@@ -0,0 +1,274 @@
+use std::{collections::HashMap, path::Path, process::Command, time::Duration};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::connect::CONNECT_CONFIG;
+
+/// The fwmark of traffic from apps that bypass the VPN.
+const BYPASS_MARK: u32 = 0x8965;
+
+/// The fwmark of traffic from apps forced through the VPN.
+const FORCE_MARK: u32 = 0x8966;
+
+/// Where the unified (v2) cgroup hierarchy is mounted. The iptables cgroup match only understands v2 paths.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How often processes are scanned for newly started apps to move into our cgroups.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Commands, as argument vectors, that undo what [setup_app_split] did, run in reverse order on teardown.
+static UNDO: Lazy<Mutex<Vec<Vec<String>>>> = Lazy::new(Default::default);
+
+/// One direction of per-app split tunneling: the apps listed in --bypass-app or --force-app.
+struct AppGroup {
+    /// Cgroup paths given directly, relative to the cgroup root.
+    cgroups: Vec<String>,
+    /// Executable names, whose processes get moved into a cgroup of our own.
+    names: Vec<String>,
+    /// Our own cgroup for the named processes, like "geph-bypass".
+    own_cgroup: &'static str,
+    mark: u32,
+    /// The cgroup each process we moved came from, to move it back on teardown.
+    original: Mutex<HashMap<u32, String>>,
+}
+
+impl AppGroup {
+    fn new(apps: &[String], own_cgroup: &'static str, mark: u32) -> Self {
+        let (cgroups, names): (Vec<_>, Vec<_>) =
+            apps.iter().cloned().partition(|app| app.starts_with('/'));
+        Self {
+            cgroups: cgroups
+                .into_iter()
+                .map(|path| path.trim_start_matches('/').to_string())
+                .collect(),
+            names,
+            own_cgroup,
+            mark,
+            original: Default::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cgroups.is_empty() && self.names.is_empty()
+    }
+
+    /// Marks the group's traffic, creating our own cgroup if any process names were given.
+    fn setup(&self) -> anyhow::Result<()> {
+        let mut paths = self.cgroups.clone();
+        if !self.names.is_empty() {
+            std::fs::create_dir_all(format!("{}/{}", CGROUP_ROOT, self.own_cgroup))?;
+            paths.push(self.own_cgroup.to_string());
+        }
+        for path in paths {
+            let rule = |action: &str| {
+                args(&[
+                    "iptables",
+                    "-t",
+                    "mangle",
+                    action,
+                    "OUTPUT",
+                    "-m",
+                    "cgroup",
+                    "--path",
+                    &path,
+                    "-j",
+                    "MARK",
+                    "--set-mark",
+                    &self.mark.to_string(),
+                ])
+            };
+            run_undoable(&rule("-A"), &rule("-D"))?;
+        }
+        Ok(())
+    }
+
+    /// Moves every running process whose executable has one of the group's names into our own cgroup, remembering where it came from. Their children then start out in it too.
+    fn scan(&self) {
+        if self.names.is_empty() {
+            return;
+        }
+        let procs = format!("{}/{}/cgroup.procs", CGROUP_ROOT, self.own_cgroup);
+        let entries = match std::fs::read_dir("/proc") {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("cannot list processes: {:?}", err);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            // comm is truncated to 15 bytes and can be changed by the process itself, unlike its executable
+            let exe = match exe_name(&entry.path()) {
+                Some(exe) => exe,
+                None => continue,
+            };
+            if !self.names.iter().any(|name| *name == exe) {
+                continue;
+            }
+            let cgroup = match cgroup_of(pid) {
+                Some(cgroup) => cgroup,
+                None => continue,
+            };
+            if cgroup == format!("/{}", self.own_cgroup) {
+                continue;
+            }
+            log::debug!("moving {} ({}) into {}", exe, pid, self.own_cgroup);
+            match std::fs::write(&procs, pid.to_string()) {
+                Ok(()) => {
+                    self.original.lock().insert(pid, cgroup);
+                }
+                Err(err) => log::warn!(
+                    "cannot move process {} into {}: {:?}",
+                    pid,
+                    self.own_cgroup,
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Moves our processes back to the cgroups they came from, so that our own cgroup can be removed. Children started inside our cgroup go back to where their nearest moved ancestor came from, and anything else, or whose cgroup is gone, to the root cgroup.
+    fn teardown(&self) {
+        if self.names.is_empty() {
+            return;
+        }
+        let original = std::mem::take(&mut *self.original.lock());
+        let dir = format!("{}/{}", CGROUP_ROOT, self.own_cgroup);
+        let pids = std::fs::read_to_string(format!("{}/cgroup.procs", dir)).unwrap_or_default();
+        for pid in pids.lines().filter_map(|pid| pid.parse::<u32>().ok()) {
+            let back = ancestors(pid)
+                .find_map(|pid| original.get(&pid))
+                .map(|cgroup| format!("{}{}/cgroup.procs", CGROUP_ROOT, cgroup));
+            let moved_back = match back {
+                Some(procs) => std::fs::write(procs, pid.to_string()).is_ok(),
+                None => false,
+            };
+            if !moved_back {
+                let _ = std::fs::write(format!("{}/cgroup.procs", CGROUP_ROOT), pid.to_string());
+            }
+        }
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+/// The file name of the executable a process runs, given its /proc directory.
+fn exe_name(proc_dir: &Path) -> Option<String> {
+    let exe = std::fs::read_link(proc_dir.join("exe")).ok()?;
+    let name = exe.file_name()?.to_string_lossy().into_owned();
+    // an executable replaced on disk, like after an upgrade, still shows its old name
+    Some(name.trim_end_matches(" (deleted)").to_string())
+}
+
+/// The cgroup v2 path of a process, like "/user.slice/user-1000.slice/session-2.scope".
+fn cgroup_of(pid: u32) -> Option<String> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.to_string())
+}
+
+/// The process itself, followed by its parent, grandparent and so on.
+fn ancestors(pid: u32) -> impl Iterator<Item = u32> {
+    std::iter::successors(Some(pid), |&pid| {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // the fields after the parenthesized command are "state ppid ..."
+        let ppid = stat.rsplit_once(')')?.1.split_whitespace().nth(1)?;
+        ppid.parse().ok().filter(|&ppid| ppid > 1)
+    })
+}
+
+static GROUPS: Lazy<[AppGroup; 2]> = Lazy::new(|| {
+    [
+        AppGroup::new(&CONNECT_CONFIG.bypass_app, "geph-bypass", BYPASS_MARK),
+        AppGroup::new(&CONNECT_CONFIG.force_app, "geph-force", FORCE_MARK),
+    ]
+});
+
+/// Sets up per-app split tunneling with fwmark-based policy routing: traffic from the apps is marked by cgroup, and the marks are routed around the tunnel or into it, ahead of any subnet rules. Must run after the routing table 8964 exists.
+pub fn setup_app_split() -> anyhow::Result<()> {
+    if GROUPS.iter().all(|group| group.is_empty()) {
+        return Ok(());
+    }
+    for group in GROUPS.iter() {
+        group.setup()?;
+    }
+    let bypass_mark = BYPASS_MARK.to_string();
+    let force_mark = FORCE_MARK.to_string();
+    let ip_rule = |action: &str, mark: &str, table: &str, pref: &str| {
+        args(&[
+            "ip", "rule", action, "fwmark", mark, "lookup", table, "pref", pref,
+        ])
+    };
+    run_undoable(
+        &ip_rule("add", &bypass_mark, "main", "2"),
+        &ip_rule("del", &bypass_mark, "main", "2"),
+    )?;
+    run_undoable(
+        &ip_rule("add", &force_mark, "8964", "3"),
+        &ip_rule("del", &force_mark, "8964", "3"),
+    )?;
+    // bypassing apps keep using the system's DNS rather than being redirected to ours
+    let nat_return = |action: &str| {
+        args(&[
+            "iptables",
+            "-t",
+            "nat",
+            action,
+            "OUTPUT",
+            "-m",
+            "mark",
+            "--mark",
+            &bypass_mark,
+            "-j",
+            "RETURN",
+        ])
+    };
+    run_undoable(&nat_return("-I"), &nat_return("-D"))?;
+    std::thread::spawn(|| loop {
+        for group in GROUPS.iter() {
+            group.scan();
+        }
+        std::thread::sleep(SCAN_INTERVAL);
+    });
+    Ok(())
+}
+
+/// Undoes [setup_app_split].
+pub fn teardown_app_split() {
+    let mut undo = UNDO.lock();
+    while let Some(cmd) = undo.pop() {
+        let _ = command(&cmd).status();
+    }
+    for group in GROUPS.iter() {
+        group.teardown();
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// A command run directly, never through a shell, so that user-given paths among its arguments stay single arguments. Finds ip and iptables in sbin even when PATH leaves it out.
+fn command(cmd: &[String]) -> Command {
+    let path = std::env::var("PATH").unwrap_or_default();
+    let mut command = Command::new(&cmd[0]);
+    command
+        .args(&cmd[1..])
+        .env("PATH", format!("{}:/usr/sbin:/sbin", path));
+    command
+}
+
+fn run_undoable(cmd: &[String], undo: &[String]) -> anyhow::Result<()> {
+    log::debug!("running {:?}", cmd);
+    let status = command(cmd).status()?;
+    if !status.success() {
+        anyhow::bail!("{:?} failed with {}", cmd, status)
+    }
+    UNDO.lock().push(undo.to_vec());
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::{
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use once_cell::sync::OnceCell;
+use signal_hook::iterator::Signals;
+
+/// Whether the network namespace is set up and ready for programs to run in.
+pub static NETNS_READY: AtomicBool = AtomicBool::new(false);
+
+static NETNS_NAME: OnceCell<String> = OnceCell::new();
+
+/// Moves the TUN device into a fresh network namespace, where it is the only way out, so that programs run in that namespace cannot leak traffic around Geph.
+pub fn setup_netns(name: &str, tun_name: &str) {
+    NETNS_NAME
+        .set(name.to_string())
+        .expect("netns set up twice");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(include_str!("linux_netns_setup.sh"))
+        .env("GEPH_NETNS", name)
+        .env("GEPH_TUN", tun_name)
+        .status()
+        .expect("cannot run ip");
+    if !status.success() {
+        panic!("could not set up network namespace {}", name)
+    }
+    unsafe {
+        libc::atexit(teardown_netns);
+    }
+    std::thread::spawn(|| {
+        let mut signals = Signals::new([libc::SIGABRT, libc::SIGTERM, libc::SIGINT])
+            .expect("did not register signal handler properly");
+        if signals.forever().next().is_some() {
+            teardown_netns();
+            std::process::exit(-1)
+        }
+    });
+    log::info!("network namespace {} is ready", name);
+    NETNS_READY.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn teardown_netns() {
+    if let Some(name) = NETNS_NAME.get() {
+        log::debug!("tearing down network namespace {}", name);
+        let _ = Command::new("sh")
+            .arg("-c")
+            .arg("export PATH=$PATH:/usr/sbin/:/sbin/; ip netns del \"$GEPH_NETNS\"; rm -rf \"/etc/netns/$GEPH_NETNS\"")
+            .env("GEPH_NETNS", name)
+            .status();
+    }
+}
@@ -0,0 +1,12 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+ip netns del "$GEPH_NETNS" 2>/dev/null
+set -e
+ip netns add "$GEPH_NETNS"
+ip link set "$GEPH_TUN" netns "$GEPH_NETNS"
+ip -n "$GEPH_NETNS" link set lo up
+ip -n "$GEPH_NETNS" addr add 100.64.89.64 peer 100.64.0.1 dev "$GEPH_TUN"
+ip -n "$GEPH_NETNS" link set "$GEPH_TUN" up
+ip -n "$GEPH_NETNS" route add default dev "$GEPH_TUN"
+# the namespace has no route to the host's resolver. Every DNS query that enters the TUN is answered by the tunnel resolver, whatever server it is addressed to, so point at the peer rather than any public server
+mkdir -p "/etc/netns/$GEPH_NETNS"
+echo "nameserver 100.64.0.1" > "/etc/netns/$GEPH_NETNS/resolv.conf"
@@ -1,14 +1,23 @@
 use std::{process::Command, time::Duration};
 
-use crate::{config::CacheStaleGuard, connect::tunnel::TunnelStatus};
+use crate::{
+    config::{CacheStaleGuard, VpnMode},
+    connect::{
+        split_tunnel::{split_rules, Route},
+        tunnel::TunnelStatus,
+    },
+};
 use dashmap::DashMap;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use signal_hook::iterator::Signals;
 use std::net::{IpAddr, Ipv4Addr};
 
 use crate::connect::{CONNECT_CONFIG, TUNNEL, TUNNEL_STATUS_CALLBACK};
 
+use super::linux_app_split::{setup_app_split, teardown_app_split};
+
 struct SingleWhitelister {
     dest: IpAddr,
 }
@@ -18,10 +27,7 @@ impl Drop for SingleWhitelister {
         log::debug!("DROPPING whitelist to {}", self.dest);
         Command::new("sh")
             .arg("-c")
-            .arg(format!(
-                "/usr/bin/env ip rule del to {} lookup main pref 1",
-                self.dest
-            ))
+            .arg(whitelist_commands(self.dest, "del", "-D"))
             .status()
             .expect("cannot run iptables");
     }
@@ -31,19 +37,93 @@ impl SingleWhitelister {
     fn new(dest: IpAddr) -> Self {
         Command::new("sh")
             .arg("-c")
-            .arg(format!(
-                "/usr/bin/env ip rule add to {} lookup main pref 1",
-                dest
-            ))
+            .arg(whitelist_commands(dest, "add", "-I"))
             .status()
             .expect("cannot run iptables");
         Self { dest }
     }
 }
 
+/// The commands adding or deleting the rules that let a bridge be reached directly. IPv6 bridges also need an exception to the IPv6 block that applies when the TUN device has no IPv6 address.
+fn whitelist_commands(dest: IpAddr, ip_action: &str, iptables_action: &str) -> String {
+    match dest {
+        IpAddr::V4(_) => format!(
+            "/usr/bin/env ip rule {} to {} lookup main pref 1",
+            ip_action, dest
+        ),
+        IpAddr::V6(_) => format!(
+            "/usr/bin/env ip -6 rule {} to {} lookup main pref 1; /usr/bin/env ip6tables {} OUTPUT -d {} -j ACCEPT",
+            ip_action, dest, iptables_action, dest
+        ),
+    }
+}
+
 static WHITELIST: Lazy<DashMap<IpAddr, SingleWhitelister>> = Lazy::new(DashMap::new);
 
-pub fn setup_routing() {
+/// An ip rule implementing a --bypass-subnet or --force-subnet, removed when dropped.
+struct SplitRouteRule {
+    /// "-4" or "-6", since ip rule doesn't tell the family from the subnet.
+    family: &'static str,
+    spec: String,
+}
+
+impl Drop for SplitRouteRule {
+    fn drop(&mut self) {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "/usr/bin/env ip {} rule del {}",
+                self.family, self.spec
+            ))
+            .status()
+            .expect("cannot run ip rule");
+    }
+}
+
+impl SplitRouteRule {
+    fn new(family: &'static str, spec: String) -> Self {
+        log::debug!("adding split tunneling rule {} {}", family, spec);
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("/usr/bin/env ip {} rule add {}", family, spec))
+            .status()
+            .expect("cannot run ip rule");
+        Self { family, spec }
+    }
+}
+
+static SPLIT_ROUTE_RULES: Lazy<Mutex<Vec<SplitRouteRule>>> = Lazy::new(Default::default);
+
+/// Installs the split tunneling rules between the per-app rules (prefs 2 and 3) and the catch-all tunnel rule (pref 1000), most specific first.
+fn setup_split_routes() {
+    let rules = split_rules();
+    if rules.len() > 990 {
+        log::warn!("only the 990 most specific split tunneling rules are applied in VPN mode");
+    }
+    let mut installed = SPLIT_ROUTE_RULES.lock();
+    for (i, rule) in rules.iter().take(990).enumerate() {
+        let table = match rule.route {
+            Route::Direct => "main",
+            Route::Tunnel => "8964",
+        };
+        let family = if rule.subnet.addr.is_ipv4() {
+            "-4"
+        } else {
+            "-6"
+        };
+        installed.push(SplitRouteRule::new(
+            family,
+            format!("to {} lookup {} pref {}", rule.subnet, table, i + 10),
+        ));
+    }
+}
+
+/// Sends all non-Geph traffic through the given TUN device once the tunnel is up, and undoes that on exit.
+pub fn setup_routing(tun_name: &str) {
+    std::env::set_var("GEPH_TUN", tun_name);
+    if CONNECT_CONFIG.vpn_mode == Some(VpnMode::Tun) && CONNECT_CONFIG.tun_address6.is_some() {
+        std::env::set_var("GEPH_TUN6", tun_name);
+    }
     std::thread::spawn(|| {
         *TUNNEL_STATUS_CALLBACK.write() = Box::new(|status| {
             if let TunnelStatus::PreConnect { addr, protocol: _ } = status {
@@ -68,6 +148,10 @@ pub fn setup_routing() {
         let cmd = include_str!("linux_routing_setup.sh");
         let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
         child.wait().expect("iptables was not set up properly");
+        if let Err(err) = setup_app_split() {
+            log::error!("could not set up per-app split tunneling: {:?}", err);
+        }
+        setup_split_routes();
         unsafe {
             libc::atexit(teardown_routing);
         }
@@ -84,6 +168,8 @@ pub fn setup_routing() {
 extern "C" fn teardown_routing() {
     log::debug!("teardown_routing starting!");
     WHITELIST.clear();
+    SPLIT_ROUTE_RULES.lock().clear();
+    teardown_app_split();
     let cmd = include_str!("linux_routing_setup.sh")
         .lines()
         .filter(|l| l.contains("-D") || l.contains("del") || l.contains("flush"))
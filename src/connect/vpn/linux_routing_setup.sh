@@ -1,12 +1,13 @@
 export PATH=$PATH:/usr/sbin/:/sbin/
 ip route flush table 8964
-ip route add default dev tun-geph table 8964
+ip route add default dev $GEPH_TUN table 8964
 # ip rule del not fwmark 8964 table 8964
 # ip rule add not fwmark 8964 table 8964
 ip rule del table main suppress_prefixlength 0
 ip rule add table main suppress_prefixlength 0
-ip rule del to all lookup 8964 pref 2
-ip rule add to all lookup 8964 pref 2
+# prefs between 1 (Geph's own connections) and 1000 are left for split tunneling rules: 2 and 3 per app, 10 and up per subnet
+ip rule del to all lookup 8964 pref 1000
+ip rule add to all lookup 8964 pref 1000
 iptables -t nat -D OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS
 iptables -t nat -D OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
 iptables -t nat -A OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS
@@ -29,8 +30,14 @@ iptables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
 # # clamp MTU
 # iptables -t mangle -D OUTPUT -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240
 # iptables -t mangle -A OUTPUT -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240
-# block ipv6 completely
+# with an IPv6 address on the TUN device (GEPH_TUN6), route ipv6 like ipv4; otherwise block it completely, except to bridges
+ip -6 route flush table 8964
+ip -6 rule del table main suppress_prefixlength 0
+ip -6 rule del to all lookup 8964 pref 1000
+[ -n "$GEPH_TUN6" ] && ip -6 route add default dev $GEPH_TUN table 8964
+[ -n "$GEPH_TUN6" ] && ip -6 rule add table main suppress_prefixlength 0
+[ -n "$GEPH_TUN6" ] && ip -6 rule add to all lookup 8964 pref 1000
 ip6tables -D OUTPUT -o lo -j ACCEPT
-ip6tables -A OUTPUT -o lo -j ACCEPT
 ip6tables -D OUTPUT  -j REJECT
-ip6tables -A OUTPUT  -j REJECT
\ No newline at end of file
+[ -z "$GEPH_TUN6" ] && ip6tables -A OUTPUT -o lo -j ACCEPT
+[ -z "$GEPH_TUN6" ] && ip6tables -A OUTPUT  -j REJECT
\ No newline at end of file
@@ -0,0 +1,66 @@
+use std::process::Command;
+
+use anyhow::Context;
+
+use crate::connect::CONNECT_CONFIG;
+
+use super::linux_routing;
+
+/// Creates the TUN device described by the --tun-* options and routes the --tun-route and --tun-route6 subnets through it, or with --tun-default-route, all non-Geph traffic. The routes go away along with the device when the process exits.
+pub fn setup_tun() -> anyhow::Result<tun::platform::Device> {
+    let name = &CONNECT_CONFIG.tun_name;
+    let device = tun::platform::Device::new(
+        tun::Configuration::default()
+            .name(name)
+            .address(CONNECT_CONFIG.tun_address)
+            .netmask(CONNECT_CONFIG.tun_netmask)
+            .mtu(CONNECT_CONFIG.tun_mtu as i32)
+            .up(),
+    )
+    .context("could not initialize TUN device")?;
+    log::info!(
+        "created TUN device {} at {}/{} with MTU {}",
+        name,
+        CONNECT_CONFIG.tun_address,
+        CONNECT_CONFIG.tun_netmask,
+        CONNECT_CONFIG.tun_mtu
+    );
+    for subnet in CONNECT_CONFIG.tun_route.iter() {
+        ip(&["route", "replace", &subnet.to_string(), "dev", name])
+            .with_context(|| format!("could not route {} through {}", subnet, name))?;
+        log::debug!("routed {} through {}", subnet, name);
+    }
+    // the tun crate only knows IPv4, so IPv6 is set up by hand
+    if let Some(address6) = CONNECT_CONFIG.tun_address6 {
+        ip(&[
+            "-6",
+            "addr",
+            "replace",
+            &format!("{}/64", address6),
+            "dev",
+            name,
+        ])
+        .with_context(|| format!("could not give {} the address {}", name, address6))?;
+        log::info!("gave TUN device {} the address {}/64", name, address6);
+        for subnet in CONNECT_CONFIG.tun_route6.iter() {
+            ip(&["-6", "route", "replace", &subnet.to_string(), "dev", name])
+                .with_context(|| format!("could not route {} through {}", subnet, name))?;
+            log::debug!("routed {} through {}", subnet, name);
+        }
+    }
+    if CONNECT_CONFIG.tun_default_route {
+        linux_routing::setup_routing(name);
+    }
+    Ok(device)
+}
+
+fn ip(args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .context("cannot run ip")?;
+    if !status.success() {
+        anyhow::bail!("ip {} failed with {}", args.join(" "), status)
+    }
+    Ok(())
+}
@@ -4,7 +4,12 @@ use dashmap::DashMap;
 use once_cell::sync::Lazy;
 use std::net::IpAddr;
 
-use crate::connect::TUNNEL;
+use crate::connect::{
+    split_tunnel::{split_rules, Route},
+    CONNECT_CONFIG, TUNNEL,
+};
+
+use super::hotspot::{start_hotspot, HOTSPOT_BRIDGE, HOTSPOT_SUBNET};
 
 static WHITELIST: Lazy<DashMap<IpAddr, smol::Task<()>>> = Lazy::new(DashMap::new);
 pub fn setup_routing(tun_name: &str) {
@@ -17,11 +22,34 @@ pub fn setup_routing(tun_name: &str) {
     let uname = whoami::username();
     let interface = default_net::get_default_interface().expect("cannot get default interface");
     let iname = interface.name;
+    // pf applies the first matching quick rule. Shared devices go first, since they are always tunneled: replacing the main ruleset also drops Internet Sharing's own NAT, so they have no direct path anyway. Then split tunneling rules, most specific first.
+    // the kill switch loads its rules into an anchor under com.apple, like the stock pf.conf does (escaped for the shell's double quotes)
+    let mut rules = vec!["anchor \\\"com.apple/*\\\"".to_string()];
+    if CONNECT_CONFIG.share_hotspot {
+        if let Err(err) = start_hotspot(&iname) {
+            log::error!("could not turn on Internet Sharing: {:?}", err);
+        }
+        rules.push(format!(
+            "pass in quick on {HOTSPOT_BRIDGE} route-to {tun_name} from {HOTSPOT_SUBNET} to ! {HOTSPOT_SUBNET}"
+        ));
+    }
+    rules.extend(split_rules().iter().map(|rule| match rule.route {
+        Route::Direct => format!("pass out quick on {iname} to {}", rule.subnet),
+        Route::Tunnel => format!(
+            "pass out quick on {iname} route-to {tun_name} to {} user != {uname}",
+            rule.subnet
+        ),
+    }));
+    rules.push(format!(
+        "pass out quick on {iname} route-to {tun_name} user != {uname}"
+    ));
     std::process::Command::new("/bin/sh")
         .arg("-c")
         .arg(format!(
-            "echo \"pass out quick on {iname} route-to {tun_name} user != {uname}\" | pfctl -ef -"
+            "printf '%s\\n' \"{}\" | pfctl -ef -",
+            rules.join("\" \"")
         ))
         .status()
         .expect("could not run pfctl");
+    super::encrypted_dns::register_encrypted_dns();
 }
@@ -0,0 +1,95 @@
+use std::process::Command;
+
+use anyhow::Context;
+use tun::Device;
+
+use crate::connect::CONNECT_CONFIG;
+
+use super::macos_routing;
+
+/// Opens a utun device as described by the --tun-* options and routes the --tun-route and --tun-route6 subnets through it, or with --tun-default-route, all non-Geph traffic. utun devices can only be called "utunN", so any other --tun-name lets the kernel pick the next free unit. The device and its routes go away when the process exits.
+pub fn setup_utun() -> anyhow::Result<tun::platform::Device> {
+    let mut config = tun::Configuration::default();
+    if CONNECT_CONFIG.tun_name.starts_with("utun") {
+        config.name(&CONNECT_CONFIG.tun_name);
+    }
+    let device = tun::platform::Device::new(config.mtu(CONNECT_CONFIG.tun_mtu as i32).up())
+        .context("could not open utun device")?;
+    let name = device.name().to_string();
+    // utun is point-to-point, so the address doubles as the peer
+    let address = CONNECT_CONFIG.tun_address.to_string();
+    run(
+        "ifconfig",
+        &[
+            &name,
+            "inet",
+            &address,
+            &address,
+            "netmask",
+            &CONNECT_CONFIG.tun_netmask.to_string(),
+            "mtu",
+            &CONNECT_CONFIG.tun_mtu.to_string(),
+            "up",
+        ],
+    )?;
+    log::info!(
+        "opened utun device {} at {}/{} with MTU {}",
+        name,
+        CONNECT_CONFIG.tun_address,
+        CONNECT_CONFIG.tun_netmask,
+        CONNECT_CONFIG.tun_mtu
+    );
+    for subnet in CONNECT_CONFIG.tun_route.iter() {
+        run(
+            "route",
+            &[
+                "-n",
+                "add",
+                "-net",
+                &subnet.to_string(),
+                "-interface",
+                &name,
+            ],
+        )
+        .with_context(|| format!("could not route {} through {}", subnet, name))?;
+        log::debug!("routed {} through {}", subnet, name);
+    }
+    if let Some(address6) = CONNECT_CONFIG.tun_address6 {
+        run(
+            "ifconfig",
+            &[&name, "inet6", &address6.to_string(), "prefixlen", "64"],
+        )?;
+        log::info!("gave utun device {} the address {}/64", name, address6);
+        for subnet in CONNECT_CONFIG.tun_route6.iter() {
+            run(
+                "route",
+                &[
+                    "-n",
+                    "add",
+                    "-inet6",
+                    "-net",
+                    &subnet.to_string(),
+                    "-interface",
+                    &name,
+                ],
+            )
+            .with_context(|| format!("could not route {} through {}", subnet, name))?;
+            log::debug!("routed {} through {}", subnet, name);
+        }
+    }
+    if CONNECT_CONFIG.tun_default_route {
+        macos_routing::setup_routing(&name);
+    }
+    Ok(device)
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("cannot run {}", program))?;
+    if !status.success() {
+        anyhow::bail!("{} {} failed with {}", program, args.join(" "), status)
+    }
+    Ok(())
+}
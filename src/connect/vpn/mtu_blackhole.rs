@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use pnet_packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::Ipv4Packet,
+    tcp::{TcpFlags, TcpPacket},
+    Packet,
+};
+
+use crate::connect::audit::audit;
+
+use super::fix_all_checksums;
+
+/// The MSS advertised on connections to destinations that look like MTU blackholes.
+const CLAMPED_MSS: u16 = 1200;
+
+/// How long a destination stays clamped after a blackhole was detected.
+const CLAMP_DURATION: Duration = Duration::from_secs(3600);
+
+/// Segments at least this large are the ones that get lost in a blackhole.
+const LARGE_SEGMENT: usize = 1000;
+
+/// Retransmissions of the same large segment, without any reply data, before we give up on the path MTU.
+const RETRANSMIT_THRESHOLD: u32 = 3;
+
+/// How long a TLS connection may go without any reply data after the client spoke before it counts as stalled.
+const STALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Destinations that look like MTU blackholes, with when they were detected.
+static BLACKHOLES: Lazy<Mutex<HashMap<Ipv4Addr, Instant>>> = Lazy::new(Default::default);
+
+/// Client port, destination address and destination port.
+type FlowKey = (u16, Ipv4Addr, u16);
+
+static FLOWS: Lazy<Mutex<HashMap<FlowKey, FlowState>>> = Lazy::new(Default::default);
+
+const MAX_FLOWS: usize = 10000;
+
+#[derive(Default)]
+struct FlowState {
+    last_large_seq: Option<u32>,
+    retransmits: u32,
+    first_sent: Option<Instant>,
+    got_reply: bool,
+}
+
+/// Looks at a packet from the client before it enters the tunnel, clamping the MSS of new connections to known blackholes and watching for stalls that suggest one.
+pub fn inspect_up(pkt: &mut [u8]) {
+    let (dest, key, flags, seq, payload_len) = match parse(pkt, true) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if flags & TcpFlags::SYN != 0 {
+        if is_blackhole(dest) {
+            clamp_mss(pkt);
+        }
+        return;
+    }
+    let mut flows = FLOWS.lock();
+    if flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+        // a client giving up on a TLS connection that never got a byte back is the classic symptom
+        if let Some(flow) = flows.remove(&key) {
+            let stalled = flow
+                .first_sent
+                .map(|t| t.elapsed() >= STALL_THRESHOLD)
+                .unwrap_or(false);
+            if stalled && !flow.got_reply && (key.2 == 443 || key.2 == 8443) {
+                mark_blackhole(dest, "TLS connection stalled with no reply data");
+            }
+        }
+        return;
+    }
+    if payload_len == 0 {
+        return;
+    }
+    if flows.len() >= MAX_FLOWS && !flows.contains_key(&key) {
+        flows.clear();
+    }
+    let flow = flows.entry(key).or_default();
+    flow.first_sent.get_or_insert_with(Instant::now);
+    if payload_len >= LARGE_SEGMENT && !flow.got_reply {
+        if flow.last_large_seq == Some(seq) {
+            flow.retransmits += 1;
+            if flow.retransmits == RETRANSMIT_THRESHOLD {
+                mark_blackhole(dest, "large segment retransmitted without reply");
+            }
+        } else {
+            flow.last_large_seq = Some(seq);
+            flow.retransmits = 0;
+        }
+    }
+}
+
+/// Looks at a packet coming out of the tunnel towards the client, noting replies and clamping the MSS of SYN-ACKs from known blackholes.
+pub fn inspect_down(pkt: &mut [u8]) {
+    let (source, key, flags, _, payload_len) = match parse(pkt, false) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if flags & TcpFlags::SYN != 0 {
+        if is_blackhole(source) {
+            clamp_mss(pkt);
+        }
+        return;
+    }
+    if payload_len > 0 {
+        if let Some(flow) = FLOWS.lock().get_mut(&key) {
+            flow.got_reply = true;
+        }
+    }
+}
+
+fn is_blackhole(addr: Ipv4Addr) -> bool {
+    let mut blackholes = BLACKHOLES.lock();
+    match blackholes.get(&addr) {
+        Some(when) if when.elapsed() < CLAMP_DURATION => true,
+        Some(_) => {
+            blackholes.remove(&addr);
+            false
+        }
+        None => false,
+    }
+}
+
+fn mark_blackhole(addr: Ipv4Addr, reason: &str) {
+    if BLACKHOLES.lock().insert(addr, Instant::now()).is_none() {
+        log::warn!(
+            "{} looks like an MTU blackhole ({}); clamping MSS to {} on new connections for {:?}",
+            addr,
+            reason,
+            CLAMPED_MSS,
+            CLAMP_DURATION
+        );
+        audit("vpn", "mss_clamp", &format!("{} ({})", addr, reason));
+    }
+}
+
+/// Returns the remote address, the flow key, TCP flags, sequence number and payload length of an IPv4 TCP packet. `up` says whether the packet is headed away from the client.
+fn parse(pkt: &[u8], up: bool) -> Option<(Ipv4Addr, FlowKey, u16, u32, usize)> {
+    if pkt.first()? >> 4 != 4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new(pkt)?;
+    if ip.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let tcp = TcpPacket::new(ip.payload())?;
+    let (remote, key) = if up {
+        let dest = ip.get_destination();
+        (dest, (tcp.get_source(), dest, tcp.get_destination()))
+    } else {
+        let source = ip.get_source();
+        (source, (tcp.get_destination(), source, tcp.get_source()))
+    };
+    Some((
+        remote,
+        key,
+        tcp.get_flags(),
+        tcp.get_sequence(),
+        tcp.payload().len(),
+    ))
+}
+
+/// Lowers the MSS option of a SYN or SYN-ACK to CLAMPED_MSS, fixing up checksums.
+fn clamp_mss(pkt: &mut [u8]) {
+    let ip_header_len = match pkt.first() {
+        Some(b) => (b & 0x0f) as usize * 4,
+        None => return,
+    };
+    let tcp = match pkt.get_mut(ip_header_len..) {
+        Some(tcp) if tcp.len() >= 20 => tcp,
+        _ => return,
+    };
+    let tcp_header_len = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+    let mut i = 20;
+    let mut clamped = false;
+    while i < tcp_header_len {
+        match tcp[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = match tcp.get(i + 1) {
+                    Some(len) if *len >= 2 => *len as usize,
+                    _ => break,
+                };
+                if kind == 2 && len == 4 && i + 4 <= tcp_header_len {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss > CLAMPED_MSS {
+                        tcp[i + 2..i + 4].copy_from_slice(&CLAMPED_MSS.to_be_bytes());
+                        clamped = true;
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+    if clamped {
+        fix_all_checksums(pkt);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use once_cell::sync::Lazy;
+
+/// How many free buffers the pool keeps around, and allocates up front.
+const POOL_SIZE: usize = 1024;
+
+/// The capacity of a fresh buffer: enough for any packet under the usual MTUs.
+const BUFFER_CAPACITY: usize = 2048;
+
+/// Buffers that grew past this, because of some jumbo packet, are freed rather than kept.
+const MAX_KEPT_CAPACITY: usize = 65536;
+
+/// Free buffers. A bounded channel makes for a lock-free free list that the VPN threads and the FFI can share.
+static FREE: Lazy<(flume::Sender<Vec<u8>>, flume::Receiver<Vec<u8>>)> = Lazy::new(|| {
+    let (send, recv) = flume::bounded(POOL_SIZE);
+    for _ in 0..POOL_SIZE {
+        let _ = send.try_send(Vec::with_capacity(BUFFER_CAPACITY));
+    }
+    (send, recv)
+});
+
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static REUSED: AtomicU64 = AtomicU64::new(0);
+
+/// How often the pool had to allocate a buffer, and how often it handed out one it already had, since startup. In a steady state, only the latter goes up.
+pub fn pool_stats() -> (u64, u64) {
+    (
+        ALLOCATED.load(Ordering::Relaxed),
+        REUSED.load(Ordering::Relaxed),
+    )
+}
+
+/// A packet in a buffer that goes back to the pool once dropped, so that the VPN hot path doesn't allocate per packet.
+#[derive(Debug)]
+pub struct PooledPacket {
+    buf: Vec<u8>,
+}
+
+impl PooledPacket {
+    /// Copies the given packet into a buffer from the pool.
+    pub fn copy_from(pkt: &[u8]) -> Self {
+        let mut buf = match FREE.1.try_recv() {
+            Ok(buf) => {
+                REUSED.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            Err(_) => {
+                ALLOCATED.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(BUFFER_CAPACITY.max(pkt.len()))
+            }
+        };
+        buf.extend_from_slice(pkt);
+        Self { buf }
+    }
+}
+
+impl Deref for PooledPacket {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledPacket {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl AsRef<[u8]> for PooledPacket {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PooledPacket {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        if buf.capacity() <= MAX_KEPT_CAPACITY {
+            buf.clear();
+            let _ = FREE.0.try_send(buf);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{connect::CONNECT_CONFIG, storage};
+
+/// System DNS settings that VPN mode changed, kept on disk until they are put back, so that a run that crashed before putting them back has the next one do it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Changed {
+    /// A macOS network service and the DNS servers it had, where none means those from DHCP.
+    Networksetup {
+        service: String,
+        servers: Vec<String>,
+    },
+    /// A Windows interface index and its DNS servers, comma-separated, where none means those from DHCP.
+    Windows { index: u32, servers: String },
+    /// A DNS server registered with Windows for DNS-over-HTTPS, and its previous registration, if it had one.
+    WindowsDoh {
+        server: String,
+        previous: Option<DohRegistration>,
+    },
+}
+
+/// How Windows upgrades a DNS server to DNS-over-HTTPS.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DohRegistration {
+    template: String,
+    allow_fallback: bool,
+    auto_upgrade: bool,
+}
+
+static CHANGED: Lazy<Mutex<Vec<Changed>>> = Lazy::new(Default::default);
+
+/// Where the changed settings are remembered, next to the usage log.
+fn changed_path() -> PathBuf {
+    CONNECT_CONFIG.usage_path.with_extension("dns-restore.json")
+}
+
+/// Puts back the system DNS settings that an earlier run changed and never put back, because it crashed or was killed.
+pub fn restore_leftover() {
+    let leftover: Vec<Changed> = storage::read(&changed_path())
+        .ok()
+        .and_then(|bts| serde_json::from_slice(&bts).ok())
+        .unwrap_or_default();
+    if !leftover.is_empty() {
+        log::warn!(
+            "putting back DNS settings left changed by an earlier run: {:?}",
+            leftover
+        );
+        leftover.iter().rev().for_each(restore);
+        let _ = storage::remove_file(&changed_path());
+    }
+}
+
+/// Points the system resolver of the default interface at the given address, which VPN mode routes into the tunnel, so that not even a resolver on the local network, which split tunneling may route around the tunnel, gets plaintext queries. What was there before is put back on exit.
+pub fn point_system_dns(resolver: &str) {
+    let changed = match change(resolver) {
+        Ok(changed) => changed,
+        Err(err) => {
+            log::warn!("could not point the system DNS at {}: {:?}", resolver, err);
+            return;
+        }
+    };
+    log::info!("pointed the system DNS at {} (was {:?})", resolver, changed);
+    remember(changed);
+}
+
+/// Registers a DNS server with Windows for DNS-over-HTTPS with the given template, upgrading automatically and never falling back to plain DNS. Whatever registration it had before is put back on exit.
+#[cfg(windows)]
+pub fn register_doh_server(server: &str, template: &str) -> anyhow::Result<()> {
+    let previous = super::encrypted_dns::powershell(&format!(
+        "Get-DnsClientDohServerAddress -ServerAddress {server} -ErrorAction SilentlyContinue | \
+        ForEach-Object {{ Write-Output \"$($_.DohTemplate);$($_.AllowFallbackToUdp);$($_.AutoUpgrade)\" }}"
+    ))?;
+    let previous = parse_doh_registration(&previous);
+    let verb = if previous.is_some() { "Set" } else { "Add" };
+    super::encrypted_dns::powershell(&format!(
+        "{verb}-DnsClientDohServerAddress -ServerAddress {server} -DohTemplate {template} -AllowFallbackToUdp $false -AutoUpgrade $true | Out-Null"
+    ))?;
+    remember(Changed::WindowsDoh {
+        server: server.to_string(),
+        previous,
+    });
+    Ok(())
+}
+
+/// Parses a "template;fallback;upgrade" line, as [register_doh_server] asks PowerShell to print it.
+#[cfg(any(windows, test))]
+fn parse_doh_registration(line: &str) -> Option<DohRegistration> {
+    let mut fields = line.trim().split(';');
+    let template = fields.next().filter(|t| !t.is_empty())?.to_string();
+    let mut flag = || fields.next().map(|f| f.eq_ignore_ascii_case("true"));
+    Some(DohRegistration {
+        template,
+        allow_fallback: flag()?,
+        auto_upgrade: flag()?,
+    })
+}
+
+/// Remembers a change, in memory and on disk, so that it is put back on exit, or at the next startup if this run never gets to exit cleanly.
+fn remember(changed: Changed) {
+    let mut all = CHANGED.lock();
+    if all.is_empty() {
+        shutdown_hooks::add_shutdown_hook(restore_on_exit);
+    }
+    all.push(changed);
+    match serde_json::to_vec(&*all) {
+        Ok(bts) => {
+            if let Err(err) = storage::write(&changed_path(), bts) {
+                log::warn!("cannot remember the previous DNS settings: {:?}", err)
+            }
+        }
+        Err(err) => log::warn!("cannot serialize the previous DNS settings: {:?}", err),
+    }
+}
+
+extern "C" fn restore_on_exit() {
+    let changed = std::mem::take(&mut *CHANGED.lock());
+    if !changed.is_empty() {
+        changed.iter().rev().for_each(restore);
+        let _ = storage::remove_file(&changed_path());
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn change(resolver: &str) -> anyhow::Result<Changed> {
+    use anyhow::Context;
+    let interface = default_net::get_default_interface()
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("cannot get default interface")?;
+    // network services are named after their hardware port, which is listed with its device
+    let ports = networksetup(&["-listallhardwareports"])?;
+    let service = ports
+        .lines()
+        .collect::<Vec<_>>()
+        .windows(2)
+        .find_map(|pair| {
+            let port = pair[0].strip_prefix("Hardware Port: ")?;
+            let device = pair[1].strip_prefix("Device: ")?;
+            (device.trim() == interface.name).then(|| port.trim().to_string())
+        })
+        .with_context(|| format!("no network service for {}", interface.name))?;
+    let current = networksetup(&["-getdnsservers", &service])?;
+    // "There aren't any DNS Servers set on ..." when they come from DHCP
+    let servers = current
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.parse::<std::net::IpAddr>().is_ok())
+        .map(|line| line.to_string())
+        .collect();
+    networksetup(&["-setdnsservers", &service, resolver])?;
+    Ok(Changed::Networksetup { service, servers })
+}
+
+#[cfg(target_os = "macos")]
+fn networksetup(args: &[&str]) -> anyhow::Result<String> {
+    let output = std::process::Command::new("networksetup")
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "networksetup {} failed with {}",
+            args.join(" "),
+            output.status
+        )
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(windows)]
+fn change(resolver: &str) -> anyhow::Result<Changed> {
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'; \
+        $i = (Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | Select-Object -First 1).InterfaceIndex; \
+        $old = (Get-DnsClientServerAddress -InterfaceIndex $i -AddressFamily IPv4).ServerAddresses -join ','; \
+        Set-DnsClientServerAddress -InterfaceIndex $i -ServerAddresses {resolver}; \
+        Write-Output \"$i;$old\""
+    );
+    let output = super::encrypted_dns::powershell(&script)?;
+    let (index, servers) = output
+        .trim()
+        .split_once(';')
+        .ok_or_else(|| anyhow::anyhow!("unexpected output {:?}", output))?;
+    Ok(Changed::Windows {
+        index: index.parse()?,
+        servers: servers.to_string(),
+    })
+}
+
+fn restore(changed: &Changed) {
+    let res = match changed {
+        #[cfg(target_os = "macos")]
+        Changed::Networksetup { service, servers } => {
+            let mut args = vec!["-setdnsservers", service.as_str()];
+            if servers.is_empty() {
+                args.push("Empty");
+            } else {
+                args.extend(servers.iter().map(|s| s.as_str()));
+            }
+            networksetup(&args).map(|_| ())
+        }
+        #[cfg(windows)]
+        Changed::Windows { index, servers } => {
+            let servers = if servers.is_empty() {
+                "-ResetServerAddresses".to_string()
+            } else {
+                format!("-ServerAddresses {}", servers)
+            };
+            super::encrypted_dns::powershell(&format!(
+                "Set-DnsClientServerAddress -InterfaceIndex {} {}",
+                index, servers
+            ))
+            .map(|_| ())
+        }
+        #[cfg(windows)]
+        Changed::WindowsDoh { server, previous } => super::encrypted_dns::powershell(&match previous {
+            Some(previous) => format!(
+                "Set-DnsClientDohServerAddress -ServerAddress {} -DohTemplate {} -AllowFallbackToUdp ${} -AutoUpgrade ${} | Out-Null",
+                server, previous.template, previous.allow_fallback, previous.auto_upgrade
+            ),
+            None => format!("Remove-DnsClientDohServerAddress -ServerAddress {} -Force", server),
+        })
+        .map(|_| ()),
+        #[allow(unreachable_patterns)]
+        other => Err(anyhow::anyhow!(
+            "{:?} was not changed on this platform",
+            other
+        )),
+    };
+    if let Err(err) = res {
+        log::warn!("could not put back the previous DNS settings: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_doh_registrations() {
+        let reg = parse_doh_registration("https://dns.google/dns-query;False;True\r\n").unwrap();
+        assert_eq!(reg.template, "https://dns.google/dns-query");
+        assert!(!reg.allow_fallback);
+        assert!(reg.auto_upgrade);
+        // nothing printed when the server was never registered
+        assert!(parse_doh_registration("").is_none());
+        assert!(parse_doh_registration("https://dns.google/dns-query").is_none());
+    }
+}
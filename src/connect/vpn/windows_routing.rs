@@ -10,9 +10,16 @@ use std::{
     time::Duration,
 };
 
-use crate::connect::{vpn::vpn_upload, TUNNEL, TUNNEL_STATUS_CALLBACK};
+use crate::connect::{
+    split_tunnel::{route_for, Route},
+    vpn::vpn_upload,
+    CONNECT_CONFIG, TUNNEL, TUNNEL_STATUS_CALLBACK,
+};
 
-use super::vpn_download_blocking;
+use super::{
+    hotspot::{start_hotspot, HOTSPOT_SUBNET},
+    vpn_download_blocking,
+};
 
 mod windivert;
 
@@ -33,18 +40,46 @@ pub fn start_routing() -> Infallible {
     }
 
     let _stale_guard = CacheStaleGuard::new();
+    super::encrypted_dns::register_encrypted_dns();
+
+    if CONNECT_CONFIG.share_hotspot {
+        match start_hotspot() {
+            Ok(()) => {
+                std::thread::spawn(forward_loop);
+            }
+            Err(err) => log::error!("could not turn on the Mobile Hotspot: {:?}", err),
+        }
+    }
 
     std::thread::spawn(upload_loop);
     download_loop()
 }
 
+/// Captures what shared devices send through the hotspot before Windows forwards it, and tunnels it instead. Replies come back through [download_loop] like any other packet, and Windows forwards them on to the devices.
+fn forward_loop() {
+    let (first, last) = HOTSPOT_SUBNET;
+    let filter = format!(
+        "ip and ip.SrcAddr >= {first} and ip.SrcAddr <= {last} and not (ip.DstAddr >= {first} and ip.DstAddr <= {last})"
+    );
+    let handle = windivert::PacketHandle::open_forward(&filter, -150).unwrap();
+    loop {
+        match handle.receive() {
+            Ok(pkt) => vpn_upload(&pkt),
+            Err(err) => {
+                log::error!("windivert error: {:?}", err);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
 static FAKE_DNS_SERVER: AtomicU32 = AtomicU32::new(0);
 static REAL_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
 
 fn download_loop() -> Infallible {
     let handle = windivert::PacketHandle::open("false", -200).unwrap();
     loop {
-        let mut pkt = vpn_download_blocking().to_vec();
+        let mut pkt = vpn_download_blocking();
         let mut mangled = false;
         if let Some(mut ip_pkt) = pnet_packet::ipv4::MutableIpv4Packet::new(&mut pkt) {
             if let Some(udp_pkt) = pnet_packet::udp::MutableUdpPacket::new(ip_pkt.payload_mut()) {
@@ -72,9 +107,10 @@ fn upload_loop() {
                 let pkt_dest: Option<Ipv4Addr> =
                     pnet_packet::ipv4::Ipv4Packet::new(&pkt).map(|parsed| parsed.get_destination());
                 if let Some(pkt_dest) = pkt_dest {
+                    let bypassed = route_for(pkt_dest.into()) == Some(Route::Direct);
                     let pkt_dest: IpAddr = pkt_dest.into();
                     let is_geph = GEPH_OWN_ADDRS.contains(&pkt_dest);
-                    if is_geph {
+                    if is_geph || bypassed {
                         // merely reinject
                         handle.inject(&pkt, true).expect("cannot inject");
                     } else {
@@ -100,7 +136,7 @@ fn upload_loop() {
                             fix_all_checksums(&mut pkt);
                         }
                         // pass to geph
-                        vpn_upload(pkt.into());
+                        vpn_upload(&pkt);
                     }
                 }
             }
@@ -8,6 +8,7 @@ use std::collections::VecDeque;
 pub struct PacketHandle {
     handle: Handle,
     buffer: VecDeque<Vec<u8>>,
+    layer: Layer,
 }
 //
 // unsafe impl Sync for PacketHandle {}
@@ -23,6 +24,17 @@ impl PacketHandle {
         Ok(Self {
             handle: Handle::open(filter, Layer::Network, priority, flag as _)?,
             buffer: VecDeque::new(),
+            layer: Layer::Network,
+        })
+    }
+
+    /// Opens a handle capturing packets that Windows is about to forward, rather than send or receive itself. Such a handle only receives.
+    pub fn open_forward(filter: &str, priority: i16) -> Result<Self, InternalError> {
+        let flag: u32 = 0;
+        Ok(Self {
+            handle: Handle::open(filter, Layer::NetworkForward, priority, flag as _)?,
+            buffer: VecDeque::new(),
+            layer: Layer::NetworkForward,
         })
     }
 
@@ -32,7 +44,10 @@ impl PacketHandle {
         let packet_len = self.handle.receive(Some(&mut packet), Some(&mut addr))?;
         let addr = unsafe { addr.assume_init() };
         packet.truncate(packet_len);
-        *LAST_RECV_ADDR.lock() = Some(addr);
+        // forwarded packets' addresses are no good for injecting packets to ourselves
+        if matches!(self.layer, Layer::Network) {
+            *LAST_RECV_ADDR.lock() = Some(addr);
+        }
         Ok(packet)
     }
 
@@ -0,0 +1,414 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use winapi::shared::{
+    guiddef::GUID,
+    ifdef::NET_LUID,
+    netioapi::{
+        ConvertInterfaceAliasToLuid, ConvertInterfaceLuidToGuid, CreateIpForwardEntry2,
+        CreateUnicastIpAddressEntry, DeleteIpForwardEntry2, GetBestRoute2, GetIpInterfaceEntry,
+        InitializeIpForwardEntry, InitializeIpInterfaceEntry, InitializeUnicastIpAddressEntry,
+        SetIpInterfaceEntry, MIB_IPFORWARD_ROW2, MIB_IPINTERFACE_ROW, MIB_UNICASTIPADDRESS_ROW,
+    },
+    nldef::IpDadStatePreferred,
+    ntdef::PWSTR,
+    winerror::{ERROR_OBJECT_ALREADY_EXISTS, NO_ERROR},
+    ws2def::{AF_INET, AF_INET6},
+    ws2ipdef::SOCKADDR_INET,
+};
+
+use crate::{
+    config::{CacheStaleGuard, Subnet},
+    connect::{
+        split_tunnel::{split_rules, Route},
+        tunnel::{bridge_cover::cover_hosts, dial_resolve::bootstrap_resolvers, TunnelStatus},
+        vpn::vpn_upload,
+        CONNECT_CONFIG, TUNNEL, TUNNEL_STATUS_CALLBACK,
+    },
+};
+
+use super::vpn_download_blocking;
+
+/// The DNS server given to the adapter. Queries to it go through the tunnel, where the up loop points them at the real resolver anyway. Not 1.1.1.1, which dialing bootstraps DoH from, so that goes around the adapter.
+const ADAPTER_DNS: &str = "1.0.0.1";
+
+/// How far ahead of every other interface the adapter's routes are.
+const ADAPTER_METRIC: u32 = 1;
+
+/// The routes that traffic to the internet took before the adapter took over, as an interface and a next hop. There may be no IPv6 one.
+static ORIGINAL_ROUTES: Lazy<Mutex<Option<OriginalRoutes>>> = Lazy::new(Default::default);
+
+/// Destinations to route around the adapter once it takes over, like the bridges Geph connected to before that.
+static PENDING_BYPASS: Lazy<Mutex<Vec<(IpAddr, u8)>>> = Lazy::new(Default::default);
+
+/// The adapter, whose DNS server is cleared on the way out.
+static ADAPTER_LUID: Lazy<Mutex<Option<NET_LUID>>> = Lazy::new(Default::default);
+
+/// Routes around the adapter, added to the original interface, which are not removed along with the adapter.
+static BYPASS_ROUTES: Lazy<Mutex<Vec<MIB_IPFORWARD_ROW2>>> = Lazy::new(Default::default);
+
+#[derive(Clone, Copy)]
+struct OriginalRoutes {
+    v4: (NET_LUID, SOCKADDR_INET),
+    v6: Option<(NET_LUID, SOCKADDR_INET)>,
+}
+
+/// Creates a WinTun adapter once the tunnel is up, points the default route and DNS at it, and shuffles packets between it and the tunnel. Never returns.
+pub fn start_wintun() -> Infallible {
+    *TUNNEL_STATUS_CALLBACK.write() = Box::new(|status| {
+        if let TunnelStatus::PreConnect { addr, protocol: _ } = status {
+            bypass(host_prefix(addr.ip()));
+        }
+    });
+
+    while !TUNNEL.status().connected() {
+        log::debug!("waiting for tunnel to connect first...");
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    let _stale_guard = CacheStaleGuard::new();
+    let wintun = unsafe { wintun::load() }.expect("cannot load wintun.dll");
+    let adapter = wintun::Adapter::create(&wintun, &CONNECT_CONFIG.tun_name, "Geph", None)
+        .expect("cannot create WinTun adapter");
+    if let Err(err) = configure_adapter() {
+        panic!("cannot configure WinTun adapter: {:?}", err)
+    }
+    let session = adapter
+        .start_session(wintun::MAX_RING_CAPACITY)
+        .expect("cannot start WinTun session");
+
+    let up_session = session.clone();
+    std::thread::Builder::new()
+        .name("wintun-up".into())
+        .spawn(move || loop {
+            match up_session.receive_blocking() {
+                Ok(packet) => vpn_upload(packet.bytes()),
+                Err(err) => {
+                    log::error!("WinTun error: {:?}", err);
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        })
+        .unwrap();
+    download_loop(session)
+}
+
+fn download_loop(session: Arc<wintun::Session>) -> Infallible {
+    loop {
+        let pkt = vpn_download_blocking();
+        match session.allocate_send_packet(pkt.len() as u16) {
+            Ok(mut packet) => {
+                packet.bytes_mut().copy_from_slice(&pkt);
+                session.send_packet(packet);
+            }
+            Err(err) => log::warn!("cannot allocate WinTun packet: {:?}", err),
+        }
+    }
+}
+
+/// Gives the adapter its address, MTU and DNS server, then routes everything through it, except for what goes around it.
+fn configure_adapter() -> anyhow::Result<()> {
+    let luid = adapter_luid(&CONNECT_CONFIG.tun_name)?;
+    *ADAPTER_LUID.lock() = Some(luid);
+    unsafe {
+        let mut row: MIB_UNICASTIPADDRESS_ROW = std::mem::zeroed();
+        InitializeUnicastIpAddressEntry(&mut row);
+        row.InterfaceLuid = luid;
+        row.Address = sockaddr(CONNECT_CONFIG.tun_address.into());
+        row.OnLinkPrefixLength = u32::from(CONNECT_CONFIG.tun_netmask).count_ones() as u8;
+        row.DadState = IpDadStatePreferred;
+        check(
+            CreateUnicastIpAddressEntry(&row),
+            "CreateUnicastIpAddressEntry",
+        )?;
+
+        let mut row: MIB_IPINTERFACE_ROW = std::mem::zeroed();
+        InitializeIpInterfaceEntry(&mut row);
+        row.InterfaceLuid = luid;
+        row.Family = AF_INET as u16;
+        check(GetIpInterfaceEntry(&mut row), "GetIpInterfaceEntry")?;
+        row.NlMtu = CONNECT_CONFIG.tun_mtu as u32;
+        row.UseAutomaticMetric = 0;
+        row.Metric = ADAPTER_METRIC;
+        // must be zero for IPv4, or setting fails
+        row.SitePrefixLength = 0;
+        check(SetIpInterfaceEntry(&mut row), "SetIpInterfaceEntry")?;
+    }
+    // what Geph itself dials without the tunnel has to keep going around the adapter, or reconnecting would go through the dead tunnel; this is resolved before DNS points into the tunnel
+    PENDING_BYPASS
+        .lock()
+        .extend(control_plane().into_iter().map(host_prefix));
+    set_dns(luid, ADAPTER_DNS)?;
+    // Windows asks the DNS servers of every interface, so the physical one is pointed into the tunnel too
+    super::system_dns::point_system_dns(ADAPTER_DNS);
+
+    // remember the way out before taking it over, for what has to go around the adapter
+    let original = OriginalRoutes {
+        v4: best_route(Ipv4Addr::new(1, 1, 1, 1).into())?,
+        v6: best_route(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111).into()).ok(),
+    };
+    *ORIGINAL_ROUTES.lock() = Some(original);
+    for prefix in PENDING_BYPASS.lock().drain(..) {
+        add_bypass(original, prefix);
+    }
+    shutdown_hooks::add_shutdown_hook(teardown);
+    for rule in split_rules() {
+        let prefix = subnet_prefix(rule.subnet);
+        match rule.route {
+            Route::Direct => add_bypass(original, prefix),
+            Route::Tunnel => {
+                let next_hop: IpAddr = match prefix.0 {
+                    IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                };
+                add_route(luid, prefix, sockaddr(next_hop))?;
+            }
+        }
+    }
+    // two halves, which beat the default route without replacing it
+    for half in ["0.0.0.0/1", "128.0.0.0/1"] {
+        let half: Subnet = half.parse()?;
+        add_route(
+            luid,
+            subnet_prefix(half),
+            sockaddr(Ipv4Addr::UNSPECIFIED.into()),
+        )?;
+    }
+    // IPv6 goes into the adapter too, where it is refused, rather than leaking out of the physical interface around the tunnel
+    for half in [
+        Ipv6Addr::UNSPECIFIED,
+        Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0),
+    ] {
+        if let Err(err) = add_route(
+            luid,
+            (half.into(), 1),
+            sockaddr(Ipv6Addr::UNSPECIFIED.into()),
+        ) {
+            log::warn!("cannot route IPv6 into the adapter: {:?}", err);
+        }
+    }
+    log::info!(
+        "WinTun adapter {} is up at {}",
+        CONNECT_CONFIG.tun_name,
+        CONNECT_CONFIG.tun_address
+    );
+    Ok(())
+}
+
+/// The addresses that Geph dials directly: the binder fronts, the hosts cover steps fetch from, and the bootstrap DoH resolvers. Bridges are added as they are dialed.
+fn control_plane() -> Vec<IpAddr> {
+    let hosts = CONNECT_CONFIG
+        .common
+        .binder_front_hosts()
+        .into_iter()
+        .map(|host| (host, 443))
+        .chain(cover_hosts());
+    let mut addrs: Vec<IpAddr> = bootstrap_resolvers().collect();
+    for (host, port) in hosts {
+        match (host.as_str(), port).to_socket_addrs() {
+            Ok(resolved) => addrs.extend(resolved.map(|addr| addr.ip())),
+            Err(err) => log::warn!(
+                "cannot resolve {} to route it around the adapter: {}",
+                host,
+                err
+            ),
+        }
+    }
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs
+}
+
+/// Routes the given destination around the adapter, now if it already took over, or else once it does.
+fn bypass(prefix: (IpAddr, u8)) {
+    let original = *ORIGINAL_ROUTES.lock();
+    match original {
+        Some(original) => add_bypass(original, prefix),
+        None => PENDING_BYPASS.lock().push(prefix),
+    }
+}
+
+fn add_bypass(original: OriginalRoutes, (addr, prefix_len): (IpAddr, u8)) {
+    let (luid, next_hop) = match (addr, original.v6) {
+        (IpAddr::V4(_), _) => original.v4,
+        (IpAddr::V6(_), Some(v6)) => v6,
+        // without an IPv6 route there is nothing to go around the adapter on
+        (IpAddr::V6(_), None) => return,
+    };
+    log::debug!("routing {}/{} around the WinTun adapter", addr, prefix_len);
+    match add_route(luid, (addr, prefix_len), next_hop) {
+        Ok(row) => BYPASS_ROUTES.lock().push(row),
+        Err(err) => log::warn!(
+            "cannot route {}/{} around the adapter: {:?}",
+            addr,
+            prefix_len,
+            err
+        ),
+    }
+}
+
+/// Removes the routes around the adapter and clears its DNS server. The physical interface's DNS servers are put back by the system DNS hook.
+extern "C" fn teardown() {
+    for row in BYPASS_ROUTES.lock().drain(..) {
+        unsafe {
+            DeleteIpForwardEntry2(&row);
+        }
+    }
+    if let Some(luid) = ADAPTER_LUID.lock().take() {
+        if let Err(err) = set_dns(luid, "") {
+            log::warn!("cannot clear the adapter's DNS server: {:?}", err);
+        }
+    }
+}
+
+fn host_prefix(addr: IpAddr) -> (IpAddr, u8) {
+    (addr, if addr.is_ipv4() { 32 } else { 128 })
+}
+
+fn subnet_prefix(subnet: Subnet) -> (IpAddr, u8) {
+    (subnet.addr, subnet.prefix_len as u8)
+}
+
+fn add_route(
+    luid: NET_LUID,
+    (addr, prefix_len): (IpAddr, u8),
+    next_hop: SOCKADDR_INET,
+) -> anyhow::Result<MIB_IPFORWARD_ROW2> {
+    unsafe {
+        let mut row: MIB_IPFORWARD_ROW2 = std::mem::zeroed();
+        InitializeIpForwardEntry(&mut row);
+        row.InterfaceLuid = luid;
+        row.DestinationPrefix.Prefix = sockaddr(addr);
+        row.DestinationPrefix.PrefixLength = prefix_len;
+        row.NextHop = next_hop;
+        row.Metric = 0;
+        match CreateIpForwardEntry2(&row) {
+            ERROR_OBJECT_ALREADY_EXISTS => Ok(row),
+            status => check(status, "CreateIpForwardEntry2").map(|_| row),
+        }
+    }
+}
+
+/// The interface and next hop that traffic to the given address currently takes.
+fn best_route(dest: IpAddr) -> anyhow::Result<(NET_LUID, SOCKADDR_INET)> {
+    unsafe {
+        let mut route: MIB_IPFORWARD_ROW2 = std::mem::zeroed();
+        let mut source: SOCKADDR_INET = std::mem::zeroed();
+        check(
+            GetBestRoute2(
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null(),
+                &sockaddr(dest),
+                0,
+                &mut route,
+                &mut source,
+            ),
+            "GetBestRoute2",
+        )?;
+        Ok((route.InterfaceLuid, route.NextHop))
+    }
+}
+
+fn adapter_luid(name: &str) -> anyhow::Result<NET_LUID> {
+    let alias = wide(name);
+    unsafe {
+        let mut luid: NET_LUID = std::mem::zeroed();
+        check(
+            ConvertInterfaceAliasToLuid(alias.as_ptr(), &mut luid),
+            "ConvertInterfaceAliasToLuid",
+        )
+        .with_context(|| format!("no adapter named {}", name))?;
+        Ok(luid)
+    }
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct DNS_INTERFACE_SETTINGS {
+    Version: u32,
+    Flags: u64,
+    Domain: PWSTR,
+    NameServer: PWSTR,
+    SearchList: PWSTR,
+    RegistrationEnabled: u32,
+    RegisterAdapterName: u32,
+    EnableLLMNR: u32,
+    QueryAdapterName: u32,
+    ProfileNameServer: PWSTR,
+}
+
+const DNS_INTERFACE_SETTINGS_VERSION1: u32 = 1;
+const DNS_SETTING_NAMESERVER: u64 = 0x0002;
+
+#[link(name = "iphlpapi")]
+extern "system" {
+    // not in winapi, since it only came with Windows 10 1809
+    fn SetInterfaceDnsSettings(interface: GUID, settings: *const DNS_INTERFACE_SETTINGS) -> u32;
+}
+
+/// Sets the adapter's DNS server, or clears it given an empty string.
+fn set_dns(luid: NET_LUID, server: &str) -> anyhow::Result<()> {
+    let mut name_server = wide(server);
+    unsafe {
+        let mut guid: GUID = std::mem::zeroed();
+        check(
+            ConvertInterfaceLuidToGuid(&luid, &mut guid),
+            "ConvertInterfaceLuidToGuid",
+        )?;
+        let settings = DNS_INTERFACE_SETTINGS {
+            Version: DNS_INTERFACE_SETTINGS_VERSION1,
+            Flags: DNS_SETTING_NAMESERVER,
+            Domain: std::ptr::null_mut(),
+            NameServer: name_server.as_mut_ptr(),
+            SearchList: std::ptr::null_mut(),
+            RegistrationEnabled: 0,
+            RegisterAdapterName: 0,
+            EnableLLMNR: 0,
+            QueryAdapterName: 0,
+            ProfileNameServer: std::ptr::null_mut(),
+        };
+        check(
+            SetInterfaceDnsSettings(guid, &settings),
+            "SetInterfaceDnsSettings",
+        )
+    }
+}
+
+fn sockaddr(ip: IpAddr) -> SOCKADDR_INET {
+    unsafe {
+        let mut addr: SOCKADDR_INET = std::mem::zeroed();
+        match ip {
+            IpAddr::V4(ip) => {
+                let v4 = addr.Ipv4_mut();
+                v4.sin_family = AF_INET as u16;
+                *v4.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                let v6 = addr.Ipv6_mut();
+                v6.sin6_family = AF_INET6 as u16;
+                *v6.sin6_addr.u.Byte_mut() = ip.octets();
+            }
+        }
+        addr
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn check(status: u32, what: &str) -> anyhow::Result<()> {
+    if status == NO_ERROR {
+        Ok(())
+    } else {
+        anyhow::bail!("{} failed with error {}", what, status)
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Context;
+
+use crate::config::{get_cached_binder_client, ConnectOpt};
+
+use super::tunnel::exit_select::select_exit;
+
+/// Refreshes everything a connect with these options will ask the binder for — the exit list, the choice of exit, and that exit's bridges — so that the eventual connect finds it all in the on-disk cache.
+pub async fn warm_caches(opt: &ConnectOpt) -> anyhow::Result<()> {
+    let ccache = get_cached_binder_client(&opt.common, &opt.auth)?;
+    let selected_exit = select_exit(
+        &ccache,
+        &opt.exit_server.clone().unwrap_or_default(),
+        opt.ignore_load,
+        opt.exit_select,
+    )
+    .await
+    .context("cannot get closest exit")?;
+    log::debug!("warming bridges of {}", selected_exit.hostname);
+    let bridges = ccache
+        .get_bridges_v2(&selected_exit.hostname, true)
+        .await
+        .context("cannot get bridges")?;
+    log::info!(
+        "caches warm: exit {} with {} bridges",
+        selected_exit.hostname,
+        bridges.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305};
+use serde::{Deserialize, Serialize};
+
+/// Below this AES-GCM throughput, in MB/s, encrypting everything twice (as TLS-based transports do) becomes the bottleneck.
+const SLOW_AES_MBPS: f64 = 40.0;
+
+/// How the client should spend its limited crypto budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CryptoBackend {
+    /// Fast enough for everything.
+    Standard,
+    /// AES is slow (e.g. MIPS routers without crypto extensions), so transports that layer TLS on top of sosistab's ChaCha20 are used only as a fallback.
+    ChaChaOnly,
+}
+
+impl CryptoBackend {
+    /// How many pipes of the given bridge protocol to keep. TLS-based pipes encrypt everything twice, so with slow AES they are only kept as a fallback.
+    pub fn pipes_for(&self, protocol: &str) -> usize {
+        let tls_based = protocol.contains("tls") || protocol.contains("wss");
+        if tls_based && *self == CryptoBackend::ChaChaOnly {
+            1
+        } else {
+            3
+        }
+    }
+}
+
+/// The result of probing the platform's crypto performance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CryptoReport {
+    pub arch: String,
+    pub aes_hardware: bool,
+    pub chacha20_mbps: f64,
+    pub aes_gcm_mbps: f64,
+    pub backend: CryptoBackend,
+}
+
+/// The crypto report for this machine, measured once on first use.
+pub static CRYPTO_REPORT: Lazy<CryptoReport> = Lazy::new(|| {
+    let report = probe_crypto(Duration::from_millis(30));
+    log::info!(
+        "crypto: {:?} backend (AES hardware: {}, ChaCha20 {:.0} MB/s, AES-GCM {:.0} MB/s)",
+        report.backend,
+        report.aes_hardware,
+        report.chacha20_mbps,
+        report.aes_gcm_mbps
+    );
+    report
+});
+
+/// Measures crypto performance for roughly the given duration per cipher, and picks a backend.
+pub fn probe_crypto(duration: Duration) -> CryptoReport {
+    let aes_hardware = aes_hardware();
+    let chacha20_mbps = aead_throughput(&CHACHA20_POLY1305, duration);
+    let aes_gcm_mbps = aead_throughput(&AES_256_GCM, duration);
+    CryptoReport {
+        arch: std::env::consts::ARCH.into(),
+        aes_hardware,
+        chacha20_mbps,
+        aes_gcm_mbps,
+        backend: select_backend(aes_hardware, aes_gcm_mbps),
+    }
+}
+
+/// Picks a backend given whether AES is hardware-accelerated and how fast it measured.
+pub fn select_backend(aes_hardware: bool, aes_gcm_mbps: f64) -> CryptoBackend {
+    if aes_hardware || aes_gcm_mbps >= SLOW_AES_MBPS {
+        CryptoBackend::Standard
+    } else {
+        CryptoBackend::ChaChaOnly
+    }
+}
+
+fn aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Encrypts packet-sized buffers for roughly the given duration, returning the throughput in MB/s.
+fn aead_throughput(algorithm: &'static ring::aead::Algorithm, duration: Duration) -> f64 {
+    let key = LessSafeKey::new(UnboundKey::new(algorithm, &[0x42; 32]).unwrap());
+    let mut buffer = vec![0u8; 1400];
+    let mut bytes = 0u64;
+    let mut counter = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        counter += 1;
+        buffer.truncate(1400);
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut buffer,
+        )
+        .unwrap();
+        bytes += 1400;
+    }
+    bytes as f64 / start.elapsed().as_secs_f64() / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_aes_falls_back_to_chacha() {
+        assert_eq!(select_backend(true, 1.0), CryptoBackend::Standard);
+        assert_eq!(select_backend(false, 500.0), CryptoBackend::Standard);
+        assert_eq!(select_backend(false, 5.0), CryptoBackend::ChaChaOnly);
+    }
+
+    #[test]
+    fn chacha_only_keeps_tls_as_a_fallback() {
+        let standard = CryptoBackend::Standard;
+        let chacha = CryptoBackend::ChaChaOnly;
+        assert_eq!(standard.pipes_for("sosistab2-obfstls"), 3);
+        assert_eq!(chacha.pipes_for("sosistab2-obfstls"), 1);
+        assert_eq!(chacha.pipes_for("sosistab2-wss"), 1);
+        assert_eq!(chacha.pipes_for("sosistab2-obfsudp"), 3);
+    }
+
+    #[test]
+    fn benchmark_ciphers() {
+        let report = probe_crypto(Duration::from_millis(100));
+        assert!(report.chacha20_mbps > 0.0);
+        assert!(report.aes_gcm_mbps > 0.0);
+        assert_eq!(
+            report.backend,
+            select_backend(report.aes_hardware, report.aes_gcm_mbps)
+        );
+    }
+}
@@ -13,6 +13,7 @@ use structopt::StructOpt;
 
 use crate::{
     config::{CommonOpt, CONFIG},
+    logs::crash_log_line,
     ALLOCATOR,
 };
 
@@ -36,6 +37,7 @@ pub static DEBUGPACK: Lazy<Arc<DebugPack>> = Lazy::new(|| {
         crate::config::Opt::Connect(connect_opt) => {
             DebugPack::new(&connect_opt.common.debugpack_path).unwrap()
         }
+        #[cfg(not(feature = "router"))]
         crate::config::Opt::BridgeTest(bt_pot) => {
             DebugPack::new(&bt_pot.common.debugpack_path).unwrap()
         }
@@ -48,6 +50,48 @@ pub static DEBUGPACK: Lazy<Arc<DebugPack>> = Lazy::new(|| {
         crate::config::Opt::Debugpack(dp_opt) => {
             DebugPack::new(&dp_opt.common.debugpack_path).unwrap()
         }
+        crate::config::Opt::Exits(exits_opt) => {
+            DebugPack::new(&exits_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Cache(cache_opt) => {
+            DebugPack::new(&cache_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Usage(usage_opt) => {
+            DebugPack::new(&usage_opt.common.debugpack_path).unwrap()
+        }
+        #[cfg(not(feature = "router"))]
+        crate::config::Opt::Setup(setup_opt) => {
+            DebugPack::new(&setup_opt.common.debugpack_path).unwrap()
+        }
+        #[cfg(not(feature = "router"))]
+        crate::config::Opt::Completions(completions_opt) => {
+            DebugPack::new(&completions_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Config(config_opt) => {
+            DebugPack::new(&config_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Run(run_opt) => {
+            DebugPack::new(&run_opt.connect.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::PingExit(ping_opt) => {
+            DebugPack::new(&ping_opt.connect.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Speedtest(speedtest_opt) => {
+            DebugPack::new(&speedtest_opt.connect.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::Doctor(doctor_opt) => {
+            DebugPack::new(&doctor_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::ImportUri(import_opt) => {
+            DebugPack::new(&import_opt.common.debugpack_path).unwrap()
+        }
+        crate::config::Opt::PreviousLogs(logs_opt) => {
+            DebugPack::new(&logs_opt.common.debugpack_path).unwrap()
+        }
+        #[cfg(not(feature = "router"))]
+        crate::config::Opt::Pair(pair_opt) => {
+            DebugPack::new(&pair_opt.common.debugpack_path).unwrap()
+        }
     };
 
     Arc::new(dp)
@@ -68,6 +112,12 @@ pub static TIMESERIES_LOOP: Lazy<Task<()>> = Lazy::new(|| {
 
 impl DebugPack {
     pub fn new(db_path: &str) -> anyhow::Result<Self> {
+        // in ephemeral mode, logs are kept in memory whatever the path
+        let db_path = if crate::storage::ephemeral() {
+            "file::memory:?cache=shared"
+        } else {
+            db_path
+        };
         // open database & create tables if not exist
         let conn = Connection::open(db_path)?;
         conn.execute(
@@ -128,6 +178,7 @@ impl DebugPack {
     }
 
     pub fn add_logline(&self, logline: &str) {
+        crash_log_line(logline);
         let _ = self.send_log.try_send(logline.into());
     }
 
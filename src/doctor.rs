@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::CommonOpt,
+    crypto::{CryptoBackend, CRYPTO_REPORT},
+    import_uri::PROTOCOLS,
+    l10n::{tr, tr_args},
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct DoctorOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(long)]
+    /// Print the diagnosis as JSON.
+    pub json: bool,
+}
+
+/// Entry point to the doctor subcommand, which diagnoses how well this machine can run Geph.
+pub fn main_doctor(opt: DoctorOpt) -> anyhow::Result<()> {
+    let report = CRYPTO_REPORT.clone();
+    // what the picked backend means for each transport
+    let pipes: BTreeMap<&str, usize> = PROTOCOLS
+        .iter()
+        .map(|protocol| (*protocol, report.backend.pipes_for(protocol)))
+        .collect();
+    if opt.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(
+                &serde_json::json!({ "crypto": report, "pipes_per_protocol": pipes })
+            )?
+        );
+        return Ok(());
+    }
+    let aes_hardware = if report.aes_hardware {
+        tr("doctor-yes")
+    } else {
+        tr("doctor-no")
+    };
+    println!("{}", tr_args("doctor-arch", &[("arch", &report.arch)]));
+    println!(
+        "{}",
+        tr_args("doctor-aes-hardware", &[("value", &aes_hardware)])
+    );
+    println!(
+        "{}",
+        tr_args(
+            "doctor-chacha",
+            &[("mbps", &format!("{:.0}", report.chacha20_mbps))]
+        )
+    );
+    println!(
+        "{}",
+        tr_args(
+            "doctor-aes-gcm",
+            &[("mbps", &format!("{:.0}", report.aes_gcm_mbps))]
+        )
+    );
+    println!(
+        "{}",
+        tr(match report.backend {
+            CryptoBackend::Standard => "doctor-backend-standard",
+            CryptoBackend::ChaChaOnly => "doctor-backend-chacha-only",
+        })
+    );
+    for (protocol, count) in pipes {
+        println!(
+            "    {}",
+            tr_args(
+                "doctor-pipes",
+                &[("protocol", &protocol), ("count", &count)]
+            )
+        );
+    }
+    Ok(())
+}
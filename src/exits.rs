@@ -0,0 +1,57 @@
+use colored::Colorize;
+use geph4_protocol::binder::protocol::Level;
+use itertools::Itertools;
+use pad::PadStr;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{get_cached_binder_client, AuthOpt, CommonOpt},
+    connect::tunnel::exit_select::OVERLOADED_THRESHOLD,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct ExitsOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+}
+
+/// Entry point to the exits subcommand, which lists all exits together with their current load.
+pub async fn main_exits(opt: ExitsOpt) -> anyhow::Result<()> {
+    let binder_client = get_cached_binder_client(&opt.common, &opt.auth)?;
+    let mut exits = binder_client.get_summary().await?.exits;
+    exits.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    println!(
+        "{}{}{}LOAD",
+        "HOSTNAME".pad_to_width(40),
+        "LOCATION".pad_to_width(12),
+        "LEVELS".pad_to_width(12),
+    );
+    for exit in exits {
+        let levels = exit
+            .allowed_levels
+            .iter()
+            .map(|l| match l {
+                Level::Free => "free",
+                Level::Plus => "plus",
+            })
+            .join(",");
+        let load = format!("{:.0}%", exit.load * 100.0);
+        let load = if exit.load > OVERLOADED_THRESHOLD {
+            format!("{} (overloaded)", load).red()
+        } else {
+            load.normal()
+        };
+        println!(
+            "{}{}{}{}",
+            exit.hostname.as_str().pad_to_width(40),
+            format!("{}-{}", exit.country_code, exit.city_code).pad_to_width(12),
+            levels.pad_to_width(12),
+            load
+        );
+    }
+    Ok(())
+}
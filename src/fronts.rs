@@ -1,6 +1,6 @@
 use std::{
     sync::atomic::{AtomicUsize, Ordering},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -8,6 +8,8 @@ use async_trait::async_trait;
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
 use geph4_protocol::binder::client::E2eeHttpTransport;
 
+use crate::{binder_stats::record_call, puzzle::Puzzle};
+
 use itertools::Itertools;
 use nanorpc::{DynRpcTransport, RpcTransport};
 use once_cell::sync::Lazy;
@@ -43,6 +45,27 @@ impl RpcTransport for MultiRpcTransport {
         &self,
         req: nanorpc::JrpcRequest,
     ) -> Result<nanorpc::JrpcResponse, Self::Error> {
+        let start = Instant::now();
+        let method = req.method.clone();
+        let res = async {
+            let resp = self.call_with_retries(req.clone()).await?;
+            match Puzzle::from_response(&resp) {
+                Some(puzzle) => self.call_with_retries(puzzle.solve_for(req).await?).await,
+                None => Ok(resp),
+            }
+        }
+        .await;
+        record_call(&method, start.elapsed(), res.is_ok());
+        res
+    }
+}
+
+impl MultiRpcTransport {
+    /// Tries the fronts in turn, with exponential backoff between failures.
+    async fn call_with_retries(
+        &self,
+        req: nanorpc::JrpcRequest,
+    ) -> anyhow::Result<nanorpc::JrpcResponse> {
         let mut backoff = ExponentialBackoffBuilder::new()
             .with_max_elapsed_time(None)
             .build();
@@ -0,0 +1,241 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+use http_types::Url;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{config::CommonOpt, l10n::tr_args, storage};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct ImportUriOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// The geph:// URI to import.
+    pub uri: String,
+
+    #[structopt(long, default_value = "auto")]
+    /// Where to write the profile. The default value is "auto", meaning a platform-specific path named after the endpoint.
+    pub profile: String,
+}
+
+/// What a shared endpoint is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShareKind {
+    /// A self-hosted exit.
+    Exit,
+    /// A private bridge in front of an exit.
+    Bridge,
+}
+
+/// A self-hosted exit or private bridge, shared out of band (e.g. as a QR code) as a URI like
+///
+/// `geph://exit?endpoint=203.0.113.7:19831&pk=<64 hex digits>&protocol=sosistab2-obfsudp&name=home`
+///
+/// where `pk` is the endpoint's public key, pinned so that nobody in between can impersonate it. Any protocol that bridges use can be shared, with `key` giving the hex of the key material as the binder would list it for such a bridge; a bare obfsudp public key may be given as `pk` instead. A private bridge must also give `exit`, the hex of its exit's end-to-end public key, so that the bridge cannot stand in for the exit; an exit may give it too.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareUri {
+    pub kind: ShareKind,
+    pub endpoint: String,
+    pub key: Vec<u8>,
+    pub exit_key: Option<[u8; 32]>,
+    pub protocol: String,
+    pub name: Option<String>,
+}
+
+/// The protocols that can be dialed without the binder, which are all the bridge protocols there are.
+pub(crate) const PROTOCOLS: &[&str] = &[
+    "sosistab2-obfsudp",
+    "sosistab2-obfstls",
+    "sosistab2-wss",
+    "sosistab2-quic",
+];
+
+impl FromStr for ShareUri {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim()).context("invalid URI")?;
+        if url.scheme() != "geph" {
+            anyhow::bail!("URI must start with geph://")
+        }
+        let kind = match url.host_str() {
+            Some("exit") => ShareKind::Exit,
+            Some("bridge") => ShareKind::Bridge,
+            other => anyhow::bail!("unrecognized endpoint kind {:?}", other.unwrap_or_default()),
+        };
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+        };
+        let protocol = param("protocol").unwrap_or_else(|| "sosistab2-obfsudp".into());
+        if !PROTOCOLS.contains(&protocol.as_str()) {
+            anyhow::bail!("unsupported protocol {}", protocol)
+        }
+        let key = match (param("pk"), param("key")) {
+            (Some(pk), None) => {
+                if protocol != "sosistab2-obfsudp" {
+                    anyhow::bail!("pk is only for sosistab2-obfsudp; give key instead")
+                }
+                let pk = hex::decode(&pk).context("pk is not hex")?;
+                if pk.len() != 32 {
+                    anyhow::bail!("pk must be 32 bytes")
+                }
+                pk
+            }
+            (None, Some(key)) => hex::decode(&key).context("key is not hex")?,
+            (Some(_), Some(_)) => anyhow::bail!("URI has both pk and key"),
+            (None, None) => anyhow::bail!("URI has no key"),
+        };
+        let exit_key = param("exit")
+            .map(|exit| {
+                <[u8; 32]>::try_from(hex::decode(&exit).context("exit is not hex")?)
+                    .ok()
+                    .context("exit must be 32 bytes")
+            })
+            .transpose()?;
+        if kind == ShareKind::Bridge && exit_key.is_none() {
+            anyhow::bail!("a bridge URI must give its exit's key")
+        }
+        Ok(Self {
+            kind,
+            endpoint: param("endpoint").context("URI has no endpoint")?,
+            key,
+            exit_key,
+            protocol,
+            name: param("name").filter(|name| !name.is_empty()),
+        })
+    }
+}
+
+impl ShareUri {
+    /// The connect arguments that connect to the shared endpoint.
+    pub fn connect_args(&self) -> Vec<String> {
+        let endpoint = if self.protocol == "sosistab2-obfsudp" && self.key.len() == 32 {
+            format!("{}@{}", hex::encode(&self.key), self.endpoint)
+        } else {
+            format!(
+                "{}:{}@{}",
+                self.protocol,
+                hex::encode(&self.key),
+                self.endpoint
+            )
+        };
+        let mut args = vec!["--override-connect".into(), endpoint];
+        if let Some(exit_key) = self.exit_key {
+            args.push("--override-exit-key".into());
+            args.push(hex::encode(exit_key));
+        }
+        args
+    }
+
+    fn default_profile_path(&self) -> PathBuf {
+        let name = self
+            .name
+            .as_deref()
+            .unwrap_or(&self.endpoint)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+        let mut config_dir = dirs::config_dir().unwrap();
+        config_dir.push(format!("geph4-profile-{}.json", name));
+        config_dir
+    }
+}
+
+/// Writes a profile connecting to the endpoint, at the given path or, for "auto", a path named after the endpoint. Returns where the profile went.
+pub fn write_profile(uri: &ShareUri, profile: &str) -> anyhow::Result<PathBuf> {
+    storage::refuse_if_ephemeral("import-uri")?;
+    let profile = if profile == "auto" {
+        uri.default_profile_path()
+    } else {
+        PathBuf::from(profile)
+    };
+    if let Some(parent) = profile.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&profile, serde_json::to_vec_pretty(&uri.connect_args())?)?;
+    Ok(profile)
+}
+
+/// Entry point to the import-uri subcommand, which turns a geph:// URI into a profile usable as `connect @<profile>`.
+pub fn main_import_uri(opt: ImportUriOpt) -> anyhow::Result<()> {
+    let uri: ShareUri = opt.uri.parse()?;
+    let profile = write_profile(&uri, &opt.profile)?;
+    println!(
+        "{}",
+        tr_args(
+            "import-uri-written",
+            &[("endpoint", &uri.endpoint), ("path", &profile.display())]
+        )
+    );
+    println!("    geph4-client connect @{}", profile.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const EXIT: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    #[test]
+    fn parses_exits() {
+        let uri: ShareUri = format!("geph://exit?endpoint=203.0.113.7:19831&pk={PK}&name=home")
+            .parse()
+            .unwrap();
+        assert_eq!(uri.kind, ShareKind::Exit);
+        assert_eq!(uri.protocol, "sosistab2-obfsudp");
+        assert_eq!(uri.key, vec![1; 32]);
+        assert_eq!(uri.exit_key, None);
+        assert_eq!(uri.name.as_deref(), Some("home"));
+        assert_eq!(
+            uri.connect_args(),
+            vec![
+                "--override-connect",
+                format!("{PK}@203.0.113.7:19831").as_str()
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_bridges() {
+        let uri: ShareUri = format!(
+            "geph://bridge?endpoint=bridge.example:443&protocol=sosistab2-wss&key=abcdef&exit={EXIT}"
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(uri.kind, ShareKind::Bridge);
+        assert_eq!(uri.key, vec![0xab, 0xcd, 0xef]);
+        assert_eq!(uri.exit_key, Some([2; 32]));
+        assert_eq!(
+            uri.connect_args(),
+            vec![
+                "--override-connect",
+                "sosistab2-wss:abcdef@bridge.example:443",
+                "--override-exit-key",
+                EXIT
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_uris() {
+        for bad in [
+            format!("https://exit?endpoint=a:1&pk={PK}"),
+            format!("geph://router?endpoint=a:1&pk={PK}"),
+            format!("geph://exit?pk={PK}"),
+            "geph://exit?endpoint=a:1&pk=0101".to_string(),
+            "geph://exit?endpoint=a:1".to_string(),
+            format!("geph://exit?endpoint=a:1&pk={PK}&protocol=sosistab2-obfstls"),
+            format!("geph://exit?endpoint=a:1&key=ab&protocol=carrier-pigeon"),
+            // a bridge must be pinned to its exit
+            format!("geph://bridge?endpoint=a:1&pk={PK}"),
+            format!("geph://bridge?endpoint=a:1&pk={PK}&exit=0202"),
+        ] {
+            assert!(bad.parse::<ShareUri>().is_err(), "{} was accepted", bad);
+        }
+    }
+}
@@ -1,68 +1,141 @@
 use std::{
-    ffi::CStr,
+    ffi::{CStr, CString},
     format,
     io::Write,
     os::raw::{c_char, c_int, c_uchar},
+    path::Path,
     sync::Arc,
     time::Duration,
 };
 
-use bytes::Bytes;
+use anyhow::Context;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 
 use smol::channel::Receiver;
 use structopt::StructOpt;
 
 use crate::{
     binderproxy::binderproxy_once,
-    config::{override_config, CommonOpt},
+    config::{override_config, Cli, CommonOpt},
     connect::{
+        plan_expiry::plan_status,
+        power::{power_stats, set_power_state as set_power, PowerState},
         start_main_connect,
-        vpn::{vpn_download, vpn_upload},
+        stats::status_snapshot,
+        stop_main_connect,
+        tunnel::{
+            listen_status, postmortem::last_postmortem, roaming::network_hint,
+            tunnel_actor::FATAL_TUNNEL_ERROR,
+        },
+        vpn::{vpn_download, vpn_try_download, vpn_upload, PooledPacket},
+        warm::warm_caches,
     },
     debugpack::{self, DebugPackOpt, DEBUGPACK, TIMESERIES_LOOP},
+    import_uri::ShareUri,
+    log_format::{json_line, log_format, LogFormat},
+    log_levels::{self, set_log_level},
+    logs::previous_run_logs,
     sync::{sync_json, SyncOpt},
     Opt,
 };
 
 static LOG_LINES: Lazy<Receiver<String>> = Lazy::new(|| {
     let (send, recv) = smol::channel::unbounded();
-    env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or("geph4client=debug,geph4_protocol=debug,warn"),
-    )
-    .format_timestamp_millis()
-    .format(move |buf, record| {
-        let line = format!(
-            "[{} {}]: {}",
-            record.level(),
-            record.module_path().unwrap_or("none"),
-            record.args()
-        );
-        writeln!(buf, "{}", line).unwrap();
-        DEBUGPACK.add_logline(&line);
-        // match DEBUGPACK.add_logline(&line) {
-        //     Ok(n) => {
-        //         let _ = send.send_blocking(format!("ADD_LOGLINE wrote {} rows!", n));
-        //         let _ = DEBUGPACK.loglines_count().and_then(|loglines_size| {
-        //             let _ = send.send_blocking(format!(
-        //                 "LOGLINES currently has {} entries!",
-        //                 loglines_size
-        //             ));
-        //             Ok(0)
-        //         });
-        //     }
-        //     Err(e) => {
-        //         let _ = send.send_blocking(format!("ERROR SEEN: {:?}", e));
-        //     }
-        // };
-        let _ = send.send_blocking(line);
-        Ok(())
-    })
-    .init();
+    let mut builder = env_logger::Builder::new();
+    builder
+        .format_timestamp_millis()
+        .format(move |buf, record| {
+            let line = format!(
+                "[{} {}]: {}",
+                record.level(),
+                record.module_path().unwrap_or("none"),
+                record.args()
+            );
+            DEBUGPACK.add_logline(&line);
+            // frontends asking for JSON get it in LOG_LINES too
+            let line = if log_format() == LogFormat::Json {
+                json_line(record)
+            } else {
+                line
+            };
+            writeln!(buf, "{}", line).unwrap();
+            // match DEBUGPACK.add_logline(&line) {
+            //     Ok(n) => {
+            //         let _ = send.send_blocking(format!("ADD_LOGLINE wrote {} rows!", n));
+            //         let _ = DEBUGPACK.loglines_count().and_then(|loglines_size| {
+            //             let _ = send.send_blocking(format!(
+            //                 "LOGLINES currently has {} entries!",
+            //                 loglines_size
+            //             ));
+            //             Ok(0)
+            //         });
+            //     }
+            //     Err(e) => {
+            //         let _ = send.send_blocking(format!("ERROR SEEN: {:?}", e));
+            //     }
+            // };
+            let _ = send.send_blocking(line);
+            Ok(())
+        });
+    log_levels::init(builder).expect("logger already set");
 
     recv
 });
 
+/// A caller-supplied function that gets every tunnel event as a NUL-terminated JSON object, valid only during the call. It is called from a Rust thread.
+pub type EventCallback = extern "C" fn(event: *const c_char);
+
+static EVENT_CALLBACK: Lazy<Mutex<Option<EventCallback>>> = Lazy::new(Default::default);
+
+/// Watches the tunnel once the daemon is started, reporting its state transitions, and errors that reconnecting cannot fix, to the event callback as the tunnel announces them.
+static EVENT_LOOP: Lazy<()> = Lazy::new(|| {
+    smolscale::spawn(async {
+        let mut last_event = "";
+        let mut last_fatal = None;
+        let mut was_connected = false;
+        loop {
+            let listener = listen_status();
+            let snapshot = status_snapshot();
+            let event = if snapshot.state == "connected" {
+                was_connected = true;
+                "connected"
+            } else if was_connected {
+                "reconnecting"
+            } else {
+                "connecting"
+            };
+            if event != last_event {
+                send_event(serde_json::json!({
+                    "event": event,
+                    "exit": snapshot.exit,
+                    "protocols": snapshot.protocols,
+                }));
+                last_event = event;
+            }
+            let fatal = FATAL_TUNNEL_ERROR.lock().clone();
+            if fatal.is_some() && fatal != last_fatal {
+                send_event(serde_json::json!({
+                    "event": "fatal_error",
+                    "message": fatal,
+                }));
+            }
+            last_fatal = fatal;
+            listener.await;
+        }
+    })
+    .detach();
+});
+
+fn send_event(event: serde_json::Value) {
+    let callback = *EVENT_CALLBACK.lock();
+    if let Some(callback) = callback {
+        if let Ok(event) = CString::new(event.to_string()) {
+            callback(event.as_ptr());
+        }
+    }
+}
+
 fn config_logging_ios() {
     log::debug!("TRYING TO CONFIG iOS LOGGING HERE");
     Lazy::force(&LOG_LINES);
@@ -72,7 +145,6 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
     smolscale::permanently_single_threaded();
     let version = env!("CARGO_PKG_VERSION");
     log::info!("IOS geph4-client v{} starting...", version);
-    std::env::set_var("GEPH_VERSION", version);
 
     smol::future::block_on(async move {
         let func = func.as_str();
@@ -81,7 +153,7 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
         match func {
             "start_daemon" => {
                 log::info!("start_daemon selected with args: {:?}", args);
-                let opt = Opt::from_iter_safe(
+                let opt = Cli::from_iter_safe(
                     vec![String::from("geph4-client"), String::from("connect")]
                         .into_iter()
                         .chain(args.into_iter()),
@@ -90,7 +162,8 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                     log::error!("OH NO WEIRD FAIL: {:?}", e);
                     std::thread::sleep(Duration::from_secs(10));
                     e
-                })?;
+                })?
+                .apply();
                 log::info!("parsed Opt: {:?}", opt);
                 override_config(opt);
                 log::info!("override config done");
@@ -98,9 +171,28 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 Lazy::force(&TIMESERIES_LOOP); // must be called *after* CONFIG is set
 
                 start_main_connect();
+                Lazy::force(&EVENT_LOOP);
                 log::info!("called the start_main_connect");
                 Ok("".into())
             }
+            "warm_caches" => {
+                let opt = Opt::from_iter_safe(
+                    vec![String::from("geph4-client"), String::from("connect")]
+                        .into_iter()
+                        .chain(args.into_iter()),
+                )?;
+                let connect_opt = match opt {
+                    Opt::Connect(c) => c,
+                    _ => unreachable!(),
+                };
+                // warming happens in the background, so that app launch isn't held up by the binder
+                std::thread::spawn(move || {
+                    if let Err(err) = smol::future::block_on(warm_caches(&connect_opt)) {
+                        log::warn!("could not warm caches: {:?}", err);
+                    }
+                });
+                Ok("".into())
+            }
             "sync" => {
                 let opt = Opt::from_iter_safe(
                     vec![String::from("geph4-client"), String::from("sync")]
@@ -140,6 +232,23 @@ fn dispatch_ios(func: String, args: Vec<String>) -> anyhow::Result<String> {
                 debugpack::export_debugpak(&dp_opt.export_to)?;
                 anyhow::Ok(dp_opt.export_to)
             }
+            "plan_status" => {
+                // JSON like {"expires_unix": 1717171717, "days_left": 5, "warning": "expires_soon", "downgraded": false}, filled in by the running daemon
+                let status = plan_status();
+                anyhow::Ok(serde_json::to_string(&status)?)
+            }
+            "import_uri" => {
+                // returns the connect arguments, as a JSON array, to pass on to start_daemon
+                let uri: ShareUri = args.first().context("no URI given")?.parse()?;
+                anyhow::Ok(serde_json::to_string(&uri.connect_args())?)
+            }
+            "set_log_level" => {
+                // args are the module, like "geph4client::connect::tunnel", and the level, or "default"
+                let module = args.first().context("no module given")?;
+                let level = args.get(1).context("no level given")?;
+                set_log_level(module, level)?;
+                anyhow::Ok("".into())
+            }
             "version" => anyhow::Ok(String::from(version)),
             _ => anyhow::bail!("function {func} does not exist"),
         }
@@ -206,9 +315,7 @@ pub extern "C" fn upload_packet(pkt: *const c_uchar, len: c_int) {
     // Lazy::force(&VPN_SHUFFLE_TASK);
     unsafe {
         let slice = std::slice::from_raw_parts(pkt as *mut u8, len as usize);
-        let owned = slice.to_vec();
-        let bytes: Bytes = owned.into();
-        vpn_upload(bytes);
+        vpn_upload(slice);
     }
 }
 
@@ -234,6 +341,137 @@ pub extern "C" fn download_packet(buffer: *mut c_uchar, buflen: c_int) -> c_int
     }
 }
 
+#[no_mangle]
+// tears down the tunnel started by start_daemon and flushes its state, returning 0 once done; the daemon cannot be started again in the same process
+pub extern "C" fn stop_geph() -> c_int {
+    stop_main_connect();
+    0
+}
+
+#[no_mangle]
+// tells the daemon the network may have changed, so that it reconnects right away from the new one if so; call this from the NWPathMonitor update handler. Always returns 0
+pub extern "C" fn notify_network_change() -> c_int {
+    network_hint();
+    0
+}
+
+#[no_mangle]
+// sets the function that gets tunnel events ("connecting", "connected", "reconnecting" and "fatal_error") as JSON like {"event": "connected", "exit": "us-hio-01.exits.geph.io", "protocols": ["sosistab2-obfsudp"]}, instead of having to poll the logs. Pass NULL to stop getting them.
+pub extern "C" fn register_event_callback(cb: Option<EventCallback>) {
+    *EVENT_CALLBACK.lock() = cb;
+}
+
+/// A downloaded packet that didn't fit in the caller's buffer, handed out first on the next call.
+static PENDING_DOWNLOAD: Lazy<Mutex<Option<PooledPacket>>> = Lazy::new(Default::default);
+
+#[no_mangle]
+// uploads every packet in the buffer, each prefixed by its length as a big-endian u16, returning how many there were, or -1 if the buffer is malformed
+pub extern "C" fn upload_packets(pkts: *const c_uchar, len: c_int) -> c_int {
+    if len < 0 {
+        return -1;
+    }
+    let mut rest = unsafe { std::slice::from_raw_parts(pkts as *const u8, len as usize) };
+    let mut count = 0;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return -1;
+        }
+        let pkt_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        if rest.len() < 2 + pkt_len {
+            return -1;
+        }
+        vpn_upload(&rest[2..2 + pkt_len]);
+        rest = &rest[2 + pkt_len..];
+        count += 1;
+    }
+    count
+}
+
+#[no_mangle]
+// waits for at least one packet, then fills the buffer with as many as are ready and fit, each prefixed by its length as a big-endian u16, returning the bytes written, or -1 if even the first packet doesn't fit, in which case it is kept for a call with a bigger buffer
+pub extern "C" fn download_packets(buffer: *mut c_uchar, buflen: c_int) -> c_int {
+    if buflen < 0 {
+        return -1;
+    }
+    let first = PENDING_DOWNLOAD
+        .lock()
+        .take()
+        .unwrap_or_else(|| smol::future::block_on(vpn_download()));
+    fill_packets(buffer, buflen, first)
+}
+
+#[no_mangle]
+// like download_packets, but returns 0 right away if no packet is ready
+pub extern "C" fn download_packets_nonblocking(buffer: *mut c_uchar, buflen: c_int) -> c_int {
+    if buflen < 0 {
+        return -1;
+    }
+    let first = PENDING_DOWNLOAD.lock().take().or_else(vpn_try_download);
+    match first {
+        Some(first) => fill_packets(buffer, buflen, first),
+        None => 0,
+    }
+}
+
+fn fill_packets(buffer: *mut c_uchar, buflen: c_int, first: PooledPacket) -> c_int {
+    let slice = unsafe { std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize) };
+    let mut written = 0;
+    let mut next = Some(first);
+    while let Some(pkt) = next.take() {
+        if pkt.len() > u16::MAX as usize {
+            log::debug!("from geph: dropping a packet too long to frame");
+            next = vpn_try_download();
+            continue;
+        }
+        if written + 2 + pkt.len() > slice.len() {
+            *PENDING_DOWNLOAD.lock() = Some(pkt);
+            if written == 0 {
+                log::debug!("from geph: buffer too small!");
+                return -1;
+            }
+            break;
+        }
+        slice[written..written + 2].copy_from_slice(&(pkt.len() as u16).to_be_bytes());
+        slice[written + 2..written + 2 + pkt.len()].copy_from_slice(&pkt);
+        written += 2 + pkt.len();
+        next = vpn_try_download();
+    }
+    written as c_int
+}
+
+#[no_mangle]
+// returns the logs that the last run with the given --crash-log path left behind, for post-mortem debugging
+pub extern "C" fn get_previous_run_logs(
+    crash_log: *const c_char,
+    buffer: *mut c_char,
+    buflen: c_int,
+) -> c_int {
+    let logs = unsafe { CStr::from_ptr(crash_log) }
+        .to_str()
+        .map_err(anyhow::Error::from)
+        .and_then(|path| previous_run_logs(Path::new(path)));
+    let logs = match logs {
+        Ok(logs) => logs,
+        Err(err) => {
+            log::debug!("cannot read previous run logs: {:?}", err);
+            return -1;
+        }
+    };
+
+    unsafe {
+        let mut slice: &mut [u8] =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize);
+        // the oldest lines go if the buffer is too small
+        let start = logs.len().saturating_sub(slice.len().saturating_sub(1));
+        let logs = &logs.as_bytes()[start..];
+        if slice.write_all(logs).is_err() {
+            -1
+        } else {
+            logs.len() as c_int
+        }
+    }
+}
+
 #[no_mangle]
 // returns one line of logs
 pub extern "C" fn get_logs(buffer: *mut c_char, buflen: c_int) -> c_int {
@@ -253,3 +491,85 @@ pub extern "C" fn get_logs(buffer: *mut c_char, buflen: c_int) -> c_int {
         }
     }
 }
+
+#[no_mangle]
+// returns a JSON snapshot of the tunnel's state, without blocking
+pub extern "C" fn get_statistics(buffer: *mut c_char, buflen: c_int) -> c_int {
+    let snapshot = match serde_json::to_string(&status_snapshot()) {
+        Ok(snapshot) => snapshot,
+        Err(_) => return -1,
+    };
+
+    unsafe {
+        let mut slice: &mut [u8] =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize);
+        if snapshot.len() < slice.len() {
+            if slice.write_all(snapshot.as_bytes()).is_err() {
+                -1
+            } else {
+                snapshot.len() as c_int
+            }
+        } else {
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+// tells the daemon what the device runs on: 0 when charging, 1 on battery, 2 in low power mode, which stretches keepalives and pauses background probing and stats sampling. Returns 0, or -1 for an unknown state
+pub extern "C" fn set_power_state(state: c_int) -> c_int {
+    let state = match state {
+        0 => PowerState::Charging,
+        1 => PowerState::Battery,
+        2 => PowerState::LowPower,
+        _ => return -1,
+    };
+    set_power(state);
+    0
+}
+
+#[no_mangle]
+// writes the time spent, wakeups made and background work skipped in each power state as JSON into the buffer, so that the savings can be checked
+pub extern "C" fn get_power_stats(buffer: *mut c_char, buflen: c_int) -> c_int {
+    let stats = match serde_json::to_string(&power_stats()) {
+        Ok(stats) => stats,
+        Err(_) => return -1,
+    };
+
+    unsafe {
+        let mut slice: &mut [u8] =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize);
+        if stats.len() < slice.len() {
+            if slice.write_all(stats.as_bytes()).is_err() {
+                -1
+            } else {
+                stats.len() as c_int
+            }
+        } else {
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+// writes what the latest session to end looked like (duration, traffic, pipes, the error that ended it, and network changes shortly before) as JSON into the buffer, or "null" if none has ended yet
+pub extern "C" fn get_last_postmortem(buffer: *mut c_char, buflen: c_int) -> c_int {
+    let postmortem = match serde_json::to_string(&last_postmortem()) {
+        Ok(postmortem) => postmortem,
+        Err(_) => return -1,
+    };
+
+    unsafe {
+        let mut slice: &mut [u8] =
+            std::slice::from_raw_parts_mut(buffer as *mut u8, buflen as usize);
+        if postmortem.len() < slice.len() {
+            if slice.write_all(postmortem.as_bytes()).is_err() {
+                -1
+            } else {
+                postmortem.len() as c_int
+            }
+        } else {
+            -1
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, fmt::Display};
+
+use once_cell::sync::Lazy;
+
+/// Bundled translations, in a subset of the Fluent syntax: one `id = text` message per line, with `{ $name }` placeholders and `#` comments.
+static BUNDLES: &[(&str, &str)] = &[
+    ("en", include_str!("l10n/en.ftl")),
+    ("zh-CN", include_str!("l10n/zh-CN.ftl")),
+    ("fa", include_str!("l10n/fa.ftl")),
+];
+
+/// Messages in the current language, with English filling in any that aren't translated.
+static MESSAGES: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    let mut messages = parse_bundle(BUNDLES[0].1);
+    let locale = current_locale();
+    let language = locale.split('-').next().unwrap_or_default();
+    let bundle = BUNDLES
+        .iter()
+        .find(|(tag, _)| tag.eq_ignore_ascii_case(&locale))
+        .or_else(|| {
+            BUNDLES.iter().find(|(tag, _)| {
+                tag.split('-')
+                    .next()
+                    .unwrap_or_default()
+                    .eq_ignore_ascii_case(language)
+            })
+        });
+    if let Some((tag, bundle)) = bundle {
+        log::debug!("using {} messages for locale {}", tag, locale);
+        messages.extend(parse_bundle(bundle));
+    }
+    messages
+});
+
+/// Returns the user's locale as a language tag like "zh-CN", from GEPH_LANG or the usual POSIX variables.
+fn current_locale() -> String {
+    ["GEPH_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|val| !val.is_empty())
+        .map(|val| {
+            // "zh_CN.UTF-8" -> "zh-CN"
+            val.split(['.', '@'])
+                .next()
+                .unwrap_or_default()
+                .replace('_', "-")
+        })
+        .unwrap_or_else(|| "en".into())
+}
+
+fn parse_bundle(bundle: &'static str) -> HashMap<&'static str, &'static str> {
+    bundle
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(id, text)| (id.trim(), text.trim()))
+        .collect()
+}
+
+/// Returns the message with the given ID in the user's language.
+pub fn tr(id: &str) -> String {
+    tr_args(id, &[])
+}
+
+/// Returns the message with the given ID in the user's language, filling in its placeholders.
+pub fn tr_args(id: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut message = MESSAGES.get(id).copied().unwrap_or(id).to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{ ${} }}", name), &value.to_string());
+    }
+    message
+}
@@ -0,0 +1,91 @@
+# English messages, also used as the fallback for every other language.
+
+check-credentials = credentials
+check-credential-cache = credential cache
+check-http-listener = HTTP listener
+check-socks5-listener = SOCKS5 listener
+check-stats-listener = stats listener
+check-dns-listener = DNS listener
+check-port-forward = port forward { $desc }
+check-vpn-mode = VPN mode { $mode }
+check-prelogin-conflict = cannot be combined with VPN mode or port forwarding
+check-credentials-missing = --username and --password are required
+check-cannot-bind = cannot bind { $addr }: { $error }
+check-port-forward-syntax = must be in form host:port:::host:port
+check-vpn-fd-unset = GEPH_VPN_FD is not set
+check-vpn-fd-invalid = GEPH_VPN_FD is not a file descriptor number
+check-no-dev-tun = /dev/net/tun does not exist; load the TUN driver with: sudo modprobe tun
+check-tun-needs-root = creating a TUN device requires root; run Geph with sudo
+check-unix-only = only supported on Unix
+check-windows-only = only supported on Windows
+check-no-wintun = wintun.dll was not found; download WinTun from https://www.wintun.net and put the wintun.dll for your architecture next to the Geph executable
+check-would-connect = would connect with:
+check-summary-exit = exit: { $exit }
+check-exit-automatic = automatic ({ $strategy })
+check-summary-bridges = bridges: { $bridges }
+check-yes = yes
+check-auto = auto
+check-summary-http = HTTP proxy on { $addr }
+check-summary-socks5 = SOCKS5 proxy on { $addr }
+check-summary-dns = DNS on { $addr }
+check-summary-stats = stats on { $addr }
+check-summary-forward = forwarding { $from } -> { $to }
+check-summary-vpn = VPN: { $mode }
+check-vpn-disabled = disabled
+check-netns = network namespace { $name }
+check-netns-conflict = cannot be combined with --vpn-mode
+check-linux-only = only supported on Linux
+check-linux-macos-only = only supported on Linux and macOS
+check-system-tools = system tools
+check-missing-tool = { $tool } was not found; install the { $package } package
+check-hint-addr-in-use = another program is already using it; stop that program, or pick another address
+check-hint-low-port = ports below 1024 need root; pick a port above 1024
+check-hint-addr-not-local = this machine has no such address; listen on 127.0.0.1 or 0.0.0.0 instead
+check-preflight-failed = not starting, since the system is missing something the configuration needs (see above)
+
+setup-welcome = Welcome to Geph! This wizard writes a profile with your connection settings.
+setup-username = Username
+setup-password = Password
+setup-logged-in = Logged in as { $username }.
+setup-login-failed = Could not log in ({ $error }), saving the credentials anyway.
+setup-countries = Available countries: { $countries }
+setup-country = Preferred exit country (blank for automatic)
+setup-no-exits-in-country = no exits in country { $country }
+setup-using-exit = Using exit { $exit }.
+setup-exits-failed = Could not fetch the list of exits ({ $error }).
+setup-exit-hostname = Exit hostname (blank for automatic)
+setup-mode = Mode: "proxy" or "vpn"
+setup-mode-invalid = Please answer "proxy" or "vpn".
+setup-written = Profile written to { $path }. Connect with:
+import-uri-written = Imported { $endpoint } into { $path }. Connect with:
+pair-connecting = Connecting to the exit's bootstrap port at { $addr }...
+pair-code = Pairing code: { $code }
+pair-confirm = Does the exit show the same code? [y/N]
+pair-aborted = pairing aborted: the codes did not match
+
+cache-cleared = cache cleared
+cache-exported = exported { $count } entries to { $path }
+cache-imported = imported { $count } entries from { $path }
+
+notify-connected = Geph connected
+notify-connected-body = Connected via { $protocol } to { $address }.
+notify-disconnected = Geph disconnected
+notify-disconnected-body = The connection dropped. Reconnecting...
+notify-plan = Geph plan expiring
+notify-plan-expires-soon = Your plan expires on { $date }. Renew it to keep full speed.
+notify-plan-expires-tomorrow = Your plan expires tomorrow ({ $date }). Renew it to keep full speed.
+notify-plan-expired = Your plan has expired. You will soon be switched to the free plan, with reduced speed.
+notify-downgraded = Geph plan expired
+notify-downgraded-body = You are now on the free plan, with reduced speed. Renew your plan to get full speed back.
+notify-failover = Geph switched exits
+notify-failover-body = Exit { $from } kept failing, so Geph switched to { $to }.
+
+doctor-arch = architecture: { $arch }
+doctor-aes-hardware = AES hardware: { $value }
+doctor-chacha = ChaCha20-Poly1305: { $mbps } MB/s
+doctor-aes-gcm = AES-256-GCM: { $mbps } MB/s
+doctor-yes = yes
+doctor-no = no
+doctor-backend-standard = crypto backend: standard, fast enough for every transport
+doctor-backend-chacha-only = crypto backend: ChaCha20 only, since AES is slow here; TLS-based transports are kept only as a fallback
+doctor-pipes = { $protocol }: up to { $count } pipes
@@ -0,0 +1,91 @@
+# فارسی
+
+check-credentials = اطلاعات حساب
+check-credential-cache = حافظهٔ نهان اطلاعات حساب
+check-http-listener = شنوندهٔ HTTP
+check-socks5-listener = شنوندهٔ SOCKS5
+check-stats-listener = شنوندهٔ آمار
+check-dns-listener = شنوندهٔ DNS
+check-port-forward = باز‌ارسال پورت { $desc }
+check-vpn-mode = حالت VPN { $mode }
+check-prelogin-conflict = نمی‌توان آن را همراه با حالت VPN یا باز‌ارسال پورت به کار برد
+check-credentials-missing = ‏--username و ‏--password الزامی هستند
+check-cannot-bind = اتصال به { $addr } ممکن نیست: { $error }
+check-port-forward-syntax = باید به شکل host:port:::host:port باشد
+check-vpn-fd-unset = ‏GEPH_VPN_FD تنظیم نشده است
+check-vpn-fd-invalid = ‏GEPH_VPN_FD شمارهٔ توصیف‌گر فایل نیست
+check-no-dev-tun = ‏/dev/net/tun وجود ندارد؛ درایور TUN را با این دستور بارگذاری کنید: sudo modprobe tun
+check-tun-needs-root = ساختن دستگاه TUN به دسترسی root نیاز دارد؛ Geph را با sudo اجرا کنید
+check-unix-only = فقط روی یونیکس پشتیبانی می‌شود
+check-windows-only = فقط روی ویندوز پشتیبانی می‌شود
+check-no-wintun = ‏wintun.dll پیدا نشد؛ WinTun را از https://www.wintun.net بگیرید و wintun.dll معماری خود را کنار برنامهٔ Geph بگذارید
+check-would-connect = با این تنظیمات وصل می‌شد:
+check-summary-exit = خروجی: { $exit }
+check-exit-automatic = خودکار ({ $strategy })
+check-summary-bridges = پل‌ها: { $bridges }
+check-yes = بله
+check-auto = خودکار
+check-summary-http = پراکسی HTTP روی { $addr }
+check-summary-socks5 = پراکسی SOCKS5 روی { $addr }
+check-summary-dns = ‏DNS روی { $addr }
+check-summary-stats = آمار روی { $addr }
+check-summary-forward = باز‌ارسال { $from } -> { $to }
+check-summary-vpn = ‏VPN: { $mode }
+check-vpn-disabled = غیرفعال
+check-netns = فضای نام شبکه { $name }
+check-netns-conflict = نمی‌توان آن را همراه با ‏--vpn-mode به کار برد
+check-linux-only = فقط روی لینوکس پشتیبانی می‌شود
+check-linux-macos-only = فقط روی لینوکس و مک‌اواس پشتیبانی می‌شود
+check-system-tools = ابزارهای سیستم
+check-missing-tool = ‏{ $tool } پیدا نشد؛ بستهٔ { $package } را نصب کنید
+check-hint-addr-in-use = برنامهٔ دیگری از آن استفاده می‌کند؛ آن برنامه را ببندید یا نشانی دیگری انتخاب کنید
+check-hint-low-port = پورت‌های زیر ۱۰۲۴ به دسترسی root نیاز دارند؛ پورتی بالاتر از ۱۰۲۴ انتخاب کنید
+check-hint-addr-not-local = این دستگاه چنین نشانی‌ای ندارد؛ به‌جای آن روی 127.0.0.1 یا 0.0.0.0 گوش دهید
+check-preflight-failed = اجرا نمی‌شود، چون سیستم چیزی را که تنظیمات لازم دارد ندارد (بالا را ببینید)
+
+setup-welcome = به Geph خوش آمدید! این راهنما تنظیمات اتصال شما را در یک فایل پروفایل می‌نویسد.
+setup-username = نام کاربری
+setup-password = گذرواژه
+setup-logged-in = با نام { $username } وارد شدید.
+setup-login-failed = ورود ممکن نشد ({ $error })، با این حال اطلاعات حساب ذخیره می‌شود.
+setup-countries = کشورهای موجود: { $countries }
+setup-country = کشور خروجی دلخواه (برای انتخاب خودکار خالی بگذارید)
+setup-no-exits-in-country = هیچ خروجی‌ای در کشور { $country } نیست
+setup-using-exit = از خروجی { $exit } استفاده می‌شود.
+setup-exits-failed = دریافت فهرست خروجی‌ها ممکن نشد ({ $error }).
+setup-exit-hostname = نام میزبان خروجی (برای انتخاب خودکار خالی بگذارید)
+setup-mode = حالت: "proxy" (پراکسی) یا "vpn"
+setup-mode-invalid = لطفاً "proxy" یا "vpn" را وارد کنید.
+setup-written = پروفایل در { $path } نوشته شد. برای اتصال:
+import-uri-written = { $endpoint } در { $path } وارد شد. برای اتصال:
+pair-connecting = در حال اتصال به درگاه راه‌اندازی خروجی در { $addr }...
+pair-code = کد جفت‌سازی: { $code }
+pair-confirm = آیا خروجی همین کد را نشان می‌دهد؟ [y/N]
+pair-aborted = جفت‌سازی لغو شد: کدها یکسان نبودند
+
+cache-cleared = حافظهٔ نهان پاک شد
+cache-exported = { $count } مورد به { $path } صادر شد
+cache-imported = { $count } مورد از { $path } وارد شد
+
+notify-connected = ‏Geph وصل شد
+notify-connected-body = از طریق { $protocol } به { $address } وصل شد.
+notify-disconnected = اتصال Geph قطع شد
+notify-disconnected-body = اتصال قطع شد. در حال اتصال دوباره...
+notify-plan = اشتراک Geph در حال پایان است
+notify-plan-expires-soon = اشتراک شما در { $date } به پایان می‌رسد. برای حفظ سرعت کامل آن را تمدید کنید.
+notify-plan-expires-tomorrow = اشتراک شما فردا ({ $date }) به پایان می‌رسد. برای حفظ سرعت کامل آن را تمدید کنید.
+notify-plan-expired = اشتراک شما به پایان رسیده است. به‌زودی به طرح رایگان با سرعت کمتر منتقل می‌شوید.
+notify-downgraded = اشتراک Geph به پایان رسید
+notify-downgraded-body = اکنون از طرح رایگان با سرعت کمتر استفاده می‌کنید. برای بازگشت سرعت کامل، اشتراک خود را تمدید کنید.
+notify-failover = ‏Geph سرور خروجی را عوض کرد
+notify-failover-body = سرور خروجی { $from } مدام قطع می‌شد، بنابراین Geph به { $to } رفت.
+
+doctor-arch = معماری: { $arch }
+doctor-aes-hardware = شتاب‌دهنده‌ی سخت‌افزاری AES: { $value }
+doctor-chacha = ChaCha20-Poly1305: { $mbps } MB/s
+doctor-aes-gcm = AES-256-GCM: { $mbps } MB/s
+doctor-yes = بله
+doctor-no = خیر
+doctor-backend-standard = رمزنگاری: استاندارد، به‌اندازه‌ی کافی سریع برای همه‌ی روش‌های انتقال
+doctor-backend-chacha-only = رمزنگاری: فقط ChaCha20، چون AES در اینجا کند است؛ روش‌های انتقال مبتنی بر TLS فقط به‌عنوان پشتیبان نگه داشته می‌شوند
+doctor-pipes = { $protocol }: حداکثر { $count } کانال
@@ -0,0 +1,91 @@
+# 简体中文
+
+check-credentials = 账号凭据
+check-credential-cache = 凭据缓存
+check-http-listener = HTTP 监听端口
+check-socks5-listener = SOCKS5 监听端口
+check-stats-listener = 统计监听端口
+check-dns-listener = DNS 监听端口
+check-port-forward = 端口转发 { $desc }
+check-vpn-mode = VPN 模式 { $mode }
+check-prelogin-conflict = 不能与 VPN 模式或端口转发同时使用
+check-credentials-missing = 必须提供 --username 和 --password
+check-cannot-bind = 无法绑定 { $addr }：{ $error }
+check-port-forward-syntax = 格式必须为 host:port:::host:port
+check-vpn-fd-unset = 未设置 GEPH_VPN_FD
+check-vpn-fd-invalid = GEPH_VPN_FD 不是有效的文件描述符编号
+check-no-dev-tun = /dev/net/tun 不存在；请用以下命令加载 TUN 驱动：sudo modprobe tun
+check-tun-needs-root = 创建 TUN 设备需要 root 权限；请用 sudo 运行迷雾通
+check-unix-only = 仅支持 Unix
+check-windows-only = 仅支持 Windows
+check-no-wintun = 找不到 wintun.dll；请从 https://www.wintun.net 下载 WinTun，并把对应架构的 wintun.dll 放到迷雾通程序旁边
+check-would-connect = 将使用以下设置连接：
+check-summary-exit = 出口：{ $exit }
+check-exit-automatic = 自动（{ $strategy }）
+check-summary-bridges = 网桥：{ $bridges }
+check-yes = 是
+check-auto = 自动
+check-summary-http = HTTP 代理位于 { $addr }
+check-summary-socks5 = SOCKS5 代理位于 { $addr }
+check-summary-dns = DNS 位于 { $addr }
+check-summary-stats = 统计接口位于 { $addr }
+check-summary-forward = 转发 { $from } -> { $to }
+check-summary-vpn = VPN：{ $mode }
+check-vpn-disabled = 未启用
+check-netns = 网络命名空间 { $name }
+check-netns-conflict = 不能与 --vpn-mode 同时使用
+check-linux-only = 仅支持 Linux
+check-linux-macos-only = 仅支持 Linux 和 macOS
+check-system-tools = 系统工具
+check-missing-tool = 找不到 { $tool }；请安装 { $package } 软件包
+check-hint-addr-in-use = 已有其他程序在使用该地址；请关闭该程序，或换一个地址
+check-hint-low-port = 1024 以下的端口需要 root 权限；请换一个 1024 以上的端口
+check-hint-addr-not-local = 本机没有这个地址；请改为监听 127.0.0.1 或 0.0.0.0
+check-preflight-failed = 系统缺少当前配置所需的条件（见上文），因此不启动
+
+setup-welcome = 欢迎使用迷雾通！本向导会把您的连接设置写入一个配置文件。
+setup-username = 用户名
+setup-password = 密码
+setup-logged-in = 已登录为 { $username }。
+setup-login-failed = 无法登录（{ $error }），仍将保存凭据。
+setup-countries = 可用国家：{ $countries }
+setup-country = 首选出口国家（留空则自动选择）
+setup-no-exits-in-country = 国家 { $country } 没有可用出口
+setup-using-exit = 使用出口 { $exit }。
+setup-exits-failed = 无法获取出口列表（{ $error }）。
+setup-exit-hostname = 出口主机名（留空则自动选择）
+setup-mode = 模式："proxy"（代理）或 "vpn"
+setup-mode-invalid = 请回答 "proxy" 或 "vpn"。
+setup-written = 配置文件已写入 { $path }。连接命令：
+import-uri-written = 已将 { $endpoint } 导入到 { $path }。连接命令：
+pair-connecting = 正在连接出口的引导端口 { $addr }……
+pair-code = 配对码：{ $code }
+pair-confirm = 出口显示的配对码是否相同？[y/N]
+pair-aborted = 配对已取消：配对码不一致
+
+cache-cleared = 缓存已清除
+cache-exported = 已导出 { $count } 个条目到 { $path }
+cache-imported = 已从 { $path } 导入 { $count } 个条目
+
+notify-connected = 迷雾通已连接
+notify-connected-body = 已通过 { $protocol } 连接到 { $address }。
+notify-disconnected = 迷雾通已断开
+notify-disconnected-body = 连接已中断，正在重新连接……
+notify-plan = 迷雾通套餐即将到期
+notify-plan-expires-soon = 您的套餐将于 { $date } 到期。请续费以保持全速。
+notify-plan-expires-tomorrow = 您的套餐将于明天（{ $date }）到期。请续费以保持全速。
+notify-plan-expired = 您的套餐已到期，即将切换为免费套餐，速度会受到限制。
+notify-downgraded = 迷雾通套餐已到期
+notify-downgraded-body = 您已切换为免费套餐，速度受到限制。续费即可恢复全速。
+notify-failover = 迷雾通已切换出口
+notify-failover-body = 出口 { $from } 多次连接失败，迷雾通已切换到 { $to }。
+
+doctor-arch = 架构：{ $arch }
+doctor-aes-hardware = AES 硬件加速：{ $value }
+doctor-chacha = ChaCha20-Poly1305：{ $mbps } MB/s
+doctor-aes-gcm = AES-256-GCM：{ $mbps } MB/s
+doctor-yes = 是
+doctor-no = 否
+doctor-backend-standard = 加密后端：标准，所有传输方式都足够快
+doctor-backend-chacha-only = 加密后端：仅 ChaCha20，因为本机 AES 较慢；基于 TLS 的传输方式仅作为备用
+doctor-pipes = { $protocol }：最多 { $count } 条管道
@@ -2,6 +2,7 @@ use std::{io::Write, sync::atomic::AtomicUsize};
 use std::{ops::Deref, sync::atomic::Ordering};
 
 mod config;
+mod configshow;
 mod fronts;
 
 mod socks2http;
@@ -14,17 +15,43 @@ use pad::{Alignment, PadStr};
 use crate::{
     config::{Opt, CONFIG},
     debugpack::{DEBUGPACK, TIMESERIES_LOOP},
+    log_format::{json_line, log_format, LogFormat},
 };
+mod binder_stats;
 mod binderproxy;
+mod cache;
 mod china;
+#[cfg(not(feature = "router"))]
+mod completions;
 mod connect;
+mod crypto;
 
 // #[cfg(target_os = "ios")]
 pub mod ios;
 
 mod debugpack;
+mod doctor;
+mod exits;
+mod import_uri;
+mod l10n;
+mod log_format;
+mod log_levels;
+mod logs;
+#[cfg(not(feature = "router"))]
 mod main_bridgetest;
+#[cfg(not(feature = "router"))]
+mod pair;
+mod ping_exit;
+mod plain_output;
+mod puzzle;
+mod run;
+#[cfg(not(feature = "router"))]
+mod setup;
+mod speedtest;
+mod storage;
 mod sync;
+mod uci;
+mod usage;
 
 #[global_allocator]
 pub static ALLOCATOR: Cap<std::alloc::System> = Cap::new(std::alloc::System, usize::max_value());
@@ -36,7 +63,6 @@ pub fn dispatch() -> anyhow::Result<()> {
     config_logging();
     let version = env!("CARGO_PKG_VERSION");
     log::info!("geph4-client v{} starting...", version);
-    std::env::set_var("GEPH_VERSION", version);
 
     smolscale::block_on(async move {
         match CONFIG.deref() {
@@ -46,8 +72,25 @@ pub fn dispatch() -> anyhow::Result<()> {
             }
             Opt::Sync(opt) => sync::main_sync(opt.clone()).await,
             Opt::BinderProxy(opt) => binderproxy::main_binderproxy(opt.clone()).await,
+            #[cfg(not(feature = "router"))]
             Opt::BridgeTest(opt) => main_bridgetest::main_bridgetest(opt.clone()).await,
             Opt::Debugpack(opt) => debugpack::export_debugpak(&opt.export_to),
+            Opt::Exits(opt) => exits::main_exits(opt.clone()).await,
+            Opt::Cache(opt) => cache::main_cache(opt.clone()),
+            Opt::Usage(opt) => usage::main_usage(opt.clone()),
+            #[cfg(not(feature = "router"))]
+            Opt::Setup(opt) => setup::main_setup(opt.clone()).await,
+            #[cfg(not(feature = "router"))]
+            Opt::Completions(opt) => completions::main_completions(opt.clone()),
+            Opt::Config(opt) => configshow::main_config(opt.clone()),
+            Opt::Run(opt) => run::main_run(opt.clone()).await,
+            Opt::PingExit(opt) => ping_exit::main_ping_exit(opt.clone()).await,
+            Opt::Speedtest(opt) => speedtest::main_speedtest(opt.clone()).await,
+            Opt::Doctor(opt) => doctor::main_doctor(opt.clone()),
+            Opt::ImportUri(opt) => import_uri::main_import_uri(opt.clone()),
+            Opt::PreviousLogs(opt) => logs::main_previous_logs(opt.clone()),
+            #[cfg(not(feature = "router"))]
+            Opt::Pair(opt) => pair::main_pair(opt.clone()).await,
         }
     })
 }
@@ -55,37 +98,50 @@ pub fn dispatch() -> anyhow::Result<()> {
 static LONGEST_LINE_EVER: AtomicUsize = AtomicUsize::new(0);
 
 fn config_logging() {
-    if let Err(e) = env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or("geph4client=debug,geph4_protocol=debug,warn"),
-    )
-    .format_timestamp_millis()
-    .format(move |buf, record| {
-        let preamble = format!(
-            "[{} {}]:",
-            record.module_path().unwrap_or("none").dimmed(),
-            match record.level() {
-                log::Level::Error => "ERRO".red(),
-                log::Level::Warn => "WARN".bright_yellow(),
-                log::Level::Info => "INFO".bright_green(),
-                log::Level::Debug => "DEBG".bright_blue(),
-                log::Level::Trace => "TRAC".bright_black(),
-            },
-        );
-        let len = strip_ansi_escapes::strip(&preamble).unwrap().len();
-        let longest = LONGEST_LINE_EVER.fetch_max(len, Ordering::SeqCst);
-        let preamble = ""
-            .pad_to_width_with_alignment(longest.saturating_sub(len), Alignment::Right)
-            + &preamble;
-        let line = format!("{} {}", preamble, record.args());
-        writeln!(buf, "{}", line).unwrap();
-        DEBUGPACK.add_logline(&String::from_utf8_lossy(
-            &strip_ansi_escapes::strip(line).unwrap(),
-        ));
-        Ok(())
-    })
-    .format_target(false)
-    .try_init()
-    {
+    // the log format is picked while parsing the command line
+    Lazy::force(&CONFIG);
+    let mut builder = env_logger::Builder::new();
+    builder
+        .format_timestamp_millis()
+        .format(move |buf, record| {
+            if log_format() == LogFormat::Json {
+                writeln!(buf, "{}", json_line(record)).unwrap();
+                DEBUGPACK.add_logline(&format!(
+                    "[{} {}]: {}",
+                    record.module_path().unwrap_or("none"),
+                    record.level(),
+                    record.args()
+                ));
+                return Ok(());
+            }
+            let preamble = format!(
+                "[{} {}]:",
+                record.module_path().unwrap_or("none").dimmed(),
+                match record.level() {
+                    log::Level::Error => "ERRO".red(),
+                    log::Level::Warn => "WARN".bright_yellow(),
+                    log::Level::Info => "INFO".bright_green(),
+                    log::Level::Debug => "DEBG".bright_blue(),
+                    log::Level::Trace => "TRAC".bright_black(),
+                },
+            );
+            let preamble = if plain_output::plain_output() {
+                preamble
+            } else {
+                let len = strip_ansi_escapes::strip(&preamble).unwrap().len();
+                let longest = LONGEST_LINE_EVER.fetch_max(len, Ordering::SeqCst);
+                "".pad_to_width_with_alignment(longest.saturating_sub(len), Alignment::Right)
+                    + &preamble
+            };
+            let line = format!("{} {}", preamble, record.args());
+            writeln!(buf, "{}", line).unwrap();
+            DEBUGPACK.add_logline(&String::from_utf8_lossy(
+                &strip_ansi_escapes::strip(line).unwrap(),
+            ));
+            Ok(())
+        })
+        .format_target(false);
+    if let Err(e) = log_levels::init(builder) {
         log::debug!("{}", e);
     }
 }
@@ -0,0 +1,60 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Aligned, colored lines for people.
+    Text,
+    /// One JSON object per line for frontends.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => anyhow::bail!("unrecognized log format {}", other),
+        }
+    }
+}
+
+/// Switches to the given log format. Must be called before logging is set up.
+pub fn set_log_format(format: LogFormat) {
+    JSON_LOGS.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// The log format in use.
+pub fn log_format() -> LogFormat {
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+/// Renders a log record as a single-line JSON object, with its timestamp, level, module and message, and where it was logged from as fields.
+pub fn json_line(record: &log::Record) -> String {
+    serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "module": record.module_path().unwrap_or("none"),
+        "message": record.args().to_string(),
+        "fields": {
+            "target": record.target(),
+            "file": record.file(),
+            "line": record.line(),
+        },
+    })
+    .to_string()
+}
@@ -0,0 +1,98 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
+
+/// The filter used when RUST_LOG isn't set.
+pub const DEFAULT_FILTER: &str = "geph4client=debug,geph4_protocol=debug,warn";
+
+/// Levels set at runtime, by module path.
+static OVERRIDES: Lazy<RwLock<BTreeMap<String, LevelFilter>>> = Lazy::new(Default::default);
+
+/// The most verbose level that the start-time filter lets through anywhere.
+static DEFAULT_MAX: OnceCell<LevelFilter> = OnceCell::new();
+
+/// Filters by the levels set at runtime, falling back to the filter given at start.
+struct DynamicLogger {
+    inner: env_logger::Logger,
+    default: env_logger::filter::Filter,
+}
+
+impl Log for DynamicLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match override_for(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.default.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// The level set at runtime for the most specific module containing the target, if any.
+fn override_for(target: &str) -> Option<LevelFilter> {
+    let overrides = OVERRIDES.read();
+    overrides
+        .iter()
+        .filter(|(module, _)| {
+            target == module.as_str()
+                || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|(_, level)| *level)
+}
+
+/// Installs the logger built by `builder`, filtered by RUST_LOG (or [DEFAULT_FILTER]) until [set_log_level] says otherwise. The builder's own filter is ignored.
+pub fn init(mut builder: env_logger::Builder) -> Result<(), log::SetLoggerError> {
+    let spec = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let default = env_logger::filter::Builder::new().parse(&spec).build();
+    let _ = DEFAULT_MAX.set(default.filter());
+    let inner = builder.filter_level(LevelFilter::Trace).build();
+    log::set_boxed_logger(Box::new(DynamicLogger { inner, default }))?;
+    update_max_level();
+    Ok(())
+}
+
+/// Sets the level of a module and its submodules, like "geph4client::connect::tunnel", to "off", "error", "warn", "info", "debug" or "trace", or back to the start-time filter with "default". Lasts until the process exits.
+pub fn set_log_level(module: &str, level: &str) -> anyhow::Result<()> {
+    if module.is_empty() {
+        anyhow::bail!("no module given")
+    }
+    if level == "default" {
+        OVERRIDES.write().remove(module);
+    } else {
+        let level = LevelFilter::from_str(level)
+            .map_err(|_| anyhow::anyhow!("unrecognized log level {}", level))?;
+        OVERRIDES.write().insert(module.to_string(), level);
+    }
+    update_max_level();
+    log::info!("log level of {} set to {}", module, level);
+    Ok(())
+}
+
+/// The levels set at runtime, by module.
+pub fn log_levels() -> Vec<(String, String)> {
+    OVERRIDES
+        .read()
+        .iter()
+        .map(|(module, level)| (module.clone(), level.to_string().to_lowercase()))
+        .collect()
+}
+
+/// Lets the log macros skip right away whatever no filter would let through.
+fn update_max_level() {
+    let max = OVERRIDES.read().values().copied().fold(
+        DEFAULT_MAX.get().copied().unwrap_or(LevelFilter::Off),
+        LevelFilter::max,
+    );
+    log::set_max_level(max);
+}
@@ -1,30 +1,142 @@
-use std::collections::VecDeque;
+use std::{
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+};
 
+use anyhow::Context;
+use memmap2::MmapMut;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::config::CommonOpt;
+
+/// Marks a ring log file, and the layout version.
+const MAGIC: &[u8; 8] = b"GEPHLOG1";
+
+/// The magic, then how many bytes were ever written, as a little-endian u64.
+const HEADER_LEN: usize = 16;
+
+/// How much of the latest logs the crash log keeps, in bytes.
+const CRASH_LOG_CAPACITY: usize = 1 << 20;
+
+/// A ring of the latest log lines in a memory-mapped file. Every line is in the file as soon as it is added, so the logs survive the process crashing or being killed (as the OS does to iOS network extensions).
 pub struct LogBuffer {
-    logs: VecDeque<char>,
-    mem_limit: usize, // in # of characters
+    map: MmapMut,
 }
 
 impl LogBuffer {
-    pub fn new(mem_limit: usize) -> Self {
-        Self {
-            logs: VecDeque::new(),
-            mem_limit,
-        }
+    /// Creates a fresh ring file at the given path, holding up to `mem_limit` bytes of logs.
+    pub fn create(path: &Path, mem_limit: usize) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_LEN + mem_limit) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        map[..MAGIC.len()].copy_from_slice(MAGIC);
+        Ok(Self { map })
     }
 
     pub fn add_line(&mut self, line: &str) {
-        for c in line.chars() {
-            self.logs.push_back(c);
+        let capacity = self.map.len() - HEADER_LEN;
+        let mut written = self.written();
+        for &b in line.as_bytes().iter().chain(b"\n") {
+            self.map[HEADER_LEN + (written % capacity as u64) as usize] = b;
+            written += 1;
         }
-        self.logs.push_back('\n');
+        self.map[MAGIC.len()..HEADER_LEN].copy_from_slice(&written.to_le_bytes());
+    }
+
+    pub fn get_logs(&self) -> String {
+        decode(&self.map).unwrap_or_default()
+    }
+
+    fn written(&self) -> u64 {
+        u64::from_le_bytes(self.map[MAGIC.len()..HEADER_LEN].try_into().unwrap())
+    }
+}
+
+/// Reads the logs out of the contents of a ring file, oldest first. Once the ring has wrapped around, the oldest, partly overwritten line is left out.
+fn decode(bytes: &[u8]) -> anyhow::Result<String> {
+    if bytes.len() <= HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("not a ring log file")
+    }
+    let written = u64::from_le_bytes(bytes[MAGIC.len()..HEADER_LEN].try_into()?);
+    let data = &bytes[HEADER_LEN..];
+    let capacity = data.len() as u64;
+    if written <= capacity {
+        return Ok(String::from_utf8_lossy(&data[..written as usize]).into_owned());
+    }
+    let split = (written % capacity) as usize;
+    let mut ordered = data[split..].to_vec();
+    ordered.extend_from_slice(&data[..split]);
+    let start = ordered
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    Ok(String::from_utf8_lossy(&ordered[start..]).into_owned())
+}
 
-        while self.logs.len() > self.mem_limit {
-            self.logs.pop_front();
+static CRASH_LOG: OnceCell<Mutex<LogBuffer>> = OnceCell::new();
+
+/// Starts keeping the logs of this run in a ring file at the given path, moving the previous run's to the same path with a ".prev" extension.
+pub fn init_crash_log(path: &Path) {
+    let res = (|| {
+        if path.exists() {
+            std::fs::rename(path, previous_path(path))?;
+        }
+        LogBuffer::create(path, CRASH_LOG_CAPACITY)
+    })();
+    match res {
+        Ok(buffer) => {
+            let _ = CRASH_LOG.set(Mutex::new(buffer));
         }
+        Err(err) => log::warn!("cannot keep crash log at {:?}: {:?}", path, err),
     }
+}
 
-    pub fn get_logs(&self) -> String {
-        self.logs.clone().into_iter().collect()
+/// Adds a line to the crash log, if there is one.
+pub fn crash_log_line(line: &str) {
+    if let Some(buffer) = CRASH_LOG.get() {
+        buffer.lock().add_line(line);
     }
 }
+
+/// The logs of the last run that kept a crash log at the given path, other than this one.
+pub fn previous_run_logs(path: &Path) -> anyhow::Result<String> {
+    // this run's logs only took the place of the previous run's if it keeps a crash log itself
+    let path = if CRASH_LOG.get().is_some() {
+        previous_path(path)
+    } else {
+        path.to_owned()
+    };
+    let bytes = std::fs::read(&path).with_context(|| format!("cannot read {:?}", path))?;
+    decode(&bytes)
+}
+
+fn previous_path(path: &Path) -> PathBuf {
+    let mut previous = path.as_os_str().to_owned();
+    previous.push(".prev");
+    previous.into()
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct PreviousLogsOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(long)]
+    /// The --crash-log path the daemon was run with.
+    pub crash_log: PathBuf,
+}
+
+/// Prints the logs the last daemon run left in its crash log, for post-mortem debugging.
+pub fn main_previous_logs(opt: PreviousLogsOpt) -> anyhow::Result<()> {
+    print!("{}", previous_run_logs(&opt.crash_log)?);
+    Ok(())
+}
@@ -1,10 +1,28 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-
+use anyhow::Context;
+use futures_util::future::join_all;
+use geph4_protocol::{binder::protocol::BridgeDescriptor, client_exit::CLIENT_EXIT_PSEUDOHOST};
+use http_types::{url::Position, Url};
 use serde::{Deserialize, Serialize};
-
+use smol_str::SmolStr;
+use smol_timeout::TimeoutExt;
+use sosistab2::{Multiplex, MuxPublic, MuxSecret, ObfsUdpPublic};
 use structopt::StructOpt;
 
-use crate::config::{AuthOpt, CommonOpt};
+use crate::{
+    config::{get_cached_binder_client, AuthOpt, CommonOpt},
+    connect::tunnel::{
+        getsess::{connect_bridge_pipe, test_bridge},
+        tunnel_actor::authenticate_session,
+    },
+};
 
 #[derive(Debug, StructOpt, Clone, Deserialize, Serialize)]
 pub struct BridgeTestOpt {
@@ -17,53 +35,236 @@ pub struct BridgeTestOpt {
     #[structopt(long)]
     /// Whether to use TCP
     use_tcp: bool,
+
+    #[structopt(long)]
+    /// Only test bridges of the given exits. May have multiple ones. If not given, bridges of all exits are tested.
+    exit: Vec<String>,
+
+    #[structopt(long)]
+    /// Instead of testing bridges known to the binder, test the bridge described in the given JSON file end-to-end, including the handshake with its exit.
+    descriptor: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Hex-encoded end-to-end public key of the exit behind --descriptor. Only needed when it cannot be deduced from the descriptor itself, as with obfstls bridges.
+    exit_key: Option<String>,
+
+    #[structopt(long)]
+    /// Keep testing the bridges on an interval, reporting whenever a bridge becomes reachable or blocked.
+    watch: bool,
+
+    #[structopt(long, default_value = "60")]
+    /// Interval, in seconds, between two rounds of testing in watch mode.
+    interval: u64,
+
+    #[structopt(long)]
+    /// In watch mode, HTTP(S) URL to which every reachability change is POSTed as JSON.
+    webhook: Option<String>,
+
+    #[structopt(long)]
+    /// In watch mode, file to which every reachability change is appended as a line of JSON.
+    events_file: Option<PathBuf>,
+}
+
+/// A change in the reachability of a bridge, reported in watch mode.
+#[derive(Clone, Debug, Serialize)]
+struct BridgeEvent {
+    timestamp: u64,
+    exit: SmolStr,
+    endpoint: SocketAddr,
+    protocol: SmolStr,
+    reachable: bool,
+    error: Option<String>,
 }
 
 /// Entry point to the bridgetest subcommand, which sweeps through all available bridges and displays their reachability and performance in table.
-pub async fn main_bridgetest(_opt: BridgeTestOpt) -> anyhow::Result<()> {
-    todo!()
-    // let exits = CACHED_BINDER_CLIENT.get_summary().await?.exits;
-    // for exit in exits {
-    //     log::debug!(
-    //         "EXIT: {} ({}-{})",
-    //         exit.hostname,
-    //         exit.country_code,
-    //         exit.city_code
-    //     );
-    //     let bridges = CACHED_BINDER_CLIENT.get_bridges(&exit.hostname).await?;
-    //     let proto = if opt.use_tcp {
-    //         sosistab::Protocol::DirectTcp
-    //     } else {
-    //         sosistab::Protocol::DirectUdp
-    //     };
-    //     let iter = bridges
-    //         .into_iter()
-    //         .map(|bridge| {
-    //             let proto = proto.clone();
-    //             smolscale::spawn(async move {
-    //                 let start = Instant::now();
-    //                 let sess = sosistab::ClientConfig::new(
-    //                     proto,
-    //                     bridge.endpoint,
-    //                     bridge.sosistab_key,
-    //                     Default::default(),
-    //                 )
-    //                 .connect()
-    //                 .timeout(Duration::from_secs(5))
-    //                 .await;
-    //                 match sess {
-    //                     Some(Ok(_)) => {
-    //                         log::debug!(">>> {} ({:?})", bridge.endpoint, start.elapsed())
-    //                     }
-    //                     Some(Err(e)) => log::debug!(">>> {} (!! ERR: {} !!)", bridge.endpoint, e),
-    //                     None => log::debug!(">>> {} (!! TIMEOUT !!)", bridge.endpoint),
-    //                 }
-    //             })
-    //         })
-    //         .collect::<Vec<_>>();
-    //     for task in iter {
-    //         task.await;
-    //     }
-    // }
-    // Ok(())
+pub async fn main_bridgetest(opt: BridgeTestOpt) -> anyhow::Result<()> {
+    if let Some(descriptor) = &opt.descriptor {
+        return test_descriptor(&opt, descriptor).await;
+    }
+    let mut last_state: HashMap<(SocketAddr, SmolStr), bool> = HashMap::new();
+    loop {
+        let results = match test_once(&opt).await {
+            Ok(results) => results,
+            Err(err) if opt.watch => {
+                log::warn!("could not test bridges: {:?}", err);
+                smol::Timer::after(Duration::from_secs(opt.interval)).await;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        for (bridge, result) in results {
+            match &result {
+                Ok(latency) => println!(
+                    ">>> {} / {} / {} ({:?})",
+                    bridge.exit_hostname, bridge.protocol, bridge.endpoint, latency
+                ),
+                Err(err) => println!(
+                    ">>> {} / {} / {} (!! ERR: {} !!)",
+                    bridge.exit_hostname, bridge.protocol, bridge.endpoint, err
+                ),
+            }
+            let reachable = result.is_ok();
+            let key = (bridge.endpoint, bridge.protocol.clone());
+            // only transitions are reported, not the first observation of a bridge
+            if opt.watch
+                && matches!(last_state.insert(key, reachable), Some(prev) if prev != reachable)
+            {
+                let event = BridgeEvent {
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    exit: bridge.exit_hostname.clone(),
+                    endpoint: bridge.endpoint,
+                    protocol: bridge.protocol.clone(),
+                    reachable,
+                    error: result.err().map(|e| e.to_string()),
+                };
+                if let Err(err) = report_event(&opt, &event).await {
+                    log::warn!("could not report bridge event: {:?}", err);
+                }
+            }
+        }
+        if !opt.watch {
+            return Ok(());
+        }
+        smol::Timer::after(Duration::from_secs(opt.interval)).await;
+    }
+}
+
+/// Tests a locally supplied bridge descriptor end-to-end: pipe connection, multiplex stream to the exit, and authentication.
+async fn test_descriptor(opt: &BridgeTestOpt, path: &Path) -> anyhow::Result<()> {
+    let desc: BridgeDescriptor =
+        serde_json::from_slice(&std::fs::read(path).context("cannot read bridge descriptor")?)
+            .context("cannot parse bridge descriptor")?;
+    let e2e_key = if let Some(exit_key) = &opt.exit_key {
+        let raw: [u8; 32] = hex::decode(exit_key)
+            .context("exit key is not hex")?
+            .as_slice()
+            .try_into()
+            .context("exit key must be 32 bytes")?;
+        MuxPublic::from_bytes(raw)
+    } else {
+        bincode::deserialize::<(ObfsUdpPublic, MuxPublic)>(&desc.sosistab_key)
+            .context("cannot deduce the exit key from the descriptor; pass --exit-key")?
+            .1
+    };
+    println!(
+        ">>> testing {} / {} (exit {})",
+        desc.protocol, desc.endpoint, desc.exit_hostname
+    );
+
+    let start = Instant::now();
+    let pipe = connect_bridge_pipe(&desc).await?;
+    println!(">>> pipe connected in {:?}", start.elapsed());
+
+    let mplex = Multiplex::new(MuxSecret::generate(), Some(e2e_key));
+    mplex.add_pipe(pipe);
+    let start = Instant::now();
+    mplex
+        .open_conn(CLIENT_EXIT_PSEUDOHOST)
+        .timeout(Duration::from_secs(30))
+        .await
+        .context("exit handshake timed out")??;
+    println!(">>> exit handshake completed in {:?}", start.elapsed());
+
+    let binder_client = get_cached_binder_client(&opt.common, &opt.auth)?;
+    let token = binder_client.get_auth_token().await?.1;
+    let start = Instant::now();
+    let vpn_ip = authenticate_session(&mplex, &token)
+        .timeout(Duration::from_secs(30))
+        .await
+        .context("authentication timed out")??;
+    println!(
+        ">>> authenticated in {:?}, VPN address {}",
+        start.elapsed(),
+        vpn_ip
+    );
+    Ok(())
+}
+
+/// Tests every selected bridge once, concurrently.
+async fn test_once(
+    opt: &BridgeTestOpt,
+) -> anyhow::Result<Vec<(BridgeDescriptor, anyhow::Result<Duration>)>> {
+    let binder_client = get_cached_binder_client(&opt.common, &opt.auth)?;
+    let exits = binder_client.get_summary().await?.exits;
+    let mut bridges = vec![];
+    for exit in exits {
+        if !opt.exit.is_empty() && !opt.exit.iter().any(|e| e == exit.hostname.as_str()) {
+            continue;
+        }
+        log::debug!(
+            "EXIT: {} ({}-{})",
+            exit.hostname,
+            exit.country_code,
+            exit.city_code
+        );
+        bridges.extend(
+            binder_client
+                .get_bridges_v2(&exit.hostname, true)
+                .await?
+                .into_iter()
+                .filter(|b| !opt.use_tcp || b.protocol == "sosistab2-obfstls"),
+        );
+    }
+    Ok(join_all(bridges.into_iter().map(|bridge| async move {
+        let result = test_bridge(&bridge).await;
+        (bridge, result)
+    }))
+    .await)
+}
+
+/// Reports a bridge event to the events file and the webhook, if configured.
+async fn report_event(opt: &BridgeTestOpt, event: &BridgeEvent) -> anyhow::Result<()> {
+    let line = serde_json::to_string(event)?;
+    log::info!("bridge event: {}", line);
+    if let Some(path) = &opt.events_file {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", line)?;
+    }
+    if let Some(webhook) = opt.webhook.clone() {
+        smol::unblock(move || post_json(&webhook, &line)).await?;
+    }
+    Ok(())
+}
+
+/// POSTs a JSON body to the given HTTP(S) URL, blockingly.
+fn post_json(url: &str, body: &str) -> anyhow::Result<()> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().context("webhook URL has no host")?;
+    let port = url
+        .port_or_known_default()
+        .context("webhook URL has no port")?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        &url[Position::BeforePath..],
+        host,
+        body.len(),
+        body
+    );
+    let tcp = std::net::TcpStream::connect((host, port))?;
+    tcp.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut response = String::new();
+    match url.scheme() {
+        "http" => {
+            let mut tcp = tcp;
+            tcp.write_all(request.as_bytes())?;
+            tcp.read_to_string(&mut response)?;
+        }
+        "https" => {
+            let mut tls = native_tls::TlsConnector::new()?.connect(host, tcp)?;
+            tls.write_all(request.as_bytes())?;
+            tls.read_to_string(&mut response)?;
+        }
+        other => anyhow::bail!("unsupported webhook scheme {other}"),
+    }
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        anyhow::bail!("webhook returned status {:?}", status)
+    }
+    Ok(())
 }
@@ -0,0 +1,234 @@
+use std::{collections::BTreeMap, io::Write, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use smol::{io::BufReader, prelude::*};
+use smol_timeout::TimeoutExt;
+use structopt::StructOpt;
+
+use crate::{
+    config::CommonOpt,
+    import_uri::{write_profile, ShareKind, ShareUri},
+    l10n::{tr, tr_args},
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct PairOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    /// Host name or IP address of the self-hosted exit.
+    pub host: String,
+
+    #[structopt(long, default_value = "19830")]
+    /// The exit's bootstrap port, which it opens while waiting to be paired.
+    pub bootstrap_port: u16,
+
+    #[structopt(long, default_value = "auto")]
+    /// Where to write the profile. The default value is "auto", meaning a platform-specific path named after the exit.
+    pub profile: String,
+}
+
+// The pairing exchange, one JSON object per line, which the exit's bootstrap port speaks:
+//
+// 1. the exit sends a `PairCommit`, committing to its fresh nonce and its key;
+// 2. we send a `PairRequest` with our own fresh nonce;
+// 3. the exit reveals the nonce and key in a `PairResponse`, which must match the commitment.
+//
+// Both sides then show the code derived from both nonces and the key. Someone in between has to commit to a key and nonce before seeing ours, so each attempt only has a one-in-a-million chance of showing the same code as the real exit.
+
+/// What the exit sends first: a commitment to what it reveals later.
+#[derive(Serialize, Deserialize)]
+struct PairCommit {
+    /// The hash of the exit's nonce and public key, in hex, as computed by [commitment].
+    commitment: String,
+}
+
+/// What we send to the bootstrap port: a fresh nonce, so that the pairing code is different every time.
+#[derive(Serialize, Deserialize)]
+struct PairRequest {
+    nonce: String,
+}
+
+/// What the exit answers with.
+#[derive(Serialize, Deserialize)]
+struct PairResponse {
+    /// The exit's public key, in hex.
+    pk: String,
+    /// The protocols the exit supports, and the port of each.
+    protocols: BTreeMap<String, u16>,
+    /// The exit's own fresh nonce.
+    nonce: String,
+}
+
+/// The exit's commitment to its nonce and key.
+fn commitment(server_nonce: &[u8], pk: &[u8]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"geph-pair-commit");
+    hasher.update(server_nonce);
+    hasher.update(pk);
+    hasher.finalize()
+}
+
+/// The short code shown on both sides. Since the exit committed to its nonce and key before seeing our nonce, nobody in between can grind a key of their own until the codes match.
+fn pairing_code(client_nonce: &[u8], server_nonce: &[u8], pk: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"geph-pair");
+    hasher.update(client_nonce);
+    hasher.update(server_nonce);
+    hasher.update(pk);
+    let hash = hasher.finalize();
+    let num = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    format!("{:06}", num % 1_000_000)
+}
+
+/// Runs our side of the pairing exchange, returning the exit's key, its protocols, and the pairing code.
+async fn exchange(
+    conn: &smol::net::TcpStream,
+) -> anyhow::Result<([u8; 32], BTreeMap<String, u16>, String)> {
+    let mut reader = BufReader::new(conn.clone().take(65536));
+    let commit: PairCommit = serde_json::from_str(&read_line(&mut reader).await?)
+        .context("exit sent an invalid pairing commitment")?;
+    let client_nonce: [u8; 16] = rand::random();
+    let mut request = serde_json::to_vec(&PairRequest {
+        nonce: hex::encode(client_nonce),
+    })?;
+    request.push(b'\n');
+    (&*conn).write_all(&request).await?;
+    let resp: PairResponse = serde_json::from_str(&read_line(&mut reader).await?)
+        .context("exit sent an invalid pairing response")?;
+    let pk = <[u8; 32]>::try_from(hex::decode(&resp.pk).context("pk is not hex")?)
+        .ok()
+        .context("pk must be 32 bytes")?;
+    let server_nonce = hex::decode(&resp.nonce).context("nonce is not hex")?;
+    if commitment(&server_nonce, &pk).to_hex().as_str() != commit.commitment {
+        anyhow::bail!("exit revealed a key that does not match its commitment")
+    }
+    let code = pairing_code(&client_nonce, &server_nonce, &pk);
+    Ok((pk, resp.protocols, code))
+}
+
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out waiting for the exit")??;
+    Ok(line)
+}
+
+/// Entry point to the pair subcommand, which pairs with a self-hosted exit over its bootstrap port and writes a profile connecting to it.
+pub async fn main_pair(opt: PairOpt) -> anyhow::Result<()> {
+    let bootstrap = format!("{}:{}", opt.host, opt.bootstrap_port);
+    println!("{}", tr_args("pair-connecting", &[("addr", &bootstrap)]));
+    let conn = smol::net::TcpStream::connect(&bootstrap)
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out connecting to the bootstrap port")??;
+    let (pk, protocols, code) = exchange(&conn).await?;
+    // only obfsudp can be dialed without the binder for now
+    let port = *protocols
+        .get("sosistab2-obfsudp")
+        .context("exit does not support sosistab2-obfsudp")?;
+
+    println!("{}", tr_args("pair-code", &[("code", &code)]));
+    print!("{} ", tr("pair-confirm"));
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        anyhow::bail!(tr("pair-aborted"))
+    }
+
+    let uri = ShareUri {
+        kind: ShareKind::Exit,
+        endpoint: format!("{}:{}", opt.host, port),
+        key: pk.to_vec(),
+        exit_key: None,
+        protocol: "sosistab2-obfsudp".into(),
+        name: Some(opt.host.clone()),
+    };
+    let profile = write_profile(&uri, &opt.profile)?;
+    println!(
+        "{}",
+        tr_args("setup-written", &[("path", &profile.display())])
+    );
+    println!("    geph4-client connect @{}", profile.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The exit's side of the exchange, as its bootstrap port runs it.
+    async fn serve(conn: smol::net::TcpStream, pk: [u8; 32], nonce: [u8; 16]) -> String {
+        let mut reader = BufReader::new(conn.clone());
+        let commit = PairCommit {
+            commitment: commitment(&nonce, &pk).to_hex().to_string(),
+        };
+        let mut line = serde_json::to_vec(&commit).unwrap();
+        line.push(b'\n');
+        (&conn).write_all(&line).await.unwrap();
+        let request: PairRequest =
+            serde_json::from_str(&read_line(&mut reader).await.unwrap()).unwrap();
+        let resp = PairResponse {
+            pk: hex::encode(pk),
+            protocols: [("sosistab2-obfsudp".to_string(), 19831)]
+                .into_iter()
+                .collect(),
+            nonce: hex::encode(nonce),
+        };
+        let mut line = serde_json::to_vec(&resp).unwrap();
+        line.push(b'\n');
+        (&conn).write_all(&line).await.unwrap();
+        pairing_code(&hex::decode(request.nonce).unwrap(), &nonce, &pk)
+    }
+
+    #[test]
+    fn codes_match_on_both_sides() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = smol::spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                serve(conn, [7; 32], [9; 16]).await
+            });
+            let conn = smol::net::TcpStream::connect(addr).await.unwrap();
+            let (pk, protocols, code) = exchange(&conn).await.unwrap();
+            assert_eq!(pk, [7; 32]);
+            assert_eq!(protocols["sosistab2-obfsudp"], 19831);
+            assert_eq!(code, server.await);
+        });
+    }
+
+    #[test]
+    fn revealing_another_key_fails() {
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let _server = smol::spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(conn.clone());
+                let commit = PairCommit {
+                    commitment: commitment(&[9; 16], &[7; 32]).to_hex().to_string(),
+                };
+                let mut line = serde_json::to_vec(&commit).unwrap();
+                line.push(b'\n');
+                (&conn).write_all(&line).await.unwrap();
+                read_line(&mut reader).await.unwrap();
+                let resp = PairResponse {
+                    pk: hex::encode([8u8; 32]),
+                    protocols: BTreeMap::new(),
+                    nonce: hex::encode([9u8; 16]),
+                };
+                let mut line = serde_json::to_vec(&resp).unwrap();
+                line.push(b'\n');
+                (&conn).write_all(&line).await.unwrap();
+            });
+            let conn = smol::net::TcpStream::connect(addr).await.unwrap();
+            assert!(exchange(&conn).await.is_err());
+        });
+    }
+}
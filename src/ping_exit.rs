@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{str_to_duration, ConnectOpt},
+    connect::{ping::ping_exit, TUNNEL},
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct PingExitOpt {
+    #[structopt(flatten)]
+    pub connect: ConnectOpt,
+
+    #[structopt(long, short = "c", default_value = "10")]
+    /// How many probes to send.
+    pub count: u32,
+
+    #[structopt(long, default_value = "1s", parse(try_from_str = str_to_duration))]
+    /// How long to wait between probes, e.g. "500ms".
+    pub interval: Duration,
+
+    #[structopt(long)]
+    /// Print the report as JSON.
+    pub json: bool,
+}
+
+/// Entry point to the ping-exit subcommand, which brings up a tunnel of its own (without any listeners, so it can run next to a daemon) and reports round-trip times to the exit. To probe the session of a running daemon instead, call ping_exit on its control API.
+pub async fn main_ping_exit(opt: PingExitOpt) -> anyhow::Result<()> {
+    log::info!("waiting for the tunnel before probing the exit");
+    while !TUNNEL.status().connected() {
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+    let report = ping_exit(opt.count, opt.interval).await;
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!(
+            "{}: {} probes sent, {} answered, {:.0}% loss",
+            report.exit.as_deref().unwrap_or("exit"),
+            report.sent,
+            report.received,
+            report.loss * 100.0
+        );
+        if let (Some(min), Some(avg), Some(p95)) = (report.min_ms, report.avg_ms, report.p95_ms) {
+            println!("rtt min/avg/p95 = {:.1}/{:.1}/{:.1} ms", min, avg, p95);
+        }
+    }
+    if report.received == 0 {
+        anyhow::bail!("the exit never answered")
+    }
+    Ok(())
+}
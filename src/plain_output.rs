@@ -0,0 +1,21 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PLAIN_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Turns on plain output: no ANSI colors or alignment padding, and stable single-line status updates that screen readers can follow.
+pub fn enable_plain_output() {
+    PLAIN_OUTPUT.store(true, Ordering::Relaxed);
+    colored::control::set_override(false);
+}
+
+/// Whether plain output is on.
+pub fn plain_output() -> bool {
+    PLAIN_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Prints a status update on a line of its own, in the form "status: <state>", only in plain output mode.
+pub fn plain_status(status: &str) {
+    if plain_output() {
+        println!("status: {}", status);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use geph4_protocol::binder::protocol::AuthError;
+use nanorpc::{JrpcRequest, JrpcResponse};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Puzzles harder than this many leading zero bits are refused rather than solved, bounding the work a binder can ask of a phone.
+const MAX_DIFFICULTY: u32 = 24;
+
+/// How long solving one puzzle may take before giving up.
+const SOLVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many puzzles are being solved right now.
+static SOLVING: AtomicUsize = AtomicUsize::new(0);
+
+/// Only one puzzle is ever solved at a time, on a single background thread.
+static SOLVER_LOCK: Lazy<smol::lock::Mutex<()>> = Lazy::new(Default::default);
+
+/// Whether a binder puzzle is being solved, in which case the client is "verifying" rather than connecting.
+pub fn is_solving() -> bool {
+    SOLVING.load(Ordering::Relaxed) > 0
+}
+
+/// A proof-of-work puzzle that the binder issues, instead of answering, when it is under credential-stuffing attack. It is solved by finding a nonce such that the BLAKE3 hash of the seed followed by the little-endian nonce starts with `difficulty` zero bits.
+///
+/// The binder protocol has no error variant of its own for puzzles, so the binder sends one the only way `authenticate` can carry structured data: as an [AuthError::Other] whose message is the puzzle as JSON. nanorpc passes a method's error to the client as the data of the JSON-RPC error.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub seed: String,
+    pub difficulty: u32,
+}
+
+/// The solution, sent as an extra last parameter when retrying the call. nanorpc servers take parameters by position, so a binder that never asked for a puzzle ignores it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PuzzleSolution {
+    puzzle_seed: String,
+    puzzle_solution: u64,
+}
+
+impl Puzzle {
+    /// Extracts the puzzle from a binder response, if the response is one.
+    pub fn from_response(resp: &JrpcResponse) -> Option<Self> {
+        let error = resp.error.as_ref()?;
+        match serde_json::from_value(error.data.clone()).ok()? {
+            AuthError::Other(msg) => serde_json::from_str(&msg).ok(),
+            _ => None,
+        }
+    }
+
+    /// Solves the puzzle on a background thread, returning the request to retry with the solution appended as an extra last parameter.
+    pub async fn solve_for(self, mut req: JrpcRequest) -> anyhow::Result<JrpcRequest> {
+        if self.difficulty > MAX_DIFFICULTY {
+            anyhow::bail!(
+                "refusing binder puzzle of difficulty {} (at most {})",
+                self.difficulty,
+                MAX_DIFFICULTY
+            )
+        }
+        let seed = hex::decode(&self.seed)?;
+        let _guard = SOLVER_LOCK.lock().await;
+        SOLVING.fetch_add(1, Ordering::Relaxed);
+        scopeguard::defer!({
+            SOLVING.fetch_sub(1, Ordering::Relaxed);
+        });
+        log::info!("solving binder puzzle of difficulty {}", self.difficulty);
+        let start = Instant::now();
+        let difficulty = self.difficulty;
+        let nonce = smol::unblock(move || solve(&seed, difficulty, SOLVE_TIMEOUT))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("timed out solving binder puzzle"))?;
+        log::info!("solved binder puzzle in {:?}", start.elapsed());
+        req.params.push(serde_json::to_value(PuzzleSolution {
+            puzzle_seed: self.seed,
+            puzzle_solution: nonce,
+        })?);
+        Ok(req)
+    }
+}
+
+fn solve(seed: &[u8], difficulty: u32, timeout: Duration) -> Option<u64> {
+    let start = Instant::now();
+    for nonce in 0u64.. {
+        if nonce % 65536 == 0 && start.elapsed() > timeout {
+            return None;
+        }
+        if leading_zero_bits(&puzzle_hash(seed, nonce)) >= difficulty {
+            return Some(nonce);
+        }
+    }
+    None
+}
+
+fn puzzle_hash(seed: &[u8], nonce: u64) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(seed);
+    hasher.update(&nonce.to_le_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use nanorpc::{JrpcError, JrpcId};
+
+    use super::*;
+
+    fn failed_with(err: AuthError) -> JrpcResponse {
+        // the way nanorpc servers send back a method's error
+        JrpcResponse {
+            jsonrpc: "2.0".into(),
+            result: None,
+            error: Some(JrpcError {
+                code: -1,
+                message: err.to_string(),
+                data: serde_json::to_value(err).unwrap(),
+            }),
+            id: JrpcId::Number(1),
+        }
+    }
+
+    #[test]
+    fn extracts_puzzles() {
+        let puzzle = Puzzle {
+            seed: "00ff".into(),
+            difficulty: 8,
+        };
+        let resp = failed_with(AuthError::Other(
+            serde_json::to_string(&puzzle).unwrap().into(),
+        ));
+        assert_eq!(Puzzle::from_response(&resp), Some(puzzle));
+        // other errors are not puzzles
+        assert_eq!(
+            Puzzle::from_response(&failed_with(AuthError::TooManyRequests)),
+            None
+        );
+        assert_eq!(
+            Puzzle::from_response(&failed_with(AuthError::Other("database down".into()))),
+            None
+        );
+    }
+
+    #[test]
+    fn solves_puzzles() {
+        let seed = b"binder puzzle";
+        let nonce = solve(seed, 12, Duration::from_secs(60)).unwrap();
+        assert!(leading_zero_bits(&puzzle_hash(seed, nonce)) >= 12);
+        assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0; 4]), 32);
+    }
+
+    #[test]
+    fn appends_the_solution() {
+        let req = JrpcRequest {
+            jsonrpc: "2.0".into(),
+            method: "authenticate".into(),
+            params: vec![serde_json::Value::Null],
+            id: JrpcId::Number(1),
+        };
+        let puzzle = Puzzle {
+            seed: "abcd".into(),
+            difficulty: 4,
+        };
+        let req = smol::block_on(puzzle.solve_for(req)).unwrap();
+        assert_eq!(req.params.len(), 2);
+        assert_eq!(req.params[1]["puzzle_seed"], "abcd");
+        let nonce = req.params[1]["puzzle_solution"].as_u64().unwrap();
+        assert!(leading_zero_bits(&puzzle_hash(&[0xab, 0xcd], nonce)) >= 4);
+    }
+
+    #[test]
+    fn refuses_hard_puzzles() {
+        let req = JrpcRequest {
+            jsonrpc: "2.0".into(),
+            method: "authenticate".into(),
+            params: vec![],
+            id: JrpcId::Number(1),
+        };
+        let puzzle = Puzzle {
+            seed: "abcd".into(),
+            difficulty: MAX_DIFFICULTY + 1,
+        };
+        assert!(smol::block_on(puzzle.solve_for(req)).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::ConnectOpt,
+    connect::{start_main_connect, TUNNEL},
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct RunOpt {
+    #[structopt(flatten)]
+    pub connect: ConnectOpt,
+
+    #[structopt(last = true, required = true)]
+    /// The command to run through Geph, given after "--".
+    pub command: Vec<String>,
+}
+
+/// Entry point to the run subcommand, which brings up the tunnel, runs a command with proxy environment variables pointing at it, and exits with the command's status.
+pub async fn main_run(opt: RunOpt) -> anyhow::Result<()> {
+    start_main_connect();
+    log::info!("waiting for the tunnel before running {:?}", opt.command);
+    while !TUNNEL.status().connected() {
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+
+    let mut cmd = if let Some(netns) = opt.connect.netns.as_ref() {
+        netns_command(netns, &opt.command).await
+    } else {
+        let http_proxy = format!("http://{}", loopback(opt.connect.http_listen));
+        let socks5_proxy = format!("socks5h://{}", loopback(opt.connect.socks5_listen));
+        let mut cmd = smol::process::Command::new(&opt.command[0]);
+        cmd.args(&opt.command[1..]);
+        for var in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+            cmd.env(var, &http_proxy);
+        }
+        for var in ["all_proxy", "ALL_PROXY"] {
+            cmd.env(var, &socks5_proxy);
+        }
+        cmd
+    };
+    let status = cmd.status().await?;
+    log::info!("{:?} exited with {}", opt.command, status);
+    // exiting the process tears down the tunnel and every listener with it
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+/// Builds a command running inside the network namespace, which needs no proxy settings. When started through sudo, the command runs as the invoking user rather than as root.
+async fn netns_command(netns: &str, command: &[String]) -> smol::process::Command {
+    #[cfg(target_os = "linux")]
+    while !crate::connect::vpn::linux_netns::NETNS_READY.load(std::sync::atomic::Ordering::SeqCst) {
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+    let mut cmd = smol::process::Command::new("ip");
+    cmd.args(["netns", "exec", netns]);
+    if let Ok(user) = std::env::var("SUDO_USER") {
+        cmd.args(["sudo", "-E", "-u", &user, "--"]);
+    }
+    cmd.args(command);
+    cmd
+}
+
+/// Listeners bound to every interface are still reached through loopback.
+fn loopback(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip("127.0.0.1".parse().unwrap());
+    }
+    addr
+}
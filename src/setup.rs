@@ -0,0 +1,257 @@
+use std::{io::Write, path::PathBuf};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{get_cached_binder_client, AuthOpt, CommonOpt},
+    l10n::{tr, tr_args},
+    storage,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct SetupOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(flatten)]
+    pub auth: AuthOpt,
+
+    #[structopt(long, default_value = "auto", parse(from_str = str_to_profile_path))]
+    /// Where to write the profile. The default value is "auto", meaning a platform-specific path that Geph gets to pick.
+    pub profile: PathBuf,
+}
+
+fn str_to_profile_path(src: &str) -> PathBuf {
+    if src == "auto" {
+        let mut config_dir = dirs::config_dir().unwrap();
+        config_dir.push("geph4-profile.json");
+        config_dir
+    } else {
+        PathBuf::from(src)
+    }
+}
+
+/// Entry point to the setup subcommand, an interactive wizard that writes a profile usable as `connect @<profile>`.
+pub async fn main_setup(opt: SetupOpt) -> anyhow::Result<()> {
+    storage::refuse_if_ephemeral("setup")?;
+    println!("{}", tr("setup-welcome"));
+    println!();
+
+    let mut auth = opt.auth.clone();
+    auth.username = prompt_nonempty(&tr("setup-username"), &auth.username)?;
+    auth.password = loop {
+        let password = prompt_password(&tr("setup-password"), &auth.password)?;
+        if !password.is_empty() {
+            break password;
+        }
+    };
+    let binder_client = get_cached_binder_client(&opt.common, &auth)?;
+    match binder_client.get_auth_token().await {
+        Ok((user_info, _)) => println!(
+            "{}",
+            tr_args("setup-logged-in", &[("username", &user_info.username)])
+        ),
+        Err(err) => println!("{}", tr_args("setup-login-failed", &[("error", &err)])),
+    }
+    println!();
+
+    let exit_server = match binder_client.get_summary().await {
+        Ok(summary) => {
+            let countries = summary
+                .exits
+                .iter()
+                .map(|exit| exit.country_code.to_lowercase())
+                .sorted()
+                .dedup()
+                .join(", ");
+            println!(
+                "{}",
+                tr_args("setup-countries", &[("countries", &countries)])
+            );
+            let country = prompt(&tr("setup-country"), "")?;
+            if country.is_empty() {
+                None
+            } else {
+                let exit = summary
+                    .exits
+                    .iter()
+                    .filter(|exit| exit.country_code.eq_ignore_ascii_case(&country))
+                    .min_by(|a, b| a.load.total_cmp(&b.load))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(tr_args(
+                            "setup-no-exits-in-country",
+                            &[("country", &country)]
+                        ))
+                    })?;
+                println!(
+                    "{}",
+                    tr_args("setup-using-exit", &[("exit", &exit.hostname)])
+                );
+                Some(exit.hostname.to_string())
+            }
+        }
+        Err(err) => {
+            println!("{}", tr_args("setup-exits-failed", &[("error", &err)]));
+            let hostname = prompt(&tr("setup-exit-hostname"), "")?;
+            if hostname.is_empty() {
+                None
+            } else {
+                Some(hostname)
+            }
+        }
+    };
+    println!();
+
+    let vpn_mode = loop {
+        match prompt(&tr("setup-mode"), "proxy")?.as_str() {
+            "proxy" => break None,
+            "vpn" => {
+                break Some(if cfg!(windows) {
+                    "windivert"
+                } else {
+                    "tun-route"
+                })
+            }
+            _ => println!("{}", tr("setup-mode-invalid")),
+        }
+    };
+
+    let mut args = vec![
+        "--username".to_string(),
+        auth.username.clone(),
+        "--password".to_string(),
+        auth.password.clone(),
+    ];
+    if let Some(exit_server) = exit_server {
+        args.push("--exit-server".into());
+        args.push(exit_server);
+    }
+    if let Some(vpn_mode) = vpn_mode {
+        args.push("--vpn-mode".into());
+        args.push(vpn_mode.into());
+    }
+    if let Some(parent) = opt.profile.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // the profile holds the password
+    storage::write_private(&opt.profile, serde_json::to_vec_pretty(&args)?)?;
+    println!();
+    println!(
+        "{}",
+        tr_args("setup-written", &[("path", &opt.profile.display())])
+    );
+    println!("    geph4-client connect @{}", opt.profile.display());
+    Ok(())
+}
+
+/// Asks a question on the terminal, returning the default if the answer is blank.
+fn prompt(question: &str, default: &str) -> anyhow::Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    std::io::stdout().flush()?;
+    let line = read_answer()?;
+    Ok(if line.is_empty() {
+        default.to_string()
+    } else {
+        line
+    })
+}
+
+/// Like [prompt], but without echoing what is typed or showing the default, for passwords.
+fn prompt_password(question: &str, default: &str) -> anyhow::Result<String> {
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let _no_echo = NoEcho::new();
+    let line = read_answer();
+    println!();
+    let line = line?;
+    Ok(if line.is_empty() {
+        default.to_string()
+    } else {
+        line
+    })
+}
+
+fn read_answer() -> anyhow::Result<String> {
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        anyhow::bail!("unexpected end of input")
+    }
+    Ok(line.trim().to_string())
+}
+
+/// Turns off the terminal's echo until dropped. Does nothing if stdin is not a terminal.
+struct NoEcho {
+    #[cfg(unix)]
+    saved: Option<libc::termios>,
+    #[cfg(windows)]
+    saved: Option<u32>,
+}
+
+impl NoEcho {
+    #[cfg(unix)]
+    fn new() -> Self {
+        let mut term: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } != 0 {
+            return Self { saved: None };
+        }
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+        Self { saved: Some(term) }
+    }
+
+    #[cfg(windows)]
+    fn new() -> Self {
+        use winapi::um::{
+            consoleapi::{GetConsoleMode, SetConsoleMode},
+            processenv::GetStdHandle,
+            winbase::STD_INPUT_HANDLE,
+            wincon::ENABLE_ECHO_INPUT,
+        };
+        unsafe {
+            let handle = GetStdHandle(STD_INPUT_HANDLE);
+            let mut mode = 0;
+            if GetConsoleMode(handle, &mut mode) == 0 {
+                return Self { saved: None };
+            }
+            SetConsoleMode(handle, mode & !ENABLE_ECHO_INPUT);
+            Self { saved: Some(mode) }
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(term) = self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) };
+        }
+        #[cfg(windows)]
+        if let Some(mode) = self.saved {
+            use winapi::um::{
+                consoleapi::SetConsoleMode, processenv::GetStdHandle, winbase::STD_INPUT_HANDLE,
+            };
+            unsafe { SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), mode) };
+        }
+    }
+}
+
+fn prompt_nonempty(question: &str, default: &str) -> anyhow::Result<String> {
+    loop {
+        let answer = prompt(question, default)?;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::connect::drain::{wait_draining, StreamGuard};
+use crate::connect::pac::{pac_file, PAC_PATHS};
 use crate::socks2http::address::{host_addr, Address};
 use crate::socks2http::http_client;
 use crate::socks2http::socks5;
@@ -8,6 +10,7 @@ use http::{
 };
 use http::{HeaderMap, HeaderValue, Version};
 use hyper::{
+    body::HttpBody,
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Request, Response,
@@ -15,8 +18,26 @@ use hyper::{
 use log::trace;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io::Result<()> {
-    let shared_server: SharedProxyServer = ProxyServer::new_shared(proxy_address);
+use std::time::Duration;
+
+/// How long a client may take to send the headers of a request, including the first one on a fresh connection, before the connection is closed.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long idle connections through the tunnel are kept around for reuse by later requests to the same host.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How many idle connections through the tunnel are kept for each host.
+const POOL_MAX_IDLE_PER_HOST: usize = 8;
+
+pub async fn run(
+    listen_addr: SocketAddr,
+    proxy_address: SocketAddr,
+    idempotent_retries: u32,
+    tcp_keepalive: Option<Duration>,
+    credentials: Option<String>,
+) -> std::io::Result<()> {
+    let shared_server: SharedProxyServer =
+        ProxyServer::new_shared(proxy_address, idempotent_retries, credentials);
     let make_service = make_service_fn(|socket: &AddrStream| {
         let client_addr = socket.remote_addr();
         let cloned_server = shared_server.clone();
@@ -27,8 +48,17 @@ pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io:
         }
     });
     let server = hyper::Server::bind(&listen_addr)
+        .tcp_keepalive(tcp_keepalive)
         .http1_only(true)
-        .serve(make_service);
+        .http1_keepalive(true)
+        // some clients shut down their writing half once the request is sent, and still expect the response
+        .http1_half_close(true)
+        // some apps and servers choke on lowercased header names
+        .http1_preserve_header_case(true)
+        .http1_header_read_timeout(HEADER_READ_TIMEOUT)
+        .serve(make_service)
+        // stop accepting once shutdown starts, letting requests in flight finish
+        .with_graceful_shutdown(wait_draining());
     if let Err(err) = server.await {
         use std::io::Error;
         return Err(Error::new(std::io::ErrorKind::Other, err));
@@ -42,6 +72,23 @@ async fn server_dispatch(
     client_addr: SocketAddr,
     proxy_server: SharedProxyServer,
 ) -> std::io::Result<Response<Body>> {
+    let _guard = StreamGuard::new();
+    // a request for the PAC file is addressed to us, rather than being a proxy request with a full URL
+    if req.method() == Method::GET
+        && req.uri().authority().is_none()
+        && PAC_PATHS.contains(&req.uri().path())
+    {
+        return Ok(make_pac_response(&req));
+    }
+    if !proxy_server.is_authorized(&req) {
+        trace!(
+            "HTTP {} {} from {} not authorized",
+            req.method(),
+            req.uri(),
+            client_addr
+        );
+        return Ok(make_auth_required());
+    }
     let host = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
@@ -125,7 +172,27 @@ async fn server_dispatch(
     };
     if Method::CONNECT == req.method() {
         let addr: SocketAddr = proxy_server.addr;
-        let stream = socks5::connect(&host, &addr).await?;
+        let stream = match socks5::connect(&host, &addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                trace!(
+                    "CONNECT {} <-> {} ({}) failed, error: {}",
+                    client_addr,
+                    addr,
+                    host,
+                    err
+                );
+                let status = if err.kind() == ErrorKind::TimedOut {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                };
+                return Ok(make_error_page(
+                    status,
+                    &format!("Cannot connect to {} through the tunnel.", host),
+                ));
+            }
+        };
         trace!(
             "CONNECT relay connected {} <-> {} ({})",
             client_addr,
@@ -159,29 +226,128 @@ async fn server_dispatch(
     } else {
         let method = req.method().clone();
         trace!("HTTP {} {}", method, host);
+        // hyper answers "100 Continue" by itself once the body is read, so the expectation is met here rather than forwarded
+        match req
+            .headers()
+            .get("Expect")
+            .map(|v| v.as_bytes().to_ascii_lowercase())
+        {
+            None => {}
+            Some(expect) if expect == b"100-continue" => {
+                req.headers_mut().remove("Expect");
+            }
+            Some(_) => {
+                return Ok(make_error_page(
+                    StatusCode::EXPECTATION_FAILED,
+                    "Only the 100-continue expectation is supported.",
+                ))
+            }
+        }
+        let client_version = req.version();
+        // an upgrade (like WebSocket) is negotiated with the origin, so its headers have to survive the hop-by-hop cleanup
+        let upgrade = upgrade_protocol(req.headers());
+        let client_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut req));
         let conn_keep_alive = check_keep_alive(req.version(), req.headers(), true);
         clear_hop_headers(req.headers_mut());
         set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
-        let mut res: Response<Body> = match proxy_server.client.request(req).await {
-            Ok(res) => res,
-            Err(err) => {
-                trace!(
-                    "HTTP {} {} <-> {} ({}) relay failed, error: {}",
-                    method,
-                    client_addr,
-                    "127.0.0.1:1080",
-                    host,
-                    err
-                );
-                let mut resp = Response::new(Body::from(format!("Relay failed to {}", host)));
-                *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return Ok(resp);
+        if let Some(protocol) = &upgrade {
+            set_upgrade(req.headers_mut(), protocol);
+        }
+        // bodiless GETs and HEADs can be replayed safely if the tunnel drops them before any response arrives
+        let replayable = (method == Method::GET || method == Method::HEAD)
+            && req.body().is_end_stream()
+            && proxy_server.idempotent_retries > 0;
+        let (uri, version, headers) = (req.uri().clone(), req.version(), req.headers().clone());
+        let mut attempt = 0;
+        let mut res: Response<Body> = loop {
+            match proxy_server.client.request(req).await {
+                Ok(res) => break res,
+                Err(err) if replayable && attempt < proxy_server.idempotent_retries => {
+                    attempt += 1;
+                    log::debug!(
+                        "HTTP {} {} failed before any response ({}), retrying ({}/{})",
+                        method,
+                        uri,
+                        err,
+                        attempt,
+                        proxy_server.idempotent_retries
+                    );
+                    // give the tunnel a moment to fail over to another pipe
+                    smol::Timer::after(Duration::from_millis(500) * attempt).await;
+                    let mut builder = Request::builder()
+                        .method(method.clone())
+                        .uri(uri.clone())
+                        .version(version);
+                    if let Some(h) = builder.headers_mut() {
+                        *h = headers.clone();
+                    }
+                    req = builder.body(Body::empty()).expect("cannot rebuild request");
+                }
+                Err(err) => {
+                    trace!(
+                        "HTTP {} {} <-> {} ({}) relay failed, error: {}",
+                        method,
+                        client_addr,
+                        "127.0.0.1:1080",
+                        host,
+                        err
+                    );
+                    let status = if err.is_timeout() {
+                        StatusCode::GATEWAY_TIMEOUT
+                    } else {
+                        StatusCode::BAD_GATEWAY
+                    };
+                    return Ok(make_error_page(
+                        status,
+                        &format!("Cannot relay the request to {} through the tunnel.", host),
+                    ));
+                }
             }
         };
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let (Some(requested), Some(client_upgrade)) = (upgrade, client_upgrade) {
+                let protocol = upgrade_protocol(res.headers()).unwrap_or(requested);
+                let server_upgrade = hyper::upgrade::on(&mut res);
+                tokio::spawn(async move {
+                    match future::try_join(client_upgrade, server_upgrade).await {
+                        Ok((mut client, mut server)) => {
+                            trace!(
+                                "{} upgrade established {} <-> {}",
+                                protocol,
+                                client_addr,
+                                host
+                            );
+                            if let Err(err) =
+                                tokio::io::copy_bidirectional(&mut client, &mut server).await
+                            {
+                                trace!(
+                                    "{} relay {} <-> {} closed with error {}",
+                                    protocol,
+                                    client_addr,
+                                    host,
+                                    err
+                                );
+                            }
+                        }
+                        Err(err) => trace!(
+                            "failed to upgrade {} <-> {} ({}), error: {}",
+                            client_addr,
+                            host,
+                            protocol,
+                            err
+                        ),
+                    }
+                });
+                clear_hop_headers(res.headers_mut());
+                set_upgrade(res.headers_mut(), &protocol);
+                return Ok(res);
+            }
+        }
         let res_keep_alive =
             conn_keep_alive && check_keep_alive(res.version(), res.headers(), false);
         clear_hop_headers(res.headers_mut());
-        set_conn_keep_alive(res.version(), res.headers_mut(), res_keep_alive);
+        // the response goes out in the client's version, whatever the origin spoke
+        set_conn_keep_alive(client_version, res.headers_mut(), res_keep_alive);
         Ok(res)
     }
 }
@@ -272,10 +438,59 @@ async fn establish_connect_tunnel(
     );
 }
 
+fn make_pac_response(req: &Request<Body>) -> Response<Body> {
+    // point the PAC at the address the client reached us at, since we may be listening on all interfaces
+    let host = req
+        .headers()
+        .get("Host")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| Authority::from_str(h).ok())
+        .map(|authority| authority.host().to_string())
+        .unwrap_or_else(|| "127.0.0.1".into());
+    Response::builder()
+        .header("Content-Type", "application/x-ns-proxy-autoconfig")
+        .body(Body::from(pac_file(&host)))
+        .expect("cannot build PAC response")
+}
+
+fn make_auth_required() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header("Proxy-Authenticate", "Basic realm=\"geph\"")
+        .body(Body::empty())
+        .expect("cannot build 407 response")
+}
+
 fn make_bad_request() -> Response<Body> {
-    let mut resp = Response::new(Body::empty());
-    *resp.status_mut() = StatusCode::BAD_REQUEST;
-    resp
+    make_error_page(
+        StatusCode::BAD_REQUEST,
+        "The request has no valid host to proxy to.",
+    )
+}
+
+/// A small error page that browsers can show, closing the connection since its state can't be trusted afterwards.
+fn make_error_page(status: StatusCode, detail: &str) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let page = format!(
+        "<!DOCTYPE html>\n<html><head><title>{code} {reason}</title></head>\n<body><h1>{code} {reason}</h1>\n<p>{detail}</p>\n<hr><p>geph</p></body></html>\n",
+        code = status.as_u16(),
+        reason = reason,
+        detail = html_escape(detail),
+    );
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Content-Length", page.len())
+        .header("Connection", "close")
+        .body(Body::from(page))
+        .expect("cannot build error page")
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
@@ -324,7 +539,8 @@ fn check_keep_alive(version: Version, headers: &HeaderMap<HeaderValue>, check_pr
     let mut conn_keep_alive = match version {
         Version::HTTP_10 => false,
         Version::HTTP_11 => true,
-        _ => unimplemented!("HTTP Proxy only supports 1.0 and 1.1"),
+        // HTTP/0.9 has no persistent connections
+        _ => false,
     };
 
     if check_proxy {
@@ -372,6 +588,27 @@ fn check_keep_alive(version: Version, headers: &HeaderMap<HeaderValue>, check_pr
     conn_keep_alive
 }
 
+/// The protocol a request asks to switch to, or a response agrees to, through "Connection: upgrade" and "Upgrade".
+fn upgrade_protocol(headers: &HeaderMap<HeaderValue>) -> Option<HeaderValue> {
+    let wants_upgrade = headers
+        .get_all("Connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|part| part.trim().eq_ignore_ascii_case("upgrade"));
+    if wants_upgrade {
+        headers.get("Upgrade").cloned()
+    } else {
+        None
+    }
+}
+
+/// Puts back the upgrade headers that `clear_hop_headers` removes.
+fn set_upgrade(headers: &mut HeaderMap<HeaderValue>, protocol: &HeaderValue) {
+    headers.insert("Connection", HeaderValue::from_static("upgrade"));
+    headers.insert("Upgrade", protocol.clone());
+}
+
 fn clear_hop_headers(headers: &mut HeaderMap<HeaderValue>) {
     // Clear headers indicated by Connection and Proxy-Connection
     let mut extra_headers = Vec::new();
@@ -440,25 +677,207 @@ fn set_conn_keep_alive(version: Version, headers: &mut HeaderMap<HeaderValue>, k
                 headers.insert("Connection", HeaderValue::from_static("close"));
             }
         }
-        _ => unimplemented!("HTTP Proxy only supports 1.0 and 1.1"),
+        _ => {}
     }
 }
 #[derive(Clone)]
 pub struct ProxyServer {
     client: http_client::SocksClient,
     addr: SocketAddr,
+    idempotent_retries: u32,
+    /// The USERNAME:PASSWORD clients must send as Basic credentials, if authentication is on.
+    expected_auth: Option<String>,
 }
 pub type SharedProxyServer = std::sync::Arc<ProxyServer>;
 impl ProxyServer {
-    fn new(addr: SocketAddr) -> ProxyServer {
+    fn new(addr: SocketAddr, idempotent_retries: u32, credentials: Option<String>) -> ProxyServer {
         let connector = http_client::SocksConnector::new(addr);
-        let proxy_client: http_client::SocksClient = hyper::Client::builder().build(connector);
+        let proxy_client: http_client::SocksClient = hyper::Client::builder()
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .http1_preserve_header_case(true)
+            .build(connector);
         ProxyServer {
             addr,
             client: proxy_client,
+            idempotent_retries,
+            expected_auth: credentials,
+        }
+    }
+    fn new_shared(
+        addr: SocketAddr,
+        idempotent_retries: u32,
+        credentials: Option<String>,
+    ) -> SharedProxyServer {
+        std::sync::Arc::new(ProxyServer::new(addr, idempotent_retries, credentials))
+    }
+
+    /// Whether the request carries the right Basic credentials, or no credentials are needed.
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let expected = match &self.expected_auth {
+            Some(expected) => expected,
+            None => return true,
+        };
+        req.headers()
+            .get_all("Proxy-Authorization")
+            .iter()
+            .filter_map(basic_credentials)
+            .any(|creds| {
+                ring::constant_time::verify_slices_are_equal(&creds, expected.as_bytes()).is_ok()
+            })
+    }
+}
+
+/// Decodes the credentials of a Basic Proxy-Authorization value. The scheme name is case-insensitive, as every HTTP authentication scheme's is.
+fn basic_credentials(value: &HeaderValue) -> Option<Vec<u8>> {
+    let (scheme, creds) = value.to_str().ok()?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    base64::decode(creds.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Reads up to and including the blank line ending an HTTP head.
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// A SOCKS5 server that sends every connection to the given address, whatever the client asked for.
+    async fn fake_socks5(listener: TcpListener, target: SocketAddr) {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0u8; greeting[1] as usize];
+                client.read_exact(&mut methods).await.unwrap();
+                client.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await.unwrap();
+                let addr_len = match request[3] {
+                    1 => 4,
+                    4 => 16,
+                    _ => client.read_u8().await.unwrap() as usize,
+                };
+                let mut addr = vec![0u8; addr_len + 2];
+                client.read_exact(&mut addr).await.unwrap();
+                let mut origin = TcpStream::connect(target).await.unwrap();
+                client
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut origin).await;
+            });
         }
     }
-    fn new_shared(addr: SocketAddr) -> SharedProxyServer {
-        std::sync::Arc::new(ProxyServer::new(addr))
+
+    /// An origin that accepts a WebSocket upgrade, then echoes whatever it gets.
+    async fn websocket_echo(listener: TcpListener) {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut conn).await.to_ascii_lowercase();
+        assert!(head.contains("connection: upgrade\r\n"), "{}", head);
+        assert!(head.contains("upgrade: websocket\r\n"), "{}", head);
+        assert!(head.contains("sec-websocket-key: dghlihnhbxbszsbub25jzq==\r\n"));
+        conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = conn.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            conn.write_all(&buf[..n]).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn websocket_passes_through() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let origin_addr = origin.local_addr().unwrap();
+            tokio::spawn(websocket_echo(origin));
+            let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socks_addr = socks.local_addr().unwrap();
+            tokio::spawn(fake_socks5(socks, origin_addr));
+            let proxy_addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            tokio::spawn(run(proxy_addr, socks_addr, 0, None, None));
+
+            let mut client = loop {
+                match TcpStream::connect(proxy_addr).await {
+                    Ok(client) => break client,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            };
+            client
+                .write_all(
+                    format!(
+                        "GET http://{}/chat HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                        origin_addr, origin_addr
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let head = read_head(&mut client).await;
+            assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+            let lower = head.to_ascii_lowercase();
+            assert!(lower.contains("upgrade: websocket\r\n"), "{}", head);
+            assert!(lower.contains("sec-websocket-accept: "), "{}", head);
+
+            client.write_all(b"hello through the proxy").await.unwrap();
+            let mut echoed = [0u8; 23];
+            client.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"hello through the proxy");
+        });
+    }
+
+    #[test]
+    fn basic_scheme_any_case() {
+        let creds = |value| basic_credentials(&HeaderValue::from_static(value));
+        assert_eq!(creds("Basic dXNlcjpwYXNz"), Some(b"user:pass".to_vec()));
+        assert_eq!(creds("basic dXNlcjpwYXNz"), Some(b"user:pass".to_vec()));
+        assert_eq!(creds("BASIC  dXNlcjpwYXNz"), Some(b"user:pass".to_vec()));
+        assert_eq!(creds("Bearer dXNlcjpwYXNz"), None);
+        assert_eq!(creds("Basic"), None);
+    }
+
+    #[test]
+    fn upgrade_needs_connection_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Upgrade", HeaderValue::from_static("websocket"));
+        assert_eq!(upgrade_protocol(&headers), None);
+        headers.insert(
+            "Connection",
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        assert_eq!(
+            upgrade_protocol(&headers),
+            Some(HeaderValue::from_static("websocket"))
+        );
+        clear_hop_headers(&mut headers);
+        assert!(headers.get("Upgrade").is_none());
+        set_upgrade(&mut headers, &HeaderValue::from_static("websocket"));
+        assert_eq!(
+            upgrade_protocol(&headers),
+            Some(HeaderValue::from_static("websocket"))
+        );
     }
 }
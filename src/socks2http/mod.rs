@@ -3,10 +3,22 @@ mod consts;
 mod http_client;
 mod http_local;
 mod socks5;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-pub async fn run_tokio(local_listen_addr: SocketAddr, proxy_address: SocketAddr) {
-    http_local::run(local_listen_addr, proxy_address)
-        .await
-        .unwrap()
+pub async fn run_tokio(
+    local_listen_addr: SocketAddr,
+    proxy_address: SocketAddr,
+    idempotent_retries: u32,
+    tcp_keepalive: Option<Duration>,
+    credentials: Option<String>,
+) {
+    http_local::run(
+        local_listen_addr,
+        proxy_address,
+        idempotent_retries,
+        tcp_keepalive,
+        credentials,
+    )
+    .await
+    .unwrap()
 }
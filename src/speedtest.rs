@@ -0,0 +1,243 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use smol::{io::BufReader, prelude::*};
+use structopt::StructOpt;
+
+use crate::{
+    config::{str_to_duration, ConnectOpt},
+    connect::{
+        ping::{ping_exit, PingReport},
+        tunnel::control::current_exit,
+        TUNNEL,
+    },
+};
+
+/// How many latency probes are sent before any load, and how far apart probes are.
+const IDLE_PROBES: u32 = 5;
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How much each upload request carries. Uploaded bytes only count once the server has answered the request carrying them, so that whatever is still sitting in buffers along the way when time runs out isn't counted.
+const UPLOAD_CHUNK: usize = 4 << 20;
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct SpeedtestOpt {
+    #[structopt(flatten)]
+    pub connect: ConnectOpt,
+
+    #[structopt(long, default_value = "speed.cloudflare.com")]
+    /// The speed test server to download from and upload to, which must answer plain HTTP on port 80 to "GET /__down?bytes=N" and "POST /__up" like Cloudflare's does. Exits have no throughput endpoint of their own, so the results also depend on the path from the exit to this server.
+    pub server: String,
+
+    #[structopt(long, default_value = "10s", parse(try_from_str = str_to_duration))]
+    /// How long to measure each direction for, e.g. "20s".
+    pub duration: Duration,
+
+    #[structopt(long, default_value = "4")]
+    /// How many streams to run at once in each direction, since a single one rarely fills the tunnel.
+    pub streams: usize,
+}
+
+/// What the speed test measured, printed as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpeedtestReport {
+    pub exit: Option<String>,
+    pub server: String,
+    pub duration_secs: f64,
+    pub streams: usize,
+    pub download_bytes: u64,
+    pub download_mbps: f64,
+    pub upload_bytes: u64,
+    pub upload_mbps: f64,
+    /// Round-trip times to the exit before any load.
+    pub idle_latency: PingReport,
+    /// Round-trip times to the exit while downloading, which grow with bufferbloat in the tunnel.
+    pub loaded_latency: PingReport,
+}
+
+/// Entry point to the speedtest subcommand, which brings up a tunnel of its own (without any listeners) to the exit picked by --exit-server and measures download and upload throughput through it, and latency to the exit while idle and under load.
+pub async fn main_speedtest(opt: SpeedtestOpt) -> anyhow::Result<()> {
+    log::info!("waiting for the tunnel before testing its speed");
+    while !TUNNEL.status().connected() {
+        smol::Timer::after(Duration::from_millis(100)).await;
+    }
+    let idle_latency = ping_exit(IDLE_PROBES, PROBE_INTERVAL).await;
+
+    log::info!("measuring download speed for {:?}", opt.duration);
+    let downloaded = Arc::new(AtomicU64::new(0));
+    let loaded_probes = (opt.duration.as_millis() / PROBE_INTERVAL.as_millis()) as u32;
+    let ((), loaded_latency) = futures_util::future::join(
+        measure(opt.duration, opt.streams, {
+            let downloaded = downloaded.clone();
+            let server = opt.server.clone();
+            move || download(server.clone(), downloaded.clone())
+        }),
+        ping_exit(loaded_probes.max(1), PROBE_INTERVAL),
+    )
+    .await;
+
+    log::info!("measuring upload speed for {:?}", opt.duration);
+    let uploaded = Arc::new(AtomicU64::new(0));
+    measure(opt.duration, opt.streams, {
+        let uploaded = uploaded.clone();
+        let server = opt.server.clone();
+        move || upload(server.clone(), uploaded.clone())
+    })
+    .await;
+
+    let secs = opt.duration.as_secs_f64();
+    let download_bytes = downloaded.load(Ordering::Relaxed);
+    let upload_bytes = uploaded.load(Ordering::Relaxed);
+    if download_bytes == 0 && upload_bytes == 0 {
+        anyhow::bail!("could not transfer anything through {}", opt.server)
+    }
+    let report = SpeedtestReport {
+        exit: current_exit(),
+        server: opt.server,
+        duration_secs: secs,
+        streams: opt.streams,
+        download_bytes,
+        download_mbps: download_bytes as f64 * 8.0 / secs / 1_000_000.0,
+        upload_bytes,
+        upload_mbps: upload_bytes as f64 * 8.0 / secs / 1_000_000.0,
+        idle_latency,
+        loaded_latency,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Runs the given transfer on several streams at once for the duration, restarting any stream that ends early, then cancels them all.
+async fn measure<F: Future<Output = anyhow::Result<()>> + Send + 'static>(
+    duration: Duration,
+    streams: usize,
+    transfer: impl Fn() -> F + Clone + Send + 'static,
+) {
+    let workers = (0..streams.max(1))
+        .map(|_| {
+            let transfer = transfer.clone();
+            smolscale::spawn(async move {
+                loop {
+                    if let Err(err) = transfer().await {
+                        log::debug!("speed test stream failed: {:?}", err);
+                        smol::Timer::after(Duration::from_millis(100)).await;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<smol::Task<()>>>();
+    smol::Timer::after(duration).await;
+    drop(workers);
+}
+
+/// Downloads as much as the server will send, counting the bytes, until the stream ends or is dropped.
+async fn download(server: String, counter: Arc<AtomicU64>) -> anyhow::Result<()> {
+    let mut stream = TUNNEL
+        .connect_stream(&format!("{}:80", server))
+        .await
+        .context("cannot open download stream")?;
+    stream
+        .write_all(
+            format!(
+                "GET /__down?bytes=1000000000 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                server
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Uploads zeros as fast as the tunnel takes them, one [UPLOAD_CHUNK] per request on a kept-alive connection, counting the bytes of every request the server answered, until the stream is dropped.
+async fn upload(server: String, counter: Arc<AtomicU64>) -> anyhow::Result<()> {
+    let mut stream = TUNNEL
+        .connect_stream(&format!("{}:80", server))
+        .await
+        .context("cannot open upload stream")?;
+    let mut replies = BufReader::new(stream.clone());
+    let buf = vec![0u8; 65536];
+    loop {
+        stream
+            .write_all(
+                format!(
+                    "POST /__up HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+                    server, UPLOAD_CHUNK
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut left = UPLOAD_CHUNK;
+        while left > 0 {
+            let n = left.min(buf.len());
+            stream.write_all(&buf[..n]).await?;
+            left -= n;
+        }
+        read_reply(&mut replies).await?;
+        counter.fetch_add(UPLOAD_CHUNK as u64, Ordering::Relaxed);
+    }
+}
+
+/// Reads one HTTP response, failing unless it is a success.
+async fn read_reply(replies: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<()> {
+    let mut status = String::new();
+    replies.read_line(&mut status).await?;
+    if !status.starts_with("HTTP/1.1 2") {
+        anyhow::bail!("upload rejected: {}", status.trim())
+    }
+    let mut body_len = 0;
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        if replies.read_line(&mut header).await? == 0 {
+            anyhow::bail!("server hung up mid-reply")
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                body_len = value.trim().parse().context("bad Content-Length")?;
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.trim().eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+    if !chunked {
+        skip(replies, body_len).await?;
+        return Ok(());
+    }
+    loop {
+        let mut size = String::new();
+        replies.read_line(&mut size).await?;
+        let size = usize::from_str_radix(size.trim(), 16).context("bad chunk size")?;
+        // every chunk, even the last and empty one, ends in a CRLF
+        skip(replies, size + 2).await?;
+        if size == 0 {
+            return Ok(());
+        }
+    }
+}
+
+async fn skip(replies: &mut (impl AsyncBufRead + Unpin), len: usize) -> anyhow::Result<()> {
+    if len > 1 << 20 {
+        anyhow::bail!("reply body too large")
+    }
+    let mut body = vec![0u8; len];
+    replies.read_exact(&mut body).await?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+static EPHEMERAL: AtomicBool = AtomicBool::new(false);
+
+/// The files "written" in ephemeral mode, gone when the process exits.
+static MEMORY: Lazy<DashMap<PathBuf, Vec<u8>>> = Lazy::new(DashMap::new);
+
+/// Turns on ephemeral mode, for `--ephemeral`: credentials, bridge lists, usage counters, tokens and logs live only in memory, and nothing is written to disk. Must be called before anything is loaded.
+pub fn enable_ephemeral() {
+    EPHEMERAL.store(true, Ordering::Relaxed);
+}
+
+/// Whether ephemeral mode is on.
+pub fn ephemeral() -> bool {
+    EPHEMERAL.load(Ordering::Relaxed)
+}
+
+/// Fails in ephemeral mode, for what has no point other than writing to disk, like a profile or a log file.
+pub fn refuse_if_ephemeral(what: &str) -> anyhow::Result<()> {
+    if ephemeral() {
+        anyhow::bail!("{} writes to disk, which --ephemeral rules out", what)
+    }
+    Ok(())
+}
+
+/// Reads a whole file, from memory in ephemeral mode.
+pub fn read(path: &Path) -> std::io::Result<Vec<u8>> {
+    if ephemeral() {
+        MEMORY
+            .get(path)
+            .map(|contents| contents.clone())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "not in memory"))
+    } else {
+        std::fs::read(path)
+    }
+}
+
+/// Writes a whole file, to memory in ephemeral mode.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    if ephemeral() {
+        MEMORY.insert(path.to_owned(), contents.as_ref().to_vec());
+        Ok(())
+    } else {
+        std::fs::write(path, contents)
+    }
+}
+
+/// Writes a whole file that only the current user may read. The file is created that way, and an existing one is locked down before anything is written, so that the contents are never readable by others even for a moment. In ephemeral mode it stays in memory like any other.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if !ephemeral() {
+        use std::{
+            io::Write,
+            os::unix::fs::{OpenOptionsExt, PermissionsExt},
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        return file.write_all(contents.as_ref());
+    }
+    write(path, contents)
+}
+
+/// Replaces a file with another, atomically on disk.
+pub fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+    if ephemeral() {
+        let (_, contents) = MEMORY
+            .remove(from)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "not in memory"))?;
+        MEMORY.insert(to.to_owned(), contents);
+        Ok(())
+    } else {
+        std::fs::rename(from, to)
+    }
+}
+
+/// Creates a directory and its parents. Memory needs no directories, so in ephemeral mode this does nothing.
+pub fn create_dir_all(path: &Path) -> std::io::Result<()> {
+    if ephemeral() {
+        Ok(())
+    } else {
+        std::fs::create_dir_all(path)
+    }
+}
+
+/// Removes a file, from memory in ephemeral mode.
+pub fn remove_file(path: &Path) -> std::io::Result<()> {
+    if ephemeral() {
+        MEMORY
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "not in memory"))
+    } else {
+        std::fs::remove_file(path)
+    }
+}
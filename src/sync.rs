@@ -4,7 +4,10 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
-use crate::config::{get_cached_binder_client, AuthOpt, CommonOpt};
+use crate::{
+    config::{get_cached_binder_client, AuthOpt, CommonOpt, ExitSelect},
+    connect::tunnel::exit_select::select_exit,
+};
 
 #[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
 pub struct SyncOpt {
@@ -17,6 +20,14 @@ pub struct SyncOpt {
     /// Forces synchronization of fresh data.
     #[structopt(long)]
     pub force: bool,
+
+    /// Also output the bridges known for the selected exit. Obfuscation keys are not included.
+    #[structopt(long)]
+    pub include_bridges: bool,
+
+    /// Which exit to output bridges for, with --include-bridges. Picked the same way as in connect mode.
+    #[structopt(long)]
+    pub exit_server: Option<String>,
 }
 
 pub async fn main_sync(opt: SyncOpt) -> anyhow::Result<()> {
@@ -56,12 +67,46 @@ pub async fn sync_json(opt: SyncOpt) -> anyhow::Result<String> {
             load: exit.load,
         })
         .collect_vec();
-    Ok(format!(
-        "{{\"exits\": {}, \"user\": {}, \"version\": {:?}}}",
-        serde_json::to_string(&exits)?,
-        serde_json::to_string(&user)?,
-        VERSION
-    ))
+    let bridges = if opt.include_bridges {
+        let exit = select_exit(
+            &binder_client,
+            opt.exit_server.as_deref().unwrap_or_default(),
+            false,
+            ExitSelect::Closest,
+        )
+        .await?;
+        let bridges = binder_client
+            .get_bridges_v2(&exit.hostname, opt.force)
+            .await?
+            .into_iter()
+            .map(|bridge| DumbedDownBridgeDescriptor {
+                exit_hostname: bridge.exit_hostname.into(),
+                protocol: bridge.protocol.into(),
+                endpoint: bridge.endpoint.to_string(),
+                is_direct: bridge.is_direct,
+                alloc_group: bridge.alloc_group.into(),
+                update_time: bridge.update_time,
+            })
+            .collect_vec();
+        Some(bridges)
+    } else {
+        None
+    };
+    Ok(match bridges {
+        Some(bridges) => format!(
+            "{{\"exits\": {}, \"user\": {}, \"version\": {:?}, \"bridges\": {}}}",
+            serde_json::to_string(&exits)?,
+            serde_json::to_string(&user)?,
+            VERSION,
+            serde_json::to_string(&bridges)?
+        ),
+        None => format!(
+            "{{\"exits\": {}, \"user\": {}, \"version\": {:?}}}",
+            serde_json::to_string(&exits)?,
+            serde_json::to_string(&user)?,
+            VERSION
+        ),
+    })
 }
 
 #[derive(Serialize)]
@@ -73,3 +118,13 @@ struct DumbedDownExitDescriptor {
     allowed_levels: Vec<String>,
     load: f64,
 }
+
+#[derive(Serialize)]
+struct DumbedDownBridgeDescriptor {
+    exit_hostname: String,
+    protocol: String,
+    endpoint: String,
+    is_direct: bool,
+    alloc_group: String,
+    update_time: u64,
+}
@@ -0,0 +1,70 @@
+use std::process::Command;
+
+use anyhow::Context;
+use structopt::StructOpt;
+
+use crate::config::ConnectOpt;
+
+/// Reads the `connect` settings stored in OpenWrt's UCI, given as "config" or "config.section" (the section defaults to "main"), and turns them into command-line arguments.
+pub fn uci_args(spec: &str) -> anyhow::Result<Vec<String>> {
+    let spec = if spec.contains('.') {
+        spec.to_string()
+    } else {
+        format!("{}.main", spec)
+    };
+    let output = Command::new("uci")
+        .args(["-q", "show", &spec])
+        .output()
+        .context("cannot run uci")?;
+    if !output.status.success() {
+        anyhow::bail!("uci has no section {}", spec)
+    }
+    let app = ConnectOpt::clap();
+    let is_flag = |name: &str| app.p.flags.iter().any(|flag| flag.b.name == name);
+
+    let mut args = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // lines look like config.section.option='value' 'value2'
+        let (key, values) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let option = match key.strip_prefix(&spec).and_then(|k| k.strip_prefix('.')) {
+            Some(option) if option != "enabled" => option.replace('_', "-"),
+            _ => continue,
+        };
+        let values = parse_values(values);
+        if is_flag(&option) {
+            if values.first().map(|v| v.as_str()) == Some("1") {
+                args.push(format!("--{}", option));
+            }
+        } else {
+            for value in values.into_iter().filter(|v| !v.is_empty()) {
+                args.push(format!("--{}", option));
+                args.push(value);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Parses a UCI value, which is one or more single-quoted strings, with quotes inside written as '\''.
+fn parse_values(raw: &str) -> Vec<String> {
+    let mut values = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = raw.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => quoted = !quoted,
+            '\\' if !quoted && chars.peek() == Some(&'\'') => {
+                current.push('\'');
+                chars.next();
+            }
+            ' ' if !quoted => values.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    values.push(current);
+    values
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::{
+    config::{str_to_usage_path, CommonOpt},
+    storage,
+};
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub struct UsageOpt {
+    #[structopt(flatten)]
+    pub common: CommonOpt,
+
+    #[structopt(
+        long,
+        default_value = "auto",
+        parse(from_str = str_to_usage_path)
+    )]
+    /// Where the daily usage totals recorded by connect are stored, as an SQLite database. The default value is "auto", meaning a platform-specific path that Geph gets to pick.
+    pub usage_path: PathBuf,
+
+    #[structopt(subcommand)]
+    pub action: UsageAction,
+}
+
+#[derive(Debug, StructOpt, Deserialize, Serialize, Clone)]
+pub enum UsageAction {
+    /// Exports the bytes sent and received and the number of sessions, per day.
+    Export {
+        #[structopt(long)]
+        /// Split each day up by the exit the traffic went through.
+        per_exit: bool,
+        #[structopt(long, default_value = "csv")]
+        /// Either "csv" or "json".
+        format: UsageFormat,
+        #[structopt(long)]
+        /// Only export the given month, like "2024-05".
+        month: Option<String>,
+        #[structopt(long)]
+        /// Write to this file instead of standard output.
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UsageFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for UsageFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            x => anyhow::bail!("unrecognized usage export format {}", x),
+        }
+    }
+}
+
+/// Usage totals for one day.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct DayUsage {
+    pub sent_bytes: u64,
+    pub recv_bytes: u64,
+    /// How many times the tunnel came up.
+    pub sessions: u64,
+}
+
+impl DayUsage {
+    pub fn add(&mut self, other: DayUsage) {
+        self.sent_bytes += other.sent_bytes;
+        self.recv_bytes += other.recv_bytes;
+        self.sessions += other.sessions;
+    }
+}
+
+/// Daily usage totals, keyed by local date in the form "2024-05-31".
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageLog {
+    pub days: BTreeMap<String, DayUsage>,
+    /// The same totals split up by exit hostname, for each date. Usage while no exit was known, as with --override-connect, is only in the daily totals.
+    #[serde(default)]
+    pub exits: BTreeMap<String, BTreeMap<String, DayUsage>>,
+}
+
+/// The usage database, with one row per day and exit, where usage while no exit was known has an empty exit. Adding usage only touches that one row, however long the history gets.
+pub struct UsageDb {
+    conn: Connection,
+}
+
+impl UsageDb {
+    /// Opens the usage database, creating it if need be and importing a usage log from the JSON file that older versions kept. In ephemeral mode the database lives in memory, and is gone along with this value.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut legacy = find_legacy_log(path)?;
+        let importing = path.with_extension("json.importing");
+        if let Some((_, found_at)) = legacy.as_mut() {
+            // the database goes where the old log is, so the log has to make way first; an import that fails from here on is retried from where it was moved to
+            if found_at.as_path() == path {
+                storage::rename(found_at, &importing)?;
+                *found_at = importing.clone();
+            }
+        }
+        let conn = if storage::ephemeral() {
+            Connection::open_in_memory()?
+        } else {
+            if let Some(parent) = path.parent() {
+                storage::create_dir_all(parent)?;
+            }
+            Connection::open(path).context("cannot open usage database")?
+        };
+        conn.execute(
+            "create table if not exists usage (
+                date text not null,
+                exit text not null,
+                sent_bytes integer not null,
+                recv_bytes integer not null,
+                sessions integer not null,
+                primary key (date, exit))",
+            [],
+        )?;
+        conn.execute(
+            "create table if not exists legacy_import (imported_from text not null)",
+            [],
+        )?;
+        let db = Self { conn };
+        if let Some((legacy, found_at)) = legacy {
+            db.import_legacy(&legacy, &found_at)?;
+            // only once the rows are in, so that a failed import is retried
+            storage::rename(&found_at, &path.with_extension("json.imported"))?;
+        }
+        Ok(db)
+    }
+
+    /// Opens the usage database without creating or changing anything, for reading it out. None if connect has not created it yet.
+    fn open_read_only(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context("cannot open usage database")?;
+        Ok(Some(Self { conn }))
+    }
+
+    /// Adds the totals of an old JSON usage log, all or nothing, unless a previous import whose log could not be moved aside already did.
+    fn import_legacy(&self, legacy: &UsageLog, found_at: &Path) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let already: i64 =
+            self.conn
+                .query_row("select count(*) from legacy_import", [], |row| row.get(0))?;
+        if already > 0 {
+            log::warn!("the old usage log at {:?} was already imported", found_at);
+            return Ok(());
+        }
+        log::info!("importing the old usage log at {:?}", found_at);
+        for (date, exits) in legacy.exits.iter() {
+            for (exit, usage) in exits {
+                self.add(date, exit, *usage)?;
+            }
+        }
+        for (date, day) in legacy.days.iter() {
+            // whatever the per-exit totals don't account for
+            let mut rest = *day;
+            for usage in legacy.exits.get(date).into_iter().flat_map(|e| e.values()) {
+                rest.sent_bytes = rest.sent_bytes.saturating_sub(usage.sent_bytes);
+                rest.recv_bytes = rest.recv_bytes.saturating_sub(usage.recv_bytes);
+                rest.sessions = rest.sessions.saturating_sub(usage.sessions);
+            }
+            self.add(date, "", rest)?;
+        }
+        self.conn.execute(
+            "insert into legacy_import values (?1)",
+            params![found_at.to_string_lossy()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add(&self, date: &str, exit: &str, usage: DayUsage) -> anyhow::Result<()> {
+        self.conn.execute(
+            "insert into usage values (?1, ?2, ?3, ?4, ?5)
+            on conflict (date, exit) do update set
+                sent_bytes = sent_bytes + excluded.sent_bytes,
+                recv_bytes = recv_bytes + excluded.recv_bytes,
+                sessions = sessions + excluded.sessions",
+            params![
+                date,
+                exit,
+                usage.sent_bytes as i64,
+                usage.recv_bytes as i64,
+                usage.sessions as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Adds each usage to today's totals, and to those of its exit, all or nothing.
+    pub fn add_today(&self, usages: &[(Option<String>, DayUsage)]) -> anyhow::Result<()> {
+        let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let tx = self.conn.unchecked_transaction()?;
+        for (exit, usage) in usages {
+            self.add(&today, exit.as_deref().unwrap_or_default(), *usage)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Reads out every day's totals.
+    pub fn log(&self) -> anyhow::Result<UsageLog> {
+        let mut stmt = self
+            .conn
+            .prepare("select date, exit, sent_bytes, recv_bytes, sessions from usage")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                DayUsage {
+                    sent_bytes: row.get::<_, i64>(2)? as u64,
+                    recv_bytes: row.get::<_, i64>(3)? as u64,
+                    sessions: row.get::<_, i64>(4)? as u64,
+                },
+            ))
+        })?;
+        let mut log = UsageLog::default();
+        for row in rows {
+            let (date, exit, usage) = row?;
+            if !exit.is_empty() {
+                log.exits
+                    .entry(date.clone())
+                    .or_default()
+                    .insert(exit, usage);
+            }
+            log.days.entry(date).or_default().add(usage);
+        }
+        Ok(log)
+    }
+}
+
+/// Finds the usage log older versions kept as JSON: at the given path, where the default used to be, or where an import that failed left it. Nothing is moved; that is up to [UsageDb::open] once the log is imported.
+fn find_legacy_log(path: &Path) -> anyhow::Result<Option<(UsageLog, PathBuf)>> {
+    let mut candidates = vec![path.to_path_buf()];
+    if path.extension().map(|e| e != "json").unwrap_or(true) {
+        candidates.push(path.with_extension("json"));
+    }
+    candidates.push(path.with_extension("json.importing"));
+    for candidate in candidates {
+        let bts = match storage::read(&candidate) {
+            Ok(bts) => bts,
+            Err(_) => continue,
+        };
+        if bts.first() != Some(&b'{') {
+            continue;
+        }
+        let legacy: UsageLog =
+            serde_json::from_slice(&bts).context("cannot parse the old usage log")?;
+        return Ok(Some((legacy, candidate)));
+    }
+    Ok(None)
+}
+
+impl UsageLog {
+    /// Loads the usage log for reading it out, without creating or changing anything: from the database connect keeps, or from the JSON log of older versions if connect hasn't imported it yet. Empty if nothing was recorded yet.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if let Some((legacy, found_at)) = find_legacy_log(path)? {
+            // the database can only be elsewhere if the log is not at its path
+            let db = if found_at == path {
+                None
+            } else {
+                UsageDb::open_read_only(path)?
+            };
+            return match db {
+                Some(db) => db.log(),
+                None => Ok(legacy),
+            };
+        }
+        match UsageDb::open_read_only(path)? {
+            Some(db) => db.log(),
+            None => Ok(UsageLog::default()),
+        }
+    }
+}
+
+/// Entry point to the usage subcommand, which exports the usage recorded by connect.
+pub fn main_usage(opt: UsageOpt) -> anyhow::Result<()> {
+    match opt.action {
+        UsageAction::Export {
+            per_exit,
+            format,
+            month,
+            output,
+        } => {
+            if let Some(month) = &month {
+                chrono::NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+                    .with_context(|| format!("month {:?} is not in the form 2024-05", month))?;
+            }
+            let log = UsageLog::load(&opt.usage_path)?;
+            // one row per day, or per day and exit, where the exit is None for whole days
+            let rows: Vec<(&String, Option<&String>, &DayUsage)> = if per_exit {
+                log.exits
+                    .iter()
+                    .flat_map(|(date, exits)| {
+                        exits.iter().map(move |(exit, day)| (date, Some(exit), day))
+                    })
+                    .collect()
+            } else {
+                log.days
+                    .iter()
+                    .map(|(date, day)| (date, None, day))
+                    .collect()
+            };
+            let days = rows
+                .into_iter()
+                .filter(|(date, _, _)| {
+                    month
+                        .as_ref()
+                        .map(|month| date.starts_with(&format!("{}-", month)))
+                        .unwrap_or(true)
+                })
+                .collect::<Vec<_>>();
+            let mut out: Box<dyn Write> = match &output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            match format {
+                UsageFormat::Csv => {
+                    if per_exit {
+                        write!(out, "date,exit,")?;
+                    } else {
+                        write!(out, "date,")?;
+                    }
+                    writeln!(out, "sent_bytes,recv_bytes,total_bytes,sessions")?;
+                    for (date, exit, day) in days {
+                        match exit {
+                            Some(exit) => write!(out, "{},{},", date, exit)?,
+                            None => write!(out, "{},", date)?,
+                        }
+                        writeln!(
+                            out,
+                            "{},{},{},{}",
+                            day.sent_bytes,
+                            day.recv_bytes,
+                            day.sent_bytes + day.recv_bytes,
+                            day.sessions
+                        )?;
+                    }
+                }
+                UsageFormat::Json => {
+                    let rows = days
+                        .into_iter()
+                        .map(|(date, exit, day)| {
+                            let mut row = serde_json::json!({
+                                "date": date,
+                                "sent_bytes": day.sent_bytes,
+                                "recv_bytes": day.recv_bytes,
+                                "total_bytes": day.sent_bytes + day.recv_bytes,
+                                "sessions": day.sessions,
+                            });
+                            if let Some(exit) = exit {
+                                row["exit"] = exit.as_str().into();
+                            }
+                            row
+                        })
+                        .collect::<Vec<_>>();
+                    writeln!(out, "{}", serde_json::to_string_pretty(&rows)?)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("geph-usage-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn loading_creates_nothing() {
+        let dir = scratch_dir("load");
+        let log = UsageLog::load(&dir.join("usage.db")).unwrap();
+        assert!(log.days.is_empty());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn imports_the_old_log_once() {
+        let dir = scratch_dir("import");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("usage.db");
+        let mut legacy = UsageLog::default();
+        legacy.days.insert(
+            "2024-05-31".into(),
+            DayUsage {
+                sent_bytes: 1,
+                recv_bytes: 2,
+                sessions: 3,
+            },
+        );
+        std::fs::write(
+            path.with_extension("json"),
+            serde_json::to_vec(&legacy).unwrap(),
+        )
+        .unwrap();
+        // exporting reads the old log where it is
+        assert_eq!(
+            UsageLog::load(&path).unwrap().days["2024-05-31"].recv_bytes,
+            2
+        );
+        assert!(!path.exists());
+        for _ in 0..2 {
+            let log = UsageDb::open(&path).unwrap().log().unwrap();
+            assert_eq!(log.days["2024-05-31"].recv_bytes, 2);
+        }
+        assert!(path.with_extension("json.imported").exists());
+        assert!(!path.with_extension("json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}